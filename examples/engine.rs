@@ -0,0 +1,27 @@
+// A chess engine for GUIs on a model, speaking UCI or xboard/CECP over stdin and stdout.
+//   cargo run --release --example engine -- <model.pt> [uci|xboard] [depth]
+
+use std::io::{self, BufReader};
+
+use shallowNNUE::cecp::CecpFrontend;
+use shallowNNUE::engine::{run_frontend, EngineCore, Frontend};
+use shallowNNUE::search::SearchOptions;
+use shallowNNUE::shared_model::SharedModel;
+use shallowNNUE::smp::SmpSettings;
+use shallowNNUE::tools::match_runner::shared_evaluator;
+use shallowNNUE::uci::frontend::UciFrontend;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let model = args.get(1).expect("usage: engine <model.pt> [uci|xboard] [depth]").clone();
+    let depth = args.get(3).map_or(64, |depth| depth.parse().unwrap());
+
+    // Every search thread evaluates with the one loaded model, Threads and Hash are set by the GUI
+    let evaluator = shared_evaluator(SharedModel::load(model, None).unwrap());
+    let core = EngineCore::new(Box::new(evaluator), SearchOptions { depth, ..SearchOptions::default() }, SmpSettings::default()).unwrap();
+    let mut frontend: Box<dyn Frontend> = match args.get(2).map(String::as_str) {
+        Some("xboard") => Box::new(CecpFrontend::new(core, "shallowNNUE".to_string())),
+        _ => Box::new(UciFrontend::new(core, "shallowNNUE".to_string())),
+    };
+    run_frontend(frontend.as_mut(), BufReader::new(io::stdin()), io::stdout().lock()).unwrap();
+}
@@ -0,0 +1,36 @@
+// Scores every FEN in a file with a model, writing fen,score rows. Interrupted runs pick up at the
+// last finished batch when started again with the same arguments, --restart begins from scratch.
+//   cargo run --release --example eval_file -- <model.pt> positions.fen --out scores.csv [--batch 4096] [--restart]
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use shallowNNUE::eval_server::TorchBatchEvaluator;
+use shallowNNUE::tools::eval_file::{eval_file, EvalFileOptions};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let usage = "usage: eval_file <model.pt> <positions.fen> --out <scores.csv> [--batch n] [--restart]";
+    let (model, input) = (args.get(1).expect(usage).clone(), PathBuf::from(args.get(2).expect(usage)));
+    let flag = |name: &str| args.iter().position(|arg| arg == name).and_then(|at| args.get(at + 1));
+    let output = PathBuf::from(flag("--out").expect(usage));
+    let mut options = EvalFileOptions { resume: !args.iter().any(|arg| arg == "--restart"), ..EvalFileOptions::default() };
+    if let Some(batch) = flag("--batch") {
+        options.batch_size = batch.parse().expect(usage);
+    }
+
+    let mut evaluator = TorchBatchEvaluator::load(model, None).unwrap();
+    let done = eval_file(&mut evaluator, &input, &output, &options, &|progress| {
+        eprint!(
+            "\r{:>12} positions {:>6.2}% {:>10.0} positions/s {:>8} skipped",
+            progress.positions,
+            progress.fraction() * 100.0,
+            progress.positions_per_second,
+            progress.skipped
+        );
+        std::io::stderr().flush().ok();
+    })
+    .unwrap();
+    eprintln!();
+    println!("{} positions scored, {} lines skipped, written to {}", done.positions, done.skipped, output.display());
+}
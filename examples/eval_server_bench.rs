@@ -0,0 +1,84 @@
+// Compares batched evaluation through an EvalServer with every thread running its own model.
+//   cargo run --release --example eval_server_bench -- <model.pt> [threads] [positions per thread] [max batch] [max wait us]
+
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chess::{Board, MoveGen};
+use shallowNNUE::eval_server::{EvalServer, EvalServerOptions, TorchBatchEvaluator};
+use shallowNNUE::shallow_nnue::{ShallowNNUE, NNUE};
+
+fn positions(count: usize) -> Vec<Board> {
+    // Walks a fixed line of first legal moves, restarting whenever a game ends
+    let start = Board::from_str("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3").unwrap();
+    let mut board = start;
+    let mut boards = Vec::with_capacity(count);
+    for ply in 0..count {
+        let moves: Vec<_> = MoveGen::new_legal(&board).collect();
+        if moves.is_empty() {
+            board = start;
+            continue;
+        }
+        board = board.make_move_new(moves[ply % moves.len()]);
+        boards.push(board);
+    }
+    boards
+}
+
+fn report(name: &str, positions: usize, elapsed: Duration) {
+    println!("{:<12} {:>10} positions {:>8.1} ms {:>12.0} positions/s", name, positions, elapsed.as_secs_f64() * 1000.0, positions as f64 / elapsed.as_secs_f64());
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let model = args.get(1).expect("usage: eval_server_bench <model.pt> [threads] [positions] [max batch] [max wait us]").clone();
+    let arg = |index: usize, default: usize| args.get(index).map_or(default, |value| value.parse().unwrap());
+    let (threads, count) = (arg(2, 8), arg(3, 2000));
+    let boards = positions(count);
+
+    // Synchronous baseline: one model per thread, one forward per position
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let (model, boards) = (model.clone(), boards.clone());
+            thread::spawn(move || {
+                let mut nnue = ShallowNNUE::new(model).unwrap();
+                for board in &boards {
+                    nnue.set_board_hard(*board).unwrap();
+                    nnue.evaluate().unwrap();
+                }
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|handle| handle.join().unwrap());
+    report("per-thread", threads * boards.len(), start.elapsed());
+
+    for double_buffer in [false, true] {
+        let options = EvalServerOptions {
+            max_batch: arg(4, 256),
+            max_wait: Duration::from_micros(arg(5, 200) as u64),
+            double_buffer,
+            ..EvalServerOptions::default()
+        };
+        let server = EvalServer::new(TorchBatchEvaluator::load(model.clone(), None).unwrap(), options);
+        let start = Instant::now();
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let (client, boards) = (server.client(), boards.clone());
+                thread::spawn(move || {
+                    for board in &boards {
+                        client.evaluate(board).unwrap();
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|handle| handle.join().unwrap());
+        let name = if double_buffer { "double" } else { "server" };
+        report(name, threads * boards.len(), start.elapsed());
+        println!("{:<12} average batch {:.1}, evaluator busy {:.1} ms", "", server.stats().average_batch(), server.stats().busy.as_secs_f64() * 1000.0);
+        if let Some(fallback) = server.stats().last_fallback {
+            println!("{:<12} {} device fallbacks, last {:?}", "", server.stats().device_fallbacks, fallback);
+        }
+    }
+}
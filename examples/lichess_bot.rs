@@ -0,0 +1,23 @@
+// Plays on Lichess as a bot account with a model and the built in search.
+//   LICHESS_TOKEN=<bot token> cargo run --release --features lichess-bot --example lichess_bot -- <model.pt> [depth]
+
+use shallowNNUE::lichess_bot::{BotConfig, LichessBot};
+use shallowNNUE::search::SearchOptions;
+use shallowNNUE::shallow_nnue::ShallowNNUE;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let model = args.get(1).expect("usage: lichess_bot <model.pt> [depth]").clone();
+    let token = std::env::var("LICHESS_TOKEN").expect("set LICHESS_TOKEN to the bot account's API token");
+
+    let mut config = BotConfig::new(token);
+    if let Some(depth) = args.get(2) {
+        config.search = SearchOptions { depth: depth.parse().unwrap(), ..config.search };
+    }
+    config.greeting = Some("Good luck! Type !eval for my evaluation.".to_string());
+
+    let evaluator = ShallowNNUE::new(model).unwrap();
+    let mut bot = LichessBot::connect(config, Box::new(evaluator)).unwrap();
+    println!("playing as {}", bot.account());
+    bot.run().unwrap();
+}
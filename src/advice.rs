@@ -0,0 +1,145 @@
+use chess::{Board, Color, ALL_PIECES};
+
+use crate::search::{piece_value, MATE_SCORE, MAX_PLY};
+
+// Resign and draw offer decisions for a bot playing people, e.g. behind a Lichess bot bridge. Like
+// the match runner's adjudication, but one sided: only the bot's own scores count, and the material
+// on the board has to agree, so a shallow net misjudging a few positions can't give up a level game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    Play,
+    OfferDraw,
+    Resign,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdviceSettings {
+    pub resign_score: i16, // Resign when the bot's score stays at least this far below zero
+    pub resign_moves: usize,
+    pub resign_material: i16, // and it is at least this many pawns down, unless it sees itself mated
+    pub draw_score: i16, // Offer a draw when the score stays within this of zero
+    pub draw_moves: usize, // Also the number of moves before offering again
+    pub draw_min_ply: usize, // No offers before this ply
+    pub draw_material: i16, // Most pawns the material may be out of balance for an offer
+}
+
+impl Default for AdviceSettings {
+    fn default() -> AdviceSettings {
+        AdviceSettings {
+            resign_score: 1000,
+            resign_moves: 4,
+            resign_material: 3,
+            draw_score: 15,
+            draw_moves: 10,
+            draw_min_ply: 60,
+            draw_material: 1,
+        }
+    }
+}
+
+fn material_balance(board: &Board, colour: Color) -> i16 {
+    // In pawns, kings left out
+    ALL_PIECES[..5]
+        .iter()
+        .map(|piece| {
+            let pieces = board.pieces(*piece);
+            let balance = (pieces & board.color_combined(colour)).popcnt() as i32 - (pieces & board.color_combined(!colour)).popcnt() as i32;
+            balance * piece_value(*piece)
+        })
+        .sum::<i32>() as i16
+}
+
+// Keeps the streaks over the bot's moves in one game, record is called once per move
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameAdvisor {
+    settings: AdviceSettings,
+    moves: usize, // Moves recorded
+    ply: usize, // Of the last recorded position
+    losing: usize, // Consecutive moves each rule held for
+    drawish: usize,
+    mated: bool, // The last score was a mate against the bot
+    material: i16, // The bot's balance in the last recorded position
+    offered_at: Option<usize>, // moves when a draw was last offered
+}
+
+impl GameAdvisor {
+    pub fn new(settings: AdviceSettings) -> GameAdvisor {
+        GameAdvisor { settings, moves: 0, ply: 0, losing: 0, drawish: 0, mated: false, material: 0, offered_at: None }
+    }
+
+    pub fn settings(&self) -> AdviceSettings {
+        self.settings
+    }
+
+    pub fn reset(&mut self) {
+        // For a new game, the settings stay
+        *self = GameAdvisor::new(self.settings);
+    }
+
+    pub fn record(&mut self, board: &Board, score: i16, ply: usize) {
+        // board is the position the bot is to move in, at ply plies into the game, and score its
+        // search score for it, from the bot's side
+        let extend = |streak: usize, holds: bool| if holds { streak + 1 } else { 0 };
+        self.losing = extend(self.losing, score <= self.settings.resign_score.saturating_neg());
+        self.drawish = extend(self.drawish, score.saturating_abs() <= self.settings.draw_score);
+        self.mated = score < MAX_PLY as i16 - MATE_SCORE;
+        self.material = material_balance(board, board.side_to_move());
+        self.ply = ply;
+        self.moves += 1;
+    }
+
+    pub fn game_advice(&mut self) -> Advice {
+        // What to do along with the next move. A draw offer is only advised once every draw_moves
+        // moves, so the bot doesn't repeat it after every move the opponent declines.
+        let settings = &self.settings;
+        if self.losing >= settings.resign_moves && (self.mated || self.material <= -settings.resign_material) {
+            return Advice::Resign;
+        }
+        let offered_recently = matches!(self.offered_at, Some(offered) if self.moves < offered + settings.draw_moves);
+        if self.drawish >= settings.draw_moves
+            && self.ply >= settings.draw_min_ply
+            && self.material.abs() <= settings.draw_material
+            && !offered_recently
+        {
+            self.offered_at = Some(self.moves);
+            return Advice::OfferDraw;
+        }
+        Advice::Play
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_game_advice() {
+        let settings = AdviceSettings { resign_moves: 2, draw_moves: 2, draw_min_ply: 10, ..AdviceSettings::default() };
+        let mut advisor = GameAdvisor::new(settings);
+
+        // A bad score with the material level is no reason to resign, a queen down or a mate is
+        let level = Board::default();
+        let queen_down = Board::from_str("rnb1kbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1").unwrap();
+        advisor.record(&level, -1500, 20);
+        advisor.record(&level, -1500, 22);
+        assert_eq!(advisor.game_advice(), Advice::Play);
+        advisor.record(&level, 10 - MATE_SCORE, 24);
+        assert_eq!(advisor.game_advice(), Advice::Resign);
+        advisor.reset();
+        advisor.record(&queen_down, -1200, 20);
+        assert_eq!(advisor.game_advice(), Advice::Play);
+        advisor.record(&queen_down, -1200, 22);
+        assert_eq!(advisor.game_advice(), Advice::Resign);
+
+        // Draws are offered once the scores settle past draw_min_ply, then not again for draw_moves moves
+        advisor.reset();
+        let mut advice = Vec::new();
+        for ply in [6, 8, 10, 12, 14, 16] {
+            advisor.record(&level, 5, ply);
+            advice.push(advisor.game_advice());
+        }
+        assert_eq!(advice, [Advice::Play, Advice::Play, Advice::OfferDraw, Advice::Play, Advice::OfferDraw, Advice::Play]);
+    }
+}
@@ -0,0 +1,120 @@
+use chess::{Board, ChessMove};
+use serde::{Deserialize, Serialize};
+
+use crate::error::NNUEError;
+use crate::eval_server::BatchEvaluator;
+use crate::tools::blunders::score_moves;
+
+// Move scores of one position shaped for a GUI: arrows for the best moves and, per destination
+// square, how good the best move landing there is. Squares are numbered a1 = 0 to h8 = 63 and moves
+// written in long algebraic, so the JSON needs no chess library to draw.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Arrow {
+    pub chess_move: String,
+    pub from: u8,
+    pub to: u8,
+    pub score: i16, // From the mover's side
+    pub rank: usize, // 0 for the best move
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SquareInterest {
+    pub square: u8,
+    pub best_move: String, // Best move ending on the square
+    pub score: i16, // Its score from the mover's side
+    pub moves: usize, // Legal moves ending on the square
+    pub interest: f32, // The score scaled to 0.0 for the position's worst move and 1.0 for its best
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnalysisMap {
+    pub fen: String,
+    pub arrows: Vec<Arrow>, // Best first
+    pub squares: Vec<SquareInterest>, // Only squares some legal move ends on, in square order
+}
+
+impl AnalysisMap {
+    pub fn from_scores(board: &Board, scored: &[(ChessMove, i16)], arrows: usize) -> AnalysisMap {
+        // scored holds moves with their scores from the mover's side, as score_moves returns them
+        let mut ranked = scored.to_vec();
+        ranked.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+        let best = ranked.first().map_or(0.0, |(_, score)| *score as f32);
+        let worst = ranked.last().map_or(0.0, |(_, score)| *score as f32);
+
+        let mut squares: Vec<SquareInterest> = Vec::new();
+        for (chess_move, score) in &ranked {
+            let square = chess_move.get_dest().to_index() as u8;
+            match squares.iter_mut().find(|interest| interest.square == square) {
+                Some(interest) => interest.moves += 1, // Ranked best first, the first move seen is the best
+                None => squares.push(SquareInterest {
+                    square,
+                    best_move: chess_move.to_string(),
+                    score: *score,
+                    moves: 1,
+                    interest: if best > worst { (*score as f32 - worst) / (best - worst) } else { 1.0 },
+                }),
+            }
+        }
+        squares.sort_by_key(|interest| interest.square);
+
+        AnalysisMap {
+            fen: board.to_string(),
+            arrows: ranked
+                .iter()
+                .take(arrows)
+                .enumerate()
+                .map(|(rank, (chess_move, score))| Arrow {
+                    chess_move: chess_move.to_string(),
+                    from: chess_move.get_source().to_index() as u8,
+                    to: chess_move.get_dest().to_index() as u8,
+                    score: *score,
+                    rank,
+                })
+                .collect(),
+            squares,
+        }
+    }
+
+    pub fn heatmap(&self) -> [f32; 64] {
+        // interest per square, 0.0 where no move ends
+        let mut heat = [0.0; 64];
+        for interest in &self.squares {
+            heat[interest.square as usize] = interest.interest;
+        }
+        heat
+    }
+
+    pub fn to_json(&self) -> Result<String, NNUEError> {
+        serde_json::to_string(self).map_err(|err| NNUEError::InvalidData(err.to_string()))
+    }
+}
+
+pub fn analyze<E: BatchEvaluator + ?Sized>(evaluator: &mut E, board: &Board, arrows: usize) -> Result<AnalysisMap, NNUEError> {
+    // Every legal move scored in one batch, see score_moves
+    Ok(AnalysisMap::from_scores(board, &score_moves(evaluator, board)?, arrows))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::classical::{ClassicalEval, ClassicalWeights};
+
+    #[test]
+    fn test_analysis_map() {
+        // The pawn and the knight can both take the queen on d5
+        let board = Board::from_str("4k3/8/8/3q4/4P3/2N5/8/4K3 w - - 0 1").unwrap();
+        let map = analyze(&mut ClassicalEval::new(ClassicalWeights::default()), &board, 3).unwrap();
+        assert_eq!(map.arrows.len(), 3);
+        assert_eq!(map.arrows[0].to, 35); // d5
+        assert!(map.arrows[0].score >= map.arrows[1].score);
+
+        let d5 = map.squares.iter().find(|interest| interest.square == 35).unwrap();
+        assert_eq!((d5.moves, d5.interest), (2, 1.0));
+        assert_eq!(map.heatmap()[35], 1.0);
+        assert!(map.squares.windows(2).all(|pair| pair[0].square < pair[1].square));
+        let json: AnalysisMap = serde_json::from_str(&map.to_json().unwrap()).unwrap();
+        assert_eq!(json, map);
+    }
+}
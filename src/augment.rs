@@ -0,0 +1,109 @@
+use chess::{Board, BoardBuilder, CastleRights, ChessMove, Color, Square, ALL_SQUARES};
+
+use crate::dataset::Sample;
+use crate::features::FeatureSet;
+
+// Symmetric copies of training samples. Chess doesn't change when the board is mirrored left to
+// right (without castling rights) or turned around with the colours swapped, so each sample can
+// stand in for its mirror image as well, with the labels following the side they belong to.
+
+fn rebuild(board: &Board, square_map: impl Fn(usize) -> usize, swap_colours: bool) -> Option<Board> {
+    let source = BoardBuilder::from(board);
+    let colour = |colour: Color| if swap_colours { !colour } else { colour };
+    let mut target = BoardBuilder::new();
+    for square in ALL_SQUARES {
+        target[ALL_SQUARES[square_map(square.to_index())]] = source[square].map(|(piece, owner)| (piece, colour(owner)));
+    }
+    for owner in [Color::White, Color::Black] {
+        target.castle_rights(colour(owner), source.get_castle_rights(owner));
+    }
+    let en_passant = source.get_en_passant().map(|square| ALL_SQUARES[square_map(square.to_index())].get_file());
+    target.side_to_move(colour(source.get_side_to_move())).en_passant(en_passant);
+    Board::try_from(&target).ok()
+}
+
+fn mirror_move(chess_move: ChessMove, flip: usize) -> ChessMove {
+    let square = |square: Square| ALL_SQUARES[square.to_index() ^ flip];
+    ChessMove::new(square(chess_move.get_source()), square(chess_move.get_dest()), chess_move.get_promotion())
+}
+
+pub fn mirror_files(sample: &Sample) -> Option<Sample> {
+    // The board mirrored left to right, a file to h file, labels unchanged. None while castling
+    // rights remain, a king on d1 can't castle.
+    let rights = [Color::White, Color::Black].map(|colour| sample.board.castle_rights(colour));
+    if rights.iter().any(|rights| *rights != CastleRights::NoRights) {
+        return None;
+    }
+    let board = rebuild(&sample.board, |square| square ^ 7, false)?;
+    Some(Sample { board, best_move: sample.best_move.map(|chess_move| mirror_move(chess_move, 7)), ..*sample })
+}
+
+pub fn flip_colours(sample: &Sample) -> Option<Sample> {
+    // Ranks mirrored and colours swapped, so white's position is now black's and the other side is to
+    // move. Scores and results are from white's side, so both change sign. None if the flipped board
+    // isn't valid, the labels only make sense with the board they were flipped for.
+    let board = rebuild(&sample.board, |square| square ^ 56, true)?;
+    Some(Sample {
+        board,
+        score: sample.score.saturating_neg(),
+        result: 1.0 - sample.result,
+        best_move: sample.best_move.map(|chess_move| mirror_move(chess_move, 56)),
+    })
+}
+
+pub fn mirrored(sample: &Sample, features: &FeatureSet) -> Option<Sample> {
+    // The copy that is a new input for the feature set. Relative feature sets turn black's side around
+    // the way orient does, which makes the colour flip encode like the left to right mirror, and it
+    // keeps castling rights. Absolute sets get the mirror itself, which keeps white's pieces white.
+    match features {
+        FeatureSet::Relative768(_) => flip_colours(sample),
+        FeatureSet::Absolute768(_) => mirror_files(sample),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::perspective::from_white;
+
+    fn sample(fen: &str, score: i16, result: f32) -> Sample {
+        Sample { board: Board::from_str(fen).unwrap(), score, result, best_move: None }
+    }
+
+    #[test]
+    fn test_mirror_labels() {
+        // White to move and a queen up: the flip has black to move and a queen up, the same label for the
+        // side to move but the opposite from white's side
+        let original = sample("4k3/8/8/8/8/8/1Q6/4K3 w - - 0 1", 900, 1.0);
+        let flipped = flip_colours(&original).unwrap();
+        assert_eq!(flipped, sample("4k3/1q6/8/8/8/8/8/4K3 b - - 0 1", -900, 0.0));
+        let label = |sample: &Sample| from_white(sample.score, sample.board.side_to_move());
+        assert_eq!(label(&flipped), label(&original));
+        assert_eq!(flip_colours(&flipped), Some(original));
+
+        // The mirror keeps the labels, and both keep en passant
+        assert_eq!(mirror_files(&original), Some(sample("3k4/8/8/8/8/8/6Q1/3K4 w - - 0 1", 900, 1.0)));
+        let passant = sample("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", 50, 0.5);
+        assert_eq!(mirror_files(&passant).unwrap().board, Board::from_str("3k4/8/8/3Pp3/8/8/8/3K4 w - e6 0 1").unwrap());
+        assert_eq!(flip_colours(&passant).unwrap().board, Board::from_str("4k3/8/8/8/3Pp3/8/8/4K3 b - d3 0 1").unwrap());
+        assert_eq!(mirror_files(&sample("4k3/8/8/8/8/8/8/4K2R w K - 0 1", 0, 0.5)), None);
+
+        // Moves follow their pieces
+        let capture = Sample { best_move: Some(ChessMove::new(Square::E5, Square::D6, None)), ..passant };
+        assert_eq!(mirror_files(&capture).unwrap().best_move, Some(ChessMove::new(Square::D5, Square::E6, None)));
+        assert_eq!(flip_colours(&capture).unwrap().best_move, Some(ChessMove::new(Square::E4, Square::D3, None)));
+    }
+
+    #[test]
+    fn test_relative_mirror_encoding() {
+        // The relative encoding of the colour flip is the left to right mirror of the original's
+        let original = sample("r3k2r/pp3ppp/2n5/3q4/8/2N2B2/PPP2PPP/R3K2R w KQkq - 0 1", 120, 0.5);
+        let mut features = FeatureSet::default().active(&mirrored(&original, &FeatureSet::default()).unwrap().board);
+        features.sort_unstable();
+        let mut expected: Vec<u16> = FeatureSet::default().active(&original.board).iter().map(|feature| feature ^ 7).collect();
+        expected.sort_unstable();
+        assert_eq!(features, expected);
+    }
+}
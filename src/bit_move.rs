@@ -1,4 +1,6 @@
-use chess::{ChessMove, Color, Square, Piece, Board};
+use chess::{ChessMove, Color, File, Square, Piece, Board};
+
+use crate::error::NNUEError;
 
 type ReorientedSq = i16;
 
@@ -23,6 +25,69 @@ pub(crate) fn get_index(piece: Piece, own_piece: bool, sq_reoriented: Reoriented
     piece_index(piece, own_piece) * 64 + (sq_reoriented as u16)
 }
 
+pub(crate) fn active_indices(board: &Board) -> Vec<u16> {
+    // All feature indices that are set for the board, from the perspective of the side to move
+    active_indices_for(board, board.side_to_move())
+}
+
+pub(crate) fn active_indices_for(board: &Board, colour: Color) -> Vec<u16> {
+    active_features(board, colour).to_vec()
+}
+
+// Feature indices kept on the stack, a board has at most 64 pieces so they always fit
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FeatureList {
+    indices: [u16; 64],
+    len: usize,
+}
+
+impl FeatureList {
+    fn new() -> FeatureList {
+        FeatureList { indices: [0; 64], len: 0 }
+    }
+
+    fn push(&mut self, index: u16) {
+        self.indices[self.len] = index;
+        self.len += 1;
+    }
+}
+
+impl std::ops::Deref for FeatureList {
+    type Target = [u16];
+
+    fn deref(&self) -> &[u16] {
+        &self.indices[..self.len]
+    }
+}
+
+pub(crate) fn active_features(board: &Board, colour: Color) -> FeatureList {
+    let mut indices = FeatureList::new();
+    for sq in *board.combined() {
+        let piece = board.piece_on(sq).expect("Occupied square should hold a piece");
+        let own_piece: bool = board.color_on(sq).expect("Square with piece should not be empty") == colour;
+        indices.push(get_index(piece, own_piece, orient(sq, colour)));
+    }
+    indices
+}
+
+pub(crate) fn feature_delta(current: &Board, target: &Board) -> (FeatureList, FeatureList) {
+    // Returns (removed, placed) feature indices needed to turn the encoding of current into target.
+    // Both are seen from the side to move of target, an encoding of current for the other side
+    // has to be turned around first (see FeatureSet::turn_flip). Nothing is allocated, the cache
+    // refreshes with it on every set_board_hard.
+    let current_indices = active_features(current, target.side_to_move());
+    let target_indices = active_features(target, target.side_to_move());
+
+    let (mut removed, mut placed) = (FeatureList::new(), FeatureList::new());
+    for index in current_indices.iter().filter(|index| !target_indices.contains(index)) {
+        removed.push(*index);
+    }
+    for index in target_indices.iter().filter(|index| !current_indices.contains(index)) {
+        placed.push(*index);
+    }
+    (removed, placed)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PieceValueChange{
     Place = 1,
@@ -38,10 +103,11 @@ pub(crate) struct PieceMove{
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum MoveType{
-    NonCapture([PieceMove; 2]), // 2-bit change
-    Promote([PieceMove; 2]),    // 2-bit change
-    Capture([PieceMove; 3]),    // 3-bit change
-    Castle([PieceMove; 4]),     // 4-bit change
+    NonCapture([PieceMove; 2]),     // 2-bit change
+    Promote([PieceMove; 2]),        // 2-bit change
+    Capture([PieceMove; 3]),        // 3-bit change, en passant included
+    PromoteCapture([PieceMove; 3]), // 3-bit change
+    Castle([PieceMove; 4]),         // 4-bit change, king and rook
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -50,80 +116,86 @@ pub(crate) struct BitMove{
 }
 
 impl BitMove{
-    pub(crate) fn new(chess_move: ChessMove, turn: Color, pre_move_board: Board) -> Result<BitMove, ()>{
-        // figure out what type of move this is (MoveType enum)
-        
-        // Castle check
-        if chess_move.to_string() == "O-O" {
-            // Kingside castle
-
-            // For both white and black, the own perspecctive of casteling looks like the white perspective
-            let king_place = PieceMove{index: get_index(Piece::King, true, orient(Square::G1, Color::White)), value: PieceValueChange::Place};
-            let king_remove = PieceMove{index: get_index(Piece::King, true, orient(Square::E1, Color::White)), value: PieceValueChange::Remove};
-            let rook_place = PieceMove{index: get_index(Piece::Rook, true, orient(Square::F1, Color::White)), value: PieceValueChange::Place};
-            let rook_remove = PieceMove{index: get_index(Piece::Rook, true, orient(Square::H1, Color::White)), value: PieceValueChange::Remove};
-            
-            let mve: MoveType = MoveType::Castle([king_place, king_remove, rook_place, rook_remove]);
+    pub(crate) fn changes(&self) -> &[PieceMove] {
+        // Every feature change of the move, regardless of the move type
+        match &self.mve {
+            MoveType::NonCapture(changes) => changes,
+            MoveType::Promote(changes) => changes,
+            MoveType::Capture(changes) => changes,
+            MoveType::PromoteCapture(changes) => changes,
+            MoveType::Castle(changes) => changes,
+        }
+    }
 
-            return Ok(BitMove{mve})
-        } else if chess_move.to_string() == "O-O-O" {
-            // Queenside castle
-
-            // For both white and black, the own perspecctive of casteling looks like the white perspective
-            let king_place = PieceMove{index: get_index(Piece::King, true, orient(Square::C1, Color::White)), value: PieceValueChange::Place};
-            let king_remove = PieceMove{index: get_index(Piece::King, true, orient(Square::E1, Color::White)), value: PieceValueChange::Remove};
-            let rook_place = PieceMove{index: get_index(Piece::Rook, true, orient(Square::D1, Color::White)), value: PieceValueChange::Place};
-            let rook_remove = PieceMove{index: get_index(Piece::Rook, true, orient(Square::A1, Color::White)), value: PieceValueChange::Remove};
-            
-            let mve: MoveType = MoveType::Castle([king_place, king_remove, rook_place, rook_remove]);
-            return Ok(BitMove{mve})
+    pub(crate) fn resets_halfmove_clock(&self) -> bool {
+        // Captures and pawn moves (promotions included), a non-capture removes the moved piece from its source
+        match &self.mve {
+            MoveType::Capture(_) | MoveType::Promote(_) | MoveType::PromoteCapture(_) => true,
+            MoveType::NonCapture([_, source]) => source.index < 64, // Own pawns are plane 0
+            MoveType::Castle(_) => false,
         }
+    }
 
-        // Promotion check
-        match chess_move.get_promotion(){
-            Some(promotion_piece) => {
-                let piece_remove: PieceMove = PieceMove { index: get_index(pre_move_board.piece_on(chess_move.get_source()).expect("Source sq should have a piece during promote"), true, orient(chess_move.get_source(), turn)), value: PieceValueChange::Remove };
-                let piece_add: PieceMove = PieceMove { index: get_index(promotion_piece, true, orient(chess_move.get_dest(), turn)), value: PieceValueChange::Place };
+    pub(crate) fn new(chess_move: ChessMove, turn: Color, pre_move_board: Board) -> Result<BitMove, NNUEError>{
+        // figure out what type of move this is (MoveType enum)
+        let source = chess_move.get_source();
+        let dest = chess_move.get_dest();
+        let piece = pre_move_board.piece_on(source).ok_or(NNUEError::IllegalMove)?;
+        let own = |piece: Piece, sq: Square, value: PieceValueChange| PieceMove { index: get_index(piece, true, orient(sq, turn)), value };
+        let source_piece = own(piece, source, PieceValueChange::Remove);
+
+        let captured = match pre_move_board.color_on(dest) {
+            Some(color) if color == turn => return Err(NNUEError::IllegalMove), // Can't capture own piece
+            Some(_) => Some(PieceMove { index: get_index(pre_move_board.piece_on(dest).ok_or(NNUEError::IllegalMove)?, false, orient(dest, turn)), value: PieceValueChange::Remove }),
+            None => None,
+        };
 
-                let mve: MoveType = MoveType::Promote([piece_add, piece_remove]);
-                return Ok(BitMove{mve})
-            },
-            None => {},
+        // Castling is a king move of two files (e1g1), the rook jumps from its corner to the king's other side
+        if piece == Piece::King && (source.get_file().to_index() as i32 - dest.get_file().to_index() as i32).abs() == 2 {
+            let (rook_from, rook_to) = match dest.get_file() {
+                File::G => (File::H, File::F),
+                _ => (File::A, File::D),
+            };
+            let rank = source.get_rank();
+            let mve = MoveType::Castle([
+                own(Piece::King, dest, PieceValueChange::Place),
+                source_piece,
+                own(Piece::Rook, Square::make_square(rank, rook_to), PieceValueChange::Place),
+                own(Piece::Rook, Square::make_square(rank, rook_from), PieceValueChange::Remove),
+            ]);
+            return Ok(BitMove{mve})
         }
 
-        
-        match pre_move_board.color_on(chess_move.get_dest()){
-            Some(color) => {
-                if color != turn {
-                    // Capture move
-                    let captured_piece = PieceMove {index: get_index(pre_move_board.piece_on(chess_move.get_dest()).expect("Dest sq should have a piece in a capture"), false, orient(chess_move.get_dest(), turn)), value: PieceValueChange::Remove};
-                    let destination_piece = PieceMove {index: get_index(pre_move_board.piece_on(chess_move.get_source()).expect("Source sq should have a piece"), true, orient(chess_move.get_dest(), turn)), value: PieceValueChange::Place};
-                    let source_piece = PieceMove {index: get_index(pre_move_board.piece_on(chess_move.get_source()).expect("Source sq should have a piece"), true, orient(chess_move.get_source(), turn)), value: PieceValueChange::Remove};
-
-                    let mve: MoveType = MoveType::Capture([captured_piece, destination_piece, source_piece]);
-                    return Ok(BitMove{mve})
-                } else {
-                    // Can't capture own piece
-                    return Err(())
-                }
-            },
-            None => {
-                /* No piece on target square */
-                // Non-capture
-                let destination_piece = PieceMove {index: get_index(pre_move_board.piece_on(chess_move.get_source()).expect("Source sq should have a piece"), true, orient(chess_move.get_dest(), turn)), value: PieceValueChange::Place};
-                let source_piece = PieceMove {index: get_index(pre_move_board.piece_on(chess_move.get_source()).expect("Source sq should have a piece"), true, orient(chess_move.get_source(), turn)), value: PieceValueChange::Remove};
-
-                let mve: MoveType = MoveType::NonCapture([destination_piece, source_piece]);
-                return Ok(BitMove{mve})
-            },
+        // Promotion check, the promoted piece replaces the pawn and may take something on the way
+        if let Some(promotion_piece) = chess_move.get_promotion() {
+            let piece_add = own(promotion_piece, dest, PieceValueChange::Place);
+            let mve = match captured {
+                Some(captured_piece) => MoveType::PromoteCapture([captured_piece, piece_add, source_piece]),
+                None => MoveType::Promote([piece_add, source_piece]),
+            };
+            return Ok(BitMove{mve})
         }
-        Err(())
+
+        let destination_piece = own(piece, dest, PieceValueChange::Place);
+        let mve = match captured {
+            Some(captured_piece) => MoveType::Capture([captured_piece, destination_piece, source_piece]),
+            // En passant, a pawn changing file onto an empty square takes the pawn beside its source
+            None if piece == Piece::Pawn && source.get_file() != dest.get_file() => {
+                let victim = Square::make_square(source.get_rank(), dest.get_file());
+                let captured_piece = PieceMove { index: get_index(Piece::Pawn, false, orient(victim, turn)), value: PieceValueChange::Remove };
+                MoveType::Capture([captured_piece, destination_piece, source_piece])
+            }
+            None => MoveType::NonCapture([destination_piece, source_piece]),
+        };
+        Ok(BitMove{mve})
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
     use super::*;
 
     #[test]
@@ -152,4 +224,60 @@ mod tests {
             assert_eq!(piece_indicies[0], PieceMove{index: 28, value: PieceValueChange::Place});
         }
      }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_feature_delta() {
+        let board: Board = Board::default();
+        let after: Board = board
+            .make_move_new(ChessMove::new(Square::E2, Square::E4, None))
+            .make_move_new(ChessMove::new(Square::E7, Square::E5, None));
+
+        let (removed, placed) = feature_delta(&board, &after);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(placed.len(), 2);
+        assert!(removed.contains(&get_index(Piece::Pawn, true, orient(Square::E2, Color::White))));
+        assert!(placed.contains(&get_index(Piece::Pawn, false, orient(Square::E5, Color::White))));
+
+        let (removed, placed) = feature_delta(&board, &board);
+        assert!(removed.is_empty() && placed.is_empty());
+    }
+
+    #[test]
+    fn test_bitmove_errors() {
+        let board: Board = Board::default();
+        // Capturing an own piece and moving from an empty square are rejected instead of panicking
+        let own_capture: ChessMove = ChessMove::new(Square::D1, Square::D2, None);
+        assert!(matches!(BitMove::new(own_capture, board.side_to_move(), board), Err(NNUEError::IllegalMove)));
+        let empty_source: ChessMove = ChessMove::new(Square::E4, Square::E5, None);
+        assert!(matches!(BitMove::new(empty_source, board.side_to_move(), board), Err(NNUEError::IllegalMove)));
+    }
+
+    #[test]
+    fn test_special_moves_match_boards() {
+        // Castling, en passant and capturing promotions change exactly the features the boards differ in
+        let fens_and_moves = [
+            ("r3k2r/pppq1ppp/2n2n2/8/8/2N2N2/PPPQ1PPP/R3K2R w KQkq - 0 1", "e1g1"),
+            ("r3k2r/pppq1ppp/2n2n2/8/8/2N2N2/PPPQ1PPP/R3K2R b KQkq - 0 1", "e8c8"),
+            ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5d6"),
+            ("4k3/8/8/8/3Pp3/8/8/4K3 b - d3 0 1", "e4d3"),
+            ("1n2k3/P7/8/8/8/8/8/4K3 w - - 0 1", "a7b8q"),
+            ("4k3/8/8/8/8/8/p7/1N2K3 b - - 0 1", "a2b1n"),
+        ];
+        for (fen, mve) in fens_and_moves {
+            let board = Board::from_str(fen).unwrap();
+            let chess_move = ChessMove::from_str(mve).unwrap();
+            let turn = board.side_to_move();
+            let (before, after) = (active_indices_for(&board, turn), active_indices_for(&board.make_move_new(chess_move), turn));
+
+            let bitmove = BitMove::new(chess_move, turn, board).unwrap();
+            let mut removed: Vec<u16> = bitmove.changes().iter().filter(|change| change.value == PieceValueChange::Remove).map(|change| change.index).collect();
+            let mut placed: Vec<u16> = bitmove.changes().iter().filter(|change| change.value == PieceValueChange::Place).map(|change| change.index).collect();
+            let mut expected_removed: Vec<u16> = before.iter().filter(|index| !after.contains(index)).copied().collect();
+            let mut expected_placed: Vec<u16> = after.iter().filter(|index| !before.contains(index)).copied().collect();
+            for indices in [&mut removed, &mut placed, &mut expected_removed, &mut expected_placed] {
+                indices.sort_unstable();
+            }
+            assert_eq!((removed, placed), (expected_removed, expected_placed), "{} {}", fen, mve);
+        }
+    }
+}
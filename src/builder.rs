@@ -0,0 +1,263 @@
+use std::env;
+use std::panic;
+use std::sync::Mutex;
+
+use tch::Device;
+
+use crate::endgame::EndgameGate;
+use crate::error::NNUEError;
+use crate::features::FeatureSet;
+use crate::guard::TorchGuard;
+use crate::network::Activation;
+use crate::perspective::ScorePerspective;
+use crate::phase::PhaseScale;
+use crate::shallow_nnue::ShallowNNUE;
+
+// Environment variable that picks the device when none is set on the builder, e.g. "cpu", "cuda" or "cuda:1"
+pub const DEVICE_ENV_VAR: &str = "SHALLOW_NNUE_DEVICE";
+
+pub(crate) fn parse_device(name: &str) -> Result<Device, NNUEError> {
+    let name = name.trim().to_lowercase();
+    match name.as_str() {
+        "cpu" => Ok(Device::Cpu),
+        "cuda" => Ok(Device::Cuda(0)),
+        "mps" => Ok(Device::Mps),
+        _ => match name.strip_prefix("cuda:").map(|index| index.parse::<usize>()) {
+            Some(Ok(index)) => Ok(Device::Cuda(index)),
+            _ => Err(NNUEError::InvalidDevice(name)),
+        },
+    }
+}
+
+pub(crate) fn resolve_device(requested: Option<Device>) -> Result<Device, NNUEError> {
+    // Priority: device set in code, then the environment, then the default.
+    // Only the default probes for CUDA, and never with the cpu-only feature.
+    let device = match requested {
+        Some(device) => device,
+        None => match env::var(DEVICE_ENV_VAR) {
+            Ok(name) => parse_device(&name)?,
+            Err(_) => default_device(),
+        },
+    };
+
+    if cfg!(feature = "cpu-only") && device != Device::Cpu {
+        return Err(NNUEError::InvalidDevice(format!("{:?} (built with cpu-only)", device)));
+    }
+    Ok(device)
+}
+
+#[cfg(feature = "cpu-only")]
+fn default_device() -> Device {
+    Device::Cpu
+}
+
+#[cfg(not(feature = "cpu-only"))]
+fn default_device() -> Device {
+    Device::cuda_if_available()
+}
+
+// libtorch takes the inter-op pool size only once per process and before any parallel work
+static INTER_OP_THREADS: Mutex<Option<usize>> = Mutex::new(None);
+
+pub fn set_torch_threads(intra_op: Option<usize>, inter_op: Option<usize>) -> Result<(), NNUEError> {
+    // Sizes of libtorch's thread pools, which are shared by every model in the process. Engines with
+    // their own search threads usually want 1 for both so the pools don't compete with them.
+    if intra_op == Some(0) || inter_op == Some(0) {
+        return Err(NNUEError::InvalidConfig("torch thread counts must be at least 1".to_string()));
+    }
+    if let Some(threads) = inter_op {
+        let mut current = INTER_OP_THREADS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match *current {
+            Some(set) if set == threads => {}
+            Some(set) => {
+                return Err(NNUEError::InvalidConfig(format!("inter-op threads are already {}, libtorch sets them once per process", set)))
+            }
+            None => {
+                panic::catch_unwind(|| tch::set_num_interop_threads(threads as i32)).map_err(|_| {
+                    NNUEError::InvalidConfig("libtorch refused the inter-op threads, set them before the first evaluation".to_string())
+                })?;
+                *current = Some(threads);
+            }
+        }
+    }
+    if let Some(threads) = intra_op {
+        tch::set_num_threads(threads as i32);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ShallowNNUEBuilder {
+    model_path: String,
+    device: Option<Device>,
+    perspective: ScorePerspective,
+    activation: Option<Activation>, // Checked against the activation the model declares
+    warmup: Option<(usize, Vec<usize>)>, // Iterations and batch sizes of dummy forwards run on load
+    features: Option<FeatureSet>, // Defaults to the feature set the model declares
+    endgame: Option<(String, EndgameGate)>, // Second model for positions with little material left
+    tempo: i16, // Centipawns for the side to move, see ShallowNNUE::set_tempo
+    phase_scale: PhaseScale,
+    intra_op_threads: Option<usize>, // See set_torch_threads
+    inter_op_threads: Option<usize>,
+    guard: TorchGuard,
+}
+
+impl ShallowNNUEBuilder {
+    pub fn new(global_path_to_model: String) -> ShallowNNUEBuilder {
+        ShallowNNUEBuilder {
+            model_path: global_path_to_model,
+            device: None,
+            perspective: ScorePerspective::default(),
+            activation: None,
+            warmup: None,
+            features: None,
+            endgame: None,
+            tempo: 0,
+            phase_scale: PhaseScale::default(),
+            intra_op_threads: None,
+            inter_op_threads: None,
+            guard: TorchGuard::default(),
+        }
+    }
+
+    pub fn device(mut self, device: Device) -> ShallowNNUEBuilder {
+        self.device = Some(device);
+        self
+    }
+
+    pub fn perspective(mut self, perspective: ScorePerspective) -> ShallowNNUEBuilder {
+        self.perspective = perspective;
+        self
+    }
+
+    pub fn activation(mut self, activation: Activation) -> ShallowNNUEBuilder {
+        self.activation = Some(activation);
+        self
+    }
+
+    pub fn features(mut self, features: FeatureSet) -> ShallowNNUEBuilder {
+        // Input layout of models trained with a different encoder
+        self.features = Some(features);
+        self
+    }
+
+    pub fn endgame(mut self, global_path_to_model: String, gate: EndgameGate) -> ShallowNNUEBuilder {
+        // Loaded on the same device, with the feature set it declares
+        self.endgame = Some((global_path_to_model, gate));
+        self
+    }
+
+    pub fn tempo(mut self, centipawns: i16) -> ShallowNNUEBuilder {
+        self.tempo = centipawns;
+        self
+    }
+
+    pub fn phase_scale(mut self, scale: PhaseScale) -> ShallowNNUEBuilder {
+        self.phase_scale = scale;
+        self
+    }
+
+    pub fn intra_op_threads(mut self, threads: usize) -> ShallowNNUEBuilder {
+        // Threads one model forward may use, applies to the whole process
+        self.intra_op_threads = Some(threads);
+        self
+    }
+
+    pub fn inter_op_threads(mut self, threads: usize) -> ShallowNNUEBuilder {
+        // Threads running independent operations side by side, libtorch accepts this once per process
+        self.inter_op_threads = Some(threads);
+        self
+    }
+
+    pub fn torch_guard(mut self, guard: TorchGuard) -> ShallowNNUEBuilder {
+        self.guard = guard;
+        self
+    }
+
+    pub fn warmup(mut self, iterations: usize, batch_sizes: &[usize]) -> ShallowNNUEBuilder {
+        // Without it the first evaluations of a search are slowed down by TorchScript's JIT
+        self.warmup = Some((iterations, batch_sizes.to_vec()));
+        self
+    }
+
+    pub fn build(self) -> Result<ShallowNNUE, NNUEError> {
+        // Before loading, the first forward may already start the pools
+        set_torch_threads(self.intra_op_threads, self.inter_op_threads)?;
+        let device = resolve_device(self.device)?;
+        let mut nnue = ShallowNNUE::load(self.model_path, device)?;
+        if let Some(activation) = self.activation {
+            nnue.check_activation(activation)?;
+        }
+        if let Some(features) = self.features {
+            nnue.check_features(&features)?;
+            nnue.set_features(features)?;
+        }
+        if let Some((path, gate)) = self.endgame {
+            let endgame = ShallowNNUE::load(path, device)?;
+            if let Some((iterations, batch_sizes)) = &self.warmup {
+                endgame.shared_model().warmup(*iterations, batch_sizes)?;
+            }
+            nnue.set_endgame(endgame, gate)?;
+        }
+        if let Some((iterations, batch_sizes)) = &self.warmup {
+            nnue.shared_model().warmup(*iterations, batch_sizes)?;
+        }
+        nnue.set_perspective(self.perspective);
+        nnue.set_tempo(self.tempo);
+        nnue.set_phase_scale(self.phase_scale)?;
+        nnue.set_torch_guard(self.guard);
+        Ok(nnue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shallow_nnue::NNUE;
+
+    #[test]
+    fn test_parse_device() {
+        assert_eq!(parse_device("cpu").unwrap(), Device::Cpu);
+        assert_eq!(parse_device(" CUDA ").unwrap(), Device::Cuda(0));
+        assert_eq!(parse_device("cuda:2").unwrap(), Device::Cuda(2));
+        assert!(matches!(parse_device("cuda:x"), Err(NNUEError::InvalidDevice(_))));
+        assert!(matches!(parse_device("tpu"), Err(NNUEError::InvalidDevice(_))));
+    }
+
+    #[test]
+    fn test_requested_device_wins() {
+        assert_eq!(resolve_device(Some(Device::Cpu)).unwrap(), Device::Cpu);
+    }
+
+    #[test]
+    fn test_warmup_keeps_scores() {
+        let path = "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt";
+        let mut warm = ShallowNNUEBuilder::new(path.to_string()).device(Device::Cpu).warmup(3, &[1, 16, 0]).build().unwrap();
+        let mut cold = ShallowNNUEBuilder::new(path.to_string()).device(Device::Cpu).build().unwrap();
+        assert_eq!(warm.evaluate().unwrap(), cold.evaluate().unwrap());
+    }
+
+    #[test]
+    fn test_torch_threads() {
+        set_torch_threads(Some(2), None).unwrap();
+        assert_eq!(tch::get_num_threads(), 2);
+        assert!(matches!(set_torch_threads(Some(0), None), Err(NNUEError::InvalidConfig(_))));
+        // Other tests may have started the pools already, either way a second size is refused
+        let _ = set_torch_threads(None, Some(1));
+        assert!(matches!(set_torch_threads(None, Some(2)), Err(NNUEError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_tempo() {
+        let path = "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt";
+        let mut plain = ShallowNNUEBuilder::new(path.to_string()).device(Device::Cpu).build().unwrap();
+        let mut tempo = ShallowNNUEBuilder::new(path.to_string()).device(Device::Cpu).tempo(15).build().unwrap();
+        assert_eq!(tempo.tempo(), 15);
+        assert_eq!(tempo.evaluate().unwrap(), plain.evaluate().unwrap() + 15);
+
+        // After a move the opponent is to move, so it counts against the mover
+        let mve = chess::ChessMove::new(chess::Square::E2, chess::Square::E4, None);
+        assert_eq!(tempo.forward(mve).unwrap(), plain.forward(mve).unwrap() - 15);
+        assert_eq!(tempo.forward_batch(&[mve]).unwrap(), vec![plain.forward(mve).unwrap() - 15]);
+    }
+}
@@ -0,0 +1,246 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use chess::{Board, BoardStatus, ChessMove, Color};
+
+use crate::engine::{EngineCore, EngineResult, Frontend, SearchLimit, MAX_TEMPO};
+use crate::error::NNUEError;
+
+// The xboard/CECP protocol (version 2) on top of an EngineCore, the same engine the UCI frontend
+// runs. Unlike UCI the engine keeps the game itself: the GUI sends the opponent's moves and the
+// engine answers with its own whenever it is to move and not in force mode. "?" makes the engine
+// move now, there is no pondering or analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Level {
+    moves_per_control: u64, // 0 for the whole game at one control
+    increment_ms: u64,
+}
+
+pub struct CecpFrontend {
+    core: EngineCore,
+    name: String,
+    force: bool, // Only record moves, don't answer them
+    engine_colour: Color,
+    post: bool, // Print thinking output
+    level: Level,
+    move_time: Option<Duration>, // "st", a fixed time per move
+    depth: Option<u8>, // "sd"
+    clock_ms: Option<u64>, // The engine's clock, from "time"
+    engine_moves: u64, // Moves the engine played this game, for the moves left to the next control
+}
+
+fn parse_clock(value: &str) -> Option<u64> {
+    // "level" base times are minutes, or minutes:seconds
+    match value.split_once(':') {
+        Some((minutes, seconds)) => Some(minutes.parse::<u64>().ok()? * 60_000 + seconds.parse::<u64>().ok()? * 1000),
+        None => Some(value.parse::<u64>().ok()? * 60_000),
+    }
+}
+
+fn xboard_score(result: &EngineResult) -> i32 {
+    // Centipawns, mates as 100000 plus the moves to mate with the sign of the winner
+    match result.report().mate_in() {
+        Some(moves) => moves.signum() * (100_000 + moves.abs()),
+        None => result.search.score as i32,
+    }
+}
+
+impl CecpFrontend {
+    pub fn new(core: EngineCore, name: String) -> CecpFrontend {
+        CecpFrontend {
+            core,
+            name,
+            force: false,
+            engine_colour: Color::Black,
+            post: false,
+            level: Level { moves_per_control: 0, increment_ms: 0 },
+            move_time: None,
+            depth: None,
+            clock_ms: None,
+            engine_moves: 0,
+        }
+    }
+
+    fn limit(&self) -> SearchLimit {
+        // sd and st win over the clock, without any of them the search runs to the options' depth
+        if let Some(depth) = self.depth {
+            return SearchLimit::Depth(depth);
+        }
+        if let Some(move_time) = self.move_time {
+            return SearchLimit::MoveTime(move_time);
+        }
+        match self.clock_ms {
+            Some(remaining_ms) => {
+                let per_control = self.level.moves_per_control;
+                let moves_to_go = (per_control > 0).then(|| per_control - self.engine_moves % per_control);
+                SearchLimit::Clock { remaining_ms, increment_ms: self.level.increment_ms, moves_to_go }
+            }
+            None => SearchLimit::Depth(self.core.options().depth),
+        }
+    }
+
+    fn game_result(&self) -> Option<&'static str> {
+        // The result line for a game that just ended, xboard expects the engine to report it
+        let board = self.core.board();
+        match board.status() {
+            BoardStatus::Checkmate if board.side_to_move() == Color::White => Some("0-1 {Black mates}"),
+            BoardStatus::Checkmate => Some("1-0 {White mates}"),
+            BoardStatus::Stalemate => Some("1/2-1/2 {Stalemate}"),
+            BoardStatus::Ongoing if self.core.is_draw() => Some("1/2-1/2 {Draw by repetition}"),
+            BoardStatus::Ongoing => None,
+        }
+    }
+
+    fn think(&mut self, out: &mut Vec<String>) -> Result<(), NNUEError> {
+        // Plays the engine's move if it is to move
+        if self.force || self.core.board().side_to_move() != self.engine_colour || self.game_result().is_some() {
+            return Ok(());
+        }
+        let result = self.core.search(self.limit())?;
+        let Some(chess_move) = result.search.best_move else {
+            return Ok(());
+        };
+        if self.post {
+            // ply score time (centiseconds) nodes pv
+            let centiseconds = result.elapsed.as_millis() / 10;
+            out.push(format!("{} {} {} {} {}", result.search.depth, xboard_score(&result), centiseconds, result.search.nodes, chess_move));
+        }
+        self.core.push_move(chess_move)?;
+        self.engine_moves += 1;
+        out.push(format!("move {}", chess_move));
+        if let Some(result) = self.game_result() {
+            out.push(result.to_string());
+        }
+        Ok(())
+    }
+
+    fn user_move(&mut self, name: &str, out: &mut Vec<String>) -> Result<(), NNUEError> {
+        let legal = ChessMove::from_str(name).ok().filter(|chess_move| self.core.board().legal(*chess_move));
+        match legal {
+            Some(chess_move) => {
+                self.core.push_move(chess_move)?;
+                match self.game_result() {
+                    Some(result) => out.push(result.to_string()),
+                    None => self.think(out)?,
+                }
+            }
+            None => out.push(format!("Illegal move: {}", name)),
+        }
+        Ok(())
+    }
+}
+
+impl Frontend for CecpFrontend {
+    fn handle(&mut self, line: &str, out: &mut Vec<String>) -> Result<bool, NNUEError> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let number = |at: usize| args.get(at).and_then(|value| value.parse::<u64>().ok());
+        match args.first().copied() {
+            Some("protover") => out.push(format!(
+                "feature myname=\"{}\" ping=1 setboard=1 usermove=1 playother=1 colors=0 sigint=0 sigterm=0 analyze=0 \
+                 option=\"Tempo -spin 0 {} {}\" done=1",
+                self.name, -MAX_TEMPO, MAX_TEMPO
+            )),
+            Some("option") => {
+                // option NAME=VALUE for the options announced above
+                match line["option".len()..].split_once('=') {
+                    Some((name, value)) => {
+                        self.core.set_option(name, value)?;
+                    }
+                    None => out.push(format!("Error (bad option): {}", line)),
+                }
+            }
+            Some("new") => {
+                self.core.new_game()?;
+                (self.force, self.engine_colour, self.depth, self.engine_moves) = (false, Color::Black, None, 0);
+            }
+            Some("force") | Some("result") => self.force = true,
+            Some("go") => {
+                self.force = false;
+                self.engine_colour = self.core.board().side_to_move();
+                self.think(out)?;
+            }
+            Some("playother") => {
+                self.force = false;
+                self.engine_colour = !self.core.board().side_to_move();
+            }
+            Some("usermove") => self.user_move(args.get(1).copied().unwrap_or_default(), out)?,
+            Some("setboard") => {
+                let fen = args[1..].join(" ");
+                match Board::from_str(&fen) {
+                    Ok(board) => {
+                        self.core.set_position(board, &[])?;
+                    }
+                    Err(_) => out.push(format!("tellusererror Illegal position: {}", fen)),
+                }
+            }
+            Some("undo") => {
+                self.core.undo();
+            }
+            Some("remove") => {
+                self.core.undo();
+                self.core.undo();
+            }
+            Some("level") => {
+                let base = args.get(2).and_then(|value| parse_clock(value));
+                let increment = args.get(3).and_then(|value| value.parse::<f64>().ok());
+                match (number(1), base, increment) {
+                    (Some(moves_per_control), Some(_), Some(increment)) => {
+                        self.level = Level { moves_per_control, increment_ms: (increment * 1000.0) as u64 };
+                        self.move_time = None;
+                    }
+                    _ => out.push(format!("Error (bad level): {}", line)),
+                }
+            }
+            Some("st") => self.move_time = number(1).map(Duration::from_secs),
+            Some("sd") => self.depth = number(1).map(|depth| depth.min(u8::MAX as u64) as u8),
+            Some("time") => self.clock_ms = number(1).map(|centiseconds| centiseconds * 10),
+            Some("ping") => out.push(format!("pong {}", args.get(1).copied().unwrap_or_default())),
+            Some("post") => self.post = true,
+            Some("nopost") => self.post = false,
+            Some("quit") => return Ok(false),
+            // Accepted without effect, there is nothing to set up for them. run_frontend already stopped
+            // the search when it read a "?".
+            Some("xboard" | "accepted" | "rejected" | "random" | "hard" | "easy" | "computer" | "otim" | "name" | "rating" | "draw" | "?")
+            | None => {}
+            Some(command) => out.push(format!("Error (unknown command): {}", command)),
+        }
+        Ok(true)
+    }
+
+    fn core(&self) -> &EngineCore {
+        &self.core
+    }
+
+    fn stop_command(&self) -> &'static str {
+        "?"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::run_frontend;
+    use crate::engine::tests::material_core;
+    use crate::search::SearchOptions;
+
+    #[test]
+    fn test_cecp_session() {
+        let core = material_core(SearchOptions::default());
+        let mut frontend = CecpFrontend::new(core, "shallow".to_string());
+        let input = "xboard\nprotover 2\noption Tempo=20\nnew\nforce\nsetboard 4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1\nsd 2\npost\ngo\n\
+                     usermove e8e7\nusermove e8e7\nping 7\nfoo\nquit\nping 8\n";
+        let mut output = Vec::new();
+        run_frontend(&mut frontend, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        // The engine takes the queen when told to go, and answers the reply with its next move
+        assert!(lines[0].starts_with("feature myname=\"shallow\"") && lines[0].contains("option=\"Tempo -spin 0 -100 100\""));
+        assert!(lines[1].starts_with("2 120 ") && lines[1].ends_with(" e4d5")); // A pawn and the tempo
+        assert_eq!(lines[2], "move e4d5");
+        assert!(lines[4].starts_with("move "));
+        assert_eq!(lines[5..], ["Illegal move: e8e7", "pong 7", "Error (unknown command): foo"]);
+        assert_eq!((frontend.core().plies(), frontend.core().tempo()), (3, 20));
+        assert_eq!(parse_clock("2:30"), Some(150_000));
+    }
+}
@@ -0,0 +1,158 @@
+use chess::{Board, ChessMove, Color, Square, ALL_PIECES};
+
+use crate::error::NNUEError;
+use crate::perspective::{from_white, ScorePerspective};
+use crate::shallow_nnue::NNUE;
+
+// Material plus piece-square tables, a baseline to compare networks against
+// Tables are from white's side, black pieces use the vertically mirrored square
+pub const NUM_PARAMETERS: usize = 6 + 6 * 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassicalWeights {
+    pub material: [i16; 6], // Indexed by Piece::to_index
+    pub psqt: [[i16; 64]; 6], // Indexed by [Piece::to_index][square from white's side]
+}
+
+impl Default for ClassicalWeights {
+    fn default() -> ClassicalWeights {
+        ClassicalWeights {
+            material: [100, 320, 330, 500, 900, 0],
+            psqt: [[0; 64]; 6],
+        }
+    }
+}
+
+fn relative_square(square: Square, colour: Color) -> usize {
+    match colour {
+        Color::White => square.to_index(),
+        Color::Black => square.to_index() ^ 56,
+    }
+}
+
+pub(crate) fn features(board: &Board) -> Vec<(usize, f32)> {
+    // Sparse (parameter index, coefficient) pairs, the eval from white's side is their dot product with the weights
+    let mut features = Vec::with_capacity(64);
+    for piece in ALL_PIECES {
+        for colour in [Color::White, Color::Black] {
+            let sign = if colour == Color::White { 1.0 } else { -1.0 };
+            for square in *board.pieces(piece) & *board.color_combined(colour) {
+                features.push((piece.to_index(), sign));
+                features.push((6 + piece.to_index() * 64 + relative_square(square, colour), sign));
+            }
+        }
+    }
+    features
+}
+
+impl ClassicalWeights {
+    pub fn to_vec(&self) -> Vec<f64> {
+        let mut parameters: Vec<f64> = self.material.iter().map(|value| *value as f64).collect();
+        for table in self.psqt.iter() {
+            parameters.extend(table.iter().map(|value| *value as f64));
+        }
+        parameters
+    }
+
+    pub fn from_vec(parameters: &[f64]) -> ClassicalWeights {
+        let round = |value: f64| value.round().max(i16::MIN as f64).min(i16::MAX as f64) as i16;
+        let mut weights = ClassicalWeights { material: [0; 6], psqt: [[0; 64]; 6] };
+        for (index, value) in parameters.iter().take(NUM_PARAMETERS).enumerate() {
+            match index {
+                0..=5 => weights.material[index] = round(*value),
+                _ => weights.psqt[(index - 6) / 64][(index - 6) % 64] = round(*value),
+            }
+        }
+        weights
+    }
+
+    pub fn evaluate_white(&self, board: &Board) -> i32 {
+        let mut score = 0;
+        for piece in ALL_PIECES {
+            let index = piece.to_index();
+            for colour in [Color::White, Color::Black] {
+                let sign = if colour == Color::White { 1 } else { -1 };
+                for square in *board.pieces(piece) & *board.color_combined(colour) {
+                    score += sign * (self.material[index] as i32 + self.psqt[index][relative_square(square, colour)] as i32);
+                }
+            }
+        }
+        score
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassicalEval {
+    weights: ClassicalWeights,
+    board: Board,
+    perspective: ScorePerspective,
+}
+
+impl ClassicalEval {
+    pub fn new(weights: ClassicalWeights) -> ClassicalEval {
+        ClassicalEval {
+            weights,
+            board: Board::default(),
+            perspective: ScorePerspective::default(),
+        }
+    }
+
+    pub fn weights(&self) -> &ClassicalWeights {
+        &self.weights
+    }
+
+    pub fn set_perspective(&mut self, perspective: ScorePerspective) {
+        self.perspective = perspective;
+    }
+
+    fn score(&self, board: &Board, side: Color) -> i16 {
+        let white = self.weights.evaluate_white(board).clamp(i16::MIN as i32 + 1, i16::MAX as i32) as i16;
+        self.perspective.from_side_to_move(from_white(white, side), side)
+    }
+}
+
+impl NNUE for ClassicalEval {
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        if !self.board.legal(chess_move) {
+            return Err(NNUEError::IllegalMove);
+        }
+        let after = self.board.make_move_new(chess_move);
+        Ok(self.score(&after, self.board.side_to_move()))
+    }
+
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+        self.board = board;
+        Ok(())
+    }
+
+    fn evaluate(&mut self) -> Result<i16, NNUEError> {
+        Ok(self.score(&self.board, self.board.side_to_move()))
+    }
+
+    fn perspective(&self) -> ScorePerspective {
+        self.perspective
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_classical_eval() {
+        let board = Board::from_str("4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1").unwrap();
+        let mut eval = ClassicalEval::new(ClassicalWeights::default());
+        eval.set_board_hard(board).unwrap();
+
+        assert_eq!(eval.evaluate().unwrap(), -800);
+        assert_eq!(eval.forward(ChessMove::new(Square::E4, Square::D5, None)).unwrap(), 100);
+
+        // The feature dot product matches the direct evaluation
+        let weights = ClassicalWeights::default().to_vec();
+        let dot: f32 = features(&board).iter().map(|(index, value)| weights[*index] as f32 * value).sum();
+        assert_eq!(dot as i32, ClassicalWeights::default().evaluate_white(&board));
+        assert_eq!(ClassicalWeights::from_vec(&weights), ClassicalWeights::default());
+    }
+}
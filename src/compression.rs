@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::error::NNUEError;
+
+// Optional zstd compression of dataset and weight files. Readers recognize compressed files by the zstd
+// frame magic, so any file can be compressed after the fact; writers compress when the path ends in
+// ".zst". Without the zstd feature compressed files are refused with an error instead of misparsed.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+#[cfg(feature = "zstd")]
+const LEVEL: i32 = 3; // Text samples and float weights gain little from higher levels
+
+pub fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ZSTD_MAGIC)
+}
+
+pub fn compresses<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().is_some_and(|extension| extension == "zst")
+}
+
+#[cfg(not(feature = "zstd"))]
+fn unsupported() -> NNUEError {
+    NNUEError::InvalidConfig("zstd compressed files need the zstd feature".to_string())
+}
+
+#[cfg(feature = "zstd")]
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, NNUEError> {
+    Ok(zstd::stream::decode_all(bytes)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn decompress(_bytes: &[u8]) -> Result<Vec<u8>, NNUEError> {
+    Err(unsupported())
+}
+
+pub fn open_reader<P: AsRef<Path>>(path: P) -> Result<Box<dyn BufRead>, NNUEError> {
+    // Decompresses while reading, so a compressed file is never held whole in memory
+    let mut reader = BufReader::new(File::open(path)?);
+    if is_compressed(reader.fill_buf()?) {
+        #[cfg(feature = "zstd")]
+        return Ok(Box::new(BufReader::new(zstd::stream::Decoder::with_buffer(reader)?)));
+        #[cfg(not(feature = "zstd"))]
+        return Err(unsupported());
+    }
+    Ok(Box::new(reader))
+}
+
+pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, NNUEError> {
+    let mut bytes = Vec::new();
+    open_reader(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Buffered file writer that compresses for ".zst" paths. finish must be called, a compressed file
+// is only complete once its last frame is written.
+pub enum FileWriter {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::Encoder<'static, BufWriter<File>>),
+}
+
+impl FileWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<FileWriter, NNUEError> {
+        let compressed = compresses(&path);
+        #[cfg(not(feature = "zstd"))]
+        if compressed {
+            return Err(unsupported());
+        }
+        let file = BufWriter::new(File::create(path)?);
+        #[cfg(feature = "zstd")]
+        if compressed {
+            return Ok(FileWriter::Zstd(zstd::stream::Encoder::new(file, LEVEL)?));
+        }
+        Ok(FileWriter::Plain(file))
+    }
+
+    pub fn finish(self) -> Result<(), NNUEError> {
+        match self {
+            FileWriter::Plain(mut file) => file.flush()?,
+            #[cfg(feature = "zstd")]
+            FileWriter::Zstd(encoder) => encoder.finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            FileWriter::Plain(file) => file.write(buf),
+            #[cfg(feature = "zstd")]
+            FileWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            FileWriter::Plain(file) => file.flush(),
+            #[cfg(feature = "zstd")]
+            FileWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+pub fn write_file<P: AsRef<Path>>(path: P, bytes: &[u8]) -> Result<(), NNUEError> {
+    let mut writer = FileWriter::create(path)?;
+    writer.write_all(bytes)?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_and_compressed_files() {
+        let plain = std::env::temp_dir().join("shallow_nnue_compression.txt");
+        write_file(&plain, b"plain text").unwrap();
+        assert_eq!(read_file(&plain).unwrap(), b"plain text");
+        std::fs::remove_file(&plain).unwrap();
+
+        let compressed = std::env::temp_dir().join("shallow_nnue_compression.txt.zst");
+        let contents = "8/8/8/8/8/8/8/K6k w - - 0 1;0;0.5\n".repeat(1000);
+        match write_file(&compressed, contents.as_bytes()) {
+            Ok(()) => {
+                let bytes = std::fs::read(&compressed).unwrap();
+                assert!(is_compressed(&bytes) && bytes.len() < contents.len() / 10);
+                assert_eq!(read_file(&compressed).unwrap(), contents.as_bytes());
+                std::fs::remove_file(&compressed).unwrap();
+            }
+            Err(err) => assert!(cfg!(not(feature = "zstd")) && matches!(err, NNUEError::InvalidConfig(_))),
+        }
+    }
+}
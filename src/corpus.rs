@@ -0,0 +1,117 @@
+use std::str::FromStr;
+
+use chess::{Board, ChessMove};
+
+use crate::error::NNUEError;
+
+// Positions that broke or nearly broke encoders before: promotions, underpromotions, en passant,
+// castling (also through check) and stalemate, with the exact features they must encode to.
+// The format is described at the top of the file.
+pub const TRICKY_POSITIONS: &str = include_str!("../data/tricky_positions.txt");
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpectedMove {
+    Delta { chess_move: ChessMove, removed: Vec<u16>, placed: Vec<u16> }, // Seen from the mover's side
+    Illegal(ChessMove),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorpusPosition {
+    pub name: String,
+    pub board: Board,
+    pub active: Vec<u16>, // Sorted, from the side to move
+    pub expected_move: Option<ExpectedMove>,
+}
+
+fn invalid(line: &str, reason: &str) -> NNUEError {
+    NNUEError::InvalidData(format!("{} in {:?}", reason, line))
+}
+
+fn parse_indices(field: &str, line: &str) -> Result<Vec<u16>, NNUEError> {
+    field.split_whitespace().map(|index| index.parse::<u16>().map_err(|_| invalid(line, "bad feature index"))).collect()
+}
+
+impl CorpusPosition {
+    pub fn parse(line: &str) -> Result<CorpusPosition, NNUEError> {
+        let fields: Vec<&str> = line.split(';').map(str::trim).collect();
+        let [name, fen, active, chess_move, removed, placed] = fields[..] else {
+            return Err(invalid(line, "expected name; fen; active; move; removed; placed"));
+        };
+
+        let board = Board::from_str(fen).map_err(|_| invalid(line, "bad fen"))?;
+        let expected_move = match chess_move {
+            "-" => None,
+            chess_move => {
+                let chess_move = ChessMove::from_str(chess_move).map_err(|_| invalid(line, "bad move"))?;
+                match removed {
+                    "illegal" => Some(ExpectedMove::Illegal(chess_move)),
+                    removed => Some(ExpectedMove::Delta {
+                        chess_move,
+                        removed: parse_indices(removed, line)?,
+                        placed: parse_indices(placed, line)?,
+                    }),
+                }
+            }
+        };
+
+        Ok(CorpusPosition {
+            name: name.to_string(),
+            board,
+            active: parse_indices(active, line)?,
+            expected_move,
+        })
+    }
+}
+
+pub fn tricky_positions() -> Result<Vec<CorpusPosition>, NNUEError> {
+    // Comments start with '#', blank lines are skipped
+    TRICKY_POSITIONS
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(CorpusPosition::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chess::BoardStatus;
+
+    use super::*;
+    use crate::bit_move::{active_indices, active_indices_for};
+    use crate::features::FeatureSet;
+
+    fn sorted(mut indices: Vec<u16>) -> Vec<u16> {
+        indices.sort_unstable();
+        indices
+    }
+
+    #[test]
+    fn test_tricky_positions_encode_exactly() {
+        let positions = tricky_positions().unwrap();
+        assert_eq!(positions.len(), 9);
+
+        for position in &positions {
+            let board = position.board;
+            assert_eq!(sorted(active_indices(&board)), position.active, "{}", position.name);
+            assert_eq!(sorted(FeatureSet::default().active(&board)), position.active, "{}", position.name);
+
+            match &position.expected_move {
+                Some(ExpectedMove::Delta { chess_move, removed, placed }) => {
+                    assert!(board.legal(*chess_move), "{}", position.name);
+                    let mover = board.side_to_move();
+                    let before = active_indices_for(&board, mover);
+                    let after = active_indices_for(&board.make_move_new(*chess_move), mover);
+                    let gone = sorted(before.iter().filter(|index| !after.contains(index)).copied().collect());
+                    let new = sorted(after.iter().filter(|index| !before.contains(index)).copied().collect());
+                    assert_eq!((&gone, &new), (removed, placed), "{}", position.name);
+                }
+                Some(ExpectedMove::Illegal(chess_move)) => assert!(!board.legal(*chess_move), "{}", position.name),
+                None => {}
+            }
+        }
+
+        let stalemate = positions.iter().find(|position| position.name == "stalemate").unwrap();
+        assert_eq!(stalemate.board.status(), BoardStatus::Stalemate);
+    }
+}
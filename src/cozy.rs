@@ -0,0 +1,98 @@
+use chess::ALL_SQUARES;
+use cozy_chess as cozy;
+
+use crate::bit_move::{get_index, orient};
+use crate::position::{BoardAdapter, CastleRights, Color, File, Piece, Square};
+
+// BoardAdapter for cozy-chess boards, so engines built on cozy-chess can hand their positions to the
+// evaluators directly. Both libraries number squares a1 = 0 to h8 = 63 and order pieces pawn to king.
+fn color(color: cozy::Color) -> Color {
+    match color {
+        cozy::Color::White => Color::White,
+        cozy::Color::Black => Color::Black,
+    }
+}
+
+fn cozy_color(color: Color) -> cozy::Color {
+    match color {
+        Color::White => cozy::Color::White,
+        Color::Black => cozy::Color::Black,
+    }
+}
+
+fn piece(piece: cozy::Piece) -> Piece {
+    match piece {
+        cozy::Piece::Pawn => Piece::Pawn,
+        cozy::Piece::Knight => Piece::Knight,
+        cozy::Piece::Bishop => Piece::Bishop,
+        cozy::Piece::Rook => Piece::Rook,
+        cozy::Piece::Queen => Piece::Queen,
+        cozy::Piece::King => Piece::King,
+    }
+}
+
+fn square(square: cozy::Square) -> Square {
+    ALL_SQUARES[square as usize]
+}
+
+impl BoardAdapter for cozy::Board {
+    fn side_to_move(&self) -> Color {
+        color(cozy::Board::side_to_move(self))
+    }
+
+    fn piece_on(&self, square: Square) -> Option<(Piece, Color)> {
+        let square = cozy::Square::index(square.to_index());
+        Some((piece(cozy::Board::piece_on(self, square)?), color(self.color_on(square)?)))
+    }
+
+    fn castle_rights(&self, color: Color) -> CastleRights {
+        // cozy-chess keeps the rook's file for Chess960, the chess crate only knows the standard castles
+        let rights = cozy::Board::castle_rights(self, cozy_color(color));
+        match (rights.short.is_some(), rights.long.is_some()) {
+            (true, true) => CastleRights::Both,
+            (true, false) => CastleRights::KingSide,
+            (false, true) => CastleRights::QueenSide,
+            (false, false) => CastleRights::NoRights,
+        }
+    }
+
+    fn en_passant(&self) -> Option<File> {
+        cozy::Board::en_passant(self).map(|file| File::from_index(file as usize))
+    }
+
+    fn active_features(&self) -> Vec<u16> {
+        // Straight from the bitboards, no square by square lookups
+        let turn = cozy::Board::side_to_move(self);
+        let mut indices = Vec::with_capacity(32);
+        for kind in cozy::Piece::ALL {
+            for side in cozy::Color::ALL {
+                for on in self.colored_pieces(side, kind) {
+                    indices.push(get_index(piece(kind), side == turn, orient(square(on), color(turn))));
+                }
+            }
+        }
+        indices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::bit_move::active_indices;
+    use crate::position::Board;
+
+    #[test]
+    fn test_cozy_board_matches_chess_board() {
+        for fen in ["r3k2r/8/8/3pP3/8/8/8/R3K2R w KQkq d6 0 1", "4k3/8/8/3q4/4P3/8/8/4K3 b - - 0 1"] {
+            let board = Board::from_str(fen).unwrap();
+            let cozy_board: cozy::Board = fen.parse().unwrap();
+            assert_eq!(cozy_board.to_board().unwrap(), board);
+            let (mut features, mut expected) = (cozy_board.active_features(), active_indices(&board));
+            features.sort();
+            expected.sort();
+            assert_eq!(features, expected);
+        }
+    }
+}
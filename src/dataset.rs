@@ -0,0 +1,102 @@
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use chess::{Board, ChessMove};
+
+use crate::compression::{open_reader, FileWriter};
+use crate::error::NNUEError;
+
+// Training data is one position per line: "fen;score;result" or "fen;score;result;move"
+//   score is the label in network output units and result the game outcome (1.0, 0.5 or 0.0),
+//   both from white's point of view so a position's labels don't depend on the side to move.
+//   move is the one played from the position or an engine's best move, in long algebraic notation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub board: Board,
+    pub score: i16,
+    pub result: f32,
+    pub best_move: Option<ChessMove>, // Target of the policy head, see TrainerOptions::policy_weight
+}
+
+fn invalid(line: &str, reason: &str) -> NNUEError {
+    NNUEError::InvalidData(format!("{} in {:?}", reason, line))
+}
+
+impl Sample {
+    pub fn parse(line: &str) -> Result<Sample, NNUEError> {
+        let mut fields = line.split(';').map(str::trim);
+        let (fen, score, result, best_move) = match (fields.next(), fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(fen), Some(score), Some(result), best_move, None) => (fen, score, result, best_move),
+            _ => return Err(invalid(line, "expected fen;score;result with an optional ;move")),
+        };
+
+        let board = Board::from_str(fen).map_err(|_| invalid(line, "bad fen"))?;
+        let score = score.parse::<i16>().map_err(|_| invalid(line, "bad score"))?;
+        let result = result.parse::<f32>().map_err(|_| invalid(line, "bad result"))?;
+        if !(0.0..=1.0).contains(&result) {
+            return Err(invalid(line, "result outside 0..1"));
+        }
+        let best_move = match best_move {
+            Some(name) => match ChessMove::from_str(name) {
+                Ok(chess_move) if board.legal(chess_move) => Some(chess_move),
+                _ => return Err(invalid(line, "bad or illegal move")),
+            },
+            None => None,
+        };
+        Ok(Sample { board, score, result, best_move })
+    }
+
+    pub fn to_line(&self) -> String {
+        match self.best_move {
+            Some(chess_move) => format!("{};{};{};{}", self.board, self.score, self.result, chess_move),
+            None => format!("{};{};{}", self.board, self.score, self.result),
+        }
+    }
+}
+
+pub fn read_samples<P: AsRef<Path>>(path: P) -> Result<Vec<Sample>, NNUEError> {
+    // Blank lines are skipped, anything else must parse. zstd compressed files are read as they decompress.
+    let mut samples = Vec::new();
+    for line in open_reader(path)?.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            samples.push(Sample::parse(&line)?);
+        }
+    }
+    Ok(samples)
+}
+
+pub fn write_samples<P: AsRef<Path>>(path: P, samples: &[Sample]) -> Result<(), NNUEError> {
+    // Compressed when the path ends in .zst, see compression
+    let mut writer = FileWriter::create(path)?;
+    for sample in samples {
+        writeln!(writer, "{}", sample.to_line())?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_samples_round_trip() {
+        let path = std::env::temp_dir().join("shallow_nnue_dataset_round_trip.txt");
+        let samples = vec![
+            Sample { board: Board::default(), score: 25, result: 0.5, best_move: None },
+            Sample::parse("4k3/8/8/8/8/8/8/3QK3 b - - 0 1;-900;0").unwrap(),
+            Sample::parse("4k3/8/8/8/8/8/8/3QK3 w - - 0 1;900;1;d1d7").unwrap(),
+        ];
+        assert_eq!(samples[2].best_move, Some(ChessMove::new(chess::Square::D1, chess::Square::D7, None)));
+        write_samples(&path, &samples).unwrap();
+        assert_eq!(read_samples(&path).unwrap(), samples);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(Sample::parse("not a fen;0;1"), Err(NNUEError::InvalidData(_))));
+        assert!(matches!(Sample::parse("8/8/8/8/8/8/8/K6k w - - 0 1;0;2"), Err(NNUEError::InvalidData(_))));
+        assert!(matches!(Sample::parse("8/8/8/8/8/8/8/K6k w - - 0 1;0;1;h1h2"), Err(NNUEError::InvalidData(_))));
+    }
+}
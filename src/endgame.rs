@@ -0,0 +1,70 @@
+use chess::{Board, Piece};
+
+use crate::phase::{phase, GamePhase};
+
+// Non-pawn material of both sides in the start position, in pawns (knight and bishop 3, rook 5, queen 9)
+pub const START_MATERIAL: u32 = 62;
+
+pub fn non_pawn_material(board: &Board) -> u32 {
+    // Counted for both sides together, kings and pawns don't count
+    [(Piece::Knight, 3), (Piece::Bishop, 3), (Piece::Rook, 5), (Piece::Queen, 9)]
+        .iter()
+        .map(|(piece, value)| board.pieces(*piece).popcnt() * value)
+        .sum()
+}
+
+// When a second, endgame network takes over from the main one, by the non-pawn material left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndgameGate {
+    Switch(u32), // Only the endgame network at or below this much material
+    Crossfade { full: u32, none: u32 }, // Endgame weight 1 at or below full, 0 at or above none, linear between
+    Phase, // Only the endgame network where phase calls it an endgame
+}
+
+impl EndgameGate {
+    pub fn weight(&self, board: &Board) -> f32 {
+        // Share of the endgame network in the blended score
+        let material = non_pawn_material(board);
+        match *self {
+            EndgameGate::Switch(threshold) => (material <= threshold) as u32 as f32,
+            EndgameGate::Phase => (phase(board) == GamePhase::Endgame) as u32 as f32,
+            EndgameGate::Crossfade { full, none } => {
+                if material <= full {
+                    1.0
+                } else if material >= none {
+                    0.0
+                } else {
+                    (none - material) as f32 / (none - full) as f32
+                }
+            }
+        }
+    }
+}
+
+pub fn blend(main: i16, endgame: i16, weight: f32) -> i16 {
+    (main as f32 * (1.0 - weight) + endgame as f32 * weight).round() as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_gate_weights() {
+        let rook_ending = Board::from_str("4k3/4r3/8/8/8/8/4R3/4K3 w - - 0 1").unwrap();
+        assert_eq!(non_pawn_material(&Board::default()), START_MATERIAL);
+        assert_eq!(non_pawn_material(&rook_ending), 10);
+
+        assert_eq!(EndgameGate::Switch(10).weight(&rook_ending), 1.0);
+        assert_eq!(EndgameGate::Switch(10).weight(&Board::default()), 0.0);
+
+        let crossfade = EndgameGate::Crossfade { full: 0, none: 20 };
+        assert_eq!(crossfade.weight(&rook_ending), 0.5);
+        assert_eq!(blend(100, 300, crossfade.weight(&rook_ending)), 200);
+        assert_eq!(blend(100, 300, crossfade.weight(&Board::default())), 100);
+        assert_eq!(EndgameGate::Phase.weight(&rook_ending), 1.0);
+        assert_eq!(EndgameGate::Phase.weight(&Board::default()), 0.0);
+    }
+}
@@ -0,0 +1,520 @@
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, AtomicI16, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chess::{Board, BoardStatus, ChessMove, MoveGen};
+
+use crate::error::NNUEError;
+use crate::repetition::{is_irreversible, RepetitionHistory};
+use crate::eval_report::EvalReport;
+use crate::search::{SearchOptions, SearchResult, Searcher};
+use crate::perspective::ScorePerspective;
+use crate::shallow_nnue::{BoxedNNUE, NNUE};
+use crate::skill::{LimitedSearcher, Skill, SkillSettings};
+use crate::smp::{LazySmp, SmpSettings};
+
+// The engine behind the protocol frontends (uci::frontend and cecp): the game being played, the
+// evaluators with the Lazy SMP and strength limited searchers, and the clock handling. Frontends only
+// translate commands, so every protocol plays the same. Searches run on the calling thread until
+// their limit or a stop command, which run_frontend reads on its own thread.
+pub const MOVE_OVERHEAD_MS: u64 = 100; // Kept back from every move for the GUI or network round trip
+const MIN_MOVE_MS: u64 = 10;
+const DEFAULT_MOVES_TO_GO: u64 = 40;
+const STOP_POLL: Duration = Duration::from_millis(5); // How often a running search checks its clock and stop commands
+pub const MAX_TEMPO: i16 = 100;
+
+// Makes the engine's evaluators, one for the main thread and one per helper thread, e.g.
+// match_runner::shared_evaluator so they all run on one loaded model
+pub type BoxedEvaluatorFactory = Box<dyn Fn() -> Result<BoxedNNUE, NNUEError> + Send + Sync>;
+
+pub fn time_budget(remaining_ms: u64, increment_ms: u64, moves_to_go: Option<u64>) -> Duration {
+    // An even share of the clock over the moves to the next time control (or DEFAULT_MOVES_TO_GO)
+    // and most of the increment, never more than half of what is left
+    let moves = moves_to_go.unwrap_or(DEFAULT_MOVES_TO_GO).clamp(1, DEFAULT_MOVES_TO_GO);
+    let budget = remaining_ms / moves + increment_ms * 3 / 4;
+    let cap = remaining_ms.saturating_sub(MOVE_OVERHEAD_MS) / 2;
+    Duration::from_millis(budget.min(cap).max(MIN_MOVE_MS))
+}
+
+fn watched<T>(stop: &AtomicBool, deadline: Option<Instant>, interrupted: impl Fn() -> bool + Sync, search: impl FnOnce() -> T) -> T {
+    // Sets stop once the deadline passed or interrupted returns true, checked every STOP_POLL until the
+    // search returns. It is set again on every check as searches clear it when they start. The watcher
+    // is joined before returning, so it can't stop the next search.
+    let (done, watcher) = mpsc::channel::<()>();
+    let interrupted = &interrupted;
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            while watcher.recv_timeout(STOP_POLL) == Err(RecvTimeoutError::Timeout) {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) || interrupted() {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            }
+        });
+        let result = search();
+        drop(done);
+        result
+    })
+}
+
+pub(crate) fn timed_search(
+    searcher: &mut Searcher,
+    evaluator: &mut dyn NNUE,
+    board: &Board,
+    depth: u8,
+    budget: Option<Duration>,
+) -> Result<SearchResult, NNUEError> {
+    // Iterative deepening to depth, stopped when the budget runs out
+    let stop = Arc::new(AtomicBool::new(false));
+    searcher.set_stop(Some(Arc::clone(&stop)));
+    let deadline = budget.map(|budget| Instant::now() + budget);
+    let result = watched(&stop, deadline, || false, || searcher.iterate(evaluator, board, depth));
+    searcher.set_stop(None);
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchLimit {
+    Depth(u8),
+    MoveTime(Duration), // Time limits search up to the search options' depth
+    Clock { remaining_ms: u64, increment_ms: u64, moves_to_go: Option<u64> }, // The engine's own clock
+}
+
+// A finished search with what protocols report about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EngineResult {
+    pub search: SearchResult, // No best move when the game is over
+    pub elapsed: Duration,
+}
+
+impl EngineResult {
+    pub fn report(&self) -> EvalReport {
+        EvalReport::from_search(&self.search, self.search.depth, self.elapsed)
+    }
+}
+
+// Stop commands read while the frontend is busy searching. Input lines are numbered as they are
+// read, and a stop line stops every search started for an earlier line, also one that only starts
+// after the stop was read.
+#[derive(Debug, Default)]
+pub struct StopRequests {
+    handling: AtomicU64, // The line the frontend is handling
+    last_stop: AtomicU64, // The last stop line read, 0 before any
+}
+
+impl StopRequests {
+    fn handle(&self, line: u64) {
+        self.handling.store(line, Ordering::Relaxed);
+    }
+
+    fn request(&self, line: u64) {
+        self.last_stop.store(line, Ordering::Relaxed);
+    }
+
+    fn handling(&self) -> u64 {
+        self.handling.load(Ordering::Relaxed)
+    }
+
+    fn stops(&self, line: u64) -> bool {
+        // Whether a search started for line has to stop
+        self.last_stop.load(Ordering::Relaxed) > line
+    }
+}
+
+// Adds the engine's Tempo option to an evaluator, on top of any tempo it applies itself (see
+// ShallowNNUE::set_tempo). Every evaluator of an engine shares the value.
+struct TempoEval {
+    inner: BoxedNNUE,
+    board: Board,
+    tempo: Arc<AtomicI16>,
+}
+
+impl NNUE for TempoEval {
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        // The score after the move is the mover's, the other side has the tempo there
+        let (perspective, mover) = (self.inner.perspective(), self.board.side_to_move());
+        let score = perspective.to_side_to_move(self.inner.forward(chess_move)?, mover);
+        Ok(perspective.from_side_to_move(score.saturating_sub(self.tempo.load(Ordering::Relaxed)), mover))
+    }
+
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+        self.board = board;
+        self.inner.set_board_hard(board)
+    }
+
+    fn evaluate(&mut self) -> Result<i16, NNUEError> {
+        let (perspective, turn) = (self.inner.perspective(), self.board.side_to_move());
+        let score = perspective.to_side_to_move(self.inner.evaluate()?, turn);
+        Ok(perspective.from_side_to_move(score.saturating_add(self.tempo.load(Ordering::Relaxed)), turn))
+    }
+
+    fn perspective(&self) -> ScorePerspective {
+        self.inner.perspective()
+    }
+}
+
+pub struct EngineCore {
+    factory: BoxedEvaluatorFactory, // For the helper threads when Threads goes up
+    tempo: Arc<AtomicI16>, // "Tempo", shared by the evaluators the factory makes
+    evaluator: BoxedNNUE,
+    options: SearchOptions,
+    smp: LazySmp, // Searches at full strength
+    skill: SkillSettings,
+    limited: LimitedSearcher, // Searches when the skill settings limit the strength
+    stop: Arc<AtomicBool>, // Shared by both searchers
+    stop_requests: Arc<StopRequests>,
+    start: Board,
+    boards: Vec<Board>, // Before each played move, for undo
+    board: Board,
+    history: RepetitionHistory,
+}
+
+impl EngineCore {
+    pub fn new(factory: BoxedEvaluatorFactory, options: SearchOptions, settings: SmpSettings) -> Result<EngineCore, NNUEError> {
+        // The limited searcher is seeded from the clock, so practice games differ
+        let tempo = Arc::new(AtomicI16::new(0));
+        let shared_tempo = Arc::clone(&tempo);
+        let factory: BoxedEvaluatorFactory = Box::new(move || {
+            let tempo = Arc::clone(&shared_tempo);
+            Ok(Box::new(TempoEval { inner: factory()?, board: Board::default(), tempo }) as BoxedNNUE)
+        });
+        let evaluator = factory()?;
+        let smp = LazySmp::new(options, settings, &*factory)?;
+        let stop = smp.stop_flag();
+        let skill = SkillSettings::default();
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64);
+        let mut limited = LimitedSearcher::new(options, skill.skill(), seed);
+        limited.set_stop(Some(Arc::clone(&stop)));
+        let board = Board::default();
+        Ok(EngineCore {
+            factory,
+            tempo,
+            evaluator,
+            options,
+            smp,
+            skill,
+            limited,
+            stop,
+            stop_requests: Arc::new(StopRequests::default()),
+            start: board,
+            boards: Vec::new(),
+            board,
+            history: RepetitionHistory::new(&board),
+        })
+    }
+
+    pub fn uci_options() -> Vec<String> {
+        // Option lines for the reply to "uci"
+        let mut options = SmpSettings::uci_options();
+        options.extend(SkillSettings::uci_options());
+        options.push(format!("option name Tempo type spin default 0 min {} max {}", -MAX_TEMPO, MAX_TEMPO));
+        options
+    }
+
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<bool, NNUEError> {
+        // Threads and Hash go to the SMP search, the strength options to the limited searcher and Tempo
+        // to the evaluators. False for options none of them knows.
+        if name.trim().eq_ignore_ascii_case("tempo") {
+            let centipawns = value.trim().parse::<i16>().map_err(|_| NNUEError::InvalidConfig(format!("bad value {:?} for Tempo", value)))?;
+            self.set_tempo(centipawns);
+            return Ok(true);
+        }
+        let mut settings = self.smp.settings();
+        if settings.set_option(name, value)? {
+            self.smp.configure(settings, &*self.factory)?;
+            return Ok(true);
+        }
+        if self.skill.set_option(name, value)? {
+            self.limited.set_skill(self.skill.skill());
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    pub fn set_tempo(&mut self, centipawns: i16) {
+        // Centipawns for the side to move, clamped to MAX_TEMPO either way
+        self.tempo.store(centipawns.clamp(-MAX_TEMPO, MAX_TEMPO), Ordering::Relaxed);
+    }
+
+    pub fn tempo(&self) -> i16 {
+        self.tempo.load(Ordering::Relaxed)
+    }
+
+    pub fn smp_settings(&self) -> SmpSettings {
+        self.smp.settings()
+    }
+
+    pub fn skill_settings(&self) -> SkillSettings {
+        self.skill
+    }
+
+    pub fn stop_requests(&self) -> Arc<StopRequests> {
+        Arc::clone(&self.stop_requests)
+    }
+
+    pub fn options(&self) -> SearchOptions {
+        self.options
+    }
+
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+
+    pub fn start(&self) -> &Board {
+        &self.start
+    }
+
+    pub fn plies(&self) -> usize {
+        self.boards.len()
+    }
+
+    pub fn new_game(&mut self) -> Result<(), NNUEError> {
+        // The start position with an empty table
+        self.smp.transposition_table().clear();
+        self.set_position(Board::default(), &[]).map(|_| ())
+    }
+
+    pub fn set_position(&mut self, start: Board, chess_moves: &[ChessMove]) -> Result<usize, NNUEError> {
+        // Replays the line like ShallowNNUE::set_line: a GUI resends the whole game with every
+        // position command, usually a move or two longer, so the moves shared with the current line
+        // are kept and only the rest taken back and played. Nothing changes if one of the moves is
+        // illegal. Returns the number of moves played.
+        let mut line = Vec::with_capacity(chess_moves.len() + 1);
+        line.push(start);
+        for chess_move in chess_moves {
+            let board = line[line.len() - 1];
+            if !board.legal(*chess_move) {
+                return Err(NNUEError::IllegalMove);
+            }
+            line.push(board.make_move_new(*chess_move));
+        }
+
+        let current = self.boards.iter().chain(std::iter::once(&self.board));
+        let shared = current.zip(&line).take_while(|(old, new)| old == new).count();
+        if shared == 0 {
+            self.start = start;
+            self.boards.clear();
+            self.board = start;
+            self.history.reset(&start);
+        }
+        while self.boards.len() >= shared.max(1) {
+            self.undo();
+        }
+        let played = &chess_moves[shared.max(1) - 1..];
+        for chess_move in played {
+            self.push_move(*chess_move)?;
+        }
+        Ok(played.len())
+    }
+
+    pub fn push_move(&mut self, chess_move: ChessMove) -> Result<(), NNUEError> {
+        if !self.board.legal(chess_move) {
+            return Err(NNUEError::IllegalMove);
+        }
+        let after = self.board.make_move_new(chess_move);
+        self.history.push(&after, is_irreversible(&self.board, &after, chess_move));
+        self.boards.push(self.board);
+        self.board = after;
+        Ok(())
+    }
+
+    pub fn undo(&mut self) -> bool {
+        // Takes back the last move, false at the start position
+        match self.boards.pop() {
+            Some(board) => {
+                self.board = board;
+                self.history.pop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_draw(&self) -> bool {
+        // Threefold repetition or stalemate, draws a frontend should claim or report
+        self.history.occurrences() >= 3 || self.board.status() == BoardStatus::Stalemate
+    }
+
+    pub fn search(&mut self, limit: SearchLimit) -> Result<EngineResult, NNUEError> {
+        // Searches the current position, the best move is not played. Full strength searches use every
+        // thread, limited ones a single thread at the skill's depth.
+        let start = Instant::now();
+        if self.board.status() != BoardStatus::Ongoing {
+            let search = SearchResult { best_move: None, score: 0, nodes: 0, depth: 0 };
+            return Ok(EngineResult { search, elapsed: start.elapsed() });
+        }
+        let (depth, budget) = match limit {
+            SearchLimit::Depth(depth) => (depth, None),
+            SearchLimit::MoveTime(budget) => (self.options.depth, Some(budget)),
+            SearchLimit::Clock { remaining_ms, increment_ms, moves_to_go } => {
+                (self.options.depth, Some(time_budget(remaining_ms, increment_ms, moves_to_go)))
+            }
+        };
+        let deadline = budget.map(|budget| start + budget);
+        let (line, requests) = (self.stop_requests.handling(), Arc::clone(&self.stop_requests));
+        let interrupted = || requests.stops(line);
+        self.stop.store(false, Ordering::Relaxed);
+
+        let (evaluator, board) = (self.evaluator.as_mut(), &self.board);
+        let mut search = match self.skill.skill() == Skill::full() {
+            true => {
+                self.smp.set_game_history(&self.history);
+                let smp = &mut self.smp;
+                watched(&self.stop, deadline, interrupted, || smp.search_to(evaluator, board, depth))?
+            }
+            false => {
+                let limited = &mut self.limited;
+                watched(&self.stop, deadline, interrupted, || limited.search_to(evaluator, board, depth))?
+            }
+        };
+
+        // Even a search stopped before its first iteration has to move
+        search.best_move = search.best_move.or_else(|| MoveGen::new_legal(&self.board).next());
+        Ok(EngineResult { search, elapsed: start.elapsed() })
+    }
+}
+
+// A text protocol on top of an EngineCore, one command line in and any number of lines out
+pub trait Frontend {
+    // False once the GUI asked the engine to quit
+    fn handle(&mut self, line: &str, out: &mut Vec<String>) -> Result<bool, NNUEError>;
+
+    fn core(&self) -> &EngineCore;
+
+    // The command that stops a running search, "stop" in UCI
+    fn stop_command(&self) -> &'static str;
+}
+
+pub fn run_frontend<F: Frontend + ?Sized>(frontend: &mut F, input: impl BufRead + Send + 'static, mut output: impl Write) -> Result<(), NNUEError> {
+    // Serves commands until quit or the end of the input. A command that fails is reported on
+    // stderr and the engine carries on, GUIs take an exiting engine for a crash. Input is read on
+    // its own thread, so a stop command reaches a search that is still running.
+    let (requests, stop_command) = (frontend.core().stop_requests(), frontend.stop_command());
+    let (lines, received) = mpsc::channel();
+    thread::spawn(move || {
+        for (number, line) in (1..).zip(input.lines()) {
+            if line.as_ref().is_ok_and(|line| line.trim() == stop_command) {
+                requests.request(number);
+            }
+            if lines.send((number, line)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let requests = frontend.core().stop_requests();
+    let mut out = Vec::new();
+    for (number, line) in received {
+        let line = line?;
+        requests.handle(number);
+        let running = frontend.handle(line.trim(), &mut out).unwrap_or_else(|err| {
+            eprintln!("{}: {}", line.trim(), err);
+            true
+        });
+        for reply in out.drain(..) {
+            writeln!(output, "{}", reply)?;
+        }
+        output.flush()?;
+        if !running {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::str::FromStr;
+
+    use chess::Square;
+
+    use super::*;
+    use crate::search::tests::MaterialEval;
+
+    pub(crate) fn material_core(options: SearchOptions) -> EngineCore {
+        let factory: BoxedEvaluatorFactory = Box::new(|| Ok(Box::new(MaterialEval { board: Board::default() }) as BoxedNNUE));
+        EngineCore::new(factory, options, SmpSettings { hash_mb: 1, ..SmpSettings::default() }).unwrap()
+    }
+
+    #[test]
+    fn test_engine_core() {
+        let mut core = material_core(SearchOptions { depth: 3, ..SearchOptions::default() });
+        let free_queen = Board::from_str("4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1").unwrap();
+        core.set_position(free_queen, &[]).unwrap();
+        let result = core.search(SearchLimit::MoveTime(Duration::from_secs(5))).unwrap();
+        assert_eq!(result.search.best_move, Some(ChessMove::new(Square::E4, Square::D5, None)));
+        assert_eq!(result.report().best_move.as_deref(), Some("e4d5"));
+
+        // An illegal move leaves the position as it was, undo walks back to the start
+        let e2e4 = ChessMove::new(Square::E2, Square::E4, None);
+        assert!(core.set_position(Board::default(), &[e2e4, e2e4]).is_err());
+        assert_eq!(*core.board(), free_queen);
+        core.new_game().unwrap();
+        core.push_move(e2e4).unwrap();
+        assert_eq!(core.plies(), 1);
+        assert!(core.undo() && !core.undo());
+
+        // A longer line only plays the new moves, a different one starts over
+        let line: Vec<ChessMove> = ["e2e4", "e7e5", "g1f3"].iter().map(|name| ChessMove::from_str(name).unwrap()).collect();
+        assert_eq!(core.set_position(Board::default(), &line[..2]).unwrap(), 2);
+        assert_eq!(core.set_position(Board::default(), &line).unwrap(), 1);
+        assert_eq!(core.set_position(Board::default(), &line[..1]).unwrap(), 0);
+        assert_eq!(core.plies(), 1);
+        assert_eq!(core.set_position(free_queen, &[]).unwrap(), 0);
+        assert_eq!((*core.start(), core.plies()), (free_queen, 0));
+
+        assert_eq!(time_budget(60000, 1000, Some(10)), Duration::from_millis(6750));
+        assert_eq!(time_budget(50, 0, None), Duration::from_millis(MIN_MOVE_MS));
+    }
+
+    #[test]
+    fn test_tempo_eval() {
+        // The side to move gains the tempo, a move's score loses it to the other side
+        let board = Board::from_str("4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1").unwrap();
+        let capture = ChessMove::new(Square::E4, Square::D5, None);
+        let mut material = MaterialEval { board };
+        let (base, forwarded) = (material.evaluate().unwrap(), material.forward(capture).unwrap());
+        let inner = Box::new(MaterialEval { board: Board::default() });
+        let mut tempo = TempoEval { inner, board: Board::default(), tempo: Arc::new(AtomicI16::new(15)) };
+        tempo.set_board_hard(board).unwrap();
+        assert_eq!(tempo.evaluate().unwrap(), base + 15);
+        assert_eq!(tempo.forward(capture).unwrap(), forwarded - 15);
+    }
+
+    #[test]
+    fn test_engine_options() {
+        let mut core = material_core(SearchOptions { depth: 3, ..SearchOptions::default() });
+        assert_eq!(EngineCore::uci_options().len(), 6);
+        assert!(core.set_option("Threads", "2").unwrap());
+        assert!(core.set_option("Hash", "2").unwrap());
+        assert_eq!(core.smp_settings(), SmpSettings { threads: 2, hash_mb: 2 });
+        assert!(!core.set_option("Ponder", "true").unwrap());
+        assert!(core.set_option("Tempo", "500").unwrap());
+        assert_eq!(core.tempo(), MAX_TEMPO);
+        assert!(core.set_option("Tempo", "fast").is_err());
+        core.set_tempo(0);
+
+        // Two threads take the free queen, the weakest level still moves
+        let free_queen = Board::from_str("4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1").unwrap();
+        core.set_position(free_queen, &[]).unwrap();
+        let capture = Some(ChessMove::new(Square::E4, Square::D5, None));
+        assert_eq!(core.search(SearchLimit::Depth(3)).unwrap().search.best_move, capture);
+        assert!(core.set_option("Skill Level", "0").unwrap());
+        assert_eq!(core.skill_settings().level, 0);
+        assert!(core.search(SearchLimit::Depth(3)).unwrap().search.best_move.is_some_and(|chess_move| free_queen.legal(chess_move)));
+
+        // A stop read for a later line ends a search that would run for a minute
+        let mut deep = material_core(SearchOptions { depth: 64, ..SearchOptions::default() });
+        deep.set_position(free_queen, &[]).unwrap();
+        let requests = deep.stop_requests();
+        requests.handle(1);
+        requests.request(2);
+        assert!(requests.stops(1) && !requests.stops(2));
+        let start = Instant::now();
+        let result = deep.search(SearchLimit::MoveTime(Duration::from_secs(60))).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(result.search.best_move.is_some());
+    }
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+use std::io;
+
+use tch::TchError;
+
+#[derive(Debug)]
+pub enum NNUEError {
+    Model(TchError), // The model could not be loaded
+    Tensor(TchError), // A tensor operation or the model forward failed
+    IllegalMove, // The move cannot be played on the current board
+    UnknownGame, // No session exists for the game id
+    InvalidDevice(String), // The requested device is unknown or unavailable in this build
+    Io(io::Error), // Reading or writing a file failed
+    InvalidWeights(String), // A native weight file is malformed
+    SearchArenaFull, // The search needed more move slots than its arena capacity
+    InvalidConfig(String), // A parameter file has an unknown name or a malformed value
+    InvalidData(String), // A training data file is malformed
+    Engine(String), // An external UCI engine failed or broke the protocol
+    Server(String), // The evaluation server stopped or could not evaluate a batch
+    Overloaded, // The evaluation server's queue was full and the request was turned away, like an HTTP 429
+    DeadlineExceeded, // The request's deadline passed before it was evaluated
+    Guard(String), // A guarded tch call got a malformed input or panicked, see TorchGuard
+    Remote(String), // A web API such as Lichess's refused a request or answered with something unexpected
+}
+
+impl fmt::Display for NNUEError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NNUEError::Model(err) => write!(f, "failed to load model: {}", err),
+            NNUEError::Tensor(err) => write!(f, "tensor operation failed: {}", err),
+            NNUEError::IllegalMove => write!(f, "illegal move for the current board"),
+            NNUEError::UnknownGame => write!(f, "unknown game id"),
+            NNUEError::InvalidDevice(name) => write!(f, "invalid device: {}", name),
+            NNUEError::Io(err) => write!(f, "io error: {}", err),
+            NNUEError::InvalidWeights(reason) => write!(f, "invalid weight file: {}", reason),
+            NNUEError::SearchArenaFull => write!(f, "search arena capacity exceeded"),
+            NNUEError::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            NNUEError::InvalidData(reason) => write!(f, "invalid training data: {}", reason),
+            NNUEError::Engine(reason) => write!(f, "external engine error: {}", reason),
+            NNUEError::Server(reason) => write!(f, "evaluation server error: {}", reason),
+            NNUEError::Overloaded => write!(f, "evaluation server overloaded"),
+            NNUEError::DeadlineExceeded => write!(f, "evaluation deadline exceeded"),
+            NNUEError::Guard(reason) => write!(f, "guarded tch call failed: {}", reason),
+            NNUEError::Remote(reason) => write!(f, "remote api error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for NNUEError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NNUEError::Model(err) | NNUEError::Tensor(err) => Some(err),
+            NNUEError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<TchError> for NNUEError {
+    fn from(err: TchError) -> NNUEError {
+        NNUEError::Tensor(err)
+    }
+}
+
+impl From<io::Error> for NNUEError {
+    fn from(err: io::Error) -> NNUEError {
+        NNUEError::Io(err)
+    }
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::search::{SearchResult, MATE_SCORE, MAX_PLY};
+
+// Score units either side of zero that count towards a draw in Wdl::from_score
+pub const DEFAULT_DRAW_MARGIN: f64 = 100.0;
+
+// Win, draw and loss chances in permille for the side to move, as UCI's "wdl" reports them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Wdl {
+    pub win: u16,
+    pub draw: u16,
+    pub loss: u16,
+}
+
+impl Wdl {
+    pub fn from_score(score: i16, scale: f64, draw_margin: f64) -> Wdl {
+        // A win needs the score to clear the draw margin, a loss the same the other way, the rest is drawn.
+        // scale is the win scale of the evaluator, see ShallowNNUE::to_win_probability.
+        let probability = |x: f64| 1.0 / (1.0 + 10f64.powf(-x / scale));
+        let win = (probability(score as f64 - draw_margin) * 1000.0).round() as u16;
+        let loss = (probability(-(score as f64) - draw_margin) * 1000.0).round() as u16;
+        Wdl { win, draw: 1000u16.saturating_sub(win + loss), loss }
+    }
+}
+
+// One evaluation or search result, for GUIs and services that want structured output. Serializes to
+// JSON with serde, to_uci_info writes the fields UCI knows as an info line.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvalReport {
+    pub score: i16, // From the side to move, as UCI reports scores
+    pub wdl: Option<Wdl>,
+    pub bucket: Option<u32>, // Network the score mostly came from, 0 main and 1 endgame (see EndgameGate)
+    pub time_ms: u64,
+    pub depth: Option<u8>, // Only for search results
+    pub nodes: Option<u64>,
+    pub best_move: Option<String>, // Long algebraic
+}
+
+impl EvalReport {
+    pub fn from_search(result: &SearchResult, depth: u8, elapsed: Duration) -> EvalReport {
+        EvalReport {
+            score: result.score,
+            time_ms: elapsed.as_millis() as u64,
+            depth: Some(depth),
+            nodes: Some(result.nodes),
+            best_move: result.best_move.map(|chess_move| chess_move.to_string()),
+            ..EvalReport::default()
+        }
+    }
+
+    pub fn mate_in(&self) -> Option<i32> {
+        // Moves to mate for search scores within MAX_PLY of the mate score, negative when getting mated
+        let plies = MATE_SCORE as i32 - (self.score as i32).abs();
+        if self.depth.is_none() || plies > MAX_PLY as i32 {
+            return None;
+        }
+        let moves = (plies + 1) / 2;
+        Some(if self.score > 0 { moves } else { -moves })
+    }
+
+    pub fn to_uci_info(&self) -> String {
+        let mut info = String::from("info");
+        if let Some(depth) = self.depth {
+            info.push_str(&format!(" depth {}", depth));
+        }
+        match self.mate_in() {
+            Some(moves) => info.push_str(&format!(" score mate {}", moves)),
+            None => info.push_str(&format!(" score cp {}", self.score)),
+        }
+        if let Some(wdl) = self.wdl {
+            info.push_str(&format!(" wdl {} {} {}", wdl.win, wdl.draw, wdl.loss));
+        }
+        if let Some(nodes) = self.nodes {
+            info.push_str(&format!(" nodes {}", nodes));
+        }
+        info.push_str(&format!(" time {}", self.time_ms));
+        // pv runs to the end of the line, so the bucket (which UCI has no field for) is only in the JSON
+        if let Some(best_move) = &self.best_move {
+            info.push_str(&format!(" pv {}", best_move));
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chess::{ChessMove, Square};
+
+    use super::*;
+    use crate::uci::client::{Info, Score};
+
+    #[test]
+    fn test_uci_info_and_json() {
+        let result = SearchResult {
+            best_move: Some(ChessMove::new(Square::E2, Square::E4, None)),
+            score: 35,
+            nodes: 1200,
+            depth: 4,
+        };
+        let mut report = EvalReport::from_search(&result, 4, Duration::from_millis(12));
+        report.wdl = Some(Wdl::from_score(report.score, 400.0, DEFAULT_DRAW_MARGIN));
+        report.bucket = Some(1);
+
+        let line = report.to_uci_info();
+        assert!(line.starts_with("info depth 4 score cp 35 wdl "));
+        assert!(line.ends_with("nodes 1200 time 12 pv e2e4"));
+        let wdl = report.wdl.unwrap();
+        assert_eq!(wdl.win + wdl.draw + wdl.loss, 1000);
+        assert!(wdl.win > wdl.loss);
+
+        // Our own UCI client reads it back
+        let info = Info::parse(&line).unwrap();
+        assert_eq!((info.depth, info.nodes, info.pv.clone()), (Some(4), Some(1200), vec!["e2e4".to_string()]));
+        assert_eq!(info.score.map(|(score, _)| score), Some(Score::Centipawns(35)));
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<EvalReport>(&json).unwrap(), report);
+
+        // Mate scores count moves, static evals are never mates
+        let mated = EvalReport { score: -MATE_SCORE + 3, depth: Some(4), ..EvalReport::default() };
+        assert!(mated.to_uci_info().contains("score mate -2"));
+        assert_eq!(EvalReport { score: MATE_SCORE, ..EvalReport::default() }.mate_in(), None);
+    }
+}
@@ -0,0 +1,761 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chess::{Board, ChessMove};
+use tch::{CModule, Device, Kind, Tensor};
+
+use crate::builder::resolve_device;
+use crate::error::NNUEError;
+use crate::features::FeatureSet;
+use crate::guard::TorchGuard;
+use crate::perspective::ScorePerspective;
+use crate::prometheus::{Histogram, MetricsText};
+use crate::shallow_nnue::{load_model, model_feature_set, model_input_kind, read_score, NNUE};
+
+// Scores whole batches of positions, every score from the side to move of its board
+pub trait BatchEvaluator: Send {
+    fn evaluate_batch(&mut self, boards: &[Board]) -> Result<Vec<i16>, NNUEError>;
+
+    // Called after a single position ran out of device memory, returns the device that was left
+    // or None if the evaluator is already on the CPU (the error is then passed on to the clients)
+    fn fall_back_to_cpu(&mut self) -> Result<Option<Device>, NNUEError> {
+        Ok(None)
+    }
+}
+
+impl<T: NNUE + Send> BatchEvaluator for T {
+    fn evaluate_batch(&mut self, boards: &[Board]) -> Result<Vec<i16>, NNUEError> {
+        // One position at a time, for evaluators without a batched forward
+        let mut scores = Vec::with_capacity(boards.len());
+        for board in boards {
+            self.set_board_hard(*board)?;
+            let score = self.evaluate()?;
+            scores.push(self.perspective().to_side_to_move(score, board.side_to_move()));
+        }
+        Ok(scores)
+    }
+}
+
+#[derive(Debug)]
+pub struct TorchBatchEvaluator {
+    model: CModule,
+    device: Device,
+    input_kind: Kind,
+    features: FeatureSet,
+    inputs: Vec<f32>, // Reused dense encoding buffer
+    guard: TorchGuard,
+}
+
+impl TorchBatchEvaluator {
+    pub fn load(global_path_to_model: String, device: Option<Device>) -> Result<TorchBatchEvaluator, NNUEError> {
+        let device = resolve_device(device)?;
+        let model = load_model(global_path_to_model, device)?;
+        Ok(TorchBatchEvaluator {
+            input_kind: model_input_kind(&model, device)?,
+            features: model_feature_set(&model)?.unwrap_or_default(),
+            model,
+            device,
+            inputs: Vec::new(),
+            guard: TorchGuard::default(),
+        })
+    }
+
+    pub fn set_features(&mut self, features: FeatureSet) -> Result<(), NNUEError> {
+        features.validate()?;
+        self.features = features;
+        Ok(())
+    }
+
+    pub fn set_torch_guard(&mut self, guard: TorchGuard) {
+        // A batch refused or panicking in the guard fails only its own requests, the server keeps going
+        self.guard = guard;
+    }
+}
+
+impl BatchEvaluator for TorchBatchEvaluator {
+    fn evaluate_batch(&mut self, boards: &[Board]) -> Result<Vec<i16>, NNUEError> {
+        self.inputs.clear();
+        self.inputs.resize(boards.len() * 768, 0.0);
+        for (row, board) in boards.iter().enumerate() {
+            for index in self.features.active(board) {
+                self.inputs[row * 768 + index as usize] = 1.0;
+            }
+        }
+        let inputs = Tensor::f_from_slice(&self.inputs)?
+            .f_view([boards.len() as i64, 768])?
+            .f_to_kind(self.input_kind)?
+            .f_to_device(self.device)?;
+        let output = self.guard.forward(&self.model, &inputs, self.input_kind, self.device)?;
+        (0..boards.len()).map(|i| read_score(&output, i as i64)).collect()
+    }
+
+    fn fall_back_to_cpu(&mut self) -> Result<Option<Device>, NNUEError> {
+        if self.device == Device::Cpu {
+            return Ok(None);
+        }
+        let from = self.device;
+        self.model.to(Device::Cpu, Kind::Float, false);
+        self.device = Device::Cpu;
+        // The parameters are now Float, so a half precision model takes Float inputs as well
+        if self.input_kind == Kind::Half {
+            self.input_kind = Kind::Float;
+        }
+        Ok(Some(from))
+    }
+}
+
+fn is_out_of_memory(err: &NNUEError) -> bool {
+    // libtorch reports CUDA allocation failures as errors with this message, not as a distinct type
+    match err {
+        NNUEError::Tensor(err) => err.to_string().contains("out of memory"),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceFallback {
+    SplitBatch { size: usize }, // A batch of this many positions ran out of memory and was halved
+    MovedToCpu { from: Device }, // Even a single position ran out of memory, the evaluator now runs on the CPU
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvalServerOptions {
+    pub max_batch: usize, // A batch is sent as soon as it holds this many positions
+    pub max_wait: Duration, // How long the first request of a batch waits for company
+    pub double_buffer: bool, // Collect the next batch while the current one is evaluated
+    pub interactive_queue: usize, // Interactive clients block once this many of their requests are waiting
+    pub bulk_queue: usize, // Same for bulk clients, keeps dataset scoring from flooding the server
+    pub reject_when_full: bool, // Turn requests away with Overloaded instead of blocking at a full queue
+    pub request_timeout: Option<Duration>, // Deadline of EvalClient::evaluate, None waits as long as it takes
+}
+
+impl Default for EvalServerOptions {
+    fn default() -> EvalServerOptions {
+        // Larger batches and waits raise GPU throughput at the cost of latency per evaluation
+        EvalServerOptions {
+            max_batch: 256,
+            max_wait: Duration::from_micros(200),
+            double_buffer: true,
+            interactive_queue: 4096,
+            bulk_queue: 1024,
+            reject_when_full: false,
+            request_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvalServerStats {
+    pub batches: u64,
+    pub positions: u64,
+    pub busy: Duration, // Time spent inside the evaluator
+    pub device_fallbacks: u64,
+    pub last_fallback: Option<DeviceFallback>,
+    pub rejected: u64, // Turned away at a full queue
+    pub expired: u64, // Past their deadline before they were evaluated
+}
+
+impl EvalServerStats {
+    pub fn average_batch(&self) -> f64 {
+        if self.batches == 0 {
+            return 0.0;
+        }
+        self.positions as f64 / self.batches as f64
+    }
+}
+
+// Upper bounds of the metric histograms, batch sizes in positions and latencies in nanoseconds
+const BATCH_SIZE_BUCKETS: [u64; 10] = [1, 2, 4, 8, 16, 32, 64, 128, 256, 512];
+const LATENCY_BUCKETS: [u64; 10] = [50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000, 5_000_000, 10_000_000, 25_000_000, 100_000_000];
+
+#[derive(Debug)]
+struct Counters {
+    batches: AtomicU64,
+    positions: AtomicU64,
+    busy_nanos: AtomicU64,
+    device_fallbacks: AtomicU64,
+    last_fallback: Mutex<Option<DeviceFallback>>,
+    rejected: AtomicU64,
+    expired: AtomicU64,
+    batch_sizes: Histogram,
+    latencies: Histogram, // From queueing a request to its reply
+}
+
+impl Default for Counters {
+    fn default() -> Counters {
+        Counters {
+            batches: AtomicU64::new(0),
+            positions: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+            device_fallbacks: AtomicU64::new(0),
+            last_fallback: Mutex::new(None),
+            rejected: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            batch_sizes: Histogram::new(&BATCH_SIZE_BUCKETS),
+            latencies: Histogram::new(&LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl Counters {
+    fn record_fallback(&self, fallback: DeviceFallback) {
+        tracing::warn!(?fallback, "evaluation server ran out of device memory");
+        self.device_fallbacks.fetch_add(1, Ordering::Relaxed);
+        *self.last_fallback.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(fallback);
+    }
+}
+
+// Batches are filled with every waiting interactive request before any bulk request is taken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    #[default]
+    Interactive, // Analysis and search, latency matters
+    Bulk, // Dataset scoring, throughput matters
+}
+
+struct Request {
+    board: Board,
+    reply: Sender<Result<i16, NNUEError>>,
+    queued: Instant,
+    deadline: Option<Instant>,
+}
+
+impl Request {
+    fn answer(self, result: Result<i16, NNUEError>, counters: &Counters) {
+        // Clients that gave up waiting have dropped their receiver, that's not an error here
+        counters.latencies.observe(self.queued.elapsed().as_nanos() as u64);
+        let _ = self.reply.send(result);
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    interactive: VecDeque<Request>,
+    bulk: VecDeque<Request>,
+    stopping: bool,
+}
+
+impl Pending {
+    fn is_empty(&self) -> bool {
+        self.interactive.is_empty() && self.bulk.is_empty()
+    }
+}
+
+// Requests waiting for a batch, shared by the clients and the collector
+#[derive(Default)]
+struct Queue {
+    pending: Mutex<Pending>,
+    available: Condvar, // Signalled when a request is queued or the server stops
+    space: Condvar, // Signalled when requests leave the queue, wakes clients held back by a limit
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn push(&self, request: Request, priority: Priority, options: &EvalServerOptions) -> Result<(), NNUEError> {
+        // At a full queue: fails with Overloaded when rejecting, otherwise waits for space until the
+        // request's deadline
+        let mut pending = self.lock();
+        loop {
+            if pending.stopping {
+                return Err(NNUEError::Server("server stopped".to_string()));
+            }
+            let (queue, limit) = match priority {
+                Priority::Interactive => (&mut pending.interactive, options.interactive_queue),
+                Priority::Bulk => (&mut pending.bulk, options.bulk_queue),
+            };
+            if queue.len() < limit.max(1) {
+                queue.push_back(request);
+                self.available.notify_one();
+                return Ok(());
+            }
+            if options.reject_when_full {
+                return Err(NNUEError::Overloaded);
+            }
+            pending = match request.deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Err(NNUEError::DeadlineExceeded);
+                    }
+                    self.space.wait_timeout(pending, timeout).unwrap_or_else(|poisoned| poisoned.into_inner()).0
+                }
+                None => self.space.wait(pending).unwrap_or_else(|poisoned| poisoned.into_inner()),
+            };
+        }
+    }
+
+    fn stop(&self) {
+        self.lock().stopping = true;
+        self.available.notify_all();
+        self.space.notify_all();
+    }
+}
+
+fn evaluate_with_fallback(evaluator: &mut dyn BatchEvaluator, boards: &[Board], counters: &Counters) -> Result<Vec<i16>, NNUEError> {
+    // Out of memory: halve the batch until it fits, and move to the CPU if a single position doesn't
+    match evaluator.evaluate_batch(boards) {
+        Err(err) if is_out_of_memory(&err) => {
+            if boards.len() > 1 {
+                counters.record_fallback(DeviceFallback::SplitBatch { size: boards.len() });
+                let (first, second) = boards.split_at(boards.len() / 2);
+                let mut scores = evaluate_with_fallback(evaluator, first, counters)?;
+                scores.extend(evaluate_with_fallback(evaluator, second, counters)?);
+                return Ok(scores);
+            }
+            match evaluator.fall_back_to_cpu()? {
+                Some(from) => {
+                    counters.record_fallback(DeviceFallback::MovedToCpu { from });
+                    evaluator.evaluate_batch(boards)
+                }
+                None => Err(err),
+            }
+        }
+        result => result,
+    }
+}
+
+fn run_batch(evaluator: &mut dyn BatchEvaluator, batch: Vec<Request>, counters: &Counters) {
+    // Requests whose deadline passed while they waited aren't worth evaluating
+    let now = Instant::now();
+    let (batch, expired): (Vec<Request>, Vec<Request>) = batch.into_iter().partition(|request| request.deadline.is_none_or(|deadline| deadline > now));
+    counters.expired.fetch_add(expired.len() as u64, Ordering::Relaxed);
+    for request in expired {
+        request.answer(Err(NNUEError::DeadlineExceeded), counters);
+    }
+    if batch.is_empty() {
+        return;
+    }
+
+    let boards: Vec<Board> = batch.iter().map(|request| request.board).collect();
+    let start = Instant::now();
+    let result = evaluate_with_fallback(evaluator, &boards, counters);
+    counters.busy_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    counters.batches.fetch_add(1, Ordering::Relaxed);
+    counters.positions.fetch_add(batch.len() as u64, Ordering::Relaxed);
+    counters.batch_sizes.observe(batch.len() as u64);
+
+    match result {
+        Ok(scores) => {
+            for (request, score) in batch.into_iter().zip(scores) {
+                request.answer(Ok(score), counters);
+            }
+        }
+        Err(err) => {
+            let reason = err.to_string();
+            for request in batch {
+                request.answer(Err(NNUEError::Server(reason.clone())), counters);
+            }
+        }
+    }
+}
+
+fn collect(queue: &Queue, options: &EvalServerOptions) -> (Vec<Request>, bool) {
+    // Blocks for the first request, then fills the batch until it is full or max_wait has passed.
+    // The flag is set once the server is stopping and nothing is left queued, the batch still has to be evaluated.
+    let max_batch = options.max_batch.max(1);
+    let mut batch = Vec::with_capacity(max_batch);
+    let mut pending = queue.lock();
+    while pending.is_empty() && !pending.stopping {
+        pending = queue.available.wait(pending).unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+    let deadline = Instant::now() + options.max_wait;
+    loop {
+        while batch.len() < max_batch {
+            match pending.interactive.pop_front().or_else(|| pending.bulk.pop_front()) {
+                Some(request) => batch.push(request),
+                None => break,
+            }
+        }
+        queue.space.notify_all();
+        // A batch never waits past the earliest deadline it holds
+        let wait_until = batch.iter().filter_map(|request| request.deadline).fold(deadline, Instant::min);
+        let timeout = wait_until.saturating_duration_since(Instant::now());
+        if batch.len() == max_batch || pending.stopping || timeout.is_zero() {
+            break;
+        }
+        pending = queue.available.wait_timeout(pending, timeout).unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+    }
+    let stopping = pending.stopping && pending.is_empty();
+    (batch, stopping)
+}
+
+fn spawn_collector(
+    queue: Arc<Queue>,
+    mut evaluator: Box<dyn BatchEvaluator>,
+    options: EvalServerOptions,
+    counters: Arc<Counters>,
+) -> Vec<JoinHandle<()>> {
+    if !options.double_buffer {
+        // Synchronous: each batch is evaluated before the next one is collected
+        return vec![thread::spawn(move || loop {
+            let (batch, stopping) = collect(&queue, &options);
+            if !batch.is_empty() {
+                run_batch(evaluator.as_mut(), batch, &counters);
+            }
+            if stopping {
+                break;
+            }
+        })];
+    }
+
+    // The worker owns the evaluator, one finished batch can wait for it while the collector starts the next
+    let (batches, pending): (SyncSender<Vec<Request>>, Receiver<Vec<Request>>) = mpsc::sync_channel(1);
+    let worker_counters = Arc::clone(&counters);
+    let worker = thread::spawn(move || {
+        for batch in pending {
+            run_batch(evaluator.as_mut(), batch, &worker_counters);
+        }
+    });
+    let collector = thread::spawn(move || loop {
+        let (batch, stopping) = collect(&queue, &options);
+        if !batch.is_empty() && batches.send(batch).is_err() {
+            break;
+        }
+        if stopping {
+            break; // Dropping the sender lets the worker finish the queued batch and exit
+        }
+    });
+    vec![collector, worker]
+}
+
+// Evaluates positions for many search threads with batched forwards on one model
+pub struct EvalServer {
+    queue: Arc<Queue>,
+    options: EvalServerOptions,
+    counters: Arc<Counters>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl EvalServer {
+    pub fn new<E: BatchEvaluator + 'static>(evaluator: E, options: EvalServerOptions) -> EvalServer {
+        let queue = Arc::new(Queue::default());
+        let counters = Arc::new(Counters::default());
+        let threads = spawn_collector(Arc::clone(&queue), Box::new(evaluator), options, Arc::clone(&counters));
+        EvalServer { queue, options, counters, threads }
+    }
+
+    pub fn client(&self) -> EvalClient {
+        self.client_with_priority(Priority::Interactive)
+    }
+
+    pub fn client_with_priority(&self, priority: Priority) -> EvalClient {
+        EvalClient {
+            queue: Arc::clone(&self.queue),
+            options: self.options,
+            counters: Arc::clone(&self.counters),
+            priority,
+        }
+    }
+
+    pub fn queued(&self) -> (usize, usize) {
+        // Interactive and bulk requests waiting for a batch
+        let pending = self.queue.lock();
+        (pending.interactive.len(), pending.bulk.len())
+    }
+
+    pub fn stats(&self) -> EvalServerStats {
+        EvalServerStats {
+            batches: self.counters.batches.load(Ordering::Relaxed),
+            positions: self.counters.positions.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(self.counters.busy_nanos.load(Ordering::Relaxed)),
+            device_fallbacks: self.counters.device_fallbacks.load(Ordering::Relaxed),
+            last_fallback: *self.counters.last_fallback.lock().unwrap_or_else(|poisoned| poisoned.into_inner()),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            expired: self.counters.expired.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn prometheus_metrics(&self) -> String {
+        // The server's counters in the Prometheus text format, for a deployment's HTTP layer to serve
+        // at /metrics. The server has no eval cache and tch exposes no device memory figures, so
+        // neither is reported.
+        let stats = self.stats();
+        let (interactive, bulk) = self.queued();
+        let mut text = MetricsText::default();
+        text.counter("shallow_nnue_positions_total", "Positions evaluated.", stats.positions as f64);
+        text.counter("shallow_nnue_batches_total", "Batches evaluated.", stats.batches as f64);
+        text.counter("shallow_nnue_busy_seconds_total", "Time spent inside the evaluator.", stats.busy.as_secs_f64());
+        text.counter("shallow_nnue_device_fallbacks_total", "Batches split or moved to the CPU after running out of device memory.", stats.device_fallbacks as f64);
+        text.counter("shallow_nnue_rejected_total", "Requests turned away at a full queue.", stats.rejected as f64);
+        text.counter("shallow_nnue_expired_total", "Requests past their deadline before they were evaluated.", stats.expired as f64);
+        text.gauge(
+            "shallow_nnue_queued_requests",
+            "Requests waiting for a batch.",
+            &[("priority=\"interactive\"", interactive as f64), ("priority=\"bulk\"", bulk as f64)],
+        );
+        text.histogram("shallow_nnue_batch_size", "Positions per evaluated batch.", &self.counters.batch_sizes, 1.0);
+        text.histogram("shallow_nnue_request_latency_seconds", "Time from queueing a request to its reply.", &self.counters.latencies, 1e9);
+        text.finish()
+    }
+}
+
+impl Drop for EvalServer {
+    fn drop(&mut self) {
+        // Requests already queued are still answered, later ones get a Server error
+        self.queue.stop();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EvalClient {
+    queue: Arc<Queue>,
+    options: EvalServerOptions,
+    counters: Arc<Counters>,
+    priority: Priority,
+}
+
+impl EvalClient {
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> EvalClient {
+        self.priority = priority;
+        self
+    }
+
+    pub fn evaluate(&self, board: &Board) -> Result<i16, NNUEError> {
+        // Blocks while the queue for this client's priority is full, then until the batch holding
+        // this board has been evaluated, both bounded by the options' request_timeout. The score is
+        // from the side to move.
+        let deadline = self.options.request_timeout.map(|timeout| Instant::now() + timeout);
+        self.evaluate_by(board, deadline)
+    }
+
+    pub fn evaluate_by(&self, board: &Board, deadline: Option<Instant>) -> Result<i16, NNUEError> {
+        // evaluate with an explicit deadline, e.g. what is left of a search's time budget
+        let (reply, response) = mpsc::channel();
+        let request = Request { board: *board, reply, queued: Instant::now(), deadline };
+        if let Err(err) = self.queue.push(request, self.priority, &self.options) {
+            let counter = match err {
+                NNUEError::Overloaded => &self.counters.rejected,
+                NNUEError::DeadlineExceeded => &self.counters.expired,
+                _ => return Err(err),
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            return Err(err);
+        }
+        let stopped = || NNUEError::Server("server stopped".to_string());
+        match deadline {
+            Some(deadline) => match response.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(result) => result,
+                Err(RecvTimeoutError::Timeout) => Err(NNUEError::DeadlineExceeded),
+                Err(RecvTimeoutError::Disconnected) => Err(stopped()),
+            },
+            None => response.recv().map_err(|_| stopped())?,
+        }
+    }
+}
+
+// Lets a search thread use the server wherever an NNUE evaluator is expected
+pub struct ServerNNUE {
+    client: EvalClient,
+    board: Board,
+    perspective: ScorePerspective,
+}
+
+impl ServerNNUE {
+    pub fn new(client: EvalClient) -> ServerNNUE {
+        ServerNNUE {
+            client,
+            board: Board::default(),
+            perspective: ScorePerspective::default(),
+        }
+    }
+
+    pub fn set_perspective(&mut self, perspective: ScorePerspective) {
+        self.perspective = perspective;
+    }
+}
+
+impl NNUE for ServerNNUE {
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        let turn = self.board.side_to_move();
+        if !self.board.legal(chess_move) {
+            return Err(NNUEError::IllegalMove);
+        }
+        // The server scores the child for its side to move, the mover wants the opposite sign
+        let score = -self.client.evaluate(&self.board.make_move_new(chess_move))?;
+        Ok(self.perspective.from_side_to_move(score, turn))
+    }
+
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+        self.board = board;
+        Ok(())
+    }
+
+    fn evaluate(&mut self) -> Result<i16, NNUEError> {
+        let score = self.client.evaluate(&self.board)?;
+        Ok(self.perspective.from_side_to_move(score, self.board.side_to_move()))
+    }
+
+    fn perspective(&self) -> ScorePerspective {
+        self.perspective
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::classical::{ClassicalEval, ClassicalWeights};
+
+    #[test]
+    fn test_server_matches_direct_evaluation() {
+        let boards: Vec<Board> = [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "4k3/8/8/8/8/8/8/3QK3 b - - 0 1",
+            "3qk3/8/8/8/8/8/8/4K3 w - - 0 1",
+        ]
+        .iter()
+        .map(|fen| Board::from_str(fen).unwrap())
+        .collect();
+        let expected = ClassicalEval::new(ClassicalWeights::default()).evaluate_batch(&boards).unwrap();
+
+        for double_buffer in [true, false] {
+            let options = EvalServerOptions { max_batch: 4, max_wait: Duration::from_millis(1), double_buffer, ..EvalServerOptions::default() };
+            let server = EvalServer::new(ClassicalEval::new(ClassicalWeights::default()), options);
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let client = server.client();
+                    let boards = boards.clone();
+                    thread::spawn(move || boards.iter().map(|board| client.evaluate(board).unwrap()).collect::<Vec<i16>>())
+                })
+                .collect();
+            for handle in handles {
+                assert_eq!(handle.join().unwrap(), expected);
+            }
+            assert_eq!(server.stats().positions, 12);
+            assert!(server.stats().batches <= 12);
+
+            let metrics = server.prometheus_metrics();
+            assert!(metrics.contains("shallow_nnue_positions_total 12\n"));
+            assert!(metrics.contains("shallow_nnue_request_latency_seconds_count 12\n"));
+            assert!(metrics.contains("shallow_nnue_batch_size_bucket{le=\"+Inf\"}"));
+            assert!(metrics.contains(&format!("shallow_nnue_batch_size_count {}\n", server.stats().batches)));
+        }
+    }
+
+    #[test]
+    fn test_interactive_requests_first() {
+        let options = EvalServerOptions { max_batch: 2, max_wait: Duration::ZERO, bulk_queue: 2, ..EvalServerOptions::default() };
+        let queue = Arc::new(Queue::default());
+        let request = |fen: &str| Request { board: Board::from_str(fen).unwrap(), reply: mpsc::channel().0, queued: Instant::now(), deadline: None };
+        let bulk = "4k3/8/8/8/8/8/8/3QK3 b - - 0 1";
+        let interactive = "3qk3/8/8/8/8/8/8/4K3 w - - 0 1";
+        queue.push(request(bulk), Priority::Bulk, &options).unwrap();
+        queue.push(request(bulk), Priority::Bulk, &options).unwrap();
+
+        // The bulk queue is full, this client waits until the collector takes requests
+        let waiting = Arc::clone(&queue);
+        let handle = thread::spawn(move || waiting.push(request(bulk), Priority::Bulk, &options));
+        queue.push(request(interactive), Priority::Interactive, &options).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(queue.lock().bulk.len(), 2);
+
+        let (batch, stopping) = collect(&queue, &options);
+        let boards: Vec<String> = batch.iter().map(|request| request.board.to_string()).collect();
+        assert_eq!(boards, vec![Board::from_str(interactive).unwrap().to_string(), Board::from_str(bulk).unwrap().to_string()]);
+        assert!(!stopping);
+        handle.join().unwrap().unwrap();
+        assert_eq!(queue.lock().bulk.len(), 2);
+
+        queue.stop();
+        assert!(queue.push(request(interactive), Priority::Interactive, &options).is_err());
+        assert_eq!(collect(&queue, &options).0.len(), 2);
+        assert!(collect(&queue, &options).1);
+    }
+
+    // Runs out of memory on batches above a size until it is moved to the CPU
+    struct LimitedEvaluator {
+        inner: ClassicalEval,
+        max_batch: usize,
+        on_cpu: bool,
+    }
+
+    impl BatchEvaluator for LimitedEvaluator {
+        fn evaluate_batch(&mut self, boards: &[Board]) -> Result<Vec<i16>, NNUEError> {
+            if !self.on_cpu && boards.len() > self.max_batch {
+                return Err(NNUEError::Tensor(tch::TchError::Torch("CUDA out of memory".to_string())));
+            }
+            self.inner.evaluate_batch(boards)
+        }
+
+        fn fall_back_to_cpu(&mut self) -> Result<Option<Device>, NNUEError> {
+            self.on_cpu = true;
+            Ok(Some(Device::Cuda(0)))
+        }
+    }
+
+    #[test]
+    fn test_backpressure_and_deadlines() {
+        let options = EvalServerOptions { bulk_queue: 1, reject_when_full: true, ..EvalServerOptions::default() };
+        let queue = Arc::new(Queue::default());
+        let request = |deadline: Option<Instant>| Request { board: Board::default(), reply: mpsc::channel().0, queued: Instant::now(), deadline };
+        queue.push(request(None), Priority::Bulk, &options).unwrap();
+        assert!(matches!(queue.push(request(None), Priority::Bulk, &options), Err(NNUEError::Overloaded)));
+        let blocking = EvalServerOptions { reject_when_full: false, ..options };
+        let past = Instant::now();
+        assert!(matches!(queue.push(request(Some(past)), Priority::Bulk, &blocking), Err(NNUEError::DeadlineExceeded)));
+
+        // Expired requests are answered without reaching the evaluator
+        let counters = Counters::default();
+        let (reply, response) = mpsc::channel();
+        let expired = Request { board: Board::default(), reply, queued: past, deadline: Some(past) };
+        let mut evaluator = ClassicalEval::new(ClassicalWeights::default());
+        run_batch(&mut evaluator, vec![expired, request(None)], &counters);
+        assert!(matches!(response.recv().unwrap(), Err(NNUEError::DeadlineExceeded)));
+        assert_eq!((counters.expired.load(Ordering::Relaxed), counters.positions.load(Ordering::Relaxed)), (1, 1));
+
+        let server = EvalServer::new(ClassicalEval::new(ClassicalWeights::default()), EvalServerOptions { request_timeout: Some(Duration::from_secs(5)), ..EvalServerOptions::default() });
+        assert!(server.client().evaluate(&Board::default()).is_ok());
+        assert!(matches!(server.client().evaluate_by(&Board::default(), Some(past)), Err(NNUEError::DeadlineExceeded)));
+    }
+
+    #[test]
+    fn test_out_of_memory_fallback() {
+        let boards = vec![Board::default(); 4];
+        let counters = Counters::default();
+        let mut evaluator = LimitedEvaluator { inner: ClassicalEval::new(ClassicalWeights::default()), max_batch: 2, on_cpu: false };
+        assert_eq!(evaluate_with_fallback(&mut evaluator, &boards, &counters).unwrap().len(), 4);
+        assert_eq!(counters.device_fallbacks.load(Ordering::Relaxed), 1);
+        assert!(!evaluator.on_cpu);
+
+        evaluator.max_batch = 0;
+        assert_eq!(evaluate_with_fallback(&mut evaluator, &boards, &counters).unwrap().len(), 4);
+        // 4 -> 2 + 2, the first 2 -> 1 + 1, then the first single position moves everything to the CPU
+        assert_eq!(counters.device_fallbacks.load(Ordering::Relaxed), 4);
+        assert_eq!(*counters.last_fallback.lock().unwrap(), Some(DeviceFallback::MovedToCpu { from: Device::Cuda(0) }));
+        assert!(evaluator.on_cpu);
+    }
+
+    #[test]
+    fn test_torch_fallback_input_kind() {
+        // A half precision model on the GPU, the device is never touched before the fallback
+        let weights = Tensor::ones([768, 1], (Kind::Float, Device::Cpu));
+        let example = Tensor::zeros(768, (Kind::Float, Device::Cpu));
+        let model = CModule::create_by_tracing("Eval", "forward", &[example], &mut |inputs| vec![inputs[0].view([-1, 768]).matmul(&weights)]).unwrap();
+        let mut evaluator = TorchBatchEvaluator {
+            model,
+            device: Device::Cuda(0),
+            input_kind: Kind::Half,
+            features: FeatureSet::default(),
+            inputs: Vec::new(),
+            guard: TorchGuard::default(),
+        };
+        assert_eq!(evaluator.fall_back_to_cpu().unwrap(), Some(Device::Cuda(0)));
+        assert_eq!(evaluator.input_kind, Kind::Float);
+        assert_eq!(evaluator.evaluate_batch(&[Board::default()]).unwrap().len(), 1);
+    }
+}
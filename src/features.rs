@@ -0,0 +1,238 @@
+use std::fmt;
+use std::sync::Arc;
+
+use chess::{Board, Color};
+use tch::{IndexOp, Tensor};
+
+use crate::bit_move::{active_indices, feature_delta};
+use crate::error::NNUEError;
+
+pub const NUM_FEATURES: u16 = 768;
+
+// How a (piece plane, square) pair is laid out in the model input. Planes 0-5 are pawn, knight,
+// bishop, rook, queen and king of one side and 6-11 of the other, which side depends on the feature set.
+#[derive(Clone, Default)]
+pub enum IndexingScheme {
+    #[default]
+    PieceMajor, // plane * 64 + square, the layout used everywhere inside this crate
+    SquareMajor, // square * 12 + plane
+    Custom(Arc<dyn Fn(u16, u16) -> u16 + Send + Sync>), // Called with (plane, square)
+}
+
+impl IndexingScheme {
+    pub fn index(&self, plane: u16, square: u16) -> u16 {
+        match self {
+            IndexingScheme::PieceMajor => plane * 64 + square,
+            IndexingScheme::SquareMajor => square * 12 + plane,
+            IndexingScheme::Custom(index) => index(plane, square),
+        }
+    }
+}
+
+impl PartialEq for IndexingScheme {
+    fn eq(&self, other: &IndexingScheme) -> bool {
+        // Custom schemes are only equal to themselves
+        match (self, other) {
+            (IndexingScheme::PieceMajor, IndexingScheme::PieceMajor) | (IndexingScheme::SquareMajor, IndexingScheme::SquareMajor) => true,
+            (IndexingScheme::Custom(first), IndexingScheme::Custom(second)) => Arc::ptr_eq(first, second),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for IndexingScheme {}
+
+impl fmt::Debug for IndexingScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexingScheme::PieceMajor => write!(f, "PieceMajor"),
+            IndexingScheme::SquareMajor => write!(f, "SquareMajor"),
+            IndexingScheme::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+// Encoding the model was trained with. Features are computed in the crate's own layout and only
+// translated when they're written into the model input, so native weights are unaffected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeatureSet {
+    Relative768(IndexingScheme), // Own/opponent planes, reoriented for the side to move
+    Absolute768(IndexingScheme), // White planes 0-5, black planes 6-11, squares as on the board
+}
+
+impl Default for FeatureSet {
+    fn default() -> FeatureSet {
+        FeatureSet::Relative768(IndexingScheme::default())
+    }
+}
+
+impl FeatureSet {
+    pub fn name(&self) -> &'static str {
+        // As declared by a TorchScript model's feature_set() method
+        match self {
+            FeatureSet::Relative768(_) => "relative768",
+            FeatureSet::Absolute768(_) => "absolute768",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FeatureSet> {
+        // Declared feature sets always use the piece major layout
+        match name.trim().to_lowercase().as_str() {
+            "relative768" => Some(FeatureSet::Relative768(IndexingScheme::PieceMajor)),
+            "absolute768" => Some(FeatureSet::Absolute768(IndexingScheme::PieceMajor)),
+            _ => None,
+        }
+    }
+
+    pub fn same_layout(&self, other: &FeatureSet) -> bool {
+        // Compares the feature set without its indexing scheme
+        self.name() == other.name()
+    }
+
+    pub fn index(&self, feature: u16, turn: Color) -> u16 {
+        // Translates a feature index of this crate, computed for turn, into the model input index
+        let (plane, square) = (feature / 64, feature % 64);
+        match self {
+            FeatureSet::Relative768(indexing) => indexing.index(plane, square),
+            FeatureSet::Absolute768(indexing) => {
+                // Own pieces belong to turn, and undoing the reorientation is the reorientation again
+                let (piece, own) = (plane % 6, plane < 6);
+                let white = own == (turn == Color::White);
+                let square = if turn == Color::White { square } else { 63 - square };
+                indexing.index(if white { piece } else { piece + 6 }, square)
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), NNUEError> {
+        // Every feature needs its own input, otherwise pieces would silently overwrite each other
+        for turn in [Color::White, Color::Black] {
+            let mut seen = vec![false; NUM_FEATURES as usize];
+            for feature in 0..NUM_FEATURES {
+                let index = self.index(feature, turn);
+                match seen.get_mut(index as usize) {
+                    Some(taken) if !*taken => *taken = true,
+                    Some(_) => return Err(NNUEError::InvalidConfig(format!("feature index {} is used twice", index))),
+                    None => return Err(NNUEError::InvalidConfig(format!("feature index {} is out of range", index))),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn turn_flip(&self) -> Option<Vec<i64>> {
+        // Permutation of the model inputs that turns an encoding around to the other side to move:
+        // own and opponent planes swap and every square is reoriented. Entry i is the input that
+        // moves to i, applying it twice restores the encoding. None if the side to move doesn't matter.
+        match self {
+            FeatureSet::Relative768(_) => {
+                let mut permutation = vec![0; NUM_FEATURES as usize];
+                for feature in 0..NUM_FEATURES {
+                    let (plane, square) = (feature / 64, feature % 64);
+                    let flipped = ((plane + 6) % 12) * 64 + (63 - square);
+                    permutation[self.index(flipped, Color::White) as usize] = self.index(feature, Color::White) as i64;
+                }
+                Some(permutation)
+            }
+            FeatureSet::Absolute768(_) => None,
+        }
+    }
+
+    pub(crate) fn active(&self, board: &Board) -> Vec<u16> {
+        // Model input indices that are set for the board
+        let turn = board.side_to_move();
+        active_indices(board).into_iter().map(|feature| self.index(feature, turn)).collect()
+    }
+
+    pub(crate) fn delta(&self, current: &Board, target: &Board) -> (Vec<u16>, Vec<u16>) {
+        // Returns (removed, placed) model input indices that turn the encoding of current into target,
+        // after the encoding was turned around if the side to move differs
+        let turn = target.side_to_move();
+        let (removed, placed) = feature_delta(current, target);
+        let remap = |features: &[u16]| features.iter().map(|feature| self.index(*feature, turn)).collect();
+        (remap(&removed), remap(&placed))
+    }
+
+    pub(crate) fn encode(&self, board: &Board, encoding_tensor: &Tensor) -> Result<(), NNUEError> {
+        // Clear encodings
+        encoding_tensor.f_i(..)?.f_fill_(0.0)?;
+
+        // Encode all pieces
+        for index in self.active(board) {
+            encoding_tensor.f_i(index as i64)?.f_fill_(1.0)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indexing_schemes() {
+        let square_major = FeatureSet::Relative768(IndexingScheme::SquareMajor);
+        assert_eq!(FeatureSet::default().index(2 * 64 + 10, Color::Black), 2 * 64 + 10);
+        assert_eq!(square_major.index(2 * 64 + 10, Color::White), 10 * 12 + 2);
+        assert!(square_major.validate().is_ok());
+
+        let reversed = FeatureSet::Relative768(IndexingScheme::Custom(Arc::new(|plane, square| 767 - (plane * 64 + square))));
+        assert_eq!(reversed.index(0, Color::White), 767);
+        assert!(reversed.validate().is_ok());
+
+        let collapsed = FeatureSet::Relative768(IndexingScheme::Custom(Arc::new(|_, square| square)));
+        assert!(matches!(collapsed.validate(), Err(NNUEError::InvalidConfig(_))));
+        let overflowing = FeatureSet::Relative768(IndexingScheme::Custom(Arc::new(|plane, square| plane * 64 + square + 1)));
+        assert!(matches!(overflowing.validate(), Err(NNUEError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_absolute_encoding() {
+        let absolute = FeatureSet::Absolute768(IndexingScheme::PieceMajor);
+        assert!(absolute.validate().is_ok());
+        assert_eq!(FeatureSet::from_name("Absolute768").unwrap().name(), "absolute768");
+
+        // The same position with either side to move has the same absolute encoding
+        let white = Board::default();
+        let black = white.null_move().unwrap();
+        let mut indices = absolute.active(&white);
+        indices.sort();
+        let mut flipped = absolute.active(&black);
+        flipped.sort();
+        assert_eq!(indices, flipped);
+        // White king on e1 is plane 5, black king on e8 plane 11
+        assert!(indices.contains(&(5 * 64 + 4)) && indices.contains(&(11 * 64 + 60)));
+
+        // After a move only the moved piece changes, although every relative feature flips
+        let after = white.make_move_new(chess::ChessMove::new(chess::Square::E2, chess::Square::E4, None));
+        let (removed, placed) = absolute.delta(&white, &after);
+        assert_eq!((removed, placed), (vec![12], vec![28]));
+        assert!(absolute.turn_flip().is_none());
+    }
+
+    #[test]
+    fn test_turn_flip() {
+        for features in [FeatureSet::default(), FeatureSet::Relative768(IndexingScheme::SquareMajor)] {
+            let permutation = features.turn_flip().unwrap();
+            let flip = |indices: &[u16]| {
+                let mut encoding = vec![false; NUM_FEATURES as usize];
+                for index in indices {
+                    encoding[*index as usize] = true;
+                }
+                let flipped: Vec<bool> = permutation.iter().map(|from| encoding[*from as usize]).collect();
+                let mut indices: Vec<u16> = (0..NUM_FEATURES).filter(|index| flipped[*index as usize]).collect();
+                indices.sort();
+                indices
+            };
+
+            // Turning the start position around gives its encoding for black, then only e2e4 differs
+            let board = Board::default();
+            let after = board.make_move_new(chess::ChessMove::new(chess::Square::E2, chess::Square::E4, None));
+            let mut expected = features.active(&board.null_move().unwrap());
+            expected.sort();
+            assert_eq!(flip(&features.active(&board)), expected);
+            let (removed, placed) = features.delta(&board, &after);
+            assert_eq!((removed.len(), placed.len()), (1, 1));
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+use tch::{CModule, Device, Kind, Tensor};
+
+use crate::error::NNUEError;
+use crate::shallow_nnue::read_score;
+
+// Keeps a single bad request from taking a long running service down. Most libtorch failures come
+// back through tch's fallible f_ calls, but some surface as panics, and a C++ exception escaping
+// tch's own handling aborts the process where nothing in Rust can catch it. So inputs are checked
+// before they reach libtorch, which keeps the usual malformed inputs away from the C++ side, and
+// panics on the calling thread become errors. Catching needs the default panic = "unwind".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TorchGuard {
+    pub validate: bool, // Check the shape, dtype and device of model inputs and the size of outputs
+    pub catch_panics: bool,
+}
+
+impl Default for TorchGuard {
+    fn default() -> TorchGuard {
+        // Both cost next to nothing against a model forward
+        TorchGuard { validate: true, catch_panics: true }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "tch call panicked".to_string(),
+        },
+    }
+}
+
+pub(crate) fn check_encoding_shape(size: &[i64]) -> Result<i64, NNUEError> {
+    // A single 768 wide encoding or a non-empty batch of them, returns the number of positions
+    match size {
+        [768] => Ok(1),
+        [rows, 768] if *rows > 0 => Ok(*rows),
+        _ => Err(NNUEError::Guard(format!("model input has shape {:?}, expected [768] or [n, 768]", size))),
+    }
+}
+
+pub(crate) fn check_output_shape(input: &[i64], output: &[i64]) -> Result<(), NNUEError> {
+    // A row of outputs per position with the evaluation first, extra heads (a policy, a variance) after
+    // it. A single position may also come back as a bare row of outputs or a scalar.
+    let positions = check_encoding_shape(input)?;
+    let rows = match (input, output) {
+        ([_], [] | [_]) => 1,
+        (_, [rows] | [rows, _]) => *rows,
+        _ => 0,
+    };
+    if rows != positions || output.contains(&0) {
+        return Err(NNUEError::Guard(format!("model returned shape {:?} for {} positions", output, positions)));
+    }
+    Ok(())
+}
+
+impl TorchGuard {
+    pub const OFF: TorchGuard = TorchGuard { validate: false, catch_panics: false };
+
+    pub fn run<R>(&self, f: impl FnOnce() -> Result<R, NNUEError>) -> Result<R, NNUEError> {
+        // The closure's tensors may be left half written by a panic, callers re-encode before reusing them
+        match self.catch_panics {
+            true => panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| Err(NNUEError::Guard(panic_message(payload)))),
+            false => f(),
+        }
+    }
+
+    pub fn check_input(&self, input: &Tensor, kind: Kind, device: Device) -> Result<(), NNUEError> {
+        if !self.validate {
+            return Ok(());
+        }
+        check_encoding_shape(&input.size())?;
+        self.check_placement(input, kind, device)
+    }
+
+    fn check_placement(&self, input: &Tensor, kind: Kind, device: Device) -> Result<(), NNUEError> {
+        if input.kind() != kind {
+            return Err(NNUEError::Guard(format!("model input is {:?}, the model expects {:?}", input.kind(), kind)));
+        }
+        if input.device() != device {
+            return Err(NNUEError::Guard(format!("model input is on {:?}, the model is on {:?}", input.device(), device)));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn forward(&self, model: &CModule, input: &Tensor, kind: Kind, device: Device) -> Result<Tensor, NNUEError> {
+        // One model forward with a row of outputs per position, see read_score
+        self.check_input(input, kind, device)?;
+        let output = self.run(|| Ok(tch::no_grad(|| model.forward_ts(&[input]))?))?;
+        if self.validate {
+            check_output_shape(&input.size(), &output.size())?;
+        }
+        Ok(output)
+    }
+
+    pub(crate) fn forward_one(&self, model: &CModule, encoding: &Tensor, kind: Kind, device: Device) -> Result<i16, NNUEError> {
+        // The single position path, which allocates nothing on the Rust side besides tch's own argument
+        // list. encoding is an evaluator's persistent [768] input, so only its dtype and device are
+        // checked, size() collects the shape into a Vec. The usual [outputs] row needs no shape check
+        // either, only an output with rows is checked to have one, and the score is read in place.
+        if self.validate {
+            self.check_placement(encoding, kind, device)?;
+        }
+        let output = self.run(|| Ok(tch::no_grad(|| model.forward_ts(&[encoding]))?))?;
+        if self.validate && output.dim() > 1 {
+            check_output_shape(&[768], &output.size())?;
+        }
+        read_score(&output, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard_turns_panics_into_errors() {
+        let result: Result<(), NNUEError> = TorchGuard::default().run(|| panic!("bad shape {}", 3));
+        assert!(matches!(result, Err(NNUEError::Guard(message)) if message == "bad shape 3"));
+        assert_eq!(TorchGuard::OFF.run(|| Ok(5)).unwrap(), 5);
+
+        assert_eq!(check_encoding_shape(&[768]).unwrap(), 1);
+        assert_eq!(check_encoding_shape(&[32, 768]).unwrap(), 32);
+        assert!(check_encoding_shape(&[0, 768]).is_err());
+        assert!(check_encoding_shape(&[767]).is_err());
+        assert!(check_encoding_shape(&[2, 3, 768]).is_err());
+
+        // Multi-output models, a policy head after the evaluation, are fine as long as the rows match
+        assert!(check_output_shape(&[768], &[129]).is_ok());
+        assert!(check_output_shape(&[768], &[1, 129]).is_ok());
+        assert!(check_output_shape(&[32, 768], &[32]).is_ok());
+        assert!(check_output_shape(&[32, 768], &[32, 129]).is_ok());
+        assert!(check_output_shape(&[32, 768], &[31, 2]).is_err());
+        assert!(check_output_shape(&[32, 768], &[32, 0]).is_err());
+        assert!(check_output_shape(&[768], &[2, 1]).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use chess::{Board, ChessMove};
+use tch::{CModule, Device};
+
+use crate::bit_move::{BitMove, PieceValueChange};
+use crate::error::NNUEError;
+use crate::native::{NativeWeights, QuantizedLayer};
+use crate::perspective::ScorePerspective;
+use crate::shallow_nnue::{load_model, read_score, NNUE};
+use crate::tensor_view::TensorBuffer;
+
+#[derive(Debug)]
+struct Quantized {
+    layer: QuantizedLayer,
+    accumulator: Vec<i16>,
+    scratch: Vec<i16>,
+}
+
+// Native first layer with a TorchScript head: the accumulator is updated incrementally in Rust and
+// the head (every layer after the first, traced to take the raw [1, N] accumulator) reads it in place
+#[derive(Debug)]
+pub struct HybridNNUE {
+    weights: NativeWeights, // Only the first layer is used
+    head: CModule,
+    board: Board,
+    accumulator: TensorBuffer,
+    scratch: TensorBuffer, // Accumulator after the move being scored by forward
+    quantized: Option<Quantized>, // Int16 accumulators, dequantized into scratch for the head
+    perspective: ScorePerspective,
+}
+
+impl HybridNNUE {
+    pub fn new(weights: NativeWeights, head: CModule) -> HybridNNUE {
+        let shape = [1, weights.accumulator_size() as i64];
+        let mut nnue = HybridNNUE {
+            weights,
+            head,
+            board: Board::default(),
+            accumulator: TensorBuffer::zeros(&shape),
+            scratch: TensorBuffer::zeros(&shape),
+            quantized: None,
+            perspective: ScorePerspective::default(),
+        };
+        nnue.weights.refresh_accumulator(&nnue.board, nnue.accumulator.as_mut_slice());
+        nnue
+    }
+
+    pub fn quantized(weights: NativeWeights, head: CModule) -> Result<HybridNNUE, NNUEError> {
+        let size = weights.accumulator_size();
+        let mut nnue = HybridNNUE::new(weights, head);
+        let mut quantized = Quantized {
+            layer: nnue.weights.quantize()?,
+            accumulator: vec![0; size],
+            scratch: vec![0; size],
+        };
+        quantized.layer.refresh_accumulator(&nnue.board, &mut quantized.accumulator);
+        nnue.quantized = Some(quantized);
+        Ok(nnue)
+    }
+
+    pub fn load(weights: NativeWeights, global_path_to_head: String) -> Result<HybridNNUE, NNUEError> {
+        // Views only exist for CPU memory, so the head always runs on the CPU
+        Ok(HybridNNUE::new(weights, load_model(global_path_to_head, Device::Cpu)?))
+    }
+
+    pub fn set_perspective(&mut self, perspective: ScorePerspective) {
+        self.perspective = perspective;
+    }
+}
+
+impl NNUE for HybridNNUE {
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        let turn = self.board.side_to_move();
+        let bitmove = BitMove::new(chess_move, turn, self.board)?;
+
+        match self.quantized.as_mut() {
+            Some(quantized) => {
+                quantized.scratch.copy_from_slice(&quantized.accumulator);
+                for change in bitmove.changes() {
+                    let sign = match change.value {
+                        PieceValueChange::Place => 1,
+                        PieceValueChange::Remove => -1,
+                    };
+                    quantized.layer.add_feature(&mut quantized.scratch, change.index as usize, sign);
+                }
+                QuantizedLayer::dequantize(&quantized.scratch, self.scratch.as_mut_slice());
+            }
+            None => {
+                self.scratch.as_mut_slice().copy_from_slice(self.accumulator.as_slice());
+                for change in bitmove.changes() {
+                    let sign = match change.value {
+                        PieceValueChange::Place => 1.0,
+                        PieceValueChange::Remove => -1.0,
+                    };
+                    self.weights.add_feature(self.scratch.as_mut_slice(), change.index as usize, sign);
+                }
+            }
+        }
+
+        let score = read_score(&self.scratch.forward(&self.head)?, 0)?;
+        Ok(self.perspective.from_side_to_move(score, turn))
+    }
+
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+        self.board = board;
+        match self.quantized.as_mut() {
+            Some(quantized) => quantized.layer.refresh_accumulator(&self.board, &mut quantized.accumulator),
+            None => self.weights.refresh_accumulator(&self.board, self.accumulator.as_mut_slice()),
+        }
+        Ok(())
+    }
+
+    fn evaluate(&mut self) -> Result<i16, NNUEError> {
+        let score = match &self.quantized {
+            Some(quantized) => {
+                QuantizedLayer::dequantize(&quantized.accumulator, self.scratch.as_mut_slice());
+                read_score(&self.scratch.forward(&self.head)?, 0)?
+            }
+            None => read_score(&self.accumulator.forward(&self.head)?, 0)?,
+        };
+        Ok(self.perspective.from_side_to_move(score, self.board.side_to_move()))
+    }
+
+    fn perspective(&self) -> ScorePerspective {
+        self.perspective
+    }
+}
@@ -1,5 +1,60 @@
+pub mod advice;
+#[cfg(test)]
+mod alloc_counter;
+pub mod analysis;
+pub mod augment;
 pub(crate) mod bit_move;
+pub mod builder;
+pub mod cecp;
+pub mod classical;
+pub mod compression;
+pub mod corpus;
+#[cfg(feature = "cozy-chess")]
+pub mod cozy;
+pub mod dataset;
+pub mod endgame;
+pub mod engine;
+pub mod error;
+pub mod eval_report;
+pub mod eval_server;
+pub mod features;
+pub mod guard;
+pub mod hybrid;
+pub mod lichess;
+#[cfg(feature = "lichess-bot")]
+pub mod lichess_bot;
+pub mod metadata;
+pub mod native;
+pub mod network;
+pub mod observer;
+pub mod onnx;
+pub mod perspective;
+pub mod pgn;
+pub mod phase;
+pub mod pipeline;
+pub mod position;
+pub mod positions;
+pub(crate) mod prometheus;
+pub mod quiet;
+#[cfg(feature = "render")]
+pub mod render;
+pub mod repetition;
+pub mod router;
+pub(crate) mod rng;
+pub mod search;
+pub mod session;
 pub mod shallow_nnue;
+pub mod shards;
+pub mod shared_model;
+pub mod skill;
+pub mod smp;
+pub mod stockfish;
+pub mod tensor_view;
+pub mod tools;
+pub mod training;
+pub mod trend;
+pub mod tt;
+pub mod uci;
 
 #[cfg(test)]
 mod tests {
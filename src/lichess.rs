@@ -0,0 +1,177 @@
+use std::path::Path;
+
+use crate::dataset::Sample;
+use crate::error::NNUEError;
+use crate::pgn::{PgnGame, PgnReader, PgnStats};
+
+// Mate in n is labelled MATE_LABEL - n (in network units) for the side delivering it
+pub const MATE_LABEL: i16 = 10000;
+
+pub fn parse_eval(comment: &str, units_per_pawn: i16) -> Option<i16> {
+    // Reads "[%eval 0.17]" or "[%eval #-3]" out of a Lichess comment, from white's side
+    let start = comment.find("[%eval")? + "[%eval".len();
+    let rest = &comment[start..];
+    let value = rest[..rest.find(']')?].trim();
+    let value = value.split(',').next()?.trim(); // Newer exports append ",depth"
+
+    match value.strip_prefix('#') {
+        Some(mate) => {
+            let moves = mate.parse::<i16>().ok()?;
+            let score = MATE_LABEL - moves.abs().min(MATE_LABEL);
+            Some(if moves < 0 { -score } else { score })
+        }
+        None => {
+            let pawns = value.parse::<f32>().ok()?;
+            let units = (pawns * units_per_pawn as f32).round();
+            Some(units.max(-(MATE_LABEL as f32) + 1.0).min(MATE_LABEL as f32 - 1.0) as i16)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeControl {
+    UltraBullet,
+    Bullet,
+    Blitz,
+    Rapid,
+    Classical,
+    Correspondence,
+}
+
+impl TimeControl {
+    pub fn from_header(header: &str) -> Option<TimeControl> {
+        // Lichess buckets games on base + 40 * increment seconds, "-" is correspondence
+        if header.trim() == "-" {
+            return Some(TimeControl::Correspondence);
+        }
+        let (base, increment) = header.trim().split_once('+')?;
+        let estimate = base.parse::<u32>().ok()? + 40 * increment.parse::<u32>().ok()?;
+        Some(match estimate {
+            0..=29 => TimeControl::UltraBullet,
+            30..=179 => TimeControl::Bullet,
+            180..=479 => TimeControl::Blitz,
+            480..=1499 => TimeControl::Rapid,
+            _ => TimeControl::Classical,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LichessFilter {
+    pub time_controls: Vec<TimeControl>, // Empty accepts every time control
+    pub min_rating: Option<u16>, // Both players must be rated at least this
+    pub max_rating: Option<u16>, // Both players must be rated at most this
+    pub min_ply: usize, // Plies played before the position, inclusive
+    pub max_ply: usize,
+    pub units_per_pawn: i16, // Network output units per pawn of Lichess eval
+}
+
+impl Default for LichessFilter {
+    fn default() -> LichessFilter {
+        LichessFilter {
+            time_controls: Vec::new(),
+            min_rating: None,
+            max_rating: None,
+            min_ply: 0,
+            max_ply: usize::MAX,
+            units_per_pawn: 100,
+        }
+    }
+}
+
+impl LichessFilter {
+    pub fn accepts_game(&self, game: &PgnGame) -> bool {
+        if !self.time_controls.is_empty() {
+            match game.header("TimeControl").and_then(TimeControl::from_header) {
+                Some(time_control) if self.time_controls.contains(&time_control) => {}
+                _ => return false,
+            }
+        }
+
+        if self.min_rating.is_some() || self.max_rating.is_some() {
+            for header in ["WhiteElo", "BlackElo"] {
+                // Unrated players ("?") can't satisfy a rating bound
+                let rating = match game.header(header).and_then(|rating| rating.parse::<u16>().ok()) {
+                    Some(rating) => rating,
+                    None => return false,
+                };
+                if self.min_rating.is_some_and(|min| rating < min) || self.max_rating.is_some_and(|max| rating > max) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    pub fn samples(&self, game: &PgnGame) -> Vec<Sample> {
+        // Positions after each move carrying an eval comment, labelled with that eval, the game result
+        // and the move played next
+        let result = match game.result {
+            Some(result) if self.accepts_game(game) => result,
+            _ => return Vec::new(),
+        };
+
+        let mut board = game.start;
+        let mut samples = Vec::new();
+        for (ply, pgn_move) in game.moves.iter().enumerate().map(|(index, pgn_move)| (index + 1, pgn_move)) {
+            board = board.make_move_new(pgn_move.chess_move);
+            if ply < self.min_ply || ply > self.max_ply {
+                continue;
+            }
+            let score = pgn_move.comment.as_deref().and_then(|comment| parse_eval(comment, self.units_per_pawn));
+            if let Some(score) = score {
+                let best_move = game.moves.get(ply).map(|next| next.chess_move);
+                samples.push(Sample { board, score, result, best_move });
+            }
+        }
+        samples
+    }
+}
+
+pub fn read_lichess_samples<P: AsRef<Path>>(path: P, filter: &LichessFilter) -> Result<(Vec<Sample>, PgnStats), NNUEError> {
+    let mut reader = PgnReader::open(path)?;
+    let mut samples = Vec::new();
+    for game in reader.by_ref() {
+        samples.extend(filter.samples(&game?));
+    }
+    Ok((samples, reader.stats().clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_eval() {
+        assert_eq!(parse_eval("[%eval 0.17] [%clk 0:00:30]", 100), Some(17));
+        assert_eq!(parse_eval(" [%eval -1.5,22] ", 100), Some(-150));
+        assert_eq!(parse_eval("[%eval #3]", 100), Some(MATE_LABEL - 3));
+        assert_eq!(parse_eval("[%eval #-1]", 100), Some(-MATE_LABEL + 1));
+        assert_eq!(parse_eval("[%clk 0:00:30]", 100), None);
+        assert_eq!(TimeControl::from_header("180+2"), Some(TimeControl::Blitz));
+        assert_eq!(TimeControl::from_header("-"), Some(TimeControl::Correspondence));
+    }
+
+    #[test]
+    fn test_lichess_filter() {
+        let pgn = r#"[WhiteElo "2100"]
+[BlackElo "1950"]
+[TimeControl "600+0"]
+[Result "0-1"]
+
+1. e4 { [%eval 0.3] } 1... e5 { [%eval 0.25] } 2. Qh5 { [%eval -0.1] } 0-1
+"#;
+        let game = PgnReader::new(pgn.as_bytes()).next().unwrap().unwrap();
+
+        let filter = LichessFilter { min_ply: 2, ..LichessFilter::default() };
+        let samples = filter.samples(&game);
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].score, 25);
+        assert_eq!(samples[1].result, 0.0);
+
+        let rapid_only = LichessFilter { time_controls: vec![TimeControl::Rapid], ..LichessFilter::default() };
+        assert!(rapid_only.accepts_game(&game));
+        let strong_only = LichessFilter { min_rating: Some(2000), ..LichessFilter::default() };
+        assert!(strong_only.samples(&game).is_empty());
+    }
+}
@@ -0,0 +1,437 @@
+use std::io::{BufRead, BufReader, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chess::{Board, BoardStatus, ChessMove, Color, MoveGen};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::advice::{Advice, AdviceSettings, GameAdvisor};
+use crate::engine::{time_budget, timed_search};
+use crate::error::NNUEError;
+use crate::lichess::TimeControl;
+use crate::repetition::{is_irreversible, RepetitionHistory};
+use crate::search::{SearchOptions, Searcher};
+use crate::shallow_nnue::BoxedNNUE;
+use crate::tt::TranspositionTable;
+
+// Plays standard chess on Lichess as a bot account through the Bot API, with the built in search
+// on any evaluator. The account's event stream brings challenges and game starts, and each game is
+// played from its own stream of states. Games are played one at a time, challenges that arrive
+// meanwhile are answered once the game is over, and may have expired by then.
+pub const LICHESS_URL: &str = "https://lichess.org";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BotConfig {
+    pub token: String, // API token of a bot account, with the bot:play scope
+    pub base_url: String,
+    pub search: SearchOptions, // The depth is the most a move may search, time permitting
+    pub hash_mb: usize,
+    pub advice: AdviceSettings, // When to resign or offer a draw
+    pub speeds: Vec<TimeControl>, // Challenges at other speeds are declined
+    pub accept_rated: bool,
+    pub greeting: Option<String>, // Said in the player chat when a game starts
+}
+
+impl BotConfig {
+    pub fn new(token: String) -> BotConfig {
+        BotConfig {
+            token,
+            base_url: LICHESS_URL.to_string(),
+            search: SearchOptions { depth: 32, ..SearchOptions::default() },
+            hash_mb: 16,
+            advice: AdviceSettings::default(),
+            speeds: vec![TimeControl::Bullet, TimeControl::Blitz, TimeControl::Rapid, TimeControl::Classical],
+            accept_rated: true,
+            greeting: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct User {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Variant {
+    pub key: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Challenge {
+    pub id: String,
+    pub rated: bool,
+    pub speed: String,
+    pub variant: Variant,
+    pub challenger: Option<User>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct GameStart {
+    pub id: String,
+}
+
+// Lines of the account's event stream, the ones the bot has no use for are Other
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    Challenge { challenge: Challenge },
+    GameStart { game: GameStart },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct GameState {
+    pub moves: String, // Every move since the initial position in UCI notation, space separated
+    pub wtime: u64, // Milliseconds on the clocks
+    pub btime: u64,
+    pub winc: u64,
+    pub binc: u64,
+    pub status: String, // "started" while the game is on
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Player {
+    pub id: Option<String>, // None for Lichess's own AI
+}
+
+// Lines of a game's stream. The first is the full game, the rest follow every move.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum GameEvent {
+    GameFull {
+        white: Player,
+        black: Player,
+        #[serde(rename = "initialFen")]
+        initial_fen: String,
+        state: GameState,
+    },
+    GameState(GameState),
+    ChatLine { username: String, text: String, room: String },
+    #[serde(other)]
+    Other,
+}
+
+fn speed(name: &str) -> Option<TimeControl> {
+    match name {
+        "ultraBullet" => Some(TimeControl::UltraBullet),
+        "bullet" => Some(TimeControl::Bullet),
+        "blitz" => Some(TimeControl::Blitz),
+        "rapid" => Some(TimeControl::Rapid),
+        "classical" => Some(TimeControl::Classical),
+        "correspondence" => Some(TimeControl::Correspondence),
+        _ => None,
+    }
+}
+
+pub fn decline_reason(config: &BotConfig, challenge: &Challenge) -> Option<&'static str> {
+    // None to accept, otherwise the reason the API shows the challenger
+    if challenge.variant.key != "standard" {
+        Some("standard")
+    } else if !speed(&challenge.speed).is_some_and(|speed| config.speeds.contains(&speed)) {
+        Some("timeControl")
+    } else if challenge.rated && !config.accept_rated {
+        Some("casual")
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BotAction {
+    pub chess_move: ChessMove,
+    pub advice: Advice, // Resign instead of the move, or offer a draw with it
+}
+
+// One game from the bot's side, fed the states of the game's stream
+#[derive(Debug, Clone)]
+pub struct BotGame {
+    colour: Color,
+    start: Board,
+    advisor: GameAdvisor,
+    last_score: Option<i16>, // Of the bot's last search, from its side
+}
+
+impl BotGame {
+    pub fn new(colour: Color, initial_fen: &str, advice: AdviceSettings) -> Result<BotGame, NNUEError> {
+        let start = match initial_fen {
+            "startpos" => Board::default(),
+            fen => Board::from_str(fen).map_err(|_| NNUEError::InvalidData(format!("bad initial fen {:?}", fen)))?,
+        };
+        Ok(BotGame { colour, start, advisor: GameAdvisor::new(advice), last_score: None })
+    }
+
+    pub fn colour(&self) -> Color {
+        self.colour
+    }
+
+    pub fn position(&self, moves: &str) -> Result<(Board, RepetitionHistory, usize), NNUEError> {
+        // The board after moves with its repetition history, and the number of plies played
+        let mut board = self.start;
+        let mut history = RepetitionHistory::new(&board);
+        let mut plies = 0;
+        for name in moves.split_whitespace() {
+            let chess_move = ChessMove::from_str(name).map_err(|_| NNUEError::InvalidData(format!("bad move {:?}", name)))?;
+            if !board.legal(chess_move) {
+                return Err(NNUEError::IllegalMove);
+            }
+            let after = board.make_move_new(chess_move);
+            history.push(&after, is_irreversible(&board, &after, chess_move));
+            board = after;
+            plies += 1;
+        }
+        Ok((board, history, plies))
+    }
+
+    pub fn on_state(&mut self, evaluator: &mut BoxedNNUE, searcher: &mut Searcher, state: &GameState) -> Result<Option<BotAction>, NNUEError> {
+        // The bot's answer when it is to move in a running game, None otherwise
+        let (board, history, plies) = self.position(&state.moves)?;
+        if state.status != "started" || board.side_to_move() != self.colour || board.status() != BoardStatus::Ongoing {
+            return Ok(None);
+        }
+        let (remaining, increment) = match self.colour {
+            Color::White => (state.wtime, state.winc),
+            Color::Black => (state.btime, state.binc),
+        };
+        searcher.set_game_history(&history);
+        let depth = searcher.options().depth;
+        let result = timed_search(searcher, evaluator.as_mut(), &board, depth, Some(time_budget(remaining, increment, None)))?;
+
+        // Even a search stopped before its first iteration has to move
+        let chess_move = match result.best_move.or_else(|| MoveGen::new_legal(&board).next()) {
+            Some(chess_move) => chess_move,
+            None => return Ok(None),
+        };
+        self.last_score = Some(result.score);
+        self.advisor.record(&board, result.score, plies);
+        Ok(Some(BotAction { chess_move, advice: self.advisor.game_advice() }))
+    }
+
+    pub fn on_chat(&self, text: &str) -> Option<String> {
+        // Replies to the commands players send in the chat
+        match text.trim() {
+            "!help" => Some("Commands: !eval".to_string()),
+            "!eval" => Some(match self.last_score {
+                Some(score) => format!("My last search scored {:+.2} for me", score as f32 / 100.0),
+                None => "I haven't searched yet".to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn http(err: ureq::Error) -> NNUEError {
+    NNUEError::Remote(err.to_string())
+}
+
+fn read_lines<T: DeserializeOwned>(reader: impl Read, mut handle: impl FnMut(T) -> Result<bool, NNUEError>) -> Result<(), NNUEError> {
+    // Streams newline delimited JSON until handle returns false, skipping the empty keep-alive lines
+    // and lines it can't parse
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(value) => {
+                if !handle(value)? {
+                    break;
+                }
+            }
+            Err(err) => warn!("skipping stream line {:?}: {}", line, err),
+        }
+    }
+    Ok(())
+}
+
+pub struct LichessBot {
+    config: BotConfig,
+    agent: ureq::Agent,
+    account: String, // The bot's user id
+    evaluator: BoxedNNUE,
+    searcher: Searcher,
+    tt: Arc<TranspositionTable>,
+}
+
+impl LichessBot {
+    pub fn connect(config: BotConfig, evaluator: BoxedNNUE) -> Result<LichessBot, NNUEError> {
+        // Looks up the account the token belongs to, which has to be a bot account to play
+        let agent = ureq::AgentBuilder::new().timeout_connect(Duration::from_secs(10)).build();
+        let response = agent
+            .get(&format!("{}/api/account", config.base_url))
+            .set("Authorization", &format!("Bearer {}", config.token))
+            .call()
+            .map_err(http)?;
+        let account: User = serde_json::from_reader(response.into_reader()).map_err(|err| NNUEError::Remote(err.to_string()))?;
+        let tt = Arc::new(TranspositionTable::new(config.hash_mb));
+        let mut searcher = Searcher::new(config.search);
+        searcher.set_transposition_table(Some(Arc::clone(&tt)));
+        Ok(LichessBot { config, agent, account: account.id, evaluator, searcher, tt })
+    }
+
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    fn get(&self, path: &str) -> Result<ureq::Response, NNUEError> {
+        self.agent
+            .get(&format!("{}{}", self.config.base_url, path))
+            .set("Authorization", &format!("Bearer {}", self.config.token))
+            .call()
+            .map_err(http)
+    }
+
+    fn post(&self, path: &str, form: &[(&str, &str)]) -> Result<(), NNUEError> {
+        self.agent
+            .post(&format!("{}{}", self.config.base_url, path))
+            .set("Authorization", &format!("Bearer {}", self.config.token))
+            .send_form(form)
+            .map_err(http)?;
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<(), NNUEError> {
+        // Follows the event stream until Lichess closes it
+        let events = self.get("/api/stream/event")?.into_reader();
+        read_lines(events, |event: Event| {
+            match event {
+                Event::Challenge { challenge } => {
+                    // Challenges can be cancelled or expire before the answer arrives, Lichess then
+                    // refuses it and the bot waits for the next event
+                    if let Err(err) = self.answer(&challenge) {
+                        warn!("answering challenge {} failed: {}", challenge.id, err);
+                    }
+                }
+                Event::GameStart { game } => {
+                    // A failed game is logged, the bot carries on with the next one
+                    if let Err(err) = self.play(&game.id) {
+                        warn!("game {} failed: {}", game.id, err);
+                    }
+                }
+                Event::Other => {}
+            }
+            Ok(true)
+        })
+    }
+
+    fn answer(&self, challenge: &Challenge) -> Result<(), NNUEError> {
+        match decline_reason(&self.config, challenge) {
+            None => self.post(&format!("/api/challenge/{}/accept", challenge.id), &[]),
+            Some(reason) => self.post(&format!("/api/challenge/{}/decline", challenge.id), &[("reason", reason)]),
+        }
+    }
+
+    pub fn play(&mut self, game_id: &str) -> Result<(), NNUEError> {
+        // Plays one game to its end
+        let stream = self.get(&format!("/api/bot/game/stream/{}", game_id))?.into_reader();
+        let mut game: Option<BotGame> = None;
+        read_lines(stream, |event: GameEvent| {
+            let state = match event {
+                GameEvent::GameFull { white, black, initial_fen, state } => {
+                    let colour = match white.id.as_deref() == Some(self.account.as_str()) {
+                        true => Color::White,
+                        false if black.id.as_deref() == Some(self.account.as_str()) => Color::Black,
+                        false => return Err(NNUEError::Remote(format!("the bot doesn't play in game {}", game_id))),
+                    };
+                    info!("game {} started as {:?}", game_id, colour);
+                    game = Some(BotGame::new(colour, &initial_fen, self.config.advice)?);
+                    self.evaluator.set_board_hard(Board::default())?;
+                    self.tt.clear();
+                    if let Some(greeting) = &self.config.greeting {
+                        self.post(&format!("/api/bot/game/{}/chat", game_id), &[("room", "player"), ("text", greeting.as_str())])?;
+                    }
+                    state
+                }
+                GameEvent::GameState(state) => state,
+                GameEvent::ChatLine { username, text, room } => {
+                    let reply = game.as_ref().filter(|_| username != self.account).and_then(|game| game.on_chat(&text));
+                    if let Some(reply) = reply {
+                        self.post(&format!("/api/bot/game/{}/chat", game_id), &[("room", room.as_str()), ("text", reply.as_str())])?;
+                    }
+                    return Ok(true);
+                }
+                GameEvent::Other => return Ok(true),
+            };
+            let game = game.as_mut().ok_or_else(|| NNUEError::Remote("game state before the full game".to_string()))?;
+            if state.status != "started" && state.status != "created" {
+                info!("game {} over: {}", game_id, state.status);
+                return Ok(false);
+            }
+            self.tt.new_search();
+            match game.on_state(&mut self.evaluator, &mut self.searcher, &state)? {
+                Some(BotAction { advice: Advice::Resign, .. }) => {
+                    self.post(&format!("/api/bot/game/{}/resign", game_id), &[])?;
+                    Ok(false)
+                }
+                Some(BotAction { chess_move, advice }) => {
+                    let offer = if advice == Advice::OfferDraw { "?offeringDraw=true" } else { "" };
+                    self.post(&format!("/api/bot/game/{}/move/{}{}", game_id, chess_move, offer), &[])?;
+                    Ok(true)
+                }
+                None => Ok(true),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::tests::MaterialEval;
+
+    #[test]
+    fn test_stream_lines() {
+        let lines = "{\"type\":\"challenge\",\"challenge\":{\"id\":\"c1\",\"rated\":true,\"speed\":\"blitz\",\
+                     \"variant\":{\"key\":\"standard\"},\"challenger\":{\"id\":\"alice\"}}}\n\n\
+                     {\"type\":\"gameStart\",\"game\":{\"id\":\"g1\",\"color\":\"white\"}}\n\
+                     {\"type\":\"challengeCanceled\",\"challenge\":{}}\n";
+        let mut events = Vec::new();
+        read_lines(lines.as_bytes(), |event: Event| {
+            events.push(event);
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1], Event::GameStart { game: GameStart { id: "g1".to_string() } });
+        assert_eq!(events[2], Event::Other);
+
+        // Standard blitz is accepted, unless the bot only plays casual games
+        let mut config = BotConfig::new("token".to_string());
+        let challenge = match &events[0] {
+            Event::Challenge { challenge } => challenge.clone(),
+            _ => panic!("not a challenge"),
+        };
+        assert_eq!(decline_reason(&config, &challenge), None);
+        assert_eq!(decline_reason(&config, &Challenge { speed: "correspondence".to_string(), ..challenge.clone() }), Some("timeControl"));
+        config.accept_rated = false;
+        assert_eq!(decline_reason(&config, &challenge), Some("casual"));
+    }
+
+    #[test]
+    fn test_bot_game_moves() {
+        let full = "{\"type\":\"gameFull\",\"white\":{\"id\":\"alice\"},\"black\":{\"id\":\"bot\"},\"initialFen\":\"startpos\",\
+                    \"state\":{\"type\":\"gameState\",\"moves\":\"e2e4 d7d5 f1b5\",\"wtime\":60000,\"btime\":60000,\"winc\":0,\"binc\":0,\"status\":\"started\"}}";
+        let state = match serde_json::from_str(full).unwrap() {
+            GameEvent::GameFull { initial_fen, state, .. } => {
+                assert_eq!(initial_fen, "startpos");
+                state
+            }
+            _ => panic!("not the full game"),
+        };
+
+        // Playing black, the bot answers Bb5+ with a legal move, and only moves when it is its turn
+        let mut game = BotGame::new(Color::Black, "startpos", AdviceSettings::default()).unwrap();
+        let mut evaluator: BoxedNNUE = Box::new(MaterialEval { board: Board::default() });
+        let mut searcher = Searcher::new(SearchOptions { depth: 3, ..SearchOptions::default() });
+        let action = game.on_state(&mut evaluator, &mut searcher, &state).unwrap().unwrap();
+        let (board, _, plies) = game.position(&state.moves).unwrap();
+        assert!(board.legal(action.chess_move));
+        assert_eq!((plies, action.advice), (3, Advice::Play));
+        assert_eq!(game.on_state(&mut evaluator, &mut searcher, &GameState { moves: "e2e4 e7e5".to_string(), ..state.clone() }).unwrap(), None);
+        assert!(game.on_chat("!eval").unwrap().starts_with("My last search scored"));
+    }
+}
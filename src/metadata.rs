@@ -0,0 +1,38 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::NNUEError;
+
+// Facts about a model found after it was exported, such as its calibration. TorchScript files can't
+// be amended from Rust, so they live in a JSON file next to the model: "<model path>.meta.json".
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    #[serde(default)]
+    pub win_scale: Option<f64>, // Score units for a tenfold change in the odds, see ShallowNNUE::to_win_probability
+    #[serde(default)]
+    pub factorizer: Option<String>, // Virtual features the net was trained with, already folded into its weights
+}
+
+pub fn metadata_path<P: AsRef<Path>>(model_path: P) -> PathBuf {
+    let mut path = model_path.as_ref().as_os_str().to_owned();
+    path.push(".meta.json");
+    PathBuf::from(path)
+}
+
+pub fn read_metadata<P: AsRef<Path>>(model_path: P) -> Result<ModelMetadata, NNUEError> {
+    // Models without a metadata file have empty metadata
+    match fs::read_to_string(metadata_path(model_path)) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|err| NNUEError::InvalidData(err.to_string())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(ModelMetadata::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn write_metadata<P: AsRef<Path>>(model_path: P, metadata: &ModelMetadata) -> Result<(), NNUEError> {
+    let contents = serde_json::to_string_pretty(metadata).map_err(|err| NNUEError::InvalidData(err.to_string()))?;
+    fs::write(metadata_path(model_path), contents)?;
+    Ok(())
+}
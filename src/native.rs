@@ -0,0 +1,932 @@
+use std::fs::File;
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use chess::{Board, ChessMove};
+use memmap2::Mmap;
+
+use crate::bit_move::{active_features, feature_delta, BitMove, PieceValueChange};
+use crate::compression::{decompress, is_compressed, read_file, write_file};
+use crate::error::NNUEError;
+use crate::network::{Activation, NetworkConfig};
+use crate::perspective::ScorePerspective;
+use crate::shallow_nnue::NNUE;
+
+// Native weight file layout, every field is 4 bytes wide and little-endian so files are portable:
+//   magic "SNUE" | version u32 | layer count u32 | activation u32 | flags u32 | (inputs u32, outputs u32) per layer | f32 data
+// Version 1 files have no activation field and are read as ReLU nets, version 2 files have no flags.
+// The f32 data holds each layer's weights followed by its biases. The first layer is stored
+// feature-major ([inputs][outputs]) so a feature's column is contiguous for the accumulator,
+// later layers use the torch.nn.Linear layout ([outputs][inputs]).
+const MAGIC: &[u8; 4] = b"SNUE";
+pub const FORMAT_VERSION: u32 = 3;
+const NUM_FEATURES: usize = 768;
+// Header flags, see Perspectives
+const FLAG_DUAL_PERSPECTIVE: u32 = 1;
+const FLAG_SHARED_WEIGHTS: u32 = 2;
+// Quantized accumulators hold multiples of 1 / QUANTIZATION_SCALE, clipped ReLU's 1.0 is 255
+pub const QUANTIZATION_SCALE: f32 = 255.0;
+
+fn invalid(reason: &str) -> NNUEError {
+    NNUEError::InvalidWeights(reason.to_string())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, NNUEError> {
+    match bytes.get(offset..offset + 4) {
+        Some(word) => Ok(u32::from_le_bytes([word[0], word[1], word[2], word[3]])),
+        None => Err(invalid("file is truncated")),
+    }
+}
+
+// Accumulators the feature transformer fills. With two, the second layer reads the side to move's
+// accumulator followed by the opponent's, so its inputs are twice the first layer's outputs.
+// The opponent's accumulator sees every feature turned around to its side. Both share the biases.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Perspectives {
+    #[default]
+    Single, // One accumulator, for the side to move
+    Dual, // Two accumulators with their own weights, the first layer stores 2 * 768 input rows
+    Shared, // Two accumulators from the same 768 rows, for symmetric nets at half the memory of Dual
+}
+
+impl Perspectives {
+    fn from_flags(flags: u32) -> Result<Perspectives, NNUEError> {
+        match flags {
+            0 => Ok(Perspectives::Single),
+            FLAG_DUAL_PERSPECTIVE => Ok(Perspectives::Dual),
+            flags if flags == FLAG_DUAL_PERSPECTIVE | FLAG_SHARED_WEIGHTS => Ok(Perspectives::Shared),
+            _ => Err(NNUEError::InvalidWeights(format!("unsupported header flags {:#x}", flags))),
+        }
+    }
+
+    fn flags(self) -> u32 {
+        match self {
+            Perspectives::Single => 0,
+            Perspectives::Dual => FLAG_DUAL_PERSPECTIVE,
+            Perspectives::Shared => FLAG_DUAL_PERSPECTIVE | FLAG_SHARED_WEIGHTS,
+        }
+    }
+
+    fn accumulators(self) -> usize {
+        match self {
+            Perspectives::Single => 1,
+            Perspectives::Dual | Perspectives::Shared => 2,
+        }
+    }
+
+    fn stored_inputs(self) -> usize {
+        // Input rows of the first layer in the file
+        match self {
+            Perspectives::Dual => 2 * NUM_FEATURES,
+            Perspectives::Single | Perspectives::Shared => NUM_FEATURES,
+        }
+    }
+
+    fn opponent_row(self, index: usize) -> Option<usize> {
+        // First layer row that feeds the opponent's accumulator for a side to move feature:
+        // own and opponent planes swap and the square is reoriented
+        let flipped = ((index / 64 + 6) % 12) * 64 + (63 - index % 64);
+        match self {
+            Perspectives::Single => None,
+            Perspectives::Dual => Some(NUM_FEATURES + flipped),
+            Perspectives::Shared => Some(flipped),
+        }
+    }
+}
+
+fn add_rows<T: Copy>(accumulator: &mut [T], weights: &[T], perspectives: Perspectives, index: usize, add: impl Fn(T, T) -> T) {
+    // Adds the feature's weights to every accumulator, with add deciding the sign
+    let size = accumulator.len() / perspectives.accumulators();
+    let rows = std::iter::once(index).chain(perspectives.opponent_row(index));
+    for (half, row) in accumulator.chunks_exact_mut(size).zip(rows) {
+        for (value, weight) in half.iter_mut().zip(&weights[row * size..(row + 1) * size]) {
+            *value = add(*value, *weight);
+        }
+    }
+}
+
+fn fill_biases<T: Copy>(accumulator: &mut [T], biases: &[T]) {
+    for half in accumulator.chunks_exact_mut(biases.len()) {
+        half.copy_from_slice(biases);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LayerLayout {
+    inputs: usize,
+    outputs: usize,
+    weights_offset: usize, // Byte offsets into the file
+    biases_offset: usize,
+}
+
+fn parse_header(bytes: &[u8]) -> Result<(Vec<LayerLayout>, Activation, Perspectives), NNUEError> {
+    if bytes.get(0..4) != Some(&MAGIC[..]) {
+        return Err(invalid("missing magic number"));
+    }
+    let version = read_u32(bytes, 4)?;
+    let read_activation = || {
+        let id = read_u32(bytes, 12)?;
+        Activation::from_id(id).ok_or_else(|| NNUEError::InvalidWeights(format!("unknown activation {}", id)))
+    };
+    let (activation, perspectives, sizes_offset) = match version {
+        1 => (Activation::Relu, Perspectives::Single, 12),
+        2 => (read_activation()?, Perspectives::Single, 16),
+        FORMAT_VERSION => (read_activation()?, Perspectives::from_flags(read_u32(bytes, 16)?)?, 20),
+        _ => return Err(NNUEError::InvalidWeights(format!("unsupported format version {}", version))),
+    };
+
+    let num_layers = read_u32(bytes, 8)? as usize;
+    if num_layers == 0 {
+        return Err(invalid("network has no layers"));
+    }
+    if num_layers == 1 && perspectives != Perspectives::Single {
+        return Err(invalid("two perspectives need a layer after the feature transformer"));
+    }
+
+    // The count comes from the file, so bound it by the bytes that could hold the sizes
+    if num_layers > bytes.len() / 8 {
+        return Err(invalid(&format!("header declares {} layers in a {} byte file", num_layers, bytes.len())));
+    }
+
+    let overflow = || invalid("layer sizes overflow");
+    let mut layers: Vec<LayerLayout> = Vec::with_capacity(num_layers);
+    let mut offset = sizes_offset + num_layers * 8;
+    for i in 0..num_layers {
+        let inputs = read_u32(bytes, sizes_offset + i * 8)? as usize;
+        let outputs = read_u32(bytes, sizes_offset + 4 + i * 8)? as usize;
+        let width = if i == 1 { perspectives.accumulators() } else { 1 };
+        if layers.last().is_some_and(|previous| previous.outputs.checked_mul(width) != Some(inputs)) {
+            return Err(invalid("layer sizes do not chain"));
+        }
+
+        let weights_bytes = inputs
+            .checked_mul(outputs)
+            .and_then(|weights| weights.checked_mul(mem::size_of::<f32>()))
+            .ok_or_else(overflow)?;
+        let biases_offset = offset.checked_add(weights_bytes).ok_or_else(overflow)?;
+        layers.push(LayerLayout {
+            inputs,
+            outputs,
+            weights_offset: offset,
+            biases_offset,
+        });
+        offset = outputs
+            .checked_mul(mem::size_of::<f32>())
+            .and_then(|biases_bytes| biases_offset.checked_add(biases_bytes))
+            .ok_or_else(overflow)?;
+    }
+
+    if offset != bytes.len() {
+        return Err(invalid("file size does not match the layer sizes"));
+    }
+    if layers[0].inputs != perspectives.stored_inputs() {
+        return Err(NNUEError::InvalidWeights(format!("the first layer must store {} inputs", perspectives.stored_inputs())));
+    }
+    Ok((layers, activation, perspectives))
+}
+
+fn resolve_config(layers: &[LayerLayout], activation: Activation, perspectives: Perspectives, config: Option<&NetworkConfig>) -> Result<NetworkConfig, NNUEError> {
+    // Without a config the file must be the default shape: 768 inputs and a single output,
+    // with the activation the file declares. The config describes a single perspective.
+    let mut sizes: Vec<(usize, usize)> = layers.iter().map(|layer| (layer.inputs, layer.outputs)).collect();
+    if perspectives != Perspectives::Single {
+        sizes[0].0 = NUM_FEATURES;
+        sizes[1].0 /= 2;
+    }
+    let config = match config {
+        Some(config) => config.clone(),
+        None => NetworkConfig {
+            hidden: sizes[..sizes.len() - 1].iter().map(|(_, outputs)| *outputs).collect(),
+            activation,
+            ..NetworkConfig::default()
+        },
+    };
+    if config.activation != activation {
+        return Err(NNUEError::InvalidWeights(format!(
+            "the file declares {} but the config expects {}",
+            activation.name(),
+            config.activation.name()
+        )));
+    }
+    if config.inputs != NUM_FEATURES {
+        return Err(NNUEError::InvalidConfig(format!("the native backend encodes {} features, not {}", NUM_FEATURES, config.inputs)));
+    }
+    config.check_layers(&sizes)?;
+    Ok(config)
+}
+
+fn check_f32_slice(bytes: &[u8]) -> Result<(), NNUEError> {
+    // Reinterpreting the mapped bytes is only sound for aligned, whole f32s
+    if !(bytes.as_ptr() as usize).is_multiple_of(mem::align_of::<f32>()) {
+        return Err(invalid("weights are not aligned"));
+    }
+    if !bytes.len().is_multiple_of(mem::size_of::<f32>()) {
+        return Err(invalid("weights are not a whole number of f32s"));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+enum WeightStorage {
+    Mapped(Mmap), // Little-endian hosts read the weights straight from the mapped file
+    Decoded(Vec<f32>), // Every 4-byte word of the file decoded, so byte offset / 4 indexes it
+}
+
+#[derive(Debug)]
+pub struct NativeWeights {
+    storage: WeightStorage,
+    layers: Vec<LayerLayout>,
+    config: NetworkConfig,
+    perspectives: Perspectives,
+}
+
+// First layer weights as int16, halves the accumulator and makes updates integer adds.
+// Sums that leave the int16 range saturate, which needs weights far larger than trained nets have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantizedLayer {
+    weights: Vec<i16>, // Same feature-major layout as the float weights
+    biases: Vec<i16>,
+    perspectives: Perspectives,
+}
+
+impl QuantizedLayer {
+    pub(crate) fn add_feature(&self, accumulator: &mut [i16], index: usize, sign: i16) {
+        add_rows(accumulator, &self.weights, self.perspectives, index, |value, weight| value.saturating_add(sign * weight));
+    }
+
+    pub(crate) fn refresh_accumulator(&self, board: &Board, accumulator: &mut [i16]) {
+        fill_biases(accumulator, &self.biases);
+        for index in active_features(board, board.side_to_move()).iter() {
+            self.add_feature(accumulator, *index as usize, 1);
+        }
+    }
+
+    pub(crate) fn dequantize(accumulator: &[i16], output: &mut [f32]) {
+        for (value, quantized) in output.iter_mut().zip(accumulator) {
+            *value = *quantized as f32 / QUANTIZATION_SCALE;
+        }
+    }
+}
+
+// Owned copy of a layer, used to write weight files
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerWeights {
+    pub inputs: usize,
+    pub outputs: usize,
+    pub weights: Vec<f32>, // [inputs][outputs] for the first layer, [outputs][inputs] after
+    pub biases: Vec<f32>,
+}
+
+impl NativeWeights {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<NativeWeights, NNUEError> {
+        NativeWeights::open(path.as_ref(), None)
+    }
+
+    pub fn load_with_config<P: AsRef<Path>>(path: P, config: &NetworkConfig) -> Result<NativeWeights, NNUEError> {
+        // Checks the file against the architecture it was trained with and uses its activation
+        NativeWeights::open(path.as_ref(), Some(config))
+    }
+
+    fn open(path: &Path, config: Option<&NetworkConfig>) -> Result<NativeWeights, NNUEError> {
+        // The file can only be used in place when the host shares its byte order (and can map files)
+        if cfg!(not(target_endian = "little")) || cfg!(target_arch = "wasm32") {
+            return NativeWeights::decode(&read_file(path)?, config);
+        }
+
+        let file = File::open(path)?;
+        // Safety: the weight file must not be modified while it is mapped
+        let mmap = unsafe { Mmap::map(&file)? };
+        if is_compressed(&mmap) {
+            // Compressed weights can't be used in place
+            return NativeWeights::decode(&decompress(&mmap)?, config);
+        }
+        let (layers, activation, perspectives) = parse_header(&mmap)?;
+        let config = resolve_config(&layers, activation, perspectives, config)?;
+
+        // Validate every slice once, so the accessors can reinterpret without checks
+        for layer in &layers {
+            check_f32_slice(&mmap[layer.weights_offset..layer.biases_offset])?;
+            check_f32_slice(&mmap[layer.biases_offset..layer.biases_offset + layer.outputs * mem::size_of::<f32>()])?;
+        }
+
+        Ok(NativeWeights {
+            storage: WeightStorage::Mapped(mmap),
+            layers,
+            config,
+            perspectives,
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<NativeWeights, NNUEError> {
+        NativeWeights::decode(bytes, None)
+    }
+
+    fn decode(bytes: &[u8], config: Option<&NetworkConfig>) -> Result<NativeWeights, NNUEError> {
+        // Decodes a weight file held in memory, works on any host byte order
+        let (layers, activation, perspectives) = parse_header(bytes)?;
+        let config = resolve_config(&layers, activation, perspectives, config)?;
+        let words = bytes
+            .chunks_exact(4)
+            .map(|word| f32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+
+        Ok(NativeWeights {
+            storage: WeightStorage::Decoded(words),
+            layers,
+            config,
+            perspectives,
+        })
+    }
+
+    fn f32_slice(&self, offset: usize, len: usize) -> &[f32] {
+        match &self.storage {
+            WeightStorage::Mapped(mmap) => {
+                let bytes = &mmap[offset..offset + len * mem::size_of::<f32>()];
+                // Safety: alignment and length were checked in load and the map lives as long as self
+                unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const f32, len) }
+            }
+            WeightStorage::Decoded(words) => {
+                let start = offset / mem::size_of::<f32>();
+                &words[start..start + len]
+            }
+        }
+    }
+
+    pub fn config(&self) -> &NetworkConfig {
+        &self.config
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn layer_sizes(&self) -> Vec<(usize, usize)> {
+        self.layers.iter().map(|layer| (layer.inputs, layer.outputs)).collect()
+    }
+
+    pub fn perspectives(&self) -> Perspectives {
+        self.perspectives
+    }
+
+    pub fn accumulator_size(&self) -> usize {
+        // Every accumulator together, the width the second layer reads
+        self.layers[0].outputs * self.perspectives.accumulators()
+    }
+
+    pub fn weights(&self, layer: usize) -> &[f32] {
+        let layout = self.layers[layer];
+        self.f32_slice(layout.weights_offset, layout.inputs * layout.outputs)
+    }
+
+    pub fn biases(&self, layer: usize) -> &[f32] {
+        let layout = self.layers[layer];
+        self.f32_slice(layout.biases_offset, layout.outputs)
+    }
+
+    pub(crate) fn add_feature(&self, accumulator: &mut [f32], index: usize, sign: f32) {
+        add_rows(accumulator, self.weights(0), self.perspectives, index, |value, weight| value + sign * weight);
+    }
+
+    pub fn quantize(&self) -> Result<QuantizedLayer, NNUEError> {
+        // First layer in int16 units of 1 / QUANTIZATION_SCALE
+        let quantize = |values: &[f32]| -> Result<Vec<i16>, NNUEError> {
+            values
+                .iter()
+                .map(|value| {
+                    let scaled = (value * QUANTIZATION_SCALE).round();
+                    if scaled.abs() > i16::MAX as f32 {
+                        return Err(invalid("first layer weight too large to quantize"));
+                    }
+                    Ok(scaled as i16)
+                })
+                .collect()
+        };
+        Ok(QuantizedLayer {
+            weights: quantize(self.weights(0))?,
+            biases: quantize(self.biases(0))?,
+            perspectives: self.perspectives,
+        })
+    }
+
+    pub(crate) fn refresh_accumulator(&self, board: &Board, accumulator: &mut [f32]) {
+        // The accumulator must already be accumulator_size long
+        fill_biases(accumulator, self.biases(0));
+        for index in active_features(board, board.side_to_move()).iter() {
+            self.add_feature(accumulator, *index as usize, 1.0);
+        }
+    }
+
+    pub(crate) fn propagate(&self, accumulator: &[f32], buffers: &mut [Vec<f32>; 2]) -> f32 {
+        self.propagate_values(accumulator.iter().copied(), buffers)
+    }
+
+    pub(crate) fn propagate_quantized(&self, accumulator: &[i16], buffers: &mut [Vec<f32>; 2]) -> f32 {
+        // Dequantized on the fly, the int16 accumulator is never stored as floats
+        self.propagate_values(accumulator.iter().map(|value| *value as f32 / QUANTIZATION_SCALE), buffers)
+    }
+
+    fn propagate_values<I: Iterator<Item = f32>>(&self, mut accumulator: I, buffers: &mut [Vec<f32>; 2]) -> f32 {
+        // Runs every layer after the feature transformer and returns the first output head.
+        // Layer values go back and forth between the two buffers, which stop growing after the first call.
+        if self.layers.len() == 1 {
+            return accumulator.next().unwrap_or(0.0); // The feature transformer is the output layer
+        }
+        let activation = self.config.activation;
+        let [input, output] = buffers;
+        input.clear();
+        input.extend(accumulator.map(|value| activation.apply(value)));
+        for layer in 1..self.layers.len() {
+            let layout = self.layers[layer];
+            let weights = self.weights(layer);
+            let last = layer == self.layers.len() - 1;
+
+            output.clear();
+            output.extend(self.biases(layer).iter().enumerate().map(|(unit, bias)| {
+                let row = &weights[unit * layout.inputs..(unit + 1) * layout.inputs];
+                let value = bias + row.iter().zip(input.iter()).map(|(w, x)| w * x).sum::<f32>();
+                if last { value } else { activation.apply(value) }
+            }));
+            mem::swap(input, output);
+        }
+        input[0]
+    }
+}
+
+pub fn save_weights<P: AsRef<Path>>(path: P, layers: &[LayerWeights]) -> Result<(), NNUEError> {
+    save_network(path, layers, Activation::Relu)
+}
+
+pub fn save_network<P: AsRef<Path>>(path: P, layers: &[LayerWeights], activation: Activation) -> Result<(), NNUEError> {
+    save_network_with_perspectives(path, layers, activation, Perspectives::Single)
+}
+
+pub fn save_network_with_perspectives<P: AsRef<Path>>(
+    path: P,
+    layers: &[LayerWeights],
+    activation: Activation,
+    perspectives: Perspectives,
+) -> Result<(), NNUEError> {
+    // The layers are written as given, their sizes have to match the perspectives
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(layers.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&activation.id().to_le_bytes());
+    bytes.extend_from_slice(&perspectives.flags().to_le_bytes());
+    for layer in layers {
+        bytes.extend_from_slice(&(layer.inputs as u32).to_le_bytes());
+        bytes.extend_from_slice(&(layer.outputs as u32).to_le_bytes());
+    }
+    for layer in layers {
+        if layer.weights.len() != layer.inputs * layer.outputs || layer.biases.len() != layer.outputs {
+            return Err(invalid("layer data does not match its sizes"));
+        }
+        for value in layer.weights.iter().chain(&layer.biases) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    // Compressed when the path ends in .zst
+    write_file(path, &bytes)
+}
+
+#[derive(Debug, Clone)]
+enum Accumulator {
+    Float(Vec<f32>), // First layer output for the side to move, before activation
+    Int16(Arc<QuantizedLayer>, Vec<i16>), // The same in int16, see QuantizedLayer. Copies share the layer.
+}
+
+impl Accumulator {
+    fn refresh(&mut self, weights: &NativeWeights, board: &Board) {
+        match self {
+            Accumulator::Float(values) => weights.refresh_accumulator(board, values),
+            Accumulator::Int16(layer, values) => layer.refresh_accumulator(board, values),
+        }
+    }
+
+    fn propagate(&self, weights: &NativeWeights, buffers: &mut [Vec<f32>; 2]) -> f32 {
+        match self {
+            Accumulator::Float(values) => weights.propagate(values, buffers),
+            Accumulator::Int16(_, values) => weights.propagate_quantized(values, buffers),
+        }
+    }
+
+    fn copy_from(&mut self, source: &Accumulator) {
+        // clone_from without the allocation, accumulators of one network match in kind and size
+        match (self, source) {
+            (Accumulator::Float(values), Accumulator::Float(source)) => values.copy_from_slice(source),
+            (Accumulator::Int16(_, values), Accumulator::Int16(_, source)) => values.copy_from_slice(source),
+            (accumulator, source) => *accumulator = source.clone(),
+        }
+    }
+
+    fn add_feature(&mut self, weights: &NativeWeights, index: usize, value: PieceValueChange) {
+        let sign = match value {
+            PieceValueChange::Place => 1,
+            PieceValueChange::Remove => -1,
+        };
+        match self {
+            Accumulator::Float(values) => weights.add_feature(values, index, sign as f32),
+            Accumulator::Int16(layer, values) => layer.add_feature(values, index, sign),
+        }
+    }
+
+    fn apply_delta(&mut self, weights: &NativeWeights, current: &Board, target: &Board) {
+        // From current's accumulator to target's, both for the same side to move
+        let (removed, placed) = feature_delta(current, target);
+        for index in removed.iter() {
+            self.add_feature(weights, *index as usize, PieceValueChange::Remove);
+        }
+        for index in placed.iter() {
+            self.add_feature(weights, *index as usize, PieceValueChange::Place);
+        }
+    }
+}
+
+// Diffs applied to a cache entry before it is rebuilt from scratch, float entries pick up rounding
+// with every diff and int16 ones can saturate
+const CACHE_REBUILD_DIFFS: u32 = 64;
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    board: Board,
+    accumulator: Accumulator,
+    diffs: u32,
+}
+
+// Finny table: the last accumulator built for each (king square, side to move), with the board it
+// was built for. A refresh starts from the entry for the new board's key and applies only the features
+// that differ, so jumping to another position (a new search root, a king move) costs a few rows
+// instead of all of them. Keying on the king keeps the cached board close to the new one.
+#[derive(Debug, Clone)]
+struct AccumulatorCache {
+    entries: Vec<Option<CacheEntry>>, // 64 king squares per side to move, filled on first use
+}
+
+impl AccumulatorCache {
+    fn new() -> AccumulatorCache {
+        AccumulatorCache { entries: vec![None; 2 * 64] }
+    }
+
+    fn key(board: &Board) -> usize {
+        let turn = board.side_to_move();
+        turn.to_index() * 64 + board.king_square(turn).to_index()
+    }
+
+    fn refresh(&mut self, accumulator: &mut Accumulator, weights: &NativeWeights, board: &Board) {
+        match &mut self.entries[AccumulatorCache::key(board)] {
+            Some(entry) => {
+                // Rebuilt in place, so only the first use of a key allocates
+                if entry.diffs < CACHE_REBUILD_DIFFS {
+                    entry.accumulator.apply_delta(weights, &entry.board, board);
+                    entry.diffs += 1;
+                } else {
+                    entry.accumulator.refresh(weights, board);
+                    entry.diffs = 0;
+                }
+                entry.board = *board;
+                accumulator.copy_from(&entry.accumulator);
+            }
+            slot => {
+                accumulator.refresh(weights, board);
+                *slot = Some(CacheEntry { board: *board, accumulator: accumulator.clone(), diffs: 0 });
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NativeNNUE {
+    weights: NativeWeights,
+    board: Board,
+    accumulator: Accumulator,
+    scratch: Accumulator, // The accumulator after the move being scored by forward
+    buffers: [Vec<f32>; 2], // Values of the layers after the accumulator
+    cache: Option<AccumulatorCache>, // Used by set_board_hard, see set_accumulator_cache
+    perspective: ScorePerspective,
+}
+
+impl NativeNNUE {
+    pub fn new(weights: NativeWeights) -> NativeNNUE {
+        let accumulator = Accumulator::Float(vec![0.0; weights.accumulator_size()]);
+        NativeNNUE::with_accumulator(weights, accumulator)
+    }
+
+    pub fn quantized(weights: NativeWeights) -> Result<NativeNNUE, NNUEError> {
+        // Keeps the accumulator in int16, scores match the float path up to rounding
+        let accumulator = Accumulator::Int16(Arc::new(weights.quantize()?), vec![0; weights.accumulator_size()]);
+        Ok(NativeNNUE::with_accumulator(weights, accumulator))
+    }
+
+    fn with_accumulator(weights: NativeWeights, mut accumulator: Accumulator) -> NativeNNUE {
+        let board = Board::default();
+        accumulator.refresh(&weights, &board);
+        NativeNNUE {
+            weights,
+            board,
+            scratch: accumulator.clone(),
+            accumulator,
+            buffers: [Vec::new(), Vec::new()],
+            cache: Some(AccumulatorCache::new()),
+            perspective: ScorePerspective::default(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<NativeNNUE, NNUEError> {
+        Ok(NativeNNUE::new(NativeWeights::load(path)?))
+    }
+
+    pub fn load_with_config<P: AsRef<Path>>(path: P, config: &NetworkConfig) -> Result<NativeNNUE, NNUEError> {
+        Ok(NativeNNUE::new(NativeWeights::load_with_config(path, config)?))
+    }
+
+    pub fn set_perspective(&mut self, perspective: ScorePerspective) {
+        self.perspective = perspective;
+    }
+
+    pub fn perspective(&self) -> ScorePerspective {
+        self.perspective
+    }
+
+    pub fn set_accumulator_cache(&mut self, enabled: bool) {
+        // On by default, up to 128 extra accumulators. Off, every set_board_hard rebuilds from the biases.
+        self.cache = enabled.then(AccumulatorCache::new);
+    }
+}
+
+impl NNUE for NativeNNUE {
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        let turn = self.board.side_to_move();
+        let bitmove = BitMove::new(chess_move, turn, self.board)?;
+
+        // Work on a copy so the accumulator never drifts from repeated add/subtract
+        self.scratch.copy_from(&self.accumulator);
+        for change in bitmove.changes() {
+            self.scratch.add_feature(&self.weights, change.index as usize, change.value);
+        }
+
+        let score = self.scratch.propagate(&self.weights, &mut self.buffers) as i16;
+        Ok(self.perspective.from_side_to_move(score, turn))
+    }
+
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+        self.board = board;
+        match &mut self.cache {
+            Some(cache) => cache.refresh(&mut self.accumulator, &self.weights, &board),
+            None => self.accumulator.refresh(&self.weights, &board),
+        }
+        Ok(())
+    }
+
+    fn evaluate(&mut self) -> Result<i16, NNUEError> {
+        let score = self.accumulator.propagate(&self.weights, &mut self.buffers) as i16;
+        Ok(self.perspective.from_side_to_move(score, self.board.side_to_move()))
+    }
+
+    fn perspective(&self) -> ScorePerspective {
+        self.perspective
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::str::FromStr;
+
+    use chess::Square;
+
+    use super::*;
+    use crate::alloc_counter::allocations_during;
+    use crate::rng::XorShift;
+
+    pub(crate) fn antisymmetric_network(seed: u64) -> Vec<LayerWeights> {
+        // 768 -> 1 with integer weights, an opponent piece is worth minus the same own piece turned
+        // around, so a position scores exactly the negation from the other side. forward(m) then has to
+        // equal minus evaluating the position after m, which tests can compare without tolerance.
+        let mut rng = XorShift::new(seed);
+        let mut weights = vec![0.0; NUM_FEATURES];
+        for weight in &mut weights[..NUM_FEATURES / 2] {
+            *weight = rng.below(101) as f32 - 50.0;
+        }
+        for opponent in NUM_FEATURES / 2..NUM_FEATURES {
+            let (piece, square) = (opponent / 64 - 6, opponent % 64);
+            weights[opponent] = -weights[piece * 64 + 63 - square];
+        }
+        vec![LayerWeights { inputs: NUM_FEATURES, outputs: 1, weights, biases: vec![0.0] }]
+    }
+
+    fn tiny_network() -> Vec<LayerWeights> {
+        // 768 -> 2 -> 1, only the own pawn on E4 (index 28) has a weight
+        let mut first = vec![0.0; NUM_FEATURES * 2];
+        first[28 * 2] = 1.0;
+        vec![
+            LayerWeights { inputs: NUM_FEATURES, outputs: 2, weights: first, biases: vec![0.5, 0.0] },
+            LayerWeights { inputs: 2, outputs: 1, weights: vec![2.0, 3.0], biases: vec![1.0] },
+        ]
+    }
+
+    #[test]
+    fn test_native_forward() {
+        let path = std::env::temp_dir().join("shallow_nnue_native_forward.bin");
+        save_weights(&path, &tiny_network()).unwrap();
+
+        let mut nnue = NativeNNUE::load(&path).unwrap();
+        nnue.set_board_hard(Board::default()).unwrap();
+        assert_eq!(nnue.evaluate().unwrap(), 2); // 2 * relu(0.5) + 1
+
+        let mve: ChessMove = ChessMove::new(Square::E2, Square::E4, None);
+        assert_eq!(nnue.forward(mve).unwrap(), 4); // 2 * relu(1.5) + 1
+        assert_eq!(nnue.evaluate().unwrap(), 2); // Forward leaves the accumulator untouched
+
+        // The activation comes from the file, a config that disagrees with it is rejected
+        let config = NetworkConfig { hidden: vec![2], activation: Activation::ClippedRelu, ..NetworkConfig::default() };
+        assert!(matches!(NativeNNUE::load_with_config(&path, &config), Err(NNUEError::InvalidWeights(_))));
+        save_network(&path, &tiny_network(), Activation::ClippedRelu).unwrap();
+        let mut clipped = NativeNNUE::load(&path).unwrap();
+        assert_eq!(clipped.forward(mve).unwrap(), 3); // 2 * min(1.5, 1) + 1
+        assert!(NativeNNUE::load_with_config(&path, &config).is_ok());
+    }
+
+    #[test]
+    fn test_forward_allocations() {
+        let path = std::env::temp_dir().join("shallow_nnue_native_allocations.bin");
+        save_weights(&path, &tiny_network()).unwrap();
+        let e4 = ChessMove::new(Square::E2, Square::E4, None);
+        for mut nnue in [NativeNNUE::load(&path).unwrap(), NativeNNUE::quantized(NativeWeights::load(&path).unwrap()).unwrap()] {
+            // Warmed up, neither forward nor evaluate allocates
+            nnue.forward(e4).unwrap();
+            let (scores, allocations) = allocations_during(|| (nnue.forward(e4).unwrap(), nnue.evaluate().unwrap()));
+            assert_eq!((scores, allocations), ((4, 2), 0));
+        }
+    }
+
+    #[test]
+    fn test_quantized_layer_is_shared() {
+        let path = std::env::temp_dir().join("shallow_nnue_native_shared_layer.bin");
+        save_weights(&path, &tiny_network()).unwrap();
+        let mut nnue = NativeNNUE::quantized(NativeWeights::load(&path).unwrap()).unwrap();
+        nnue.forward(ChessMove::new(Square::E2, Square::E4, None)).unwrap();
+        nnue.set_board_hard(Board::default()).unwrap();
+
+        // The accumulator, forward's scratch copy and the cache entry all point at one layer
+        let (Accumulator::Int16(layer, _), Accumulator::Int16(scratch, _)) = (&nnue.accumulator, &nnue.scratch) else {
+            panic!("quantized networks keep int16 accumulators");
+        };
+        assert!(Arc::ptr_eq(layer, scratch));
+        assert_eq!(Arc::strong_count(layer), 3);
+    }
+
+    #[test]
+    fn test_quantized_matches_float() {
+        let path = std::env::temp_dir().join("shallow_nnue_native_quantized.bin");
+        save_weights(&path, &tiny_network()).unwrap();
+
+        let mut quantized = NativeNNUE::quantized(NativeWeights::load(&path).unwrap()).unwrap();
+        quantized.set_board_hard(Board::default()).unwrap();
+        assert_eq!(quantized.evaluate().unwrap(), 2); // 0.5 is stored as 128 / 255
+        assert_eq!(quantized.forward(ChessMove::new(Square::E2, Square::E4, None)).unwrap(), 4);
+
+        let mut network = tiny_network();
+        network[0].weights[0] = 200.0;
+        save_weights(&path, &network).unwrap();
+        assert!(matches!(NativeWeights::load(&path).unwrap().quantize(), Err(NNUEError::InvalidWeights(_))));
+    }
+
+    #[test]
+    fn test_invalid_weights() {
+        let path = std::env::temp_dir().join("shallow_nnue_native_invalid.bin");
+        let mut network = tiny_network();
+        network[1].outputs = 2;
+        network[1].weights = vec![0.0; 4];
+        network[1].biases = vec![0.0; 2];
+        save_weights(&path, &network).unwrap();
+        assert!(matches!(NativeNNUE::load(&path), Err(NNUEError::InvalidWeights(_))));
+
+        // Extra output heads are fine once the config declares them
+        let config = NetworkConfig { hidden: vec![2], outputs: 2, ..NetworkConfig::default() };
+        assert!(NativeNNUE::load_with_config(&path, &config).is_ok());
+        let wider = NetworkConfig { hidden: vec![4], outputs: 2, ..NetworkConfig::default() };
+        assert!(matches!(NativeNNUE::load_with_config(&path, &wider), Err(NNUEError::InvalidWeights(_))));
+
+        std::fs::write(&path, b"not a weight file").unwrap();
+        assert!(matches!(NativeNNUE::load(&path), Err(NNUEError::InvalidWeights(_))));
+
+        // Header counts and sizes are untrusted: no huge allocation and no overflow
+        let header = |words: &[u32]| -> Vec<u8> { MAGIC.iter().copied().chain(words.iter().flat_map(|word| word.to_le_bytes())).collect() };
+        std::fs::write(&path, header(&[1, u32::MAX])).unwrap();
+        assert!(matches!(NativeNNUE::load(&path), Err(NNUEError::InvalidWeights(_))));
+        std::fs::write(&path, header(&[1, 2, 768, u32::MAX, u32::MAX, u32::MAX])).unwrap();
+        assert!(matches!(NativeNNUE::load(&path), Err(NNUEError::InvalidWeights(_))));
+    }
+
+    #[test]
+    fn test_decoded_matches_mapped() {
+        let path = std::env::temp_dir().join("shallow_nnue_native_decoded.bin");
+        save_weights(&path, &tiny_network()).unwrap();
+
+        // Decoding is what big-endian and wasm hosts use, it must see the same weights
+        let mapped = NativeWeights::load(&path).unwrap();
+        let decoded = NativeWeights::from_bytes(&std::fs::read(&path).unwrap()).unwrap();
+        for layer in 0..mapped.num_layers() {
+            assert_eq!(mapped.weights(layer), decoded.weights(layer));
+            assert_eq!(mapped.biases(layer), decoded.biases(layer));
+        }
+        assert_eq!(decoded.biases(1), &[1.0]);
+
+        // Version 1 files have no activation field and still load, as ReLU nets
+        let mut bytes = std::fs::read(&path).unwrap();
+        let mut version_1 = bytes.clone();
+        version_1.drain(12..20);
+        version_1[4..8].copy_from_slice(&1u32.to_le_bytes());
+        let decoded = NativeWeights::from_bytes(&version_1).unwrap();
+        assert_eq!((decoded.config().activation, decoded.biases(1)), (Activation::Relu, &[1.0][..]));
+
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(NativeWeights::from_bytes(&bytes), Err(NNUEError::InvalidWeights(_))));
+    }
+
+    #[test]
+    fn test_shared_perspectives() {
+        let path = std::env::temp_dir().join("shallow_nnue_native_shared.bin");
+        // 768 -> 1 per perspective -> 1, reading own pawns on E4 (28) and the opponent's on E5 (6 * 64 + 27
+        // from white's side), which is an own pawn on E4 again from black's side
+        let mut first = vec![0.0; NUM_FEATURES];
+        first[28] = 1.0;
+        let network = vec![
+            LayerWeights { inputs: NUM_FEATURES, outputs: 1, weights: first, biases: vec![0.0] },
+            LayerWeights { inputs: 2, outputs: 1, weights: vec![10.0, -20.0], biases: vec![0.0] },
+        ];
+        save_network_with_perspectives(&path, &network, Activation::Relu, Perspectives::Shared).unwrap();
+
+        let weights = NativeWeights::load(&path).unwrap();
+        assert_eq!((weights.perspectives(), weights.accumulator_size()), (Perspectives::Shared, 2));
+        let mut nnue = NativeNNUE::new(weights);
+        let e4 = Board::default().make_move_new(ChessMove::new(Square::E2, Square::E4, None));
+        nnue.set_board_hard(e4).unwrap();
+        assert_eq!(nnue.evaluate().unwrap(), -20); // Black to move, white's e4 pawn is the opponent's
+        assert_eq!(nnue.forward(ChessMove::new(Square::D7, Square::D5, None)).unwrap(), -10); // Now also an own pawn on "E4"
+
+        // The quantized path shares the rows the same way
+        let mut quantized = NativeNNUE::quantized(NativeWeights::load(&path).unwrap()).unwrap();
+        quantized.set_board_hard(e4).unwrap();
+        assert_eq!(quantized.evaluate().unwrap(), -20);
+
+        // A shared transformer stores 768 rows, separate ones twice that
+        save_network_with_perspectives(&path, &network, Activation::Relu, Perspectives::Dual).unwrap();
+        assert!(matches!(NativeWeights::load(&path), Err(NNUEError::InvalidWeights(_))));
+    }
+
+    #[test]
+    fn test_accumulator_cache_matches_refresh() {
+        let path = std::env::temp_dir().join("shallow_nnue_native_cache.bin");
+        // Arbitrary weights, diffed float accumulators pick up rounding so they match a rebuild
+        // within TOLERANCE, which CACHE_REBUILD_DIFFS keeps from growing with history
+        const TOLERANCE: f32 = 1e-3;
+        let mut rng = XorShift::new(5);
+        let mut random = |len: usize| (0..len).map(|_| rng.next_f64() as f32 * 2.0 - 1.0).collect::<Vec<f32>>();
+        let network = vec![
+            LayerWeights { inputs: NUM_FEATURES, outputs: 4, weights: random(NUM_FEATURES * 4), biases: random(4) },
+            LayerWeights { inputs: 4, outputs: 1, weights: random(4), biases: random(1) },
+        ];
+        save_weights(&path, &network).unwrap();
+        let assert_close = |cached: &NativeNNUE, uncached: &NativeNNUE| {
+            let (Accumulator::Float(diffed), Accumulator::Float(rebuilt)) = (&cached.accumulator, &uncached.accumulator) else {
+                panic!("float networks keep float accumulators");
+            };
+            let drift = diffed.iter().zip(rebuilt).map(|(diffed, rebuilt)| (diffed - rebuilt).abs()).fold(0.0, f32::max);
+            assert!(drift <= TOLERANCE, "drift {} on {}", drift, cached.board);
+        };
+
+        let mut cached = NativeNNUE::load(&path).unwrap();
+        let mut uncached = NativeNNUE::load(&path).unwrap();
+        uncached.set_accumulator_cache(false);
+        let fens = [
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+            "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3",
+            "r1bqk2r/pppp1ppp/2n2n2/2b1p3/2B1P3/5N2/PPPP1PPP/RNBQ1RK1 b kq - 5 5",
+            "r1bq1rk1/pppp1ppp/2n2n2/2b1p3/2B1P3/5N2/PPPP1PPP/RNBQ1RK1 w - - 6 6",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r1bq1rk1/pppp1ppp/2n2n2/2b5/2B1P3/5N2/PPPP1PPP/RNBQ1RK1 w - - 0 7",
+        ];
+        for fen in fens {
+            let board = Board::from_str(fen).unwrap();
+            cached.set_board_hard(board).unwrap();
+            uncached.set_board_hard(board).unwrap();
+            assert_close(&cached, &uncached);
+            assert!((cached.evaluate().unwrap() - uncached.evaluate().unwrap()).abs() <= 1, "{}", fen);
+        }
+        // The last board came from the entry of the castled position before it
+        let last = cached.cache.as_ref().unwrap().entries.iter().flatten().find(|entry| entry.board == Board::from_str(fens[5]).unwrap());
+        assert_eq!(last.map(|entry| entry.diffs), Some(1));
+
+        // Many more refreshes on one key than CACHE_REBUILD_DIFFS, the drift stays bounded and a
+        // refresh from a warm entry allocates nothing
+        let boards = [Board::default(), Board::from_str(fens[1]).unwrap()];
+        for i in 0..3 * CACHE_REBUILD_DIFFS as usize {
+            let board = boards[i % 2];
+            let (_, allocations) = allocations_during(|| cached.set_board_hard(board).unwrap());
+            uncached.set_board_hard(board).unwrap();
+            assert_close(&cached, &uncached);
+            assert_eq!(allocations, 0);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+use tch::{nn, Tensor};
+
+use crate::error::NNUEError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Activation {
+    #[default]
+    Relu,
+    ClippedRelu, // Clamped to [0, 1], 127 in the int8 units of quantised NNUE nets
+    Screlu, // Squared clipped ReLU
+}
+
+impl Activation {
+    pub fn apply(self, value: f32) -> f32 {
+        match self {
+            Activation::Relu => value.max(0.0),
+            Activation::ClippedRelu => value.clamp(0.0, 1.0),
+            Activation::Screlu => value.clamp(0.0, 1.0).powi(2),
+        }
+    }
+
+    pub(crate) fn apply_tensor(self, xs: &Tensor) -> Tensor {
+        match self {
+            Activation::Relu => xs.relu(),
+            Activation::ClippedRelu => xs.clamp(0.0, 1.0),
+            Activation::Screlu => {
+                let clipped = xs.clamp(0.0, 1.0);
+                &clipped * &clipped
+            }
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        // As declared in weight files and by a TorchScript model's activation() method
+        match self {
+            Activation::Relu => "relu",
+            Activation::ClippedRelu => "crelu",
+            Activation::Screlu => "screlu",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Activation> {
+        match name.trim().to_lowercase().as_str() {
+            "relu" => Some(Activation::Relu),
+            "crelu" | "clipped_relu" | "clippedrelu" => Some(Activation::ClippedRelu),
+            "screlu" => Some(Activation::Screlu),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn id(self) -> u32 {
+        self as u32
+    }
+
+    pub(crate) fn from_id(id: u32) -> Option<Activation> {
+        [Activation::Relu, Activation::ClippedRelu, Activation::Screlu].into_iter().find(|activation| activation.id() == id)
+    }
+}
+
+// Shape of a shallow network: inputs -> hidden... -> outputs, with the activation between layers.
+// The first output head is the evaluation, any others are extra training targets.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub inputs: usize, // Width of the feature set
+    pub hidden: Vec<usize>,
+    pub activation: Activation,
+    pub outputs: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            inputs: 768,
+            hidden: vec![256],
+            activation: Activation::Relu,
+            outputs: 1,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn layer_sizes(&self) -> Vec<(usize, usize)> {
+        // (inputs, outputs) of every linear layer in order
+        let mut widths = vec![self.inputs];
+        widths.extend(&self.hidden);
+        widths.push(self.outputs);
+        widths.windows(2).map(|pair| (pair[0], pair[1])).collect()
+    }
+
+    pub fn check_layers(&self, sizes: &[(usize, usize)]) -> Result<(), NNUEError> {
+        if sizes != self.layer_sizes().as_slice() {
+            return Err(NNUEError::InvalidWeights(format!(
+                "layer sizes {:?} do not match the network config {:?}",
+                sizes,
+                self.layer_sizes()
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn build(&self, root: &nn::Path) -> nn::Sequential {
+        let mut model = nn::seq();
+        let sizes = self.layer_sizes();
+        for (layer, (inputs, outputs)) in sizes.iter().enumerate() {
+            model = model.add(nn::linear(root / format!("layer{}", layer), *inputs as i64, *outputs as i64, Default::default()));
+            if layer + 1 < sizes.len() {
+                let activation = self.activation;
+                model = model.add_fn(move |xs| activation.apply_tensor(xs));
+            }
+        }
+        model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_sizes() {
+        let config = NetworkConfig { hidden: vec![512, 32], outputs: 3, ..NetworkConfig::default() };
+        assert_eq!(config.layer_sizes(), vec![(768, 512), (512, 32), (32, 3)]);
+        assert!(config.check_layers(&[(768, 512), (512, 32), (32, 3)]).is_ok());
+        assert!(matches!(config.check_layers(&[(768, 256), (256, 1)]), Err(NNUEError::InvalidWeights(_))));
+
+        let linear = NetworkConfig { hidden: Vec::new(), ..NetworkConfig::default() };
+        assert_eq!(linear.layer_sizes(), vec![(768, 1)]);
+        assert_eq!(Activation::ClippedRelu.apply(1.5), 1.0);
+    }
+
+    #[test]
+    fn test_activation_names() {
+        assert_eq!(Activation::Screlu.apply(0.5), 0.25);
+        assert_eq!(Activation::Screlu.apply(-1.0), 0.0);
+        for activation in [Activation::Relu, Activation::ClippedRelu, Activation::Screlu] {
+            assert_eq!(Activation::from_name(activation.name()), Some(activation));
+            assert_eq!(Activation::from_id(activation.id()), Some(activation));
+        }
+        assert_eq!(Activation::from_name("gelu"), None);
+    }
+}
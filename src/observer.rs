@@ -0,0 +1,25 @@
+use std::fmt;
+use std::time::Duration;
+
+use chess::ChessMove;
+
+// What an evaluator did, for engines that feed eval telemetry into their own profiling or metrics.
+// The crate only reports events, counting, timing and exporting them is left to the observer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalEvent {
+    Refresh { features: usize }, // The whole encoding was rewritten, features is the number now set
+    Update { removed: usize, placed: usize }, // The encoding followed a board change incrementally
+    CacheHit, // A sync found the encoding already on the target position, nothing was rewritten
+    Forward { chess_move: ChessMove, score: i16, elapsed: Duration }, // Score as forward returns it
+    Evaluate { score: i16, elapsed: Duration }, // Score as evaluate returns it
+    BatchFlush { size: usize, elapsed: Duration }, // One batched model call of size positions
+}
+
+// Called synchronously from the evaluator, so it should be quick: push to a channel or bump counters
+pub(crate) struct EvalObserver(pub(crate) Box<dyn Fn(&EvalEvent) + Send>);
+
+impl fmt::Debug for EvalObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EvalObserver(..)")
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::NNUEError;
+use crate::native::LayerWeights;
+use crate::network::Activation;
+
+// ONNX export of the crate's linear networks, written as protobuf by hand since tch can't export ONNX.
+// The graph reads "features" [batch, 768] and writes "score" [batch, outputs]: one Gemm per layer,
+// with the activation after every layer but the last, the same network the trainer builds.
+const IR_VERSION: u64 = 7;
+const OPSET_VERSION: u64 = 13; // Clip takes its bounds as inputs from opset 11
+const FLOAT: u64 = 1; // TensorProto.DataType
+const ATTRIBUTE_INT: u64 = 2; // AttributeProto.AttributeType
+pub const INPUT_NAME: &str = "features";
+pub const OUTPUT_NAME: &str = "score";
+
+// Protobuf wire format, only the varint and length-delimited field types are needed
+fn varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn int_field(bytes: &mut Vec<u8>, field: u64, value: u64) {
+    varint(bytes, field << 3);
+    varint(bytes, value);
+}
+
+fn bytes_field(bytes: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(bytes, (field << 3) | 2);
+    varint(bytes, value.len() as u64);
+    bytes.extend_from_slice(value);
+}
+
+fn tensor(name: &str, dims: &[usize], values: &[f32]) -> Vec<u8> {
+    let mut proto = Vec::new();
+    for dim in dims {
+        int_field(&mut proto, 1, *dim as u64);
+    }
+    int_field(&mut proto, 2, FLOAT);
+    bytes_field(&mut proto, 8, name.as_bytes());
+    let raw: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+    bytes_field(&mut proto, 9, &raw);
+    proto
+}
+
+fn value_info(name: &str, width: usize) -> Vec<u8> {
+    // Float tensor of shape [batch, width], the batch dimension is symbolic
+    let mut batch = Vec::new();
+    bytes_field(&mut batch, 2, b"batch");
+    let mut columns = Vec::new();
+    int_field(&mut columns, 1, width as u64);
+    let mut shape = Vec::new();
+    bytes_field(&mut shape, 1, &batch);
+    bytes_field(&mut shape, 1, &columns);
+
+    let mut tensor_type = Vec::new();
+    int_field(&mut tensor_type, 1, FLOAT);
+    bytes_field(&mut tensor_type, 2, &shape);
+    let mut type_proto = Vec::new();
+    bytes_field(&mut type_proto, 1, &tensor_type);
+
+    let mut proto = Vec::new();
+    bytes_field(&mut proto, 1, name.as_bytes());
+    bytes_field(&mut proto, 2, &type_proto);
+    proto
+}
+
+fn node(op_type: &str, inputs: &[&str], output: &str, attributes: &[(&str, u64)]) -> Vec<u8> {
+    let mut proto = Vec::new();
+    for input in inputs {
+        bytes_field(&mut proto, 1, input.as_bytes());
+    }
+    bytes_field(&mut proto, 2, output.as_bytes());
+    bytes_field(&mut proto, 3, output.as_bytes()); // Nodes are named after their output
+    bytes_field(&mut proto, 4, op_type.as_bytes());
+    for (name, value) in attributes {
+        let mut attribute = Vec::new();
+        bytes_field(&mut attribute, 1, name.as_bytes());
+        int_field(&mut attribute, 3, *value);
+        int_field(&mut attribute, 20, ATTRIBUTE_INT);
+        bytes_field(&mut proto, 5, &attribute);
+    }
+    proto
+}
+
+pub fn onnx_bytes(layers: &[LayerWeights], activation: Activation) -> Result<Vec<u8>, NNUEError> {
+    // layers use the torch.nn.Linear layout ([outputs][inputs]) throughout, unlike native files
+    let (Some(first), Some(last)) = (layers.first(), layers.last()) else {
+        return Err(NNUEError::InvalidWeights("a network needs at least one layer".to_string()));
+    };
+    let mut graph = Vec::new();
+    let mut initializers = Vec::new();
+    let mut current = INPUT_NAME.to_string();
+    for (index, layer) in layers.iter().enumerate() {
+        if layer.weights.len() != layer.inputs * layer.outputs || layer.biases.len() != layer.outputs {
+            return Err(NNUEError::InvalidWeights(format!("layer{} data does not match its sizes", index)));
+        }
+        let (weight, bias) = (format!("layer{}.weight", index), format!("layer{}.bias", index));
+        initializers.push(tensor(&weight, &[layer.outputs, layer.inputs], &layer.weights));
+        initializers.push(tensor(&bias, &[layer.outputs], &layer.biases));
+
+        let output = if index + 1 == layers.len() { OUTPUT_NAME.to_string() } else { format!("linear{}", index) };
+        bytes_field(&mut graph, 1, &node("Gemm", &[&current, &weight, &bias], &output, &[("transB", 1)]));
+        current = output;
+        if index + 1 == layers.len() {
+            break;
+        }
+
+        let activated = format!("activation{}", index);
+        let activation_node = match activation {
+            Activation::Relu => node("Relu", &[&current], &activated, &[]),
+            Activation::ClippedRelu => node("Clip", &[&current, "zero", "one"], &activated, &[]),
+            Activation::Screlu => {
+                let clipped = format!("clipped{}", index);
+                bytes_field(&mut graph, 1, &node("Clip", &[&current, "zero", "one"], &clipped, &[]));
+                node("Mul", &[&clipped, &clipped], &activated, &[])
+            }
+        };
+        bytes_field(&mut graph, 1, &activation_node);
+        current = activated;
+    }
+
+    bytes_field(&mut graph, 2, b"shallow_nnue");
+    if activation != Activation::Relu {
+        initializers.push(tensor("zero", &[], &[0.0]));
+        initializers.push(tensor("one", &[], &[1.0]));
+    }
+    for initializer in &initializers {
+        bytes_field(&mut graph, 5, initializer);
+    }
+    bytes_field(&mut graph, 11, &value_info(INPUT_NAME, first.inputs));
+    bytes_field(&mut graph, 12, &value_info(OUTPUT_NAME, last.outputs));
+
+    let mut opset = Vec::new();
+    bytes_field(&mut opset, 1, b"");
+    int_field(&mut opset, 2, OPSET_VERSION);
+
+    let mut model = Vec::new();
+    int_field(&mut model, 1, IR_VERSION);
+    bytes_field(&mut model, 2, b"shallowNNUE");
+    bytes_field(&mut model, 7, &graph);
+    bytes_field(&mut model, 8, &opset);
+    Ok(model)
+}
+
+pub fn save_onnx<P: AsRef<Path>>(path: P, layers: &[LayerWeights], activation: Activation) -> Result<(), NNUEError> {
+    File::create(path)?.write_all(&onnx_bytes(layers, activation)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack.windows(needle.len()).filter(|window| *window == needle).count()
+    }
+
+    #[test]
+    fn test_onnx_graph() {
+        let mut encoded = Vec::new();
+        varint(&mut encoded, 300);
+        assert_eq!(encoded, vec![0xac, 0x02]);
+
+        let layers = vec![
+            LayerWeights { inputs: 768, outputs: 4, weights: vec![0.5; 768 * 4], biases: vec![0.0; 4] },
+            LayerWeights { inputs: 4, outputs: 1, weights: vec![1.0, 2.0, 3.0, 4.0], biases: vec![-1.0] },
+        ];
+        let model = onnx_bytes(&layers, Activation::Screlu).unwrap();
+        // ir_version 7, then the producer
+        assert_eq!(&model[..4], &[0x08, 0x07, 0x12, 11]);
+        assert_eq!((count(&model, b"Gemm"), count(&model, b"Clip"), count(&model, b"Mul")), (2, 1, 1));
+        // The last layer's weights are stored raw, little-endian
+        let raw: Vec<u8> = [1f32, 2.0, 3.0, 4.0].iter().flat_map(|value| value.to_le_bytes()).collect();
+        assert_eq!(count(&model, &raw), 1);
+
+        let relu = onnx_bytes(&layers, Activation::Relu).unwrap();
+        assert_eq!((count(&relu, b"Relu"), count(&relu, b"one")), (1, 0));
+        assert!(matches!(onnx_bytes(&[], Activation::Relu), Err(NNUEError::InvalidWeights(_))));
+    }
+}
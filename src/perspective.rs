@@ -0,0 +1,67 @@
+use chess::Color;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScorePerspective {
+    #[default]
+    SideToMove, // Positive scores are good for the side to move (negamax convention)
+    White,      // Positive scores are good for white
+}
+
+impl ScorePerspective {
+    pub fn from_side_to_move(self, score: i16, side_to_move: Color) -> i16 {
+        // Converts a score from the side to move perspective into this perspective
+        match self {
+            ScorePerspective::SideToMove => score,
+            ScorePerspective::White => to_white(score, side_to_move),
+        }
+    }
+
+    pub fn to_side_to_move(self, score: i16, side_to_move: Color) -> i16 {
+        // Converts a score in this perspective back to the side to move perspective
+        match self {
+            ScorePerspective::SideToMove => score,
+            ScorePerspective::White => from_white(score, side_to_move),
+        }
+    }
+
+    pub fn to_color(self, score: i16, side_to_move: Color, color: Color) -> i16 {
+        // Converts a score in this perspective, of a position with side_to_move to play, into color's view
+        from_white(to_white(self.to_side_to_move(score, side_to_move), side_to_move), color)
+    }
+}
+
+pub fn to_white(score: i16, side_to_move: Color) -> i16 {
+    match side_to_move {
+        Color::White => score,
+        Color::Black => score.saturating_neg(),
+    }
+}
+
+pub fn from_white(score: i16, side_to_move: Color) -> i16 {
+    // Negating is its own inverse
+    to_white(score, side_to_move)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perspective_round_trip() {
+        assert_eq!(ScorePerspective::White.from_side_to_move(50, Color::Black), -50);
+        assert_eq!(ScorePerspective::White.from_side_to_move(50, Color::White), 50);
+        assert_eq!(ScorePerspective::SideToMove.from_side_to_move(50, Color::Black), 50);
+
+        for colour in [Color::White, Color::Black] {
+            for perspective in [ScorePerspective::SideToMove, ScorePerspective::White] {
+                let score = perspective.from_side_to_move(-120, colour);
+                assert_eq!(perspective.to_side_to_move(score, colour), -120);
+            }
+        }
+        assert_eq!(to_white(i16::MIN, Color::Black), i16::MAX);
+
+        // +30 for white with black to move, in both conventions
+        assert_eq!(ScorePerspective::SideToMove.to_color(-30, Color::Black, Color::White), 30);
+        assert_eq!(ScorePerspective::White.to_color(30, Color::Black, Color::Black), -30);
+    }
+}
@@ -0,0 +1,406 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use chess::{Board, ChessMove};
+
+use crate::dataset::Sample;
+use crate::error::NNUEError;
+
+// Only the first few reasons for skipped games are kept, the count is always exact
+const MAX_REPORTED_ERRORS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PgnMove {
+    pub chess_move: ChessMove,
+    pub comment: Option<String>, // Comment directly after the move, outside variations
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PgnGame {
+    pub headers: Vec<(String, String)>,
+    pub start: Board,
+    pub moves: Vec<PgnMove>,
+    pub result: Option<f32>, // From white's side, None for unfinished games
+}
+
+impl PgnGame {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn samples(&self) -> Vec<Sample> {
+        // Every position before a move, labelled with the game result and the move played, scores are
+        // unknown here so they stay 0
+        let result = match self.result {
+            Some(result) => result,
+            None => return Vec::new(),
+        };
+
+        let mut board = self.start;
+        let mut samples = Vec::with_capacity(self.moves.len());
+        for pgn_move in self.moves.iter() {
+            samples.push(Sample { board, score: 0, result, best_move: Some(pgn_move.chess_move) });
+            board = board.make_move_new(pgn_move.chess_move);
+        }
+        samples
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PgnStats {
+    pub games: usize, // Games parsed successfully
+    pub skipped: usize, // Malformed games that were dropped
+    pub moves: usize, // Moves across the parsed games
+    pub errors: Vec<String>, // Why games were skipped, capped at MAX_REPORTED_ERRORS
+}
+
+fn parse_result(token: &str) -> Option<Option<f32>> {
+    // Some(result) for termination markers, None for anything else
+    match token {
+        "1-0" => Some(Some(1.0)),
+        "0-1" => Some(Some(0.0)),
+        "1/2-1/2" => Some(Some(0.5)),
+        "*" => Some(None),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_header(line: &str) -> Option<(String, String)> {
+    // [Name "Value"], escaped quotes in the value are kept as they are
+    let inner = line.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (name, value) = inner.split_once(char::is_whitespace)?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((name.to_string(), value.to_string()))
+}
+
+fn normalise_san(token: &str) -> String {
+    // The chess crate's SAN parser doesn't accept annotations, zeros for castling or "=" before promotions
+    let token = token.trim_end_matches(['!', '?', '+', '#']);
+    let token = match token {
+        "0-0" => "O-O",
+        "0-0-0" => "O-O-O",
+        _ => token,
+    };
+    token.replace('=', "")
+}
+
+fn strip_move_number(token: &str) -> &str {
+    // "12." "12..." and "12.e4" all lose their move number
+    let rest = token.trim_start_matches(|c: char| c.is_ascii_digit());
+    if rest.len() != token.len() && rest.starts_with('.') {
+        rest.trim_start_matches('.')
+    } else {
+        token
+    }
+}
+
+#[derive(Debug)]
+struct GameBuilder {
+    headers: Vec<(String, String)>,
+    start: Board,
+    board: Board,
+    moves: Vec<PgnMove>,
+    result: Option<f32>,
+    error: Option<String>, // Set on the first problem, the rest of the game is still consumed
+    in_movetext: bool,
+    comment: Option<String>, // Open brace comment, may span lines
+    variation_depth: usize,
+}
+
+impl GameBuilder {
+    fn new() -> GameBuilder {
+        GameBuilder {
+            headers: Vec::new(),
+            start: Board::default(),
+            board: Board::default(),
+            moves: Vec::new(),
+            result: None,
+            error: None,
+            in_movetext: false,
+            comment: None,
+            variation_depth: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.headers.is_empty() && self.moves.is_empty() && self.error.is_none()
+    }
+
+    fn fail(&mut self, reason: String) {
+        if self.error.is_none() {
+            self.error = Some(reason);
+        }
+    }
+
+    fn header(&mut self, line: &str) {
+        match parse_header(line) {
+            Some((name, value)) => {
+                if name == "FEN" {
+                    match Board::from_str(&value) {
+                        Ok(board) => {
+                            self.start = board;
+                            self.board = board;
+                        }
+                        Err(_) => self.fail(format!("bad FEN header {:?}", value)),
+                    }
+                }
+                if name == "Result" {
+                    self.result = parse_result(&value).flatten();
+                }
+                self.headers.push((name, value));
+            }
+            None => self.fail(format!("bad header {:?}", line.trim())),
+        }
+    }
+
+    fn attach_comment(&mut self, comment: String) {
+        if self.variation_depth > 0 {
+            return;
+        }
+        if let Some(last) = self.moves.last_mut() {
+            let comment = comment.trim();
+            last.comment = Some(match last.comment.take() {
+                Some(existing) => format!("{} {}", existing, comment),
+                None => comment.to_string(),
+            });
+        }
+    }
+
+    fn token(&mut self, token: &str) -> bool {
+        // Returns true when the token ends the game
+        if self.variation_depth > 0 {
+            return false;
+        }
+        if let Some(result) = parse_result(token) {
+            if result.is_some() {
+                self.result = result;
+            }
+            return true;
+        }
+        if token.starts_with('$') || token == "e.p." {
+            return false;
+        }
+
+        let san = strip_move_number(token);
+        if san.is_empty() || self.error.is_some() {
+            return false;
+        }
+        match ChessMove::from_san(&self.board, &normalise_san(san)) {
+            Ok(chess_move) => {
+                self.board = self.board.make_move_new(chess_move);
+                self.moves.push(PgnMove { chess_move, comment: None });
+            }
+            Err(_) => self.fail(format!("illegal or unreadable move {:?} at ply {}", san, self.moves.len() + 1)),
+        }
+        false
+    }
+
+    fn movetext(&mut self, line: &str) -> bool {
+        // Returns true when a result token ended the game on this line
+        self.in_movetext = true;
+        let mut chars = line.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if let Some(comment) = self.comment.as_mut() {
+                if c == '}' {
+                    let comment = self.comment.take().unwrap_or_default();
+                    self.attach_comment(comment);
+                } else {
+                    comment.push(c);
+                }
+                continue;
+            }
+
+            match c {
+                '{' => self.comment = Some(String::new()),
+                ';' => return false, // Rest of line comment
+                '(' => self.variation_depth += 1,
+                ')' => match self.variation_depth.checked_sub(1) {
+                    Some(depth) => self.variation_depth = depth,
+                    None => self.fail("unbalanced ')'".to_string()),
+                },
+                c if c.is_whitespace() => {}
+                _ => {
+                    let mut end = start + c.len_utf8();
+                    while let Some((index, next)) = chars.peek() {
+                        if next.is_whitespace() || "{}();".contains(*next) {
+                            break;
+                        }
+                        end = index + next.len_utf8();
+                        chars.next();
+                    }
+                    if self.token(&line[start..end]) {
+                        return true;
+                    }
+                }
+            }
+        }
+        if let Some(comment) = self.comment.as_mut() {
+            comment.push(' ');
+        }
+        false
+    }
+
+    fn finish(self) -> Result<PgnGame, String> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if self.comment.is_some() || self.variation_depth > 0 {
+            return Err("unterminated comment or variation".to_string());
+        }
+        Ok(PgnGame {
+            headers: self.headers,
+            start: self.start,
+            moves: self.moves,
+            result: self.result,
+        })
+    }
+}
+
+pub struct PgnReader<R: BufRead> {
+    reader: R,
+    pending: Option<String>, // Header line that started the next game
+    stats: PgnStats,
+    buffer: Vec<u8>,
+}
+
+impl PgnReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PgnReader<BufReader<File>>, NNUEError> {
+        Ok(PgnReader::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> PgnReader<R> {
+    pub fn new(reader: R) -> PgnReader<R> {
+        PgnReader {
+            reader,
+            pending: None,
+            stats: PgnStats::default(),
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn stats(&self) -> &PgnStats {
+        &self.stats
+    }
+
+    fn next_line(&mut self) -> Result<Option<String>, NNUEError> {
+        if let Some(line) = self.pending.take() {
+            return Ok(Some(line));
+        }
+        // Read bytes so a stray invalid UTF-8 sequence only spoils its own game
+        self.buffer.clear();
+        if self.reader.read_until(b'\n', &mut self.buffer)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&self.buffer).into_owned()))
+    }
+
+    fn read_game(&mut self) -> Result<Option<GameBuilder>, NNUEError> {
+        let mut game = GameBuilder::new();
+        while let Some(line) = self.next_line()? {
+            let trimmed = line.trim();
+            if game.comment.is_none() && trimmed.starts_with('[') {
+                if game.in_movetext {
+                    // A new game started without the last one giving a result
+                    self.pending = Some(line);
+                    return Ok(Some(game));
+                }
+                game.header(trimmed);
+            } else if trimmed.starts_with('%') || (trimmed.is_empty() && game.comment.is_none()) {
+                continue;
+            } else if game.movetext(line.trim_end()) {
+                return Ok(Some(game));
+            }
+        }
+        Ok(if game.is_empty() { None } else { Some(game) })
+    }
+}
+
+impl<R: BufRead> Iterator for PgnReader<R> {
+    type Item = Result<PgnGame, NNUEError>;
+
+    fn next(&mut self) -> Option<Result<PgnGame, NNUEError>> {
+        // Malformed games are counted in the stats and skipped, only IO errors are returned
+        loop {
+            let game = match self.read_game() {
+                Ok(Some(game)) => game,
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+            match game.finish() {
+                Ok(game) => {
+                    self.stats.games += 1;
+                    self.stats.moves += game.moves.len();
+                    return Some(Ok(game));
+                }
+                Err(reason) => {
+                    self.stats.skipped += 1;
+                    if self.stats.errors.len() < MAX_REPORTED_ERRORS {
+                        self.stats.errors.push(reason);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn read_pgn_samples<P: AsRef<Path>>(path: P) -> Result<(Vec<Sample>, PgnStats), NNUEError> {
+    // Result labelled samples for every finished game in the file, with the file's parse statistics
+    let mut reader = PgnReader::open(path)?;
+    let mut samples = Vec::new();
+    for game in reader.by_ref() {
+        samples.extend(game?.samples());
+    }
+    Ok((samples, reader.stats().clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chess::Square;
+
+    use super::*;
+
+    const GAMES: &str = r#"[Event "First"]
+[Result "1-0"]
+
+1. e4 $1 e5 {King's pawn, a comment
+over two lines} 2. Nf3 (2. f4 exf4 (2... d5) 3. Nf3) Nc6; line comment
+3. Bc4 Nf6?! 4. O-O Bc5 1-0
+
+[Event "Broken"]
+[Result "0-1"]
+
+1. e4 e5 2. Ke3 Nc6 0-1
+
+[Event "Third"]
+[FEN "4k3/P7/8/8/8/8/8/4K3 w - - 0 1"]
+[Result "1/2-1/2"]
+
+1. a8=Q+ Kd7 1/2-1/2
+"#;
+
+    #[test]
+    fn test_pgn_reader_skips_malformed_games() {
+        let mut reader = PgnReader::new(GAMES.as_bytes());
+        let games: Vec<PgnGame> = reader.by_ref().map(|game| game.unwrap()).collect();
+
+        assert_eq!(games.len(), 2);
+        assert_eq!(reader.stats().skipped, 1);
+        assert_eq!(reader.stats().moves, 10);
+        assert!(reader.stats().errors[0].contains("Ke3"));
+
+        let first = &games[0];
+        assert_eq!(first.header("Event"), Some("First"));
+        assert_eq!(first.result, Some(1.0));
+        assert_eq!(first.moves[1].comment.as_deref(), Some("King's pawn, a comment over two lines"));
+        assert_eq!(first.moves[2].chess_move, ChessMove::new(Square::G1, Square::F3, None));
+        assert_eq!(first.moves[6].chess_move, ChessMove::new(Square::E1, Square::G1, None));
+
+        let third = &games[1];
+        assert_eq!(third.moves[0].chess_move, ChessMove::new(Square::A7, Square::A8, Some(chess::Piece::Queen)));
+        assert_eq!(third.samples().len(), 2);
+        assert_eq!(third.samples()[0].result, 0.5);
+    }
+}
@@ -0,0 +1,101 @@
+use chess::{Board, CastleRights, Color, Piece};
+
+use crate::error::NNUEError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GamePhase {
+    Opening,
+    Middlegame,
+    Endgame,
+}
+
+impl GamePhase {
+    pub fn of(board: &Board) -> GamePhase {
+        // Kept for callers from before the phase module, same as phase()
+        phase(board)
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
+
+pub fn phase_material(board: &Board) -> u32 {
+    // Non-pawn material of both sides, minors count 1, rooks 2 and queens 4, so the start position is 24
+    let count = |piece: Piece| board.pieces(piece).popcnt();
+    count(Piece::Knight) + count(Piece::Bishop) + 2 * count(Piece::Rook) + 4 * count(Piece::Queen)
+}
+
+pub fn phase(board: &Board) -> GamePhase {
+    // The opening lasts while nearly every piece is on and a king can still castle, so it ends once
+    // both kings castled or gave up the right. A rook and a minor each or less is an endgame.
+    let can_castle = [Color::White, Color::Black].iter().any(|colour| board.castle_rights(*colour) != CastleRights::NoRights);
+    match phase_material(board) {
+        0..=8 => GamePhase::Endgame,
+        20.. if can_castle => GamePhase::Opening,
+        _ => GamePhase::Middlegame,
+    }
+}
+
+// Scores multiplied by a factor per phase, for networks that are over or under confident in some
+// part of the game, shallow ones tend to overrate material in endgames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhaseScale {
+    pub opening: f32,
+    pub middlegame: f32,
+    pub endgame: f32,
+}
+
+impl Default for PhaseScale {
+    fn default() -> PhaseScale {
+        PhaseScale { opening: 1.0, middlegame: 1.0, endgame: 1.0 }
+    }
+}
+
+impl PhaseScale {
+    pub fn validate(&self) -> Result<(), NNUEError> {
+        for factor in [self.opening, self.middlegame, self.endgame] {
+            if !(factor.is_finite() && factor >= 0.0) {
+                return Err(NNUEError::InvalidConfig(format!("phase scale factors must be finite and not negative, got {}", factor)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn factor(&self, phase: GamePhase) -> f32 {
+        match phase {
+            GamePhase::Opening => self.opening,
+            GamePhase::Middlegame => self.middlegame,
+            GamePhase::Endgame => self.endgame,
+        }
+    }
+
+    pub fn apply(&self, board: &Board, score: i16) -> i16 {
+        let factor = self.factor(phase(board));
+        (score as f32 * factor).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_phase() {
+        let fen = |fen: &str| Board::from_str(fen).unwrap();
+        assert_eq!(phase(&Board::default()), GamePhase::Opening);
+        // Both sides castled with every piece still on
+        let castled = fen("r1bq1rk1/pppp1ppp/2n2n2/2b1p3/2B1P3/2N2N2/PPPP1PPP/R1BQ1RK1 w - - 6 5");
+        assert_eq!(phase(&castled), GamePhase::Middlegame);
+        assert_eq!(crate::pipeline::GamePhase::of(&castled), phase(&castled));
+        assert_eq!(phase(&fen("r3k2r/pppq1ppp/8/8/8/8/PPPQ1PPP/R3K2R w KQkq - 0 1")), GamePhase::Middlegame);
+        assert_eq!(phase(&fen("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1")), GamePhase::Endgame);
+
+        let scale = PhaseScale { endgame: 0.5, ..PhaseScale::default() };
+        assert_eq!(scale.apply(&fen("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1"), 301), 151);
+        assert_eq!(scale.apply(&castled, 301), 301);
+        assert!(PhaseScale { opening: f32::NAN, ..PhaseScale::default() }.validate().is_err());
+    }
+}
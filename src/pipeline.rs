@@ -0,0 +1,309 @@
+use chess::{Board, MoveGen, EMPTY};
+use fnv::FnvHashSet;
+
+use crate::augment::mirrored;
+use crate::dataset::Sample;
+use crate::features::FeatureSet;
+use crate::phase::phase;
+use crate::quiet::is_quiet;
+use crate::rng::XorShift;
+use crate::search::{captured_piece, piece_value};
+
+// A stage sees the samples of one game at a time and returns the ones it keeps.
+// Stages that need the whole dataset hold samples back and release them from finish.
+pub trait Stage {
+    fn process(&mut self, samples: Vec<Sample>) -> Vec<Sample>;
+    fn finish(&mut self) -> Vec<Sample> {
+        Vec::new()
+    }
+}
+
+pub use crate::phase::GamePhase; // Lived here before phase was its own module
+
+pub(crate) fn has_winning_capture(board: &Board) -> bool {
+    // Static noise check, a capture of something worth more than the capturer means the label is unstable
+    MoveGen::new_legal(board).any(|chess_move| match captured_piece(board, chess_move) {
+        Some(victim) => board.piece_on(chess_move.get_source()).map_or(0, piece_value) < piece_value(victim),
+        None => false,
+    })
+}
+
+pub struct SkipInCheck;
+
+impl Stage for SkipInCheck {
+    fn process(&mut self, samples: Vec<Sample>) -> Vec<Sample> {
+        samples.into_iter().filter(|sample| *sample.board.checkers() == EMPTY).collect()
+    }
+}
+
+pub struct SkipNoisy;
+
+impl Stage for SkipNoisy {
+    fn process(&mut self, samples: Vec<Sample>) -> Vec<Sample> {
+        samples.into_iter().filter(|sample| !has_winning_capture(&sample.board)).collect()
+    }
+}
+
+pub struct SkipTactical;
+
+impl Stage for SkipTactical {
+    fn process(&mut self, samples: Vec<Sample>) -> Vec<Sample> {
+        // Stricter than SkipNoisy, exchanges are worked out and threats against the side to move count
+        samples.into_iter().filter(|sample| is_quiet(&sample.board)).collect()
+    }
+}
+
+pub struct SkipExtremeScores {
+    pub max_abs_score: i16, // Near-mate and won positions say little about the eval
+}
+
+impl Stage for SkipExtremeScores {
+    fn process(&mut self, samples: Vec<Sample>) -> Vec<Sample> {
+        let max = self.max_abs_score as i32;
+        samples.into_iter().filter(|sample| (sample.score as i32).abs() <= max).collect()
+    }
+}
+
+pub struct CapPerGame {
+    max_samples: usize,
+    rng: XorShift,
+}
+
+impl CapPerGame {
+    pub fn new(max_samples: usize, seed: u64) -> CapPerGame {
+        CapPerGame {
+            max_samples,
+            rng: XorShift::new(seed),
+        }
+    }
+}
+
+impl Stage for CapPerGame {
+    fn process(&mut self, mut samples: Vec<Sample>) -> Vec<Sample> {
+        // Random subset, so long games don't dominate and no part of the game is favoured
+        for i in 0..samples.len().min(self.max_samples) {
+            let j = i + self.rng.below(samples.len() - i);
+            samples.swap(i, j);
+        }
+        samples.truncate(self.max_samples);
+        samples
+    }
+}
+
+#[derive(Default)]
+pub struct Deduplicate {
+    seen: FnvHashSet<u64>,
+}
+
+impl Stage for Deduplicate {
+    fn process(&mut self, samples: Vec<Sample>) -> Vec<Sample> {
+        // Keeps the first sample for each Zobrist key across the whole run
+        samples.into_iter().filter(|sample| self.seen.insert(sample.board.get_hash())).collect()
+    }
+}
+
+pub struct Mirror {
+    pub features: FeatureSet, // The encoding the samples are trained with, see augment::mirrored
+}
+
+impl Stage for Mirror {
+    fn process(&mut self, samples: Vec<Sample>) -> Vec<Sample> {
+        // Each sample followed by its mirror image, up to twice the samples
+        let mut augmented = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            augmented.push(sample);
+            augmented.extend(mirrored(&sample, &self.features));
+        }
+        augmented
+    }
+}
+
+pub struct StratifyByPhase {
+    per_phase: usize,
+    reservoirs: [Vec<Sample>; 3],
+    seen: [usize; 3],
+    rng: XorShift,
+}
+
+impl StratifyByPhase {
+    pub fn new(per_phase: usize, seed: u64) -> StratifyByPhase {
+        StratifyByPhase {
+            per_phase,
+            reservoirs: [Vec::new(), Vec::new(), Vec::new()],
+            seen: [0; 3],
+            rng: XorShift::new(seed),
+        }
+    }
+}
+
+impl Stage for StratifyByPhase {
+    fn process(&mut self, samples: Vec<Sample>) -> Vec<Sample> {
+        // Reservoir sample per phase, nothing is released until finish
+        for sample in samples {
+            let phase = phase(&sample.board).index();
+            self.seen[phase] += 1;
+            if self.reservoirs[phase].len() < self.per_phase {
+                self.reservoirs[phase].push(sample);
+            } else {
+                let slot = self.rng.below(self.seen[phase]);
+                if slot < self.per_phase {
+                    self.reservoirs[phase][slot] = sample;
+                }
+            }
+        }
+        Vec::new()
+    }
+
+    fn finish(&mut self) -> Vec<Sample> {
+        self.seen = [0; 3];
+        self.reservoirs.iter_mut().flat_map(std::mem::take).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineOptions {
+    pub skip_in_check: bool,
+    pub skip_noisy: bool,
+    pub skip_tactical: bool, // Keep only positions quiet::classify calls quiet
+    pub max_abs_score: Option<i16>,
+    pub max_per_game: Option<usize>,
+    pub deduplicate: bool,
+    pub mirror: bool, // Add the mirror image of every kept sample for the trainer's encoding
+    pub features: FeatureSet, // That encoding, it decides which mirror image is new to the network
+    pub per_phase: Option<usize>, // Samples kept for each game phase
+    pub seed: u64,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> PipelineOptions {
+        PipelineOptions {
+            skip_in_check: true,
+            skip_noisy: true,
+            skip_tactical: false,
+            max_abs_score: Some(3000),
+            max_per_game: None,
+            deduplicate: true,
+            mirror: false,
+            features: FeatureSet::default(),
+            per_phase: None,
+            seed: 0x5eed,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new(options: &PipelineOptions) -> Pipeline {
+        // Cheap per-position filters run first so the stateful stages see less data
+        let mut pipeline = Pipeline::default();
+        if options.skip_in_check {
+            pipeline = pipeline.stage(SkipInCheck);
+        }
+        if let Some(max_abs_score) = options.max_abs_score {
+            pipeline = pipeline.stage(SkipExtremeScores { max_abs_score });
+        }
+        if options.skip_noisy {
+            pipeline = pipeline.stage(SkipNoisy);
+        }
+        if options.skip_tactical {
+            pipeline = pipeline.stage(SkipTactical);
+        }
+        if options.deduplicate {
+            pipeline = pipeline.stage(Deduplicate::default());
+        }
+        if let Some(max_samples) = options.max_per_game {
+            pipeline = pipeline.stage(CapPerGame::new(max_samples, options.seed));
+        }
+        if options.mirror {
+            pipeline = pipeline.stage(Mirror { features: options.features.clone() });
+        }
+        if let Some(per_phase) = options.per_phase {
+            pipeline = pipeline.stage(StratifyByPhase::new(per_phase, options.seed));
+        }
+        pipeline
+    }
+
+    pub fn stage<S: Stage + 'static>(mut self, stage: S) -> Pipeline {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    pub fn process_game(&mut self, samples: Vec<Sample>) -> Vec<Sample> {
+        self.stages.iter_mut().fold(samples, |samples, stage| stage.process(samples))
+    }
+
+    pub fn finish(&mut self) -> Vec<Sample> {
+        // Each stage's held back samples still go through the stages after it
+        let mut released = Vec::new();
+        for index in 0..self.stages.len() {
+            let mut samples = self.stages[index].finish();
+            for stage in self.stages[index + 1..].iter_mut() {
+                samples = stage.process(samples);
+            }
+            released.extend(samples);
+        }
+        released
+    }
+
+    pub fn run<I: IntoIterator<Item = Vec<Sample>>>(&mut self, games: I) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        for game in games {
+            samples.extend(self.process_game(game));
+        }
+        samples.extend(self.finish());
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn sample(fen: &str, score: i16) -> Sample {
+        Sample { board: Board::from_str(fen).unwrap(), score, result: 0.5, best_move: None }
+    }
+
+    #[test]
+    fn test_pipeline_filters_and_deduplicates() {
+        let quiet = sample("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", 50);
+        let in_check = sample("4k3/8/8/8/8/8/4r3/4K3 w - - 0 1", -500);
+        let hanging_queen = sample("4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1", -800);
+        let mating = sample("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", 9990);
+
+        let options = PipelineOptions { max_per_game: Some(2), ..PipelineOptions::default() };
+        let mut pipeline = Pipeline::new(&options);
+        let kept = pipeline.run(vec![vec![quiet, in_check, hanging_queen, mating], vec![quiet]]);
+        assert_eq!(kept, vec![quiet]);
+
+        // A knight attacked by a pawn passes SkipNoisy but not SkipTactical
+        let threatened = sample("4k3/8/8/8/1p6/2N5/8/4K3 w - - 0 1", 0);
+        let options = PipelineOptions { skip_tactical: true, ..PipelineOptions::default() };
+        assert_eq!(Pipeline::new(&options).run(vec![vec![quiet, threatened]]), vec![quiet]);
+        assert_eq!(Pipeline::new(&PipelineOptions::default()).run(vec![vec![quiet, threatened]]), vec![quiet, threatened]);
+    }
+
+    #[test]
+    fn test_stratify_by_phase() {
+        let opening = sample("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0);
+        let endgame = sample("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", 0);
+        assert_eq!(phase(&opening.board), GamePhase::Opening);
+
+        let mut pipeline = Pipeline::default().stage(StratifyByPhase::new(1, 7));
+        let kept = pipeline.run(vec![vec![opening; 5], vec![endgame; 3]]);
+        assert_eq!(kept, vec![opening, endgame]);
+
+        // Mirror images come after their originals
+        let options = PipelineOptions { mirror: true, ..PipelineOptions::default() };
+        let kept = Pipeline::new(&options).run(vec![vec![endgame]]);
+        assert_eq!(kept, vec![endgame, sample("4k3/4p3/8/8/8/8/8/4K3 b - - 0 1", 0)]);
+        let absolute = PipelineOptions { features: FeatureSet::from_name("absolute768").unwrap(), ..options };
+        let kept = Pipeline::new(&absolute).run(vec![vec![endgame]]);
+        assert_eq!(kept, vec![endgame, sample("3k4/8/8/8/8/8/3P4/3K4 w - - 0 1", 0)]);
+    }
+}
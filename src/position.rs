@@ -0,0 +1,124 @@
+use chess::{BoardBuilder, ALL_SQUARES};
+
+use crate::bit_move::{active_indices, get_index, orient};
+use crate::error::NNUEError;
+
+// The chess crate's types appear throughout this crate's API. Naming them through this module, or
+// through the re-exported crate, keeps downstream code on the exact version the evaluator was built
+// with, and leaves one place to change if the board library is ever swapped or a second one supported.
+pub use chess;
+pub use chess::{Board, CastleRights, ChessMove, Color, File, Game, Piece, Square};
+
+// A position in another board library, converted by piece placement rather than through FEN. Only
+// side_to_move and piece_on are required, the defaults of the rest suit positions where castling and
+// en passant don't matter, e.g. evaluating a leaf.
+pub trait BoardAdapter {
+    fn side_to_move(&self) -> Color;
+    fn piece_on(&self, square: Square) -> Option<(Piece, Color)>;
+
+    fn castle_rights(&self, _color: Color) -> CastleRights {
+        CastleRights::NoRights
+    }
+
+    fn en_passant(&self) -> Option<File> {
+        // File of a pawn that just moved two squares
+        None
+    }
+
+    fn to_board(&self) -> Result<Board, NNUEError> {
+        // The chess crate panics on boards without both kings instead of rejecting them
+        let mut builder = BoardBuilder::new();
+        let mut kings = [0; 2];
+        for square in ALL_SQUARES {
+            if let Some((piece, color)) = self.piece_on(square) {
+                builder.piece(square, piece, color);
+                if piece == Piece::King {
+                    kings[color.to_index()] += 1;
+                }
+            }
+        }
+        if kings != [1, 1] {
+            return Err(NNUEError::InvalidData("the adapted position needs one king a side".to_string()));
+        }
+        builder
+            .side_to_move(self.side_to_move())
+            .castle_rights(Color::White, self.castle_rights(Color::White))
+            .castle_rights(Color::Black, self.castle_rights(Color::Black))
+            .en_passant(self.en_passant());
+        Board::try_from(&builder).map_err(|_| NNUEError::InvalidData("the adapted position is not a legal board".to_string()))
+    }
+
+    fn active_features(&self) -> Vec<u16> {
+        // The crate's 768 features from the side to move, in square order, without building a Board
+        let turn = self.side_to_move();
+        ALL_SQUARES
+            .iter()
+            .filter_map(|square| self.piece_on(*square).map(|(piece, color)| get_index(piece, color == turn, orient(*square, turn))))
+            .collect()
+    }
+}
+
+impl BoardAdapter for Board {
+    fn side_to_move(&self) -> Color {
+        Board::side_to_move(self)
+    }
+
+    fn piece_on(&self, square: Square) -> Option<(Piece, Color)> {
+        Some((Board::piece_on(self, square)?, self.color_on(square)?))
+    }
+
+    fn castle_rights(&self, color: Color) -> CastleRights {
+        Board::castle_rights(self, color)
+    }
+
+    fn en_passant(&self) -> Option<File> {
+        Board::en_passant(*self).map(|square| square.get_file())
+    }
+
+    fn to_board(&self) -> Result<Board, NNUEError> {
+        Ok(*self)
+    }
+
+    fn active_features(&self) -> Vec<u16> {
+        active_indices(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    // Mailbox board, the shape of many engines' own representations
+    struct Mailbox {
+        squares: [Option<(Piece, Color)>; 64],
+        turn: Color,
+    }
+
+    impl BoardAdapter for Mailbox {
+        fn side_to_move(&self) -> Color {
+            self.turn
+        }
+
+        fn piece_on(&self, square: Square) -> Option<(Piece, Color)> {
+            self.squares[square.to_index()]
+        }
+    }
+
+    #[test]
+    fn test_adapter_matches_board() {
+        let board = Board::from_str("4k3/8/8/3q4/4P3/8/8/4K3 b - - 0 1").unwrap();
+        let mut squares = [None; 64];
+        for square in ALL_SQUARES {
+            squares[square.to_index()] = BoardAdapter::piece_on(&board, square);
+        }
+        let mailbox = Mailbox { squares, turn: Color::Black };
+        assert_eq!(mailbox.to_board().unwrap(), board);
+        assert_eq!(mailbox.active_features(), active_indices(&board));
+        assert_eq!(board.to_board().unwrap(), board);
+
+        let kingless = Mailbox { squares: [None; 64], turn: Color::White };
+        assert!(matches!(kingless.to_board(), Err(NNUEError::InvalidData(_))));
+    }
+}
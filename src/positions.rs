@@ -0,0 +1,212 @@
+use std::str::FromStr;
+
+use chess::{Board, Color, Piece, Square, ALL_SQUARES, EMPTY};
+
+use crate::error::NNUEError;
+use crate::features::FeatureSet;
+
+// Strict FEN checking for positions people type or paste, so a UI can point at what is wrong before
+// anything reaches the evaluator. The chess crate's parser accepts castling rights without their
+// rooks and en passant squares no pawn could have passed, and says nothing about where it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidPosition {
+    pub board: Board,
+    pub halfmove_clock: u32,
+    pub fullmove_number: u32,
+}
+
+impl ValidPosition {
+    pub fn active_features(&self, features: &FeatureSet) -> Vec<u16> {
+        // The model inputs the evaluator would set for this position, in ascending order
+        let mut active = features.active(&self.board);
+        active.sort_unstable();
+        active
+    }
+}
+
+fn invalid(reason: String) -> NNUEError {
+    NNUEError::InvalidData(format!("invalid fen: {}", reason))
+}
+
+fn piece_from_char(symbol: char) -> Option<(Piece, Color)> {
+    let piece = match symbol.to_ascii_lowercase() {
+        'p' => Piece::Pawn,
+        'n' => Piece::Knight,
+        'b' => Piece::Bishop,
+        'r' => Piece::Rook,
+        'q' => Piece::Queen,
+        'k' => Piece::King,
+        _ => return None,
+    };
+    Some((piece, if symbol.is_ascii_uppercase() { Color::White } else { Color::Black }))
+}
+
+fn parse_placement(placement: &str) -> Result<[Option<(Piece, Color)>; 64], NNUEError> {
+    // Ranks from the eighth down, each exactly eight squares
+    let ranks: Vec<&str> = placement.split('/').collect();
+    if ranks.len() != 8 {
+        return Err(invalid(format!("the placement has {} ranks, expected 8", ranks.len())));
+    }
+    let mut squares = [None; 64];
+    for (row, rank) in ranks.iter().enumerate() {
+        let rank_number = 8 - row;
+        let mut file = 0;
+        for symbol in rank.chars() {
+            match (symbol.to_digit(10), piece_from_char(symbol)) {
+                (Some(empty @ 1..=8), _) => file += empty as usize,
+                (_, Some(piece)) => {
+                    if file < 8 {
+                        squares[(rank_number - 1) * 8 + file] = Some(piece);
+                    }
+                    file += 1;
+                }
+                _ => return Err(invalid(format!("unexpected {:?} on rank {}", symbol, rank_number))),
+            }
+        }
+        if file != 8 {
+            return Err(invalid(format!("rank {} has {} squares, expected 8", rank_number, file)));
+        }
+    }
+    Ok(squares)
+}
+
+fn check_material(squares: &[Option<(Piece, Color)>; 64]) -> Result<(), NNUEError> {
+    for colour in [Color::White, Color::Black] {
+        let count = |piece: Piece| squares.iter().filter(|square| **square == Some((piece, colour))).count();
+        let name = if colour == Color::White { "white" } else { "black" };
+        if count(Piece::King) != 1 {
+            return Err(invalid(format!("{} has {} kings, expected 1", name, count(Piece::King))));
+        }
+        if count(Piece::Pawn) > 8 {
+            return Err(invalid(format!("{} has {} pawns, at most 8 are possible", name, count(Piece::Pawn))));
+        }
+        let pieces = squares.iter().filter(|square| matches!(square, Some((_, owner)) if *owner == colour)).count();
+        if pieces > 16 {
+            return Err(invalid(format!("{} has {} pieces, at most 16 are possible", name, pieces)));
+        }
+    }
+    // Pawns promote on the last rank and never stand on their first
+    for (index, square) in squares.iter().enumerate() {
+        if matches!(square, Some((Piece::Pawn, _))) && !(8..56).contains(&index) {
+            return Err(invalid(format!("pawn on {}, pawns can't be on the first or last rank", ALL_SQUARES[index])));
+        }
+    }
+    Ok(())
+}
+
+fn check_castling(rights: &str, squares: &[Option<(Piece, Color)>; 64]) -> Result<(), NNUEError> {
+    // KQkq in that order, each with its king and rook still at home
+    if rights == "-" {
+        return Ok(());
+    }
+    let order = "KQkq";
+    let mut last = None;
+    for symbol in rights.chars() {
+        let position = order.find(symbol).ok_or_else(|| invalid(format!("unknown castling right {:?}", symbol)))?;
+        if last.is_some_and(|last| position <= last) {
+            return Err(invalid(format!("castling rights {:?} are repeated or out of KQkq order", rights)));
+        }
+        last = Some(position);
+        let (colour, king, rook) = match symbol {
+            'K' => (Color::White, Square::E1, Square::H1),
+            'Q' => (Color::White, Square::E1, Square::A1),
+            'k' => (Color::Black, Square::E8, Square::H8),
+            _ => (Color::Black, Square::E8, Square::A8),
+        };
+        if squares[king.to_index()] != Some((Piece::King, colour)) || squares[rook.to_index()] != Some((Piece::Rook, colour)) {
+            return Err(invalid(format!("castling right {} needs the king on {} and a rook on {}", symbol, king, rook)));
+        }
+    }
+    Ok(())
+}
+
+fn check_en_passant(field: &str, turn: Color, squares: &[Option<(Piece, Color)>; 64]) -> Result<(), NNUEError> {
+    // The square a pawn of the side that just moved passed over, with that pawn right in front of it
+    if field == "-" {
+        return Ok(());
+    }
+    let square = Square::from_str(field).map_err(|_| invalid(format!("bad en passant square {:?}", field)))?;
+    let (rank, forward, mover) = match turn {
+        Color::White => (5, 8, Color::Black), // Sixth rank, the black pawn is one rank lower
+        Color::Black => (2, -8, Color::White),
+    };
+    let index = square.to_index() as i32;
+    let pawn = index - forward;
+    let origin = index + forward;
+    if square.get_rank().to_index() != rank
+        || squares[pawn as usize] != Some((Piece::Pawn, mover))
+        || squares[index as usize].is_some()
+        || squares[origin as usize].is_some()
+    {
+        return Err(invalid(format!("en passant square {} doesn't follow a pawn's double step", square)));
+    }
+    Ok(())
+}
+
+pub fn validate_fen(fen: &str) -> Result<ValidPosition, NNUEError> {
+    // All six fields, or the first four with the clocks defaulting to 0 and 1
+    let fields: Vec<&str> = fen.split_whitespace().collect();
+    if fields.len() != 6 && fields.len() != 4 {
+        return Err(invalid(format!("{} fields, expected 6 (or 4 without the clocks)", fields.len())));
+    }
+    let squares = parse_placement(fields[0])?;
+    check_material(&squares)?;
+    let turn = match fields[1] {
+        "w" => Color::White,
+        "b" => Color::Black,
+        other => return Err(invalid(format!("side to move {:?}, expected w or b", other))),
+    };
+    check_castling(fields[2], &squares)?;
+    check_en_passant(fields[3], turn, &squares)?;
+    let number = |at: usize, name: &str| -> Result<u32, NNUEError> {
+        match fields.get(at) {
+            Some(value) => value.parse::<u32>().map_err(|_| invalid(format!("bad {} {:?}", name, value))),
+            None => Ok(if at == 4 { 0 } else { 1 }),
+        }
+    };
+    let halfmove_clock = number(4, "halfmove clock")?;
+    let fullmove_number = number(5, "fullmove number")?;
+    if fullmove_number == 0 {
+        return Err(invalid("the fullmove number starts at 1".to_string()));
+    }
+
+    // The side not to move can't be in check, its king would be captured. Checked on the board with
+    // the other side to move, as the chess crate refuses the position itself without saying why.
+    let other = if turn == Color::White { "b" } else { "w" };
+    if Board::from_str(&format!("{} {} - -", fields[0], other)).is_ok_and(|flipped| *flipped.checkers() != EMPTY) {
+        return Err(invalid("the side not to move is in check".to_string()));
+    }
+    let board = Board::from_str(&fields[..4].join(" ")).map_err(|_| invalid("the chess crate rejected the position".to_string()))?;
+    Ok(ValidPosition { board, halfmove_clock, fullmove_number })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_move::active_indices;
+
+    #[test]
+    fn test_validate_fen() {
+        let start = validate_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+        assert_eq!(start.board, Board::default());
+        let mut expected = active_indices(&Board::default());
+        expected.sort_unstable();
+        assert_eq!(start.active_features(&FeatureSet::default()), expected);
+        let after = validate_fen("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6").unwrap();
+        assert_eq!((after.halfmove_clock, after.fullmove_number), (0, 1));
+
+        let error = |fen: &str| match validate_fen(fen) {
+            Err(NNUEError::InvalidData(reason)) => reason,
+            other => panic!("{} was accepted: {:?}", fen, other),
+        };
+        assert!(error("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBN1 w KQkq - 0 1").contains("castling right K"));
+        assert!(error("4k3/8/8/8/8/8/8/4K2R w KK - 0 1").contains("out of KQkq order"));
+        assert!(error("4k3/8/8/8/8/8/8/P3K3 w - - 0 1").contains("pawn on a1"));
+        assert!(error("4k3/8/8/8/8/8/8/4R1K1 w - - 0 1").contains("side not to move is in check"));
+        assert!(validate_fen("4k3/8/8/8/8/8/8/4R1K1 b - - 0 1").is_ok());
+        assert!(error("4k3/8/8/8/8/8/8/4K3 w - e6 0 1").contains("double step"));
+        assert!(validate_fen("4k3/8/8/8/4P3/8/8/4K3 b - e3 0 1").is_ok());
+        assert!(error("4k3/8/8/9/8/8/8/4K3 w - - 0 1").contains("unexpected '9' on rank 5"));
+        assert!(error("4k3/8/8/8/8/8/8/4K3 x - - 0 1").contains("side to move"));
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Prometheus text exposition (version 0.0.4) without a client library, the evaluation server keeps
+// its own atomic counters and renders them on request
+
+// Cumulative-on-render histogram of integer observations, e.g. nanoseconds or batch sizes
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    bounds: &'static [u64], // Inclusive upper bounds, ascending, +Inf is implied
+    counts: Vec<AtomicU64>, // One per bound plus the +Inf bucket
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn new(bounds: &'static [u64]) -> Histogram {
+        Histogram { bounds, counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(), sum: AtomicU64::new(0) }
+    }
+
+    pub(crate) fn observe(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct MetricsText {
+    text: String,
+}
+
+impl MetricsText {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+    }
+
+    pub(crate) fn counter(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.text, "{} {}", name, value);
+    }
+
+    pub(crate) fn gauge(&mut self, name: &str, help: &str, values: &[(&str, f64)]) {
+        // values are (labels, value) pairs, labels written as in the exposition, e.g. priority="bulk"
+        self.header(name, "gauge", help);
+        for (labels, value) in values {
+            let _ = match labels.is_empty() {
+                true => writeln!(self.text, "{} {}", name, value),
+                false => writeln!(self.text, "{}{{{}}} {}", name, labels, value),
+            };
+        }
+    }
+
+    pub(crate) fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram, scale: f64) {
+        // Observations are divided by scale, e.g. 1e9 to report nanoseconds in seconds
+        self.header(name, "histogram", help);
+        let mut cumulative = 0;
+        for (index, count) in histogram.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let bound = match histogram.bounds.get(index) {
+                Some(bound) => (*bound as f64 / scale).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(self.text, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(self.text, "{}_sum {}", name, histogram.sum.load(Ordering::Relaxed) as f64 / scale);
+        let _ = writeln!(self.text, "{}_count {}", name, cumulative);
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.text
+    }
+}
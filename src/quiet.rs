@@ -0,0 +1,154 @@
+use chess::{
+    get_bishop_moves, get_king_moves, get_knight_moves, get_pawn_attacks, get_rook_moves, BitBoard, Board, ChessMove, Color, Piece, Square, ALL_PIECES,
+    EMPTY,
+};
+
+use crate::search::{captured_piece, piece_value};
+
+// Tells quiet positions from tactical ones by looking at the board alone, without the move that led
+// there. Shallow networks learn much better from quiet positions, where the static eval is the
+// answer, than from ones a capture or a check is about to change. A forced recapture needs no rule
+// of its own: the piece that just captured is still standing where it can be taken back, so it
+// shows up as a capture winning material.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tactics {
+    Quiet,
+    InCheck,
+    WinningCapture(Square), // The side to move wins material on this square by exchange
+    Hanging(Square), // A piece of the side to move on this square is lost if it isn't saved
+}
+
+fn attackers(board: &Board, target: Square, occupied: BitBoard) -> BitBoard {
+    // Pieces of both colours attacking target, with sliders seeing through whatever left occupied
+    let pieces = |piece: Piece| *board.pieces(piece) & occupied;
+    let diagonal = pieces(Piece::Bishop) | pieces(Piece::Queen);
+    let straight = pieces(Piece::Rook) | pieces(Piece::Queen);
+    let pawns = pieces(Piece::Pawn);
+    (get_bishop_moves(target, occupied) & diagonal)
+        | (get_rook_moves(target, occupied) & straight)
+        | (get_knight_moves(target) & pieces(Piece::Knight))
+        | (get_king_moves(target) & pieces(Piece::King))
+        | get_pawn_attacks(target, Color::White, pawns & *board.color_combined(Color::Black))
+        | get_pawn_attacks(target, Color::Black, pawns & *board.color_combined(Color::White))
+}
+
+fn least_valuable(board: &Board, attackers: BitBoard) -> Option<(Square, Piece)> {
+    ALL_PIECES.iter().find_map(|piece| {
+        let of_piece = attackers & *board.pieces(*piece);
+        (of_piece != EMPTY).then(|| (of_piece.to_square(), *piece))
+    })
+}
+
+fn exchange(board: &Board, from: Square, target: Square, victim: Piece, mut occupied: BitBoard) -> i32 {
+    // Material won by the side capturing first, in pawns, when both sides keep taking back with their
+    // least valuable piece and either may stop. The first capture is made in any case.
+    let Some(mut attacker) = board.piece_on(from) else {
+        return 0;
+    };
+    let mut colour = board.color_on(from).unwrap_or(Color::White);
+    let mut values = vec![piece_value(victim)];
+    occupied ^= BitBoard::from_square(from);
+    loop {
+        values.push(piece_value(attacker));
+        colour = !colour;
+        let candidates = attackers(board, target, occupied) & occupied & *board.color_combined(colour);
+        let Some((square, piece)) = least_valuable(board, candidates) else {
+            break;
+        };
+        // A king only takes back on a square the other side no longer attacks
+        let defended = attackers(board, target, occupied ^ BitBoard::from_square(square)) & occupied & *board.color_combined(!colour);
+        if piece == Piece::King && defended != EMPTY {
+            break;
+        }
+        occupied ^= BitBoard::from_square(square);
+        attacker = piece;
+    }
+    // values[i] is what capture i + 1 takes, each side stops once taking on loses
+    let captures = values.len() - 1;
+    let mut best = 0;
+    for capture in (1..captures).rev() {
+        best = (values[capture] - best).max(0);
+    }
+    values[0] - best
+}
+
+pub fn see(board: &Board, chess_move: ChessMove) -> i32 {
+    // Static exchange evaluation of a move, the material the mover wins in pawns, 0 for a quiet move
+    let Some(victim) = captured_piece(board, chess_move) else {
+        return 0;
+    };
+    let (source, dest) = (chess_move.get_source(), chess_move.get_dest());
+    let mut occupied = *board.combined();
+    if board.piece_on(dest).is_none() {
+        // En passant, the pawn taken stands beside the source
+        occupied ^= BitBoard::from_square(Square::make_square(source.get_rank(), dest.get_file()));
+    }
+    exchange(board, source, dest, victim, occupied)
+}
+
+fn best_capture(board: &Board, attacker: Color) -> Option<(Square, i32)> {
+    // The target where attacker wins the most material by exchange, if any gains at all. Kings
+    // are left out, an attacked king is a check.
+    let occupied = *board.combined();
+    let targets = *board.color_combined(!attacker) & !*board.pieces(Piece::King);
+    targets
+        .filter_map(|target| {
+            let candidates = attackers(board, target, occupied) & *board.color_combined(attacker);
+            let (from, _) = least_valuable(board, candidates)?;
+            let gain = exchange(board, from, target, board.piece_on(target)?, occupied);
+            (gain > 0).then_some((target, gain))
+        })
+        .max_by_key(|(_, gain)| *gain)
+}
+
+pub fn classify(board: &Board) -> Tactics {
+    if *board.checkers() != EMPTY {
+        return Tactics::InCheck;
+    }
+    if let Some((target, _)) = best_capture(board, board.side_to_move()) {
+        return Tactics::WinningCapture(target);
+    }
+    match best_capture(board, !board.side_to_move()) {
+        Some((target, _)) => Tactics::Hanging(target),
+        None => Tactics::Quiet,
+    }
+}
+
+pub fn is_quiet(board: &Board) -> bool {
+    classify(board) == Tactics::Quiet
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_see() {
+        let board = |fen: &str| Board::from_str(fen).unwrap();
+        let capture = |name: &str| ChessMove::from_str(name).unwrap();
+        // A defended pawn taken by a rook loses the exchange, an undefended one is a pawn up
+        assert_eq!(see(&board("4k3/3p4/4p3/8/8/8/8/4RK2 w - - 0 1"), capture("e1e6")), -4);
+        assert_eq!(see(&board("4k3/8/4p3/8/8/8/8/4RK2 w - - 0 1"), capture("e1e6")), 1);
+        // Rooks behind the first capturers join in through the file
+        assert_eq!(see(&board("3rk3/3r4/8/3p4/8/8/3Q4/3RK3 w - - 0 1"), capture("d2d5")), -8);
+        assert_eq!(see(&board("4k3/3r4/8/3p4/8/8/3R4/3RK3 w - - 0 1"), capture("d2d5")), 1);
+        // The king can't take back a piece still defended
+        assert_eq!(see(&board("8/8/8/3pk3/8/8/3R4/3RK3 w - - 0 1"), capture("d2d5")), 1);
+        assert_eq!(see(&board("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1"), capture("e5d6")), 1);
+    }
+
+    #[test]
+    fn test_classify() {
+        let classify_fen = |fen: &str| classify(&Board::from_str(fen).unwrap());
+        assert_eq!(classify_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"), Tactics::Quiet);
+        assert_eq!(classify_fen("4k3/8/8/8/8/8/4r3/4K3 w - - 0 1"), Tactics::InCheck);
+        // After exd5 black takes back on d5, the recapture wins the pawn back
+        assert_eq!(classify_fen("rnbqkbnr/ppp2ppp/4p3/3P4/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 3"), Tactics::WinningCapture(Square::D5));
+        // The white knight on c3 is attacked by a pawn with white to move
+        assert_eq!(classify_fen("4k3/8/8/8/1p6/2N5/8/4K3 w - - 0 1"), Tactics::Hanging(Square::C3));
+        // Defended pieces facing equal trades are quiet
+        assert!(is_quiet(&Board::from_str("4k3/2p5/3n4/8/4N3/3P4/8/4K3 w - - 0 1").unwrap()));
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt::Write;
+
+use chess::{Board, Color, Piece, ALL_SQUARES};
+
+use crate::analysis::AnalysisMap;
+use crate::shallow_nnue::DEFAULT_WIN_SCALE;
+
+// SVG drawings of positions for quick visual debugging, plain text with no dependencies. Pieces are
+// Unicode chess glyphs, so they look like whatever font the viewer picks for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SvgOptions {
+    pub square_size: u32, // In SVG user units
+    pub flipped: bool, // Black at the bottom
+}
+
+impl Default for SvgOptions {
+    fn default() -> SvgOptions {
+        SvgOptions { square_size: 48, flipped: false }
+    }
+}
+
+const LIGHT: &str = "#f0d9b5";
+const DARK: &str = "#b58863";
+const HEAT: &str = "#e63946";
+const ARROW: &str = "#1d6fb8";
+
+fn glyph(piece: Piece, color: Color) -> char {
+    let glyphs = match color {
+        Color::White => ['♙', '♘', '♗', '♖', '♕', '♔'],
+        Color::Black => ['♟', '♞', '♝', '♜', '♛', '♚'],
+    };
+    glyphs[piece.to_index()]
+}
+
+fn corner(square: usize, options: &SvgOptions) -> (u32, u32) {
+    // Top left corner of a square, a1 = 0 to h8 = 63
+    let (file, rank) = ((square % 8) as u32, (square / 8) as u32);
+    let (column, row) = match options.flipped {
+        true => (7 - file, rank),
+        false => (file, 7 - rank),
+    };
+    (column * options.square_size, row * options.square_size)
+}
+
+fn center(square: usize, options: &SvgOptions) -> (f32, f32) {
+    let (x, y) = corner(square, options);
+    let half = options.square_size as f32 / 2.0;
+    (x as f32 + half, y as f32 + half)
+}
+
+fn draw_board(svg: &mut String, board: &Board, map: Option<&AnalysisMap>, options: &SvgOptions) {
+    // Squares, then the heatmap over them, the pieces and the arrows on top, at the origin
+    let size = options.square_size;
+    for square in 0..64 {
+        let (x, y) = corner(square, options);
+        let fill = if (square % 8 + square / 8) % 2 == 0 { DARK } else { LIGHT };
+        let _ = writeln!(svg, r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}"/>"#, x, y, size, size, fill);
+    }
+    if let Some(map) = map {
+        for interest in &map.squares {
+            let (x, y) = corner(interest.square as usize, options);
+            let opacity = 0.1 + 0.5 * interest.interest;
+            let _ = writeln!(svg, r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}" fill-opacity="{:.2}"/>"#, x, y, size, size, HEAT, opacity);
+        }
+    }
+    for square in ALL_SQUARES {
+        if let (Some(piece), Some(color)) = (board.piece_on(square), board.color_on(square)) {
+            let (x, y) = center(square.to_index(), options);
+            let _ = writeln!(
+                svg,
+                r#"<text x="{:.1}" y="{:.1}" font-size="{:.1}" text-anchor="middle" dominant-baseline="central">{}</text>"#,
+                x,
+                y,
+                size as f32 * 0.8,
+                glyph(piece, color)
+            );
+        }
+    }
+    if let Some(map) = map {
+        for arrow in &map.arrows {
+            // The best move gets the boldest arrow
+            let ((x1, y1), (x2, y2)) = (center(arrow.from as usize, options), center(arrow.to as usize, options));
+            let width = size as f32 * 0.15 / (1.0 + arrow.rank as f32 * 0.5);
+            let _ = writeln!(
+                svg,
+                r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="{}" stroke-width="{:.1}" stroke-opacity="0.8" marker-end="url(#arrowhead)"><title>{} {}</title></line>"#,
+                x1, y1, x2, y2, ARROW, width, arrow.chess_move, arrow.score
+            );
+        }
+    }
+}
+
+fn arrowhead() -> String {
+    format!(r#"<defs><marker id="arrowhead" viewBox="0 0 10 10" refX="6" refY="5" markerWidth="3" markerHeight="3" orient="auto"><path d="M0,0 L10,5 L0,10 z" fill="{}"/></marker></defs>"#, ARROW)
+}
+
+fn svg_start(width: u32, height: u32) -> String {
+    let mut svg = format!(r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#, width, height, width, height);
+    svg.push('\n');
+    svg.push_str(&arrowhead());
+    svg.push('\n');
+    svg
+}
+
+pub fn board_svg(board: &Board, map: Option<&AnalysisMap>, options: &SvgOptions) -> String {
+    // The board alone, with the analysis as a heatmap and arrows if there is one
+    let side = 8 * options.square_size;
+    let mut svg = svg_start(side, side);
+    draw_board(&mut svg, board, map, options);
+    svg.push_str("</svg>\n");
+    svg
+}
+
+pub fn eval_bar_fraction(white_score: i16) -> f64 {
+    // White's share of the bar, its expected result at the default win scale
+    1.0 / (1.0 + 10f64.powf(-white_score as f64 / DEFAULT_WIN_SCALE))
+}
+
+fn draw_eval_bar(svg: &mut String, white_score: i16, width: u32, height: u32, options: &SvgOptions) {
+    // White fills the bar from its own side of the board, the score in pawns is written on the leader's part
+    let white = (eval_bar_fraction(white_score) * height as f64).round() as u32;
+    let (white_y, black_y, black_height) = match options.flipped {
+        true => (0, white, height - white),
+        false => (height - white, 0, height - white),
+    };
+    let _ = writeln!(svg, r##"<rect x="0" y="{}" width="{}" height="{}" fill="#404040"/>"##, black_y, width, black_height);
+    let _ = writeln!(svg, r##"<rect x="0" y="{}" width="{}" height="{}" fill="#f8f8f8"/>"##, white_y, width, white);
+    let (label_y, color) = match (white_score >= 0, options.flipped) {
+        (true, false) | (false, true) => (height - width / 2, "#404040"),
+        (true, true) | (false, false) => (width / 2, "#f8f8f8"),
+    };
+    let _ = writeln!(
+        svg,
+        r#"<text x="{}" y="{}" font-size="{:.1}" text-anchor="middle" dominant-baseline="central" fill="{}">{:+.1}</text>"#,
+        width / 2,
+        label_y,
+        width as f32 * 0.4,
+        color,
+        white_score as f32 / 100.0
+    );
+}
+
+pub fn position_svg(board: &Board, white_score: i16, map: Option<&AnalysisMap>, options: &SvgOptions) -> String {
+    // The board with an evaluation bar to its left, white_score is from white's side in centipawns
+    let (bar, side) = (options.square_size / 2, 8 * options.square_size);
+    let mut svg = svg_start(bar + side, side);
+    draw_eval_bar(&mut svg, white_score, bar, side, options);
+    let _ = writeln!(svg, r#"<g transform="translate({},0)">"#, bar);
+    draw_board(&mut svg, board, map, options);
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::analysis::analyze;
+    use crate::classical::{ClassicalEval, ClassicalWeights};
+
+    #[test]
+    fn test_board_svg() {
+        let board = Board::from_str("4k3/8/8/3q4/4P3/2N5/8/4K3 w - - 0 1").unwrap();
+        let map = analyze(&mut ClassicalEval::new(ClassicalWeights::default()), &board, 2).unwrap();
+        let svg = board_svg(&board, Some(&map), &SvgOptions::default());
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<text").count(), 5);
+        assert_eq!(svg.matches("<line").count(), 2);
+        assert_eq!(svg.matches(HEAT).count(), map.squares.len());
+
+        // a1 is at the bottom left, or the top right when flipped
+        assert_eq!(corner(0, &SvgOptions::default()), (0, 336));
+        assert_eq!(corner(0, &SvgOptions { flipped: true, ..SvgOptions::default() }), (336, 0));
+    }
+
+    #[test]
+    fn test_position_svg() {
+        // A level score splits the bar in half, white from the bottom
+        assert_eq!(eval_bar_fraction(0), 0.5);
+        assert!(eval_bar_fraction(900) > 0.99);
+        let svg = position_svg(&Board::default(), 0, None, &SvgOptions::default());
+        assert!(svg.contains(r#"width="408" height="384""#));
+        assert!(svg.contains(r##"<rect x="0" y="192" width="24" height="192" fill="#f8f8f8"/>"##));
+        assert!(svg.contains(">+0.0</text>"));
+        assert_eq!(svg.matches("<text").count(), 33);
+    }
+}
@@ -0,0 +1,131 @@
+use chess::{Board, ChessMove, Piece};
+
+pub fn is_irreversible(before: &Board, after: &Board, chess_move: ChessMove) -> bool {
+    // No earlier position can come back after a pawn move, a capture or a loss of castling rights
+    let colour = before.side_to_move();
+    before.piece_on(chess_move.get_source()) == Some(Piece::Pawn)
+        || before.piece_on(chess_move.get_dest()).is_some()
+        || before.castle_rights(colour) != after.castle_rights(colour)
+        || before.castle_rights(!colour) != after.castle_rights(!colour)
+}
+
+// Position hashes of the current line, pushed and popped together with the moves. Only the
+// positions since the last irreversible move are compared, earlier ones can't repeat.
+#[derive(Debug, Clone, Default)]
+pub struct RepetitionHistory {
+    hashes: Vec<u64>, // Every position since the last reset, the current one last
+    window_starts: Vec<usize>, // For each position, the first one after the last irreversible move
+}
+
+impl RepetitionHistory {
+    pub fn new(board: &Board) -> RepetitionHistory {
+        let mut history = RepetitionHistory::default();
+        history.reset(board);
+        history
+    }
+
+    pub fn reset(&mut self, board: &Board) {
+        // Keeps the allocations, so the search can reset it without allocating
+        self.hashes.clear();
+        self.window_starts.clear();
+        self.hashes.push(board.get_hash());
+        self.window_starts.push(0);
+    }
+
+    pub fn push(&mut self, after: &Board, irreversible: bool) {
+        let start = if irreversible { self.hashes.len() } else { self.current_window_start() };
+        self.hashes.push(after.get_hash());
+        self.window_starts.push(start);
+    }
+
+    pub fn pop(&mut self) -> bool {
+        // The position the history was reset to is never popped
+        if self.hashes.len() <= 1 {
+            return false;
+        }
+        self.hashes.pop();
+        self.window_starts.pop();
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn current(&self) -> Option<u64> {
+        self.hashes.last().copied()
+    }
+
+    pub fn reversible_plies(&self) -> usize {
+        // Plies played since the last irreversible move (or the reset)
+        self.hashes.len().saturating_sub(self.current_window_start() + 1)
+    }
+
+    fn current_window_start(&self) -> usize {
+        self.window_starts.last().copied().unwrap_or(0)
+    }
+
+    pub fn occurrences(&self) -> usize {
+        // How often the current position occurred in the window, counting itself. The side to move
+        // is part of the hash, so only every other position is compared.
+        let Some(current) = self.current() else {
+            return 0;
+        };
+        let start = self.current_window_start();
+        self.hashes[start..].iter().rev().step_by(2).filter(|hash| **hash == current).count()
+    }
+
+    pub fn is_repetition(&self) -> bool {
+        // Good enough for the search, a line that repeats once can be repeated again
+        self.occurrences() >= 2
+    }
+
+    pub fn is_threefold(&self) -> bool {
+        self.occurrences() >= 3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn play(history: &mut RepetitionHistory, board: &mut Board, moves: &[&str]) {
+        for mve in moves {
+            let chess_move = ChessMove::from_str(mve).unwrap();
+            let after = board.make_move_new(chess_move);
+            history.push(&after, is_irreversible(board, &after, chess_move));
+            *board = after;
+        }
+    }
+
+    #[test]
+    fn test_repetitions() {
+        let mut board = Board::default();
+        let mut history = RepetitionHistory::new(&board);
+        let shuffle = ["g1f3", "g8f6", "f3g1", "f6g8"];
+
+        play(&mut history, &mut board, &shuffle);
+        assert!(history.is_repetition() && !history.is_threefold());
+        assert_eq!(history.reversible_plies(), 4);
+        play(&mut history, &mut board, &shuffle);
+        assert!(history.is_threefold());
+
+        // Undoing moves undoes the repetitions
+        for _ in 0..4 {
+            assert!(history.pop());
+        }
+        assert!(history.is_repetition() && !history.is_threefold());
+
+        // A pawn move starts a new window
+        play(&mut history, &mut board, &["e2e4", "g8f6", "g1f3", "f6g8", "f3g1"]);
+        assert_eq!(history.reversible_plies(), 4);
+        assert!(history.is_repetition());
+        assert_eq!(history.occurrences(), 2);
+    }
+}
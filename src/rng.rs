@@ -0,0 +1,41 @@
+// Small deterministic xorshift64 generator, tools that sample need reproducible runs from a seed
+#[derive(Debug, Clone)]
+pub(crate) struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub(crate) fn new(seed: u64) -> XorShift {
+        XorShift { state: seed.max(1) } // Zero is a fixed point
+    }
+
+    pub(crate) fn state(&self) -> u64 {
+        // Feeding this back to new continues the same sequence
+        self.state
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    pub(crate) fn below(&mut self, bound: usize) -> usize {
+        // Uniform enough for sampling, bound must be non-zero
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        // Uniform in [0, 1), from the top 53 bits
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub(crate) fn shuffle<T>(&mut self, items: &mut [T]) {
+        // Fisher-Yates
+        for i in (1..items.len()).rev() {
+            let j = self.below(i + 1);
+            items.swap(i, j);
+        }
+    }
+}
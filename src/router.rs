@@ -0,0 +1,238 @@
+use std::sync::Mutex;
+
+use chess::Board;
+
+use crate::error::NNUEError;
+use crate::eval_server::{EvalClient, EvalServer, Priority};
+
+// Several named models behind one evaluation entry point, for trying a new network on production
+// traffic. Requests name a model or are split between models by percentage, and a shadow model can
+// score a share of the traffic alongside to compare its score distribution without serving it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoutedScore {
+    pub score: i16, // From the side to move
+    pub model: usize, // Index of the model that served it, see model_name
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ScoreSummary {
+    pub count: u64,
+    pub mean: f64,
+    pub std_dev: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ShadowReport {
+    pub served: ScoreSummary, // The served scores of the shadowed requests
+    pub shadow: ScoreSummary, // The shadow model's scores of the same positions
+    pub mean_difference: f64, // Shadow minus served
+    pub mean_absolute_difference: f64,
+    pub sign_agreement: f64, // Share of positions both models favour the same side in
+}
+
+#[derive(Debug, Default)]
+struct Sums {
+    count: u64,
+    served: f64,
+    served_squares: f64,
+    shadow: f64,
+    shadow_squares: f64,
+    difference: f64,
+    absolute_difference: f64,
+    agreeing: u64,
+}
+
+impl Sums {
+    fn add(&mut self, served: i16, shadow: i16) {
+        let (served, shadow) = (served as f64, shadow as f64);
+        self.count += 1;
+        self.served += served;
+        self.served_squares += served * served;
+        self.shadow += shadow;
+        self.shadow_squares += shadow * shadow;
+        self.difference += shadow - served;
+        self.absolute_difference += (shadow - served).abs();
+        self.agreeing += (served.signum() == shadow.signum()) as u64;
+    }
+
+    fn summary(&self, sum: f64, squares: f64) -> ScoreSummary {
+        let n = self.count.max(1) as f64;
+        let mean = sum / n;
+        ScoreSummary { count: self.count, mean, std_dev: (squares / n - mean * mean).max(0.0).sqrt() }
+    }
+
+    fn report(&self) -> ShadowReport {
+        let n = self.count.max(1) as f64;
+        ShadowReport {
+            served: self.summary(self.served, self.served_squares),
+            shadow: self.summary(self.shadow, self.shadow_squares),
+            mean_difference: self.difference / n,
+            mean_absolute_difference: self.absolute_difference / n,
+            sign_agreement: self.agreeing as f64 / n,
+        }
+    }
+}
+
+struct Model {
+    name: String,
+    client: EvalClient,
+    _server: EvalServer, // Stops the server when the router is dropped
+}
+
+pub struct ModelRouter {
+    models: Vec<Model>,
+    split: Vec<f64>, // Cumulative share of unnamed requests per model, empty sends them all to the first
+    shadow: Option<(usize, f64)>, // Model and the share of requests it also scores
+    sums: Mutex<Sums>,
+}
+
+fn bucket(board: &Board, salt: u64) -> f64 {
+    // Position hash mapped into [0, 1), the same position always lands in the same bucket
+    let mixed = (board.get_hash() ^ salt).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    (mixed >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Default for ModelRouter {
+    fn default() -> ModelRouter {
+        ModelRouter::new()
+    }
+}
+
+impl ModelRouter {
+    pub fn new() -> ModelRouter {
+        ModelRouter { models: Vec::new(), split: Vec::new(), shadow: None, sums: Mutex::new(Sums::default()) }
+    }
+
+    pub fn add_model(&mut self, name: &str, server: EvalServer) -> Result<usize, NNUEError> {
+        // The first model added serves every unnamed request until a split is set
+        if self.index(name).is_some() {
+            return Err(NNUEError::InvalidConfig(format!("model {:?} is already routed", name)));
+        }
+        self.models.push(Model { name: name.to_string(), client: server.client(), _server: server });
+        Ok(self.models.len() - 1)
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.models.iter().position(|model| model.name == name)
+    }
+
+    pub fn model_name(&self, index: usize) -> Option<&str> {
+        self.models.get(index).map(|model| model.name.as_str())
+    }
+
+    pub fn set_split(&mut self, shares: &[(&str, f64)]) -> Result<(), NNUEError> {
+        // Relative shares of unnamed requests, e.g. [("prod", 95.0), ("candidate", 5.0)]. Models left
+        // out get none. Positions are assigned by hash, so a position always goes to the same model.
+        let mut weights = vec![0.0; self.models.len()];
+        for (name, share) in shares {
+            let index = self.index(name).ok_or_else(|| NNUEError::InvalidConfig(format!("unknown model {:?}", name)))?;
+            if !(share.is_finite() && *share >= 0.0) {
+                return Err(NNUEError::InvalidConfig(format!("bad share {} for {:?}", share, name)));
+            }
+            weights[index] += share;
+        }
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return Err(NNUEError::InvalidConfig("a split needs a positive share".to_string()));
+        }
+        let mut cumulative = 0.0;
+        self.split = weights
+            .iter()
+            .map(|weight| {
+                cumulative += weight / total;
+                cumulative
+            })
+            .collect();
+        Ok(())
+    }
+
+    pub fn set_shadow(&mut self, shadow: Option<(&str, f64)>) -> Result<(), NNUEError> {
+        // Also scores this share of requests with the named model, in bulk priority, and starts the
+        // comparison over. Shadowing adds the shadow model's latency to the shadowed requests.
+        self.shadow = match shadow {
+            Some((name, share)) => {
+                let index = self.index(name).ok_or_else(|| NNUEError::InvalidConfig(format!("unknown model {:?}", name)))?;
+                Some((index, share.clamp(0.0, 1.0)))
+            }
+            None => None,
+        };
+        *self.sums.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Sums::default();
+        Ok(())
+    }
+
+    pub fn route(&self, board: &Board, model: Option<&str>) -> Result<usize, NNUEError> {
+        if self.models.is_empty() {
+            return Err(NNUEError::InvalidConfig("no models to route to".to_string()));
+        }
+        match model {
+            Some(name) => self.index(name).ok_or_else(|| NNUEError::InvalidConfig(format!("unknown model {:?}", name))),
+            None if self.split.is_empty() => Ok(0),
+            None => {
+                let target = bucket(board, 0);
+                Ok(self.split.iter().position(|cumulative| target < *cumulative).unwrap_or(self.split.len() - 1))
+            }
+        }
+    }
+
+    pub fn evaluate(&self, board: &Board, model: Option<&str>) -> Result<RoutedScore, NNUEError> {
+        // A failing shadow never fails the request, it's only left out of the comparison
+        let index = self.route(board, model)?;
+        let score = self.models[index].client.evaluate(board)?;
+        if let Some((shadow, share)) = self.shadow {
+            if shadow != index && bucket(board, 1) < share {
+                if let Ok(shadow_score) = self.models[shadow].client.clone().with_priority(Priority::Bulk).evaluate(board) {
+                    self.sums.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).add(score, shadow_score);
+                }
+            }
+        }
+        Ok(RoutedScore { score, model: index })
+    }
+
+    pub fn shadow_report(&self) -> ShadowReport {
+        self.sums.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).report()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::classical::{ClassicalEval, ClassicalWeights};
+    use crate::eval_server::EvalServerOptions;
+
+    fn server(weights: ClassicalWeights) -> EvalServer {
+        EvalServer::new(ClassicalEval::new(weights), EvalServerOptions::default())
+    }
+
+    #[test]
+    fn test_routing_and_shadow() {
+        let mut router = ModelRouter::new();
+        router.add_model("prod", server(ClassicalWeights::default())).unwrap();
+        let doubled = ClassicalWeights { material: [100, 320, 330, 500, 1800, 0], ..ClassicalWeights::default() };
+        router.add_model("candidate", server(doubled)).unwrap();
+        assert!(router.add_model("prod", server(ClassicalWeights::default())).is_err());
+
+        let board = Board::from_str("4k3/8/8/8/8/8/8/3QK3 w - - 0 1").unwrap();
+        assert_eq!(router.evaluate(&board, None).unwrap().model, 0);
+        let candidate = router.evaluate(&board, Some("candidate")).unwrap();
+        assert!(candidate.score > router.evaluate(&board, Some("prod")).unwrap().score);
+        assert!(router.evaluate(&board, Some("missing")).is_err());
+
+        // Roughly the requested split over many positions, and always the same model for a position
+        router.set_split(&[("prod", 3.0), ("candidate", 1.0)]).unwrap();
+        let positions = crate::tools::conformance::random_positions(400, 5);
+        let routed: Vec<usize> = positions.iter().map(|board| router.route(board, None).unwrap()).collect();
+        let share = routed.iter().filter(|model| **model == 1).count() as f64 / routed.len() as f64;
+        assert!((0.15..0.35).contains(&share), "{}", share);
+        assert_eq!(router.route(&positions[0], None).unwrap(), routed[0]);
+
+        router.set_split(&[("prod", 1.0)]).unwrap();
+        router.set_shadow(Some(("candidate", 1.0))).unwrap();
+        router.evaluate(&board, None).unwrap();
+        let report = router.shadow_report();
+        assert_eq!(report.shadow.count, 1);
+        assert!(report.mean_difference > 0.0);
+        assert_eq!(report.sign_agreement, 1.0);
+    }
+}
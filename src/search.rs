@@ -0,0 +1,926 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use chess::{Board, ChessMove, Color, MoveGen, Piece, EMPTY};
+
+use crate::error::NNUEError;
+use crate::repetition::{is_irreversible, RepetitionHistory};
+use crate::shallow_nnue::{DEFAULT_WIN_SCALE, NNUE};
+use crate::tt::{Bound, TranspositionTable, TtEntry};
+
+pub const MATE_SCORE: i16 = 30000;
+pub const DEFAULT_ARENA_CAPACITY: usize = 64 * 256; // 64 plies of up to 256 moves
+pub(crate) const MAX_PLY: usize = 128;
+
+// Move ordering bonuses, the table's move first, captures next, then killers and countermoves, then
+// quiets by history
+const TT_BONUS: i32 = 1 << 25;
+const CAPTURE_BONUS: i32 = 1 << 24;
+const KILLER_BONUS: i32 = 1 << 22;
+const COUNTERMOVE_BONUS: i32 = 1 << 21;
+const HISTORY_MAX: i32 = 1 << 20;
+
+// Depths the pruning applies at, in plies of remaining depth
+const FUTILITY_MAX_DEPTH: u8 = 2;
+const NULL_MOVE_MIN_DEPTH: u8 = 4;
+const LMR_MIN_DEPTH: u8 = 3;
+const LMR_MIN_MOVES: usize = 3; // Moves searched in full before the rest are reduced
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoveOrdering {
+    pub killers: bool, // Quiet moves that caused a cutoff at the same ply
+    pub history: bool, // Quiet moves that caused cutoffs anywhere, weighted by depth
+    pub countermoves: bool, // The quiet reply that refuted the previous move last time
+    pub history_bonus: i32, // Multiplier on depth * depth added to the history on a cutoff
+}
+
+impl Default for MoveOrdering {
+    fn default() -> MoveOrdering {
+        MoveOrdering {
+            killers: true,
+            history: true,
+            countermoves: true,
+            history_bonus: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuiescenceOptions {
+    pub enabled: bool, // Resolve captures and promotions past the frontier before trusting the eval
+    pub pawn_value: i16, // Network output units per pawn, scales the victim values used by delta pruning
+    pub delta_margin: i16, // Extra slack for delta pruning, in hundredths of a pawn
+}
+
+impl QuiescenceOptions {
+    fn delta_margin_units(&self) -> i32 {
+        self.pawn_value as i32 * self.delta_margin as i32 / 100
+    }
+}
+
+impl Default for QuiescenceOptions {
+    fn default() -> QuiescenceOptions {
+        QuiescenceOptions {
+            enabled: true,
+            pawn_value: 100,
+            delta_margin: 200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PruningOptions {
+    pub late_move_reductions: bool, // Quiet moves late in the ordering are searched shallower first
+    pub null_move: bool, // Positions still above beta after passing are cut off
+    pub futility: bool, // Quiet moves near the frontier are skipped when the static eval is far below alpha
+    pub futility_margin: i16, // Per ply of remaining depth, in hundredths of a pawn like the delta margin
+}
+
+impl PruningOptions {
+    pub fn disabled() -> PruningOptions {
+        // An exact alpha-beta search, whose score doesn't depend on move ordering or windows
+        PruningOptions { late_move_reductions: false, null_move: false, futility: false, ..PruningOptions::default() }
+    }
+}
+
+impl Default for PruningOptions {
+    fn default() -> PruningOptions {
+        PruningOptions {
+            late_move_reductions: true,
+            null_move: true,
+            futility: true,
+            futility_margin: 150,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchOptions {
+    pub depth: u8,
+    pub arena_capacity: usize, // Total moves the per-ply move lists can hold across the whole line
+    pub ordering: MoveOrdering,
+    pub quiescence: QuiescenceOptions,
+    pub aspiration_window: i16, // Half width of the first window iterative deepening tries around the last score, 0 for full windows
+    pub pruning: PruningOptions,
+}
+
+impl SearchOptions {
+    pub fn with_win_scale(self, win_scale: f64) -> SearchOptions {
+        // Margins are given in pawns and turned into network units with pawn_value. A centipawn eval
+        // has DEFAULT_WIN_SCALE, so a calibrated scale (ShallowNNUE::win_scale after
+        // calibrate::write_scale) gives the network's own pawn.
+        let pawn_value = (100.0 * win_scale / DEFAULT_WIN_SCALE).round().clamp(1.0, i16::MAX as f64) as i16;
+        SearchOptions { quiescence: QuiescenceOptions { pawn_value, ..self.quiescence }, ..self }
+    }
+
+    fn futility_margin_units(&self, depth: u8) -> i32 {
+        self.quiescence.pawn_value as i32 * self.pruning.futility_margin as i32 * depth as i32 / 100
+    }
+}
+
+impl Default for SearchOptions {
+    fn default() -> SearchOptions {
+        SearchOptions {
+            depth: 3,
+            arena_capacity: DEFAULT_ARENA_CAPACITY,
+            ordering: MoveOrdering::default(),
+            quiescence: QuiescenceOptions::default(),
+            aspiration_window: 50,
+            pruning: PruningOptions::default(),
+        }
+    }
+}
+
+pub(crate) fn piece_value(piece: Piece) -> i32 {
+    match piece {
+        Piece::Pawn => 1,
+        Piece::Knight => 3,
+        Piece::Bishop => 3,
+        Piece::Rook => 5,
+        Piece::Queen => 9,
+        Piece::King => 10,
+    }
+}
+
+pub(crate) fn is_tactical(board: &Board, chess_move: ChessMove) -> bool {
+    captured_piece(board, chess_move).is_some() || chess_move.get_promotion().is_some()
+}
+
+pub(crate) fn captured_piece(board: &Board, chess_move: ChessMove) -> Option<Piece> {
+    match board.piece_on(chess_move.get_dest()) {
+        Some(piece) => Some(piece),
+        None => {
+            // A pawn moving diagonally onto an empty square is capturing en passant
+            let diagonal = chess_move.get_source().get_file() != chess_move.get_dest().get_file();
+            if diagonal && board.piece_on(chess_move.get_source()) == Some(Piece::Pawn) {
+                Some(Piece::Pawn)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct OrderingTables {
+    killers: Vec<[Option<ChessMove>; 2]>, // Per ply
+    history: Vec<i32>, // Indexed by [colour][from][to]
+    countermoves: Vec<Option<ChessMove>>, // Indexed by the previous move's [from][to]
+}
+
+impl OrderingTables {
+    fn new() -> OrderingTables {
+        OrderingTables {
+            killers: vec![[None; 2]; MAX_PLY],
+            history: vec![0; 2 * 64 * 64],
+            countermoves: vec![None; 64 * 64],
+        }
+    }
+
+    fn new_search(&mut self) {
+        // Killers are position specific, history and countermoves carry over but are aged
+        for killers in self.killers.iter_mut() {
+            *killers = [None; 2];
+        }
+        for value in self.history.iter_mut() {
+            *value /= 2;
+        }
+    }
+
+    fn history_index(colour: Color, chess_move: ChessMove) -> usize {
+        colour.to_index() * 64 * 64 + chess_move.get_source().to_index() * 64 + chess_move.get_dest().to_index()
+    }
+
+    fn countermove_index(previous: ChessMove) -> usize {
+        previous.get_source().to_index() * 64 + previous.get_dest().to_index()
+    }
+
+    fn score(&self, ordering: &MoveOrdering, board: &Board, chess_move: ChessMove, ply: usize, previous: Option<ChessMove>) -> i32 {
+        if let Some(victim) = captured_piece(board, chess_move) {
+            // Most valuable victim, least valuable attacker
+            let attacker = board.piece_on(chess_move.get_source()).map_or(0, piece_value);
+            return CAPTURE_BONUS + piece_value(victim) * 16 - attacker;
+        }
+        if chess_move.get_promotion().is_some() {
+            return CAPTURE_BONUS;
+        }
+
+        if ordering.killers && ply < MAX_PLY {
+            if self.killers[ply][0] == Some(chess_move) {
+                return KILLER_BONUS + 1;
+            }
+            if self.killers[ply][1] == Some(chess_move) {
+                return KILLER_BONUS;
+            }
+        }
+        if ordering.countermoves {
+            if let Some(previous) = previous {
+                if self.countermoves[OrderingTables::countermove_index(previous)] == Some(chess_move) {
+                    return COUNTERMOVE_BONUS;
+                }
+            }
+        }
+        if ordering.history {
+            return self.history[OrderingTables::history_index(board.side_to_move(), chess_move)];
+        }
+        0
+    }
+
+    fn record_cutoff(&mut self, ordering: &MoveOrdering, board: &Board, chess_move: ChessMove, depth: u8, ply: usize, previous: Option<ChessMove>) {
+        // Only quiet moves are remembered, captures are already ordered first
+        if is_tactical(board, chess_move) {
+            return;
+        }
+
+        if ordering.killers && ply < MAX_PLY && self.killers[ply][0] != Some(chess_move) {
+            self.killers[ply][1] = self.killers[ply][0];
+            self.killers[ply][0] = Some(chess_move);
+        }
+        if ordering.history {
+            let entry = &mut self.history[OrderingTables::history_index(board.side_to_move(), chess_move)];
+            let bonus = ordering.history_bonus.saturating_mul(depth as i32 * depth as i32);
+            *entry = entry.saturating_add(bonus).min(HISTORY_MAX);
+        }
+        if ordering.countermoves {
+            if let Some(previous) = previous {
+                self.countermoves[OrderingTables::countermove_index(previous)] = Some(chess_move);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchResult {
+    pub best_move: Option<ChessMove>,
+    pub score: i16, // From the side to move of the searched position
+    pub nodes: u64,
+    pub depth: u8, // Of the last finished iteration, 0 if none finished
+}
+
+#[derive(Debug)]
+pub(crate) struct MoveArena {
+    // Preallocated stack of move lists, each ply pushes its moves on top and pops them when done
+    moves: Vec<ChessMove>,
+    top: usize,
+}
+
+impl MoveArena {
+    pub(crate) fn with_capacity(capacity: usize) -> MoveArena {
+        MoveArena {
+            moves: vec![ChessMove::default(); capacity],
+            top: 0,
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.top = 0;
+    }
+
+    pub(crate) fn push_legal(&mut self, board: &Board) -> Result<Range<usize>, NNUEError> {
+        let start = self.top;
+        for chess_move in MoveGen::new_legal(board) {
+            if self.top == self.moves.len() {
+                self.top = start;
+                return Err(NNUEError::SearchArenaFull);
+            }
+            self.moves[self.top] = chess_move;
+            self.top += 1;
+        }
+        Ok(start..self.top)
+    }
+
+    pub(crate) fn pop(&mut self, moves: Range<usize>) {
+        self.top = moves.start;
+    }
+
+    pub(crate) fn get(&self, index: usize) -> ChessMove {
+        self.moves[index]
+    }
+
+    pub(crate) fn slice_mut(&mut self, moves: Range<usize>) -> &mut [ChessMove] {
+        &mut self.moves[moves]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    depth: u8, // Remaining depth
+    ply: usize, // Distance from the root
+    previous: Option<ChessMove>, // Move that led to this node
+}
+
+impl Node {
+    fn child(self, chess_move: ChessMove) -> Node {
+        Node {
+            depth: self.depth - 1,
+            ply: self.ply + 1,
+            previous: Some(chess_move),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Searcher {
+    options: SearchOptions,
+    arena: MoveArena,
+    tables: OrderingTables,
+    nodes: u64,
+    evaluator_board: Option<Board>, // Board the evaluator was last set to
+    game_history: RepetitionHistory, // Positions played before the root, set by the caller
+    repetitions: RepetitionHistory, // Game history plus the line being searched
+    tt: Option<Arc<TranspositionTable>>, // Possibly shared with the other threads of a LazySmp search
+    stop: Option<Arc<AtomicBool>>, // Helper threads give up once it is set
+}
+
+fn to_table(score: i16, ply: usize) -> i16 {
+    // Mate scores count plies from the root, the table keeps them relative to the stored node
+    match score {
+        score if score > MATE_SCORE - MAX_PLY as i16 => score + ply as i16,
+        score if score < MAX_PLY as i16 - MATE_SCORE => score - ply as i16,
+        score => score,
+    }
+}
+
+fn from_table(score: i16, ply: usize) -> i16 {
+    match score {
+        score if score > MATE_SCORE - MAX_PLY as i16 => score - ply as i16,
+        score if score < MAX_PLY as i16 - MATE_SCORE => score + ply as i16,
+        score => score,
+    }
+}
+
+impl Searcher {
+    pub fn new(options: SearchOptions) -> Searcher {
+        Searcher {
+            options,
+            arena: MoveArena::with_capacity(options.arena_capacity),
+            tables: OrderingTables::new(),
+            nodes: 0,
+            evaluator_board: None,
+            game_history: RepetitionHistory::default(),
+            repetitions: RepetitionHistory::default(),
+            tt: None,
+            stop: None,
+        }
+    }
+
+    pub fn set_transposition_table(&mut self, tt: Option<Arc<TranspositionTable>>) {
+        // Probed and filled by every search from now on, None searches without one. The caller moves
+        // the table to a new generation between searches, see TranspositionTable::new_search.
+        self.tt = tt;
+    }
+
+    pub(crate) fn set_stop(&mut self, stop: Option<Arc<AtomicBool>>) {
+        self.stop = stop;
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.as_ref().is_some_and(|stop| stop.load(Ordering::Relaxed))
+    }
+
+    pub fn set_game_history(&mut self, history: &RepetitionHistory) {
+        // Lines that repeat a position of the game are scored as draws. Only used while the
+        // history ends in the searched board.
+        self.game_history.clone_from(history);
+    }
+
+    pub fn options(&self) -> SearchOptions {
+        self.options
+    }
+
+    pub fn set_ordering(&mut self, ordering: MoveOrdering) {
+        self.options.ordering = ordering;
+    }
+
+    pub fn search(&mut self, evaluator: &mut dyn NNUE, board: &Board) -> Result<SearchResult, NNUEError> {
+        self.start(board);
+        let depth = self.options.depth.max(1);
+        let root = Node { depth, ply: 0, previous: None };
+        let (score, best_move) = self.negamax(evaluator, board, root, -MATE_SCORE, MATE_SCORE)?;
+
+        Ok(SearchResult {
+            best_move,
+            score,
+            nodes: self.nodes,
+            depth,
+        })
+    }
+
+    pub(crate) fn iterate(&mut self, evaluator: &mut dyn NNUE, board: &Board, depth: u8) -> Result<SearchResult, NNUEError> {
+        // Iterative deepening up to depth, each iteration ordered by the table the last one filled.
+        // A stopped search returns the last iteration it finished.
+        self.start(board);
+        let mut result = SearchResult { best_move: None, score: 0, nodes: 0, depth: 0 };
+        for depth in 1..=depth.max(1) {
+            let root = Node { depth, ply: 0, previous: None };
+            let (score, best_move) = match depth >= 3 && self.options.aspiration_window > 0 {
+                true => self.aspiration(evaluator, board, root, result.score)?,
+                false => self.negamax(evaluator, board, root, -MATE_SCORE, MATE_SCORE)?,
+            };
+            if self.stopped() {
+                break;
+            }
+            result = SearchResult { best_move, score, nodes: 0, depth };
+        }
+        result.nodes = self.nodes;
+        Ok(result)
+    }
+
+    fn aspiration(&mut self, evaluator: &mut dyn NNUE, board: &Board, root: Node, guess: i16) -> Result<(i16, Option<ChessMove>), NNUEError> {
+        // Searches a window around the last iteration's score, widening the side that failed with a
+        // doubling margin until the score lands inside. Fail-soft scores tell how far to widen.
+        let mut delta = self.options.aspiration_window as i32;
+        let (mut alpha, mut beta) = (guess as i32 - delta, guess as i32 + delta);
+        loop {
+            let (low, high) = (alpha.max(-MATE_SCORE as i32) as i16, beta.min(MATE_SCORE as i32) as i16);
+            let (score, best_move) = self.negamax(evaluator, board, root, low, high)?;
+            if score <= low && low > -MATE_SCORE {
+                alpha = score as i32 - delta;
+            } else if score >= high && high < MATE_SCORE {
+                beta = score as i32 + delta;
+            } else {
+                return Ok((score, best_move));
+            }
+            if self.stopped() {
+                return Ok((score, best_move));
+            }
+            delta *= 2;
+        }
+    }
+
+    fn start(&mut self, board: &Board) {
+        self.arena.clear();
+        self.tables.new_search();
+        self.nodes = 0;
+        self.evaluator_board = None;
+        if self.game_history.current() == Some(board.get_hash()) {
+            self.repetitions.clone_from(&self.game_history);
+        } else {
+            self.repetitions.reset(board);
+        }
+    }
+
+    fn sync_evaluator(&mut self, evaluator: &mut dyn NNUE, board: &Board) -> Result<(), NNUEError> {
+        // Only reset the evaluator when it is not already on this board
+        if self.evaluator_board != Some(*board) {
+            evaluator.set_board_hard(*board)?;
+            self.evaluator_board = Some(*board);
+        }
+        Ok(())
+    }
+
+    fn static_eval(&mut self, evaluator: &mut dyn NNUE, board: &Board) -> Result<i16, NNUEError> {
+        // Eval of board for its side to move
+        self.sync_evaluator(evaluator, board)?;
+        let score = evaluator.evaluate()?;
+        Ok(evaluator.perspective().to_side_to_move(score, board.side_to_move()))
+    }
+
+    fn score_move(&mut self, evaluator: &mut dyn NNUE, board: &Board, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        // Incremental eval of the position after chess_move, from the side to move of board
+        self.sync_evaluator(evaluator, board)?;
+        let score = evaluator.forward(chess_move)?;
+        Ok(evaluator.perspective().to_side_to_move(score, board.side_to_move()))
+    }
+
+    fn quiescence(
+        &mut self,
+        evaluator: &mut dyn NNUE,
+        board: &Board,
+        ply: usize,
+        mut alpha: i16,
+        beta: i16,
+        stand_pat: i16,
+    ) -> Result<i16, NNUEError> {
+        // stand_pat is the static eval of board for its side to move, already known from the parent's forward
+        self.nodes += 1;
+        if stand_pat >= beta || ply >= MAX_PLY {
+            return Ok(stand_pat);
+        }
+        alpha = alpha.max(stand_pat);
+
+        let moves = self.arena.push_legal(board)?;
+        let tables = &self.tables;
+        let ordering = &self.options.ordering;
+        self.arena
+            .slice_mut(moves.clone())
+            .sort_unstable_by_key(|chess_move| std::cmp::Reverse(tables.score(ordering, board, *chess_move, ply, None)));
+
+        let quiescence = self.options.quiescence;
+        let mut best_score = stand_pat;
+        for index in moves.clone() {
+            let chess_move = self.arena.get(index);
+            if !is_tactical(board, chess_move) {
+                continue;
+            }
+
+            // Delta pruning, skip captures that can't raise alpha even with a generous margin
+            let gain = captured_piece(board, chess_move).map_or(0, piece_value) * quiescence.pawn_value as i32;
+            if chess_move.get_promotion().is_none() && stand_pat as i32 + gain + quiescence.delta_margin_units() < alpha as i32 {
+                continue;
+            }
+
+            let child_stand_pat = -self.score_move(evaluator, board, chess_move)?;
+            let child = board.make_move_new(chess_move);
+            let score = -self.quiescence(evaluator, &child, ply + 1, -beta, -alpha, child_stand_pat)?;
+
+            best_score = best_score.max(score);
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                break;
+            }
+        }
+
+        self.arena.pop(moves);
+        Ok(best_score)
+    }
+
+    fn child_score(
+        &mut self,
+        evaluator: &mut dyn NNUE,
+        child: &Board,
+        node: Node,
+        frontier: Option<i16>,
+        alpha: i16,
+        beta: i16,
+    ) -> Result<i16, NNUEError> {
+        // Score of the child node from its parent's side, frontier is the child's forward score when
+        // the parent is at depth 1
+        match frontier {
+            Some(score) if self.options.quiescence.enabled => Ok(-self.quiescence(evaluator, child, node.ply, -beta, -alpha, -score)?),
+            Some(score) => Ok(score),
+            None => Ok(-self.negamax(evaluator, child, node, -beta, -alpha)?.0),
+        }
+    }
+
+    fn negamax(
+        &mut self,
+        evaluator: &mut dyn NNUE,
+        board: &Board,
+        node: Node,
+        mut alpha: i16,
+        beta: i16,
+    ) -> Result<(i16, Option<ChessMove>), NNUEError> {
+        let Node { depth, ply, previous } = node;
+        if self.stopped() {
+            return Ok((0, None));
+        }
+        self.nodes += 1;
+
+        let key = board.get_hash();
+        let entry = self.tt.as_ref().and_then(|tt| tt.probe(key));
+        if let Some(entry) = entry.filter(|entry| ply > 0 && entry.depth >= depth) {
+            let score = from_table(entry.score, ply);
+            let cutoff = match entry.bound {
+                Bound::Exact => true,
+                Bound::Lower => score >= beta,
+                Bound::Upper => score <= alpha,
+            };
+            if cutoff {
+                return Ok((score, entry.chess_move));
+            }
+        }
+        let tt_move = entry.and_then(|entry| entry.chess_move);
+        let original_alpha = alpha;
+
+        // Null window nodes out of check may prune, they only have to tell whether a move beats alpha
+        let pruning = self.options.pruning;
+        let in_check = *board.checkers() != EMPTY;
+        let prunable = ply > 0 && !in_check && beta as i32 - alpha as i32 == 1;
+        let wants_eval = (pruning.futility && depth <= FUTILITY_MAX_DEPTH) || (pruning.null_move && depth >= NULL_MOVE_MIN_DEPTH);
+        let static_eval = match prunable && wants_eval {
+            true => Some(self.static_eval(evaluator, board)?),
+            false => None,
+        };
+
+        // Passing is almost never better than the best move, so if the opponent can't get below beta
+        // even after a pass with reduced depth, the real moves won't either. Not after another pass
+        // (no previous move), nor without pieces, where zugzwang makes passing the better option.
+        let pieces = board.color_combined(board.side_to_move()) & !(board.pieces(Piece::Pawn) | board.pieces(Piece::King));
+        if let (Some(eval), Some(null)) = (static_eval, board.null_move()) {
+            if pruning.null_move && depth >= NULL_MOVE_MIN_DEPTH && eval >= beta && previous.is_some() && pieces != EMPTY {
+                let reduction = if depth >= 6 { 3 } else { 2 };
+                let child = Node { depth: depth - 1 - reduction, ply: ply + 1, previous: None };
+                self.repetitions.push(&null, true);
+                let score = self.negamax(evaluator, &null, child, -beta, 1 - beta).map(|(score, _)| -score);
+                self.repetitions.pop();
+                if score? >= beta {
+                    return Ok((beta, None)); // Mate scores found after a pass aren't to be trusted
+                }
+            }
+        }
+
+        // Near the frontier a quiet move needs a large swing to raise alpha, with the margin in network units
+        let futility = static_eval
+            .filter(|_| pruning.futility && depth <= FUTILITY_MAX_DEPTH)
+            .map(|eval| eval as i32 + self.options.futility_margin_units(depth))
+            .filter(|value| *value <= alpha as i32);
+
+        let moves = self.arena.push_legal(board)?;
+        if moves.is_empty() {
+            self.arena.pop(moves);
+            let score = if *board.checkers() != EMPTY { -MATE_SCORE + ply as i16 } else { 0 };
+            return Ok((score, None));
+        }
+
+        // Best candidates first, sorted in place so the arena stays allocation free
+        let tables = &self.tables;
+        let ordering = &self.options.ordering;
+        self.arena
+            .slice_mut(moves.clone())
+            .sort_unstable_by_key(|chess_move| match Some(*chess_move) == tt_move {
+                true => std::cmp::Reverse(TT_BONUS),
+                false => std::cmp::Reverse(tables.score(ordering, board, *chess_move, ply, previous)),
+            });
+
+        let mut best_score = -MATE_SCORE;
+        let mut best_move = None;
+        for index in moves.clone() {
+            let chess_move = self.arena.get(index);
+            let child = board.make_move_new(chess_move);
+            let quiet = !is_tactical(board, chess_move) && *child.checkers() == EMPTY;
+            if let (Some(value), true) = (futility, quiet && index != moves.start) {
+                best_score = best_score.max(value as i16);
+                continue;
+            }
+            self.repetitions.push(&child, is_irreversible(board, &child, chess_move));
+            let score = if self.repetitions.is_repetition() {
+                0 // Repeating a position is a draw, the side ahead has to avoid it
+            } else {
+                // Frontier nodes score their children straight from the evaluator's forward
+                let frontier = match depth {
+                    1 => Some(self.score_move(evaluator, board, chess_move)?),
+                    _ => None,
+                };
+                // Principal variation search: the first move gets the full window, the rest only have
+                // to prove they are no better, and are searched again if they are
+                match index == moves.start {
+                    true => self.child_score(evaluator, &child, node.child(chess_move), frontier, alpha, beta)?,
+                    false => {
+                        // Late quiet moves are tried shallower first and only searched in full if they beat alpha
+                        let late = index - moves.start >= LMR_MIN_MOVES;
+                        let reduction = match pruning.late_move_reductions && depth >= LMR_MIN_DEPTH && !in_check && quiet && late {
+                            true if depth >= 6 && index - moves.start >= 8 => 2,
+                            true => 1,
+                            false => 0,
+                        };
+                        let null = alpha.saturating_add(1);
+                        let mut score = alpha.saturating_add(1);
+                        if reduction > 0 {
+                            let reduced = Node { depth: depth - 1 - reduction, ..node.child(chess_move) };
+                            score = self.child_score(evaluator, &child, reduced, frontier, alpha, null)?;
+                        }
+                        if score > alpha {
+                            score = self.child_score(evaluator, &child, node.child(chess_move), frontier, alpha, null)?;
+                        }
+                        match score > alpha && score < beta {
+                            true => self.child_score(evaluator, &child, node.child(chess_move), frontier, alpha, beta)?,
+                            false => score,
+                        }
+                    }
+                }
+            };
+            self.repetitions.pop();
+
+            if best_move.is_none() || score > best_score {
+                best_score = score;
+                best_move = Some(chess_move);
+            }
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                let ordering = self.options.ordering;
+                self.tables.record_cutoff(&ordering, board, chess_move, depth, ply, previous);
+                break;
+            }
+        }
+
+        self.arena.pop(moves);
+        if let (Some(tt), false) = (&self.tt, self.stopped()) {
+            let bound = match best_score {
+                score if score >= beta => Bound::Lower,
+                score if score <= original_alpha => Bound::Upper,
+                _ => Bound::Exact,
+            };
+            tt.store(key, TtEntry { score: to_table(best_score, ply), depth, bound, chess_move: best_move, generation: 0 });
+        }
+        Ok((best_score, best_move))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::str::FromStr;
+
+    use chess::{Color, Square, ALL_PIECES};
+
+    use super::*;
+    use crate::alloc_counter::allocations_during;
+    use crate::native::tests::antisymmetric_network;
+    use crate::native::{save_weights, NativeNNUE};
+
+    // Material-only evaluator, so search tests don't need a model
+    pub(crate) struct MaterialEval {
+        pub(crate) board: Board,
+    }
+
+    pub(crate) fn material(board: &Board, colour: Color) -> i16 {
+        let values = [100, 300, 300, 500, 900, 0];
+        let mut score = 0;
+        for (piece, value) in ALL_PIECES.iter().zip(values) {
+            let pieces = board.pieces(*piece);
+            score += value * (pieces & board.color_combined(colour)).popcnt() as i16;
+            score -= value * (pieces & board.color_combined(!colour)).popcnt() as i16;
+        }
+        score
+    }
+
+    impl NNUE for MaterialEval {
+        fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+            let after = self.board.make_move_new(chess_move);
+            Ok(material(&after, self.board.side_to_move()))
+        }
+
+        fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+            self.board = board;
+            Ok(())
+        }
+
+        fn evaluate(&mut self) -> Result<i16, NNUEError> {
+            Ok(material(&self.board, self.board.side_to_move()))
+        }
+    }
+
+    #[test]
+    fn test_capture_stand_pats_match_refresh() {
+        // Quiescence takes each capture's stand pat from forward, so en passant and capturing promotions
+        // must lose the captured piece there just as in the position set up after them
+        let path = std::env::temp_dir().join("shallow_nnue_search_capture_stand_pats.bin");
+        save_weights(&path, &antisymmetric_network(21)).unwrap();
+        let mut nnue = NativeNNUE::load(&path).unwrap();
+        let mut searcher = Searcher::new(SearchOptions::default());
+        for (fen, mve) in [
+            ("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5d6"),
+            ("4k3/8/8/8/3Pp3/8/8/4K3 b - d3 0 1", "e4d3"),
+            ("1n2k3/P7/8/8/8/8/8/4K3 w - - 0 1", "a7b8q"),
+            ("4k3/8/8/8/8/8/p7/1N2K3 b - - 0 1", "a2b1n"),
+        ] {
+            let board = Board::from_str(fen).unwrap();
+            let chess_move = ChessMove::from_str(mve).unwrap();
+            let forward = searcher.score_move(&mut nnue, &board, chess_move).unwrap();
+            let evaluated = searcher.static_eval(&mut nnue, &board.make_move_new(chess_move)).unwrap();
+            assert_eq!(forward, -evaluated, "{} {}", fen, mve);
+        }
+    }
+
+    #[test]
+    fn test_frontier_scores_castling() {
+        // Depth 1 scores every root move straight from forward, castling included, and has to agree
+        // with evaluating each position after the move
+        let path = std::env::temp_dir().join("shallow_nnue_search_frontier_castling.bin");
+        save_weights(&path, &antisymmetric_network(22)).unwrap();
+        let mut nnue = NativeNNUE::load(&path).unwrap();
+        let quiescence = QuiescenceOptions { enabled: false, ..QuiescenceOptions::default() };
+        let mut searcher = Searcher::new(SearchOptions { depth: 1, quiescence, ..SearchOptions::default() });
+        for fen in ["r3k2r/pppq1ppp/2n2n2/8/8/2N2N2/PPPQ1PPP/R3K2R w KQkq - 0 1", "r3k2r/pppq1ppp/2n2n2/8/8/2N2N2/PPPQ1PPP/R3K2R b KQkq - 0 1"] {
+            let board = Board::from_str(fen).unwrap();
+            let mut best = i16::MIN;
+            for chess_move in MoveGen::new_legal(&board) {
+                let evaluated = -searcher.static_eval(&mut nnue, &board.make_move_new(chess_move)).unwrap();
+                assert_eq!(searcher.score_move(&mut nnue, &board, chess_move).unwrap(), evaluated, "{} {}", fen, chess_move);
+                best = best.max(evaluated);
+            }
+            assert_eq!(searcher.search(&mut nnue, &board).unwrap().score, best);
+        }
+    }
+
+    #[test]
+    fn test_search_wins_material() {
+        let board = Board::from_str("4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1").unwrap();
+        let mut evaluator = MaterialEval { board };
+        let mut searcher = Searcher::new(SearchOptions { depth: 2, ..SearchOptions::default() });
+
+        let result = searcher.search(&mut evaluator, &board).unwrap();
+        assert_eq!(result.best_move, Some(ChessMove::new(Square::E4, Square::D5, None)));
+        assert_eq!(result.score, 100);
+    }
+
+    #[test]
+    fn test_search_arena_capacity() {
+        let board = Board::default();
+        let mut evaluator = MaterialEval { board };
+        let mut searcher = Searcher::new(SearchOptions { depth: 2, arena_capacity: 30, ..SearchOptions::default() });
+
+        // 20 root moves plus 20 replies don't fit in 30 slots
+        assert!(matches!(searcher.search(&mut evaluator, &board), Err(NNUEError::SearchArenaFull)));
+    }
+
+    #[test]
+    fn test_search_steady_state_allocations() {
+        let board = Board::default();
+        let mut evaluator = MaterialEval { board };
+        let mut searcher = Searcher::new(SearchOptions { depth: 3, ..SearchOptions::default() });
+
+        searcher.search(&mut evaluator, &board).unwrap();
+        let (result, allocations) = allocations_during(|| searcher.search(&mut evaluator, &board));
+        assert!(result.is_ok());
+        assert_eq!(allocations, 0);
+    }
+
+    #[test]
+    fn test_search_takes_repetition_draw() {
+        // A queen down, white can only save half a point by going back to a position of the game
+        let board = Board::from_str("4k3/8/8/8/8/8/q7/4K3 w - - 0 1").unwrap();
+        let earlier = Board::from_str("4k3/8/8/8/8/8/q7/3K4 b - - 0 1").unwrap();
+        let mut history = RepetitionHistory::new(&earlier);
+        history.push(&board, false);
+        let mut evaluator = MaterialEval { board };
+        let mut searcher = Searcher::new(SearchOptions { depth: 1, ..SearchOptions::default() });
+
+        assert_eq!(searcher.search(&mut evaluator, &board).unwrap().score, -900);
+        searcher.set_game_history(&history);
+        let result = searcher.search(&mut evaluator, &board).unwrap();
+        assert_eq!(result.best_move, Some(ChessMove::new(Square::E1, Square::D1, None)));
+        assert_eq!(result.score, 0);
+    }
+
+    #[test]
+    fn test_move_ordering() {
+        let board = Board::from_str("4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1").unwrap();
+        let mut tables = OrderingTables::new();
+        let ordering = MoveOrdering::default();
+        let capture = ChessMove::new(Square::E4, Square::D5, None);
+        let quiet = ChessMove::new(Square::E1, Square::F1, None);
+        let killer = ChessMove::new(Square::E1, Square::D1, None);
+
+        tables.record_cutoff(&ordering, &board, killer, 3, 2, None);
+        assert!(tables.score(&ordering, &board, capture, 2, None) > tables.score(&ordering, &board, killer, 2, None));
+        assert!(tables.score(&ordering, &board, killer, 2, None) > tables.score(&ordering, &board, quiet, 2, None));
+        assert_eq!(tables.score(&ordering, &board, killer, 3, None), 9); // Only history applies at another ply
+
+        let no_killers = MoveOrdering { killers: false, ..ordering };
+        assert_eq!(tables.score(&no_killers, &board, killer, 2, None), 9);
+    }
+
+    #[test]
+    fn test_ordering_keeps_search_result() {
+        let board = Board::from_str("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3").unwrap();
+        let mut evaluator = MaterialEval { board };
+        let disabled = MoveOrdering { killers: false, history: false, countermoves: false, history_bonus: 0 };
+
+        let exact = SearchOptions { depth: 3, pruning: PruningOptions::disabled(), ..SearchOptions::default() };
+        let mut ordered = Searcher::new(exact);
+        let mut unordered = Searcher::new(SearchOptions { ordering: disabled, ..exact });
+        let ordered_result = ordered.search(&mut evaluator, &board).unwrap();
+        let unordered_result = unordered.search(&mut evaluator, &board).unwrap();
+
+        assert_eq!(ordered_result.score, unordered_result.score);
+    }
+
+    #[test]
+    fn test_aspiration_keeps_search_result() {
+        // Narrow windows that keep failing and null windows that get re-searched still end at the
+        // full window score of the plain fixed depth search
+        let options = SearchOptions { depth: 3, aspiration_window: 5, pruning: PruningOptions::disabled(), ..SearchOptions::default() };
+        for board in crate::tools::conformance::random_positions(20, 9) {
+            let mut evaluator = MaterialEval { board };
+            let expected = Searcher::new(options).search(&mut evaluator, &board).unwrap();
+            let result = Searcher::new(options).iterate(&mut evaluator, &board, 3).unwrap();
+            assert_eq!(result.score, expected.score, "{}", board);
+        }
+    }
+
+    #[test]
+    fn test_pruning_saves_nodes() {
+        // The pruned search still wins the queen, in fewer nodes than the exact one
+        let board = Board::from_str("r1b1k2r/ppp2ppp/2n5/3q4/4P3/2N2N2/PPP2PPP/R2QKB1R w KQkq - 0 1").unwrap();
+        let mut evaluator = MaterialEval { board };
+        let options = SearchOptions { depth: 5, ..SearchOptions::default() };
+        let pruned = Searcher::new(options).search(&mut evaluator, &board).unwrap();
+        let exact = Searcher::new(SearchOptions { pruning: PruningOptions::disabled(), ..options }).search(&mut evaluator, &board).unwrap();
+        assert_eq!(pruned.best_move, exact.best_move);
+        assert!(pruned.nodes < exact.nodes, "{} >= {}", pruned.nodes, exact.nodes);
+
+        // A network with twice the calibrated scale of a centipawn eval has twice the pawn, and margins
+        let scaled = options.with_win_scale(2.0 * DEFAULT_WIN_SCALE);
+        assert_eq!(scaled.quiescence.pawn_value, 200);
+        assert_eq!(scaled.futility_margin_units(2), 2 * options.futility_margin_units(2));
+    }
+
+    #[test]
+    fn test_quiescence_sees_recapture() {
+        // Qxd5 wins a pawn at the frontier but loses the queen to cxd5
+        let board = Board::from_str("4k3/8/2p5/3p4/8/8/8/3QK3 w - - 0 1").unwrap();
+        let greedy = ChessMove::new(Square::D1, Square::D5, None);
+        let mut evaluator = MaterialEval { board };
+
+        let no_quiescence = QuiescenceOptions { enabled: false, ..QuiescenceOptions::default() };
+        let mut searcher = Searcher::new(SearchOptions { depth: 1, quiescence: no_quiescence, ..SearchOptions::default() });
+        assert_eq!(searcher.search(&mut evaluator, &board).unwrap().best_move, Some(greedy));
+
+        let mut searcher = Searcher::new(SearchOptions { depth: 1, ..SearchOptions::default() });
+        let result = searcher.search(&mut evaluator, &board).unwrap();
+        assert_ne!(result.best_move, Some(greedy));
+        assert_eq!(result.score, 700);
+    }
+}
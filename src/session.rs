@@ -0,0 +1,186 @@
+use std::sync::Arc;
+
+use chess::{Board, ChessMove, Game};
+use fnv::FnvHashMap;
+use tch::Tensor;
+
+use crate::error::NNUEError;
+use crate::features::FeatureSet;
+use crate::perspective::ScorePerspective;
+use crate::shallow_nnue::{model_feature_set, read_score, ShallowNNUE, NNUE};
+use crate::shared_model::SharedModel;
+
+pub type GameId = u64;
+
+#[derive(Debug)]
+pub struct SessionManager {
+    // One model shared by every game, each game is an evaluator on it that owns its board and encoding
+    model: Arc<SharedModel>,
+    features: FeatureSet,
+    games: FnvHashMap<GameId, ShallowNNUE>,
+    perspective: ScorePerspective,
+}
+
+impl SessionManager {
+    pub fn new(global_path_to_model: String) -> Result<SessionManager, NNUEError> {
+        SessionManager::from_shared(SharedModel::load(global_path_to_model, None)?)
+    }
+
+    pub fn from_shared(model: Arc<SharedModel>) -> Result<SessionManager, NNUEError> {
+        Ok(SessionManager {
+            features: model.with_module(model_feature_set)?.unwrap_or_default(),
+            model,
+            games: FnvHashMap::default(),
+            perspective: ScorePerspective::default(),
+        })
+    }
+
+    pub fn set_features(&mut self, features: FeatureSet) -> Result<(), NNUEError> {
+        // Switches the input layout the model was trained with, every running game is re-encoded
+        features.validate()?;
+        for game in self.games.values_mut() {
+            game.set_features(features.clone())?;
+        }
+        self.features = features;
+        Ok(())
+    }
+
+    pub fn set_perspective(&mut self, perspective: ScorePerspective) {
+        self.perspective = perspective;
+        for game in self.games.values_mut() {
+            game.set_perspective(perspective);
+        }
+    }
+
+    pub fn perspective(&self) -> ScorePerspective {
+        self.perspective
+    }
+
+    pub fn new_game(&mut self, game_id: GameId, board: Board) -> Result<(), NNUEError> {
+        // Starts (or restarts) a game from the given position
+        let mut game = ShallowNNUE::from_shared(Arc::clone(&self.model))?;
+        game.set_features(self.features.clone())?;
+        game.set_perspective(self.perspective);
+        game.set_board_hard(board)?;
+        self.games.insert(game_id, game);
+        Ok(())
+    }
+
+    pub fn new_game_from(&mut self, game_id: GameId, game: &Game) -> Result<(), NNUEError> {
+        // Starts (or restarts) a game from the current position of a chess::Game
+        self.new_game(game_id, game.current_position())
+    }
+
+    pub fn end_game(&mut self, game_id: GameId) -> Result<(), NNUEError> {
+        match self.games.remove(&game_id) {
+            Some(_) => Ok(()),
+            None => Err(NNUEError::UnknownGame),
+        }
+    }
+
+    pub fn board(&self, game_id: GameId) -> Option<&Board> {
+        self.games.get(&game_id).map(|game| game.board())
+    }
+
+    pub fn num_games(&self) -> usize {
+        self.games.len()
+    }
+
+    pub fn make_move(&mut self, game_id: GameId, chess_move: ChessMove) -> Result<(), NNUEError> {
+        // The encoding follows the move incrementally
+        self.games.get_mut(&game_id).ok_or(NNUEError::UnknownGame)?.push_move(chess_move)
+    }
+
+    pub fn evaluate(&mut self, game_id: GameId) -> Result<i16, NNUEError> {
+        // Repetitions and the fifty move rule reached through make_move are draws, as with a single evaluator
+        self.games.get_mut(&game_id).ok_or(NNUEError::UnknownGame)?.evaluate()
+    }
+
+    pub fn evaluate_batch(&self, game_ids: &[GameId]) -> Result<Vec<i16>, NNUEError> {
+        // Runs a single forward over all requested games, results are in the same order as game_ids
+        if game_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut games: Vec<&ShallowNNUE> = Vec::with_capacity(game_ids.len());
+        for game_id in game_ids {
+            games.push(self.games.get(game_id).ok_or(NNUEError::UnknownGame)?);
+        }
+        let encodings: Vec<&Tensor> = games.iter().map(|game| game.encoding()).collect();
+
+        let output = self.model.forward_ts(&[Tensor::f_stack(&encodings, 0)?])?;
+
+        // Sessions keep the evaluator defaults (no tempo, scaling or damping), so only draws and the perspective apply
+        games
+            .iter()
+            .enumerate()
+            .map(|(i, game)| {
+                let score = match game.is_draw() {
+                    true => 0,
+                    false => read_score(&output, i as i64)?,
+                };
+                Ok(self.perspective.from_side_to_move(score, game.board().side_to_move()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::Square;
+
+    use super::*;
+
+    #[test]
+    fn test_session_batch_matches_single() {
+        let mut sessions = SessionManager::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+
+        sessions.new_game(1, Board::default()).unwrap();
+        sessions.new_game(2, Board::default()).unwrap();
+        sessions
+            .make_move(2, ChessMove::new(Square::E2, Square::E4, None))
+            .unwrap();
+
+        let batch = sessions.evaluate_batch(&[1, 2]).unwrap();
+        assert_eq!(batch[0], sessions.evaluate(1).unwrap());
+        assert_eq!(batch[1], sessions.evaluate(2).unwrap());
+
+        // Illegal moves and unknown games are rejected
+        assert!(sessions
+            .make_move(1, ChessMove::new(Square::E2, Square::E5, None))
+            .is_err());
+        assert!(sessions.evaluate(3).is_err());
+
+        sessions.end_game(1).unwrap();
+        assert_eq!(sessions.num_games(), 1);
+    }
+
+    #[test]
+    fn test_sessions_share_the_model() {
+        let model = SharedModel::load(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+            None,
+        )
+        .unwrap();
+        let mut sessions = SessionManager::from_shared(Arc::clone(&model)).unwrap();
+        sessions.new_game(1, Board::default()).unwrap();
+        sessions.new_game(2, Board::default()).unwrap();
+        assert_eq!(Arc::strong_count(&model), 4);
+
+        // Moves played incrementally, castling included, score like the position set up from scratch
+        for mve in ["e2e4", "e7e5", "g1f3", "b8c6", "f1c4", "g8f6", "e1g1"] {
+            sessions.make_move(1, ChessMove::from_str(mve).unwrap()).unwrap();
+        }
+        let board = *sessions.board(1).unwrap();
+        sessions.new_game(2, board).unwrap();
+        assert_eq!(sessions.evaluate(1).unwrap(), sessions.evaluate(2).unwrap());
+        assert_eq!(sessions.evaluate_batch(&[1]).unwrap(), vec![sessions.evaluate(2).unwrap()]);
+    }
+}
@@ -1,15 +1,233 @@
-use chess::{self, Board, ChessMove, Color, ALL_SQUARES};
-use tch::{
-    nn::{Module, VarStore},
-    vision::{imagenet, resnet::resnet18},
-    CModule, Device, IndexOp, Kind, Tensor,
-};
+use std::sync::Arc;
+use std::time::Instant;
 
-use crate::bit_move::{BitMove, MoveType, PieceValueChange, piece_index, get_index, orient};
+use chess::{self, Action, Board, BoardStatus, ChessMove, Color, Game, MoveGen};
+use tch::{CModule, Device, IValue, IndexOp, Kind, Tensor};
 
+use crate::builder::ShallowNNUEBuilder;
+use crate::bit_move::{BitMove, PieceValueChange};
+use crate::endgame::{blend, EndgameGate};
+use crate::error::NNUEError;
+use crate::eval_report::{EvalReport, Wdl, DEFAULT_DRAW_MARGIN};
+use crate::features::FeatureSet;
+use crate::guard::TorchGuard;
+use crate::metadata::read_metadata;
+use crate::network::Activation;
+use crate::observer::{EvalEvent, EvalObserver};
+use crate::phase::{phase, PhaseScale};
+use crate::perspective::{from_white, to_white, ScorePerspective};
+use crate::position::BoardAdapter;
+use crate::repetition::{is_irreversible, RepetitionHistory};
+use crate::search::is_tactical;
+use crate::shared_model::SharedModel;
+use crate::trend::LineTrend;
+
+pub(crate) fn load_model(global_path_to_model: String, device: Device) -> Result<CModule, NNUEError> {
+    let mut model = match tch::CModule::load_on_device(global_path_to_model, device) {
+        Ok(model) => model,
+        Err(err) => return Err(NNUEError::Model(err)),
+    };
+
+    model.to(device, Kind::Float, false); // Send the model to the selected CPU or GPU
+    model.set_eval();
+    Ok(model)
+}
+
+pub(crate) fn model_activation(model: &CModule) -> Result<Option<Activation>, NNUEError> {
+    // Models can declare their activation with an exported activation() method returning its name,
+    // older models without one give None
+    match model.method_is("activation", &[] as &[IValue]) {
+        Ok(IValue::String(name)) => match Activation::from_name(&name) {
+            Some(activation) => Ok(Some(activation)),
+            None => Err(NNUEError::InvalidConfig(format!("model declares unknown activation {:?}", name))),
+        },
+        Ok(_) => Err(NNUEError::InvalidConfig("model activation() must return a string".to_string())),
+        Err(_) => Ok(None),
+    }
+}
+
+pub(crate) fn model_feature_set(model: &CModule) -> Result<Option<FeatureSet>, NNUEError> {
+    // Same convention as the activation, with a feature_set() method returning the set's name
+    match model.method_is("feature_set", &[] as &[IValue]) {
+        Ok(IValue::String(name)) => match FeatureSet::from_name(&name) {
+            Some(features) => Ok(Some(features)),
+            None => Err(NNUEError::InvalidConfig(format!("model declares unknown feature set {:?}", name))),
+        },
+        Ok(_) => Err(NNUEError::InvalidConfig("model feature_set() must return a string".to_string())),
+        Err(_) => Ok(None),
+    }
+}
+
+pub(crate) fn model_win_scale(model: &CModule) -> Result<Option<f64>, NNUEError> {
+    // A win_scale() method returning the calibrated scale as a float, see to_win_probability
+    match model.method_is("win_scale", &[] as &[IValue]) {
+        Ok(IValue::Double(scale)) if scale > 0.0 => Ok(Some(scale)),
+        Ok(_) => Err(NNUEError::InvalidConfig("model win_scale() must return a positive float".to_string())),
+        Err(_) => Ok(None),
+    }
+}
+
+pub(crate) fn kind_from_name(name: &str) -> Option<Kind> {
+    // Accepts torch dtype names with or without the "torch." prefix
+    match name.trim().trim_start_matches("torch.") {
+        "float" | "float32" => Some(Kind::Float),
+        "half" | "float16" => Some(Kind::Half),
+        "bfloat16" => Some(Kind::BFloat16),
+        "int8" => Some(Kind::Int8),
+        "uint8" => Some(Kind::Uint8),
+        "int" | "int32" => Some(Kind::Int),
+        "long" | "int64" => Some(Kind::Int64),
+        "bool" => Some(Kind::Bool),
+        _ => None,
+    }
+}
+
+// Tried in this order when a model doesn't declare its input dtype
+const PROBED_INPUT_KINDS: [Kind; 5] = [Kind::Float, Kind::Int8, Kind::Uint8, Kind::Bool, Kind::Half];
+
+pub(crate) fn model_input_kind(model: &CModule, device: Device) -> Result<Kind, NNUEError> {
+    // tch can't read the forward schema, so models declare the dtype with an exported input_dtype()
+    // method returning its name. Otherwise a dummy forward is tried with each candidate dtype.
+    match model.method_is("input_dtype", &[] as &[IValue]) {
+        Ok(IValue::String(name)) => {
+            return kind_from_name(&name).ok_or_else(|| NNUEError::InvalidConfig(format!("model declares unknown input dtype {:?}", name)));
+        }
+        Ok(_) => return Err(NNUEError::InvalidConfig("model input_dtype() must return a string".to_string())),
+        Err(_) => {}
+    }
+
+    let mut first_error = None;
+    for kind in PROBED_INPUT_KINDS {
+        let input = Tensor::f_zeros(768, (kind, device))?;
+        match tch::no_grad(|| model.forward_ts(&[input])) {
+            Ok(_) => return Ok(kind),
+            Err(err) => {
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    // The float error is the most useful one, the model most likely fails for another reason
+    Err(NNUEError::Model(first_error.expect("at least one dtype is probed")))
+}
+
+pub(crate) fn read_score(output: &Tensor, index: i64) -> Result<i16, NNUEError> {
+    // Reads the score of position index out of a (possibly batched) model output. Models with more
+    // outputs than the evaluation give a row per position, the evaluation is its first column.
+    let score = match output.dim() {
+        2 => output.f_int64_value(&[index, 0])?,
+        _ => output.f_view([-1])?.f_int64_value(&[index])?,
+    };
+    Ok(score as i16)
+}
+
+pub(crate) fn game_moves(game: &Game) -> impl Iterator<Item = ChessMove> + '_ {
+    // The moves of a game, without draw offers and resignations
+    game.actions().iter().filter_map(|action| match action {
+        Action::MakeMove(chess_move) => Some(*chess_move),
+        _ => None,
+    })
+}
+
+fn replays_to(start: Board, game: &Game) -> bool {
+    // Whether the game's moves are legal from start and end in its current position
+    let mut board = start;
+    for chess_move in game_moves(game) {
+        if !board.legal(chess_move) {
+            return false;
+        }
+        board = board.make_move_new(chess_move);
+    }
+    board == game.current_position()
+}
+
+// Plies without a capture or pawn move after which the game is drawn
+pub const FIFTY_MOVE_PLIES: u32 = 100;
+
+// Score units for a tenfold change in the odds of an uncalibrated model, the Texel and trainer default
+pub const DEFAULT_WIN_SCALE: f64 = 400.0;
+
+// Above this many changed features a full re-encode is cheaper than applying the delta
+const SYNC_REFRESH_THRESHOLD: usize = 16;
+
+// Kept object safe (no generic methods, no Self returns) so evaluators can be swapped at runtime as BoxedNNUE
 pub trait NNUE {
-    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, ()>; // Runs the model given the supplied move, and unmakes the move afterwards
-    fn set_board_hard(&mut self, board: Board) -> Result<(), ()>; // Slow reset of the board (cleans and adds pieces)
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError>; // Runs the model given the supplied move, and unmakes the move afterwards
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError>; // Slow reset of the board (cleans and adds pieces)
+    fn evaluate(&mut self) -> Result<i16, NNUEError>; // Runs the model on the current board, for its side to move
+    fn perspective(&self) -> ScorePerspective {
+        // Which side positive scores favour, callers such as the search convert with this
+        ScorePerspective::SideToMove
+    }
+}
+
+pub type BoxedNNUE = Box<dyn NNUE + Send>;
+
+impl<T: NNUE + ?Sized> NNUE for Box<T> {
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        (**self).forward(chess_move)
+    }
+
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+        (**self).set_board_hard(board)
+    }
+
+    fn evaluate(&mut self) -> Result<i16, NNUEError> {
+        (**self).evaluate()
+    }
+
+    fn perspective(&self) -> ScorePerspective {
+        (**self).perspective()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UncertainEval {
+    pub score: i16,
+    pub variance: f32, // Variance of the score, in score units squared
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoveDelta {
+    pub chess_move: ChessMove,
+    pub score: i16, // Eval after the move, as forward returns it
+    pub delta: i16, // score minus the static eval before the move, in the same perspective
+}
+
+pub(crate) fn mean_and_variance(samples: &[f64]) -> (f64, f64) {
+    // Sample mean and unbiased sample variance
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+    if samples.len() < 2 {
+        return (mean, 0.0);
+    }
+    let variance = samples.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1.0);
+    (mean, variance)
+}
+
+// Candidates pick_move chooses between, the rest are never played however high the temperature
+pub const PICK_CANDIDATES: usize = 8;
+
+pub(crate) fn softmax_pick(scores: &[i16], temperature: f32, uniform: f64) -> usize {
+    // Index drawn with probability proportional to exp((score - best) / (100 * temperature)), scores
+    // are from the mover's side. uniform is in [0, 1), a zero temperature always takes the best score.
+    let Some(best) = scores.iter().copied().max() else {
+        return 0;
+    };
+    if temperature <= 0.0 {
+        return scores.iter().position(|score| *score == best).unwrap_or(0);
+    }
+    let weights: Vec<f64> = scores
+        .iter()
+        .map(|score| ((*score as f64 - best as f64) / (100.0 * temperature as f64)).exp())
+        .collect();
+    let mut target = uniform * weights.iter().sum::<f64>();
+    for (index, weight) in weights.iter().enumerate() {
+        if target < *weight {
+            return index;
+        }
+        target -= weight;
+    }
+    weights.len() - 1
 }
 
 #[derive(Debug)]
@@ -17,185 +235,785 @@ pub struct ShallowNNUE {
     board: Board,
     encoding_tensor: Tensor, // Represents self
     // encoding_tensor_black: Tensor,
-    model: CModule,
+    model: Arc<SharedModel>, // Possibly shared with other evaluators, each owns its own encoding
+    features: FeatureSet,
+    turn_flip: Option<Tensor>, // Permutation that turns the encoding around to the other side to move
+    history: Vec<(Board, u32)>, // Boards and halfmove clocks before each push_move, for pop_move
+    halfmove_clock: u32, // Plies since the last capture or pawn move, Board doesn't keep it
+    fifty_move_damping: bool, // Scale scores towards a draw as the halfmove clock runs out
+    repetitions: RepetitionHistory, // Follows push_move and pop_move
+    trend: Option<LineTrend>, // Scores along the pushed line, only kept when enabled
+    endgame: Option<Box<Endgame>>, // Second network blended in as material comes off
+    win_scale: f64, // Turns scores into win probabilities
+    tempo: i16, // Bonus for the side to move, for networks that can't tell whose turn it is
+    phase_scale: PhaseScale, // Network scores scaled by game phase
+    perspective: ScorePerspective,
+    observer: Option<EvalObserver>, // See set_eval_observer
+    guard: TorchGuard, // Around every model forward, see set_torch_guard
+}
+
+// The endgame network keeps its own encoding in its own feature set, following the main board
+#[derive(Debug)]
+struct Endgame {
+    nnue: ShallowNNUE,
+    gate: EndgameGate,
+}
+
+fn turn_flip_tensor(features: &FeatureSet, device: Device) -> Result<Option<Tensor>, NNUEError> {
+    match features.turn_flip() {
+        Some(permutation) => Ok(Some(Tensor::f_from_slice(&permutation)?.f_to_device(device)?)),
+        None => Ok(None),
+    }
 }
 
 impl ShallowNNUE {
-    fn make_move(&self, bitmove: BitMove) {
-        match bitmove.mve {
-            MoveType::NonCapture(indicies) => {
-                for index in indicies {
-                    let change_value = match index.value {
-                        PieceValueChange::Place => 1.0,
-                        PieceValueChange::Remove => 0.0,
-                    };
-                    let _ = self
-                        .encoding_tensor
-                        .i(index.index as i64)
-                        .fill_(change_value);
-                }
-            }
-            MoveType::Promote(indicies) => {
-                for index in indicies {
-                    let change_value = match index.value {
-                        PieceValueChange::Place => 1.0,
-                        PieceValueChange::Remove => 0.0,
-                    };
-                    let _ = self
-                        .encoding_tensor
-                        .i(index.index as i64)
-                        .fill_(change_value);
-                }
-            }
-            MoveType::Capture(indicies) => {
-                for index in indicies {
-                    let change_value = match index.value {
-                        PieceValueChange::Place => 1.0,
-                        PieceValueChange::Remove => 0.0,
-                    };
-                    let _ = self
-                        .encoding_tensor
-                        .i(index.index as i64)
-                        .fill_(change_value);
-                }
-            }
-            MoveType::Castle(indicies) => {
-                for index in indicies {
-                    let change_value = match index.value {
-                        PieceValueChange::Place => 1.0,
-                        PieceValueChange::Remove => 0.0,
-                    };
-                    let _ = self
-                        .encoding_tensor
-                        .i(index.index as i64)
-                        .fill_(change_value);
-                }
+    fn make_move(&self, bitmove: BitMove) -> Result<(), NNUEError> {
+        self.apply_changes(bitmove, PieceValueChange::Place)
+    }
+
+    fn unmake_move(&self, bitmove: BitMove) -> Result<(), NNUEError> {
+        self.apply_changes(bitmove, PieceValueChange::Remove)
+    }
+
+    fn apply_changes(&self, bitmove: BitMove, set: PieceValueChange) -> Result<(), NNUEError> {
+        // Sets the features the move places to 1 and the ones it removes to 0, the other way around
+        // when set is Remove to undo the move
+        let turn = self.board.side_to_move(); // The move is always from the side to move of the board
+        for change in bitmove.changes() {
+            let change_value = if change.value == set { 1.0 } else { 0.0 };
+            self.encoding_tensor
+                .f_i(self.features.index(change.index, turn) as i64)?
+                .f_fill_(change_value)?;
+        }
+        Ok(())
+    }
+
+    pub fn new(global_path_to_model: String) -> Result<ShallowNNUE, NNUEError> {
+        ShallowNNUEBuilder::new(global_path_to_model).build()
+    }
+
+    pub fn builder(global_path_to_model: String) -> ShallowNNUEBuilder {
+        ShallowNNUEBuilder::new(global_path_to_model)
+    }
+
+    pub(crate) fn load(global_path_to_model: String, device: Device) -> Result<ShallowNNUE, NNUEError> {
+        // A calibrated scale in the metadata file beats the one the model was exported with
+        let metadata = read_metadata(&global_path_to_model)?;
+        let model = load_model(global_path_to_model, device)?;
+        let mut nnue = ShallowNNUE::from_shared(SharedModel::new(model, device)?)?;
+        if let Some(scale) = metadata.win_scale {
+            nnue.set_win_scale(scale)?;
+        }
+        Ok(nnue)
+    }
+
+    pub fn from_shared(model: Arc<SharedModel>) -> Result<ShallowNNUE, NNUEError> {
+        // A new evaluator on an already loaded model, starting from the default board.
+        // The encoding is kept in the model's input dtype, so forwards never convert it.
+        let board = Board::default();
+        let encoding_tensor = tch::Tensor::f_zeros(768, (model.input_kind(), model.device()))?;
+        let features = model.with_module(model_feature_set)?.unwrap_or_default();
+        features.encode(&board, &encoding_tensor)?;
+        let turn_flip = turn_flip_tensor(&features, model.device())?;
+        let win_scale = model.with_module(model_win_scale)?.unwrap_or(DEFAULT_WIN_SCALE);
+
+        Ok(ShallowNNUE {
+            board,
+            encoding_tensor,
+            model,
+            features,
+            turn_flip,
+            history: Vec::new(),
+            halfmove_clock: 0,
+            fifty_move_damping: false,
+            repetitions: RepetitionHistory::new(&board),
+            trend: None,
+            endgame: None,
+            win_scale,
+            tempo: 0,
+            phase_scale: PhaseScale::default(),
+            perspective: ScorePerspective::default(),
+            observer: None,
+            guard: TorchGuard::default(),
+        })
+    }
+
+    pub fn from_game(model: Arc<SharedModel>, game: &Game) -> Result<ShallowNNUE, NNUEError> {
+        let mut nnue = ShallowNNUE::from_shared(model)?;
+        nnue.apply_game(game)?;
+        Ok(nnue)
+    }
+
+    pub fn apply_game(&mut self, game: &Game) -> Result<(), NNUEError> {
+        // chess::Game keeps its start position private. Games from the standard start position are
+        // replayed move by move, so repetitions and the halfmove clock are known. Anything else is
+        // set to its current position, use apply_game_from with the start position instead.
+        if replays_to(Board::default(), game) {
+            return self.apply_game_from(Board::default(), game);
+        }
+        self.set_board_hard(game.current_position())
+    }
+
+    pub fn apply_game_from(&mut self, start: Board, game: &Game) -> Result<(), NNUEError> {
+        if !replays_to(start, game) {
+            return Err(NNUEError::IllegalMove);
+        }
+        self.set_board_hard(start)?;
+        for chess_move in game_moves(game) {
+            self.push_move(chess_move)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_from_startpos_and_moves(&mut self, chess_moves: &[ChessMove]) -> Result<(), NNUEError> {
+        // UCI's "position startpos moves ..." without going through a FEN, see set_line
+        self.set_line(Board::default(), chess_moves).map(|_| ())
+    }
+
+    pub fn set_line(&mut self, start: Board, chess_moves: &[ChessMove]) -> Result<usize, NNUEError> {
+        // Puts the evaluator at the end of the line, keeping what it shares with the line pushed since
+        // the last hard reset. A GUI resends the whole game with every "position ... moves", usually one
+        // or two moves longer, so only the moves that differ are popped and pushed. The moves are checked
+        // before anything changes. Returns the number of moves pushed.
+        let mut line = Vec::with_capacity(chess_moves.len() + 1);
+        line.push(start);
+        for chess_move in chess_moves {
+            let board = line[line.len() - 1];
+            if !board.legal(*chess_move) {
+                return Err(NNUEError::IllegalMove);
             }
+            line.push(board.make_move_new(*chess_move));
+        }
+
+        let current = self.history.iter().map(|(board, _)| board).chain(std::iter::once(&self.board));
+        let shared = current.zip(&line).take_while(|(old, new)| old == new).count();
+        if shared == 0 {
+            self.sync_to(&start)?;
+        }
+        for _ in shared.max(1)..self.history.len() + 1 {
+            self.pop_move()?;
+        }
+        let pushed = &chess_moves[shared.max(1) - 1..];
+        for chess_move in pushed {
+            self.push_move(*chess_move)?;
+        }
+        Ok(pushed.len())
+    }
+
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+
+    pub(crate) fn encoding(&self) -> &Tensor {
+        &self.encoding_tensor
+    }
+
+    pub fn shared_model(&self) -> Arc<SharedModel> {
+        Arc::clone(&self.model)
+    }
+
+    pub fn fork(&self) -> Result<ShallowNNUE, NNUEError> {
+        // Another evaluator on the same model, with a copy of this one's board and perspective
+        let mut nnue = ShallowNNUE::from_shared(self.shared_model())?;
+        nnue.perspective = self.perspective;
+        nnue.set_features(self.features.clone())?;
+        nnue.set_board_hard(self.board)?;
+        nnue.history = self.history.clone();
+        nnue.halfmove_clock = self.halfmove_clock;
+        nnue.fifty_move_damping = self.fifty_move_damping;
+        nnue.repetitions = self.repetitions.clone();
+        nnue.trend = self.trend.clone();
+        nnue.win_scale = self.win_scale;
+        nnue.tempo = self.tempo;
+        nnue.phase_scale = self.phase_scale;
+        nnue.guard = self.guard;
+        if let Some(endgame) = &self.endgame {
+            nnue.set_endgame(endgame.nnue.fork()?, endgame.gate)?;
+        }
+        Ok(nnue)
+    }
+
+    pub fn check_activation(&self, expected: Activation) -> Result<(), NNUEError> {
+        // Fails if the model declares a different activation, models that declare none pass
+        match self.model.with_module(model_activation)? {
+            Some(declared) if declared != expected => Err(NNUEError::InvalidConfig(format!(
+                "model declares {} but {} was expected",
+                declared.name(),
+                expected.name()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_features(&self, expected: &FeatureSet) -> Result<(), NNUEError> {
+        // Fails if the model declares a different feature set, the indexing scheme isn't declared
+        match self.model.with_module(model_feature_set)? {
+            Some(declared) if !declared.same_layout(expected) => Err(NNUEError::InvalidConfig(format!(
+                "model declares {} features but {} was expected",
+                declared.name(),
+                expected.name()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_features(&mut self, features: FeatureSet) -> Result<(), NNUEError> {
+        // Switches the input layout the model was trained with, the current board is re-encoded
+        features.validate()?;
+        self.turn_flip = turn_flip_tensor(&features, self.model.device())?;
+        self.features = features;
+        self.features.encode(&self.board, &self.encoding_tensor)
+    }
+
+    pub fn features(&self) -> &FeatureSet {
+        &self.features
+    }
+
+    pub fn set_endgame(&mut self, mut endgame: ShallowNNUE, gate: EndgameGate) -> Result<(), NNUEError> {
+        // Blends in a network trained on endgames once the gate opens. It may use another feature set
+        // or device, only its board is taken over from this evaluator from now on.
+        endgame.set_board_hard(self.board)?;
+        endgame.guard = self.guard;
+        self.endgame = Some(Box::new(Endgame { nnue: endgame, gate }));
+        Ok(())
+    }
+
+    pub fn clear_endgame(&mut self) -> Option<ShallowNNUE> {
+        self.endgame.take().map(|endgame| endgame.nnue)
+    }
+
+    pub fn endgame_gate(&self) -> Option<EndgameGate> {
+        self.endgame.as_ref().map(|endgame| endgame.gate)
+    }
+
+    fn endgame_weight(&self, board: &Board) -> f32 {
+        self.endgame.as_ref().map_or(0.0, |endgame| endgame.gate.weight(board))
+    }
+
+    fn blended(&mut self, position: &Board, score: impl Fn(&mut ShallowNNUE) -> Result<i16, NNUEError>) -> Result<i16, NNUEError> {
+        // Raw score of position from both networks, a network whose weight is zero isn't run
+        let weight = self.endgame_weight(position);
+        let main = if weight < 1.0 { score(self)? } else { 0 };
+        let endgame = match &mut self.endgame {
+            Some(endgame) if weight > 0.0 => score(&mut endgame.nnue)?,
+            _ => 0,
         };
+        Ok(self.phase_scale.apply(position, blend(main, endgame, weight)))
     }
 
-    fn unmake_move(&self, bitmove: BitMove) {
-        match bitmove.mve {
-            MoveType::NonCapture(indicies) => {
-                for index in indicies {
-                    let change_value = match index.value {
-                        PieceValueChange::Place => 0.0,
-                        PieceValueChange::Remove => 1.0,
-                    };
-                    let _ = self
-                        .encoding_tensor
-                        .i(index.index as i64)
-                        .fill_(change_value);
-                }
+    fn raw_forward(&mut self, bitmove: BitMove) -> Result<i16, NNUEError> {
+        // Model score after the move, for the side to move of the board
+        self.make_move(bitmove)?;
+
+        // The encoding is changed in place and read straight off the output, nothing is allocated here
+        let result = self.model.forward_one(&self.guard, &self.encoding_tensor);
+
+        // Reset the tensors unmaking the move, even if the forward failed
+        self.unmake_move(bitmove)?;
+        result
+    }
+
+    fn raw_evaluate(&mut self) -> Result<i16, NNUEError> {
+        self.model.forward_one(&self.guard, &self.encoding_tensor)
+    }
+
+    pub fn set_torch_guard(&mut self, guard: TorchGuard) {
+        // Checks on and panic catching around the model forwards, also for the endgame network
+        self.guard = guard;
+        if let Some(endgame) = &mut self.endgame {
+            endgame.nnue.guard = guard;
+        }
+    }
+
+    pub fn torch_guard(&self) -> TorchGuard {
+        self.guard
+    }
+
+    pub fn set_eval_observer(&mut self, observer: Box<dyn Fn(&EvalEvent) + Send>) {
+        // Called with every refresh, incremental update, forward, evaluate and batched model call of
+        // this evaluator. Forks and the endgame network start without one.
+        self.observer = Some(EvalObserver(observer));
+    }
+
+    pub fn clear_eval_observer(&mut self) {
+        self.observer = None;
+    }
+
+    fn observe(&self, event: EvalEvent) {
+        if let Some(observer) = &self.observer {
+            (observer.0)(&event);
+        }
+    }
+
+    pub fn set_perspective(&mut self, perspective: ScorePerspective) {
+        // Sets which side positive scores favour for every scoring method
+        self.perspective = perspective;
+    }
+
+    pub fn perspective(&self) -> ScorePerspective {
+        self.perspective
+    }
+
+    pub fn set_win_scale(&mut self, scale: f64) -> Result<(), NNUEError> {
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(NNUEError::InvalidConfig(format!("win scale must be positive, got {}", scale)));
+        }
+        self.win_scale = scale;
+        Ok(())
+    }
+
+    pub fn win_scale(&self) -> f64 {
+        self.win_scale
+    }
+
+    pub fn set_tempo(&mut self, centipawns: i16) {
+        // Added to static evals for the side to move. Move scores are for the position after the move,
+        // where the other side is to move, so the mover loses it there.
+        self.tempo = centipawns;
+    }
+
+    pub fn tempo(&self) -> i16 {
+        self.tempo
+    }
+
+    pub fn set_phase_scale(&mut self, scale: PhaseScale) -> Result<(), NNUEError> {
+        // Multiplies the network's score by the factor for the phase of the scored position, before the tempo
+        scale.validate()?;
+        self.phase_scale = scale;
+        Ok(())
+    }
+
+    pub fn phase_scale(&self) -> PhaseScale {
+        self.phase_scale
+    }
+
+    pub fn to_win_probability(&self, score: i16) -> f64 {
+        // Expected result for the side the score favours (see the perspective), 0.5 for a level score
+        1.0 / (1.0 + 10f64.powf(-score as f64 / self.win_scale))
+    }
+
+    pub fn eval_report(&mut self) -> Result<EvalReport, NNUEError> {
+        // Static eval of the current board with its win/draw/loss split, scored for the side to move
+        let start = Instant::now();
+        let score = self.perspective.to_side_to_move(self.evaluate()?, self.board.side_to_move());
+        let bucket = self.endgame.as_ref().map(|endgame| (endgame.gate.weight(&self.board) >= 0.5) as u32);
+        Ok(EvalReport {
+            score,
+            wdl: Some(Wdl::from_score(score, self.win_scale, DEFAULT_DRAW_MARGIN)),
+            bucket,
+            time_ms: start.elapsed().as_millis() as u64,
+            ..EvalReport::default()
+        })
+    }
+
+    fn apply_perspective(&self, score: i16) -> i16 {
+        // The model always scores for the side to move of the internal board
+        self.perspective.from_side_to_move(score, self.board.side_to_move())
+    }
+
+    pub fn sync_to(&mut self, target: &Board) -> Result<(), NNUEError> {
+        // Like set_board_hard, but only touches the features that differ. The move history starts over.
+        self.update_encoding(target)?;
+        self.history.clear();
+        self.halfmove_clock = 0;
+        self.repetitions.reset(target);
+        if let Some(trend) = &mut self.trend {
+            trend.reset();
+        }
+        Ok(())
+    }
+
+    fn update_encoding(&mut self, target: &Board) -> Result<(), NNUEError> {
+        // Brings the encoding in line with target, only touching the features that differ.
+        // Falls back to a full re-encode if the positions are too different.
+        let (removed, placed) = self.features.delta(&self.board, target);
+
+        if removed.len() + placed.len() > SYNC_REFRESH_THRESHOLD {
+            self.features.encode(target, &self.encoding_tensor)?;
+            self.observe(EvalEvent::Refresh { features: target.combined().popcnt() as usize });
+        } else if removed.is_empty() && placed.is_empty() && target.side_to_move() == self.board.side_to_move() {
+            self.observe(EvalEvent::CacheHit);
+        } else {
+            self.observe(EvalEvent::Update { removed: removed.len(), placed: placed.len() });
+            if let (Some(turn_flip), true) = (&self.turn_flip, target.side_to_move() != self.board.side_to_move()) {
+                // Every relative feature changes with the side to move, one permutation handles them all
+                self.encoding_tensor = self.encoding_tensor.f_index_select(0, turn_flip)?;
             }
-            MoveType::Promote(indicies) => {
-                for index in indicies {
-                    let change_value = match index.value {
-                        PieceValueChange::Place => 0.0,
-                        PieceValueChange::Remove => 1.0,
-                    };
-                    let _ = self
-                        .encoding_tensor
-                        .i(index.index as i64)
-                        .fill_(change_value);
-                }
+            for index in removed {
+                self.encoding_tensor.f_i(index as i64)?.f_fill_(0.0)?;
             }
-            MoveType::Capture(indicies) => {
-                for index in indicies {
-                    let change_value = match index.value {
-                        PieceValueChange::Place => 0.0,
-                        PieceValueChange::Remove => 1.0,
-                    };
-                    let _ = self
-                        .encoding_tensor
-                        .i(index.index as i64)
-                        .fill_(change_value);
-                }
+            for index in placed {
+                self.encoding_tensor.f_i(index as i64)?.f_fill_(1.0)?;
             }
-            MoveType::Castle(indicies) => {
-                for index in indicies {
-                    let change_value = match index.value {
-                        PieceValueChange::Place => 0.0,
-                        PieceValueChange::Remove => 1.0,
-                    };
-                    let _ = self
-                        .encoding_tensor
-                        .i(index.index as i64)
-                        .fill_(change_value);
-                }
+        }
+        if let Some(endgame) = &mut self.endgame {
+            endgame.nnue.update_encoding(target)?;
+        }
+        self.board = *target;
+        Ok(())
+    }
+
+    pub fn set_position<A: BoardAdapter + ?Sized>(&mut self, position: &A) -> Result<(), NNUEError> {
+        // sync_to for a position from another board library, see BoardAdapter
+        self.sync_to(&position.to_board()?)
+    }
+
+    pub fn evaluate_board_for(&mut self, board: &Board, color: Color) -> Result<i16, NNUEError> {
+        // Score of board from color's side whoever is to move, ignoring the evaluator's perspective.
+        // The evaluator is moved onto board like sync_to, which starts the move history over.
+        self.sync_to(board)?;
+        let score = self.evaluate()?;
+        Ok(self.perspective.to_color(score, board.side_to_move(), color))
+    }
+
+    pub fn push_move(&mut self, chess_move: ChessMove) -> Result<(), NNUEError> {
+        // Plays the move on the internal board, the encoding follows incrementally. Castling, en passant
+        // and promotions are handled by diffing the boards rather than by BitMove.
+        if !self.board.legal(chess_move) {
+            return Err(NNUEError::IllegalMove);
+        }
+        let previous = self.board;
+        let next = previous.make_move_new(chess_move);
+        let clock = self.clock_after(&BitMove::new(chess_move, previous.side_to_move(), previous)?);
+        self.update_encoding(&next)?;
+        self.history.push((previous, self.halfmove_clock));
+        self.halfmove_clock = clock;
+        self.repetitions.push(&next, is_irreversible(&previous, &next, chess_move));
+        if self.trend.is_some() {
+            // A move whose score can't be recorded is taken back, so the trend stays in step with the line
+            if let Err(err) = self.record_trend() {
+                self.pop_move()?;
+                return Err(err);
             }
+        }
+        Ok(())
+    }
+
+    fn record_trend(&mut self) -> Result<(), NNUEError> {
+        let turn = self.board.side_to_move();
+        let score = to_white(self.perspective.to_side_to_move(self.evaluate()?, turn), turn);
+        if let Some(trend) = &mut self.trend {
+            trend.push(score);
+        }
+        Ok(())
+    }
+
+    pub fn set_line_trend(&mut self, alpha: Option<f32>) -> Result<(), NNUEError> {
+        // Keeps an exponential moving average of the scores after each push_move, with alpha the weight
+        // of the newest score. Enabling it costs an evaluation per push, None turns it off.
+        self.trend = match alpha {
+            Some(alpha) => Some(LineTrend::new(alpha)?),
+            None => None,
         };
+        Ok(())
+    }
+
+    pub fn line_trend(&self) -> Option<i16> {
+        // The averaged score in the evaluator's perspective, None when disabled or nothing was pushed
+        // since the last hard reset
+        let white = self.trend.as_ref()?.value()?.round() as i16;
+        let turn = self.board.side_to_move();
+        Some(self.perspective.from_side_to_move(from_white(white, turn), turn))
+    }
+
+    pub fn pop_move(&mut self) -> Result<bool, NNUEError> {
+        // Takes back the last push_move, false if there is none since the last hard reset
+        match self.history.pop() {
+            Some((previous, clock)) => {
+                self.update_encoding(&previous)?;
+                self.halfmove_clock = clock;
+                self.repetitions.pop();
+                if let Some(trend) = &mut self.trend {
+                    trend.truncate(self.history.len());
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn clock_after(&self, bitmove: &BitMove) -> u32 {
+        if bitmove.resets_halfmove_clock() {
+            0
+        } else {
+            self.halfmove_clock + 1
+        }
+    }
+
+    pub fn halfmove_clock(&self) -> u32 {
+        self.halfmove_clock
     }
 
-    pub fn new(global_path_to_model: String) -> Result<ShallowNNUE, ()> {
-        let mut model = match tch::CModule::load(global_path_to_model) {
-            Ok(model) => model,
-            Err(_) => return Err(()),
+    pub fn set_halfmove_clock(&mut self, clock: u32) {
+        // For positions set up from a FEN, whose clock the Board drops
+        self.halfmove_clock = clock;
+    }
+
+    pub fn set_fifty_move_damping(&mut self, enabled: bool) {
+        self.fifty_move_damping = enabled;
+    }
+
+    pub fn is_fifty_move_draw(&self) -> bool {
+        // Mate on the last move still wins
+        self.halfmove_clock >= FIFTY_MOVE_PLIES && self.board.status() != BoardStatus::Checkmate
+    }
+
+    pub fn is_draw(&self) -> bool {
+        self.is_threefold() || self.is_fifty_move_draw()
+    }
+
+    fn damp(&self, score: i16, clock: u32) -> i16 {
+        // Shrinks the score linearly to zero as the clock approaches the fifty move rule
+        if !self.fifty_move_damping {
+            return score;
+        }
+        let remaining = FIFTY_MOVE_PLIES.saturating_sub(clock) as i32;
+        (score as i32 * remaining / FIFTY_MOVE_PLIES as i32) as i16
+    }
+
+    pub fn repetitions(&self) -> &RepetitionHistory {
+        &self.repetitions
+    }
+
+    pub fn is_repetition(&self) -> bool {
+        self.repetitions.is_repetition()
+    }
+
+    pub fn is_threefold(&self) -> bool {
+        self.repetitions.is_threefold()
+    }
+
+    pub fn forward_batch(&mut self, chess_moves: &[ChessMove]) -> Result<Vec<i16>, NNUEError> {
+        // Scores every move with a single forward (per network), results are in the same order as chess_moves
+        if chess_moves.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Moves are checked before make_move_new, which panics on an empty source square
+        let turn = self.board.side_to_move();
+        let bitmoves: Vec<BitMove> = chess_moves
+            .iter()
+            .map(|chess_move| BitMove::new(*chess_move, turn, self.board))
+            .collect::<Result<_, NNUEError>>()?;
+        let afters: Vec<Board> = chess_moves.iter().map(|chess_move| self.board.make_move_new(*chess_move)).collect();
+        let weights: Vec<f32> = afters.iter().map(|after| self.endgame_weight(after)).collect();
+        let main = match weights.iter().any(|weight| *weight < 1.0) {
+            true => self.raw_batch(chess_moves)?,
+            false => vec![0; chess_moves.len()],
+        };
+        let endgame = match &mut self.endgame {
+            Some(endgame) if weights.iter().any(|weight| *weight > 0.0) => endgame.nnue.raw_batch(chess_moves)?,
+            _ => vec![0; chess_moves.len()],
         };
 
-        model.to(Device::cuda_if_available(), Kind::Float, false); // Send the model to the CPU or GPU if available
-        model.set_eval();
+        // Damped by the clock after each move, like forward
+        Ok((0..chess_moves.len())
+            .map(|i| {
+                let clock = self.clock_after(&bitmoves[i]);
+                let score = self.phase_scale.apply(&afters[i], blend(main[i], endgame[i], weights[i]));
+                self.apply_perspective(self.damp(score.saturating_sub(self.tempo), clock))
+            })
+            .collect())
+    }
 
-        let encoding_tensor = tch::Tensor::zeros(768, (Kind::Float, Device::cuda_if_available()));
-        let board = Board::default();
+    fn raw_batch(&mut self, chess_moves: &[ChessMove]) -> Result<Vec<i16>, NNUEError> {
+        let turn = self.board.side_to_move();
+        let mut encodings: Vec<Tensor> = Vec::with_capacity(chess_moves.len());
+        for chess_move in chess_moves {
+            let bitmove = BitMove::new(*chess_move, turn, self.board)?;
+            self.make_move(bitmove)?;
+            let mut encoding = self.encoding_tensor.f_zeros_like()?;
+            let copied = encoding.f_copy_(&self.encoding_tensor);
+            self.unmake_move(bitmove)?;
+            copied?;
+            encodings.push(encoding);
+        }
 
-        Ok(ShallowNNUE {
-            board,
-            encoding_tensor,
-            model,
+        let start = Instant::now();
+        let output = self.model.guarded_forward(&self.guard, &Tensor::f_stack(&encodings, 0)?)?;
+        self.observe(EvalEvent::BatchFlush { size: chess_moves.len(), elapsed: start.elapsed() });
+        (0..chess_moves.len()).map(|i| read_score(&output, i as i64)).collect()
+    }
+
+    pub fn score_moves_with_delta(&mut self, chess_moves: &[ChessMove]) -> Result<Vec<MoveDelta>, NNUEError> {
+        // One batched forward for the moves and one for the current position, in the order of chess_moves
+        let current = self.evaluate()?;
+        let scores = self.forward_batch(chess_moves)?;
+        Ok(chess_moves
+            .iter()
+            .zip(scores)
+            .map(|(chess_move, score)| MoveDelta {
+                chess_move: *chess_move,
+                score,
+                delta: score.saturating_sub(current),
+            })
+            .collect())
+    }
+
+    pub fn best_moves(&mut self, n: usize) -> Result<Vec<(ChessMove, i16)>, NNUEError> {
+        // Scores all legal moves and returns the n best for the side to move, best first
+        let moves: Vec<ChessMove> = MoveGen::new_legal(&self.board).collect();
+        let scores = self.forward_batch(&moves)?;
+
+        let turn = self.board.side_to_move();
+        let perspective = self.perspective;
+        let mut scored: Vec<(ChessMove, i16)> = moves.into_iter().zip(scores).collect();
+        scored.sort_by_key(|(_, score)| std::cmp::Reverse(perspective.to_side_to_move(*score, turn)));
+        scored.truncate(n);
+        Ok(scored)
+    }
+
+    pub fn pick_move<R: FnMut() -> f64>(&mut self, temperature: f32, rng: &mut R) -> Result<Option<ChessMove>, NNUEError> {
+        // Samples one of the PICK_CANDIDATES best moves by a softmax over their scores, for bots that
+        // should vary their play. temperature is in pawns: at 1.0 a move a pawn worse is e times less
+        // likely, at 0.0 the best move is always played. rng returns uniform numbers in [0, 1).
+        let turn = self.board.side_to_move();
+        let perspective = self.perspective;
+        let candidates = self.best_moves(PICK_CANDIDATES)?;
+        let scores: Vec<i16> = candidates.iter().map(|(_, score)| perspective.to_side_to_move(*score, turn)).collect();
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(candidates[softmax_pick(&scores, temperature, rng())].0))
+    }
+
+    pub fn score_captures(&mut self) -> Result<Vec<(ChessMove, i16)>, NNUEError> {
+        // Legal captures and promotions with their scores, in generation order. Promotions go with the
+        // captures as in the quiescence search, so the two stages together cover every legal move once.
+        self.score_legal_where(is_tactical)
+    }
+
+    pub fn score_quiets(&mut self) -> Result<Vec<(ChessMove, i16)>, NNUEError> {
+        // Every legal move score_captures leaves out
+        self.score_legal_where(|board, chess_move| !is_tactical(board, chess_move))
+    }
+
+    fn score_legal_where(&mut self, keep: impl Fn(&Board, ChessMove) -> bool) -> Result<Vec<(ChessMove, i16)>, NNUEError> {
+        // Only the kept moves go into the batch
+        let board = self.board;
+        let moves: Vec<ChessMove> = MoveGen::new_legal(&board).filter(|chess_move| keep(&board, *chess_move)).collect();
+        let scores = self.forward_batch(&moves)?;
+        Ok(moves.into_iter().zip(scores).collect())
+    }
+
+    fn sample_uncertainty(&mut self, dropout_samples: usize) -> Result<UncertainEval, NNUEError> {
+        // Only a model with exactly two outputs has a variance head, a policy model's extra outputs are logits
+        let output = self.model.forward_ts(&[&self.encoding_tensor])?.f_view([-1])?;
+        if output.size()[0] == 2 {
+            return Ok(UncertainEval {
+                score: output.f_double_value(&[0])? as i16,
+                variance: output.f_double_value(&[1])? as f32,
+            });
+        }
+
+        // Train mode enables dropout
+        let samples: Result<Vec<f64>, NNUEError> = self.model.with_train_mode(|model| {
+            (0..dropout_samples.max(2))
+                .map(|_| Ok(model.forward_ts(&[&self.encoding_tensor])?.f_view([-1])?.f_double_value(&[0])?))
+                .collect()
+        });
+
+        let (mean, variance) = mean_and_variance(&samples?);
+        Ok(UncertainEval {
+            score: mean as i16,
+            variance: variance as f32,
         })
     }
-}
 
-impl NNUE for ShallowNNUE {
-    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, ()> {
+    pub fn evaluate_with_uncertainty(&mut self, chess_move: ChessMove, dropout_samples: usize) -> Result<UncertainEval, NNUEError> {
+        // Like forward, but also estimates how unsure the model is of the score.
+        // Models with exactly two outputs are read as [score, variance], otherwise the
+        // variance is approximated by sampling the model with dropout enabled. Only the main network is sampled,
+        // the endgame network, phase scale, tempo and clock damping then apply as in forward.
         let turn = self.board.side_to_move();
         let bitmove = BitMove::new(chess_move, turn, self.board)?;
+        let clock = self.clock_after(&bitmove);
+        let after = self.board.make_move_new(chess_move);
 
-        // Apply the move to the tensors
-        self.make_move(bitmove);
+        self.make_move(bitmove)?;
+        let result = self.sample_uncertainty(dropout_samples);
+        // Unmake even if the model failed, so the encoding stays in sync with the board
+        self.unmake_move(bitmove)?;
+        let result = result?;
 
-        let result = self
-            .model
-            .forward(&self.encoding_tensor)
-            .f_int64_value(&[0])
-            .expect("Model forward should not fail") as i16;
+        let weight = self.endgame_weight(&after);
+        let endgame = match &mut self.endgame {
+            Some(endgame) if weight > 0.0 => endgame.nnue.raw_forward(bitmove)?,
+            _ => 0,
+        };
+        let score = self.phase_scale.apply(&after, blend(result.score, endgame, weight)).saturating_sub(self.tempo);
 
-        // Reset the tensors unmaking the move
-        self.unmake_move(bitmove);
+        // The variance scales with the square of the main network's share of the score
+        let damping = match self.fifty_move_damping {
+            true => FIFTY_MOVE_PLIES.saturating_sub(clock) as f32 / FIFTY_MOVE_PLIES as f32,
+            false => 1.0,
+        };
+        let share = (1.0 - weight) * self.phase_scale.factor(phase(&after)) * damping;
+        Ok(UncertainEval {
+            score: self.apply_perspective(self.damp(score, clock)),
+            variance: result.variance * share * share,
+        })
+    }
+}
 
-        Ok(result)
+impl NNUE for ShallowNNUE {
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        let start = Instant::now();
+        let turn = self.board.side_to_move();
+        let bitmove = BitMove::new(chess_move, turn, self.board)?;
+        let clock = self.clock_after(&bitmove);
+        let after = self.board.make_move_new(chess_move);
+
+        let score = self.blended(&after, |nnue| nnue.raw_forward(bitmove))?.saturating_sub(self.tempo);
+        let score = self.apply_perspective(self.damp(score, clock));
+        self.observe(EvalEvent::Forward { chess_move, score, elapsed: start.elapsed() });
+        Ok(score)
     }
 
-    fn set_board_hard(&mut self, board: Board) -> Result<(), ()> {
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
         self.board = board;
+        self.history.clear();
+        self.halfmove_clock = 0;
+        self.repetitions.reset(&board);
+        if let Some(trend) = &mut self.trend {
+            trend.reset();
+        }
+        if let Some(endgame) = &mut self.endgame {
+            endgame.nnue.set_board_hard(board)?;
+        }
+        self.features.encode(&self.board, &self.encoding_tensor)?;
+        self.observe(EvalEvent::Refresh { features: board.combined().popcnt() as usize });
+        Ok(())
+    }
 
-        // Clear encodings
-        let _ = self.encoding_tensor.i(..).fill_(0.0);
-
-        // Encode all pieces
-        for sq in ALL_SQUARES{
-            // Iterate through all squares, if there is a piece, add it to the tensor
-            match self.board.piece_on(sq){
-                Some(piece) => {
-                    let colour = self.board.side_to_move();
-                    let own_piece: bool = self.board.color_on(sq).expect("Square with piece should not be empty") == self.board.side_to_move();
-                    let index = get_index(piece, own_piece, orient(sq, colour));
-                    let _ = self.encoding_tensor.i(index as i64).fill_(1.0);
-                },
-                None => {/* Skip */},
+    fn evaluate(&mut self) -> Result<i16, NNUEError> {
+        // Repetitions and the fifty move rule reached through push_move are draws whatever the network says
+        let start = Instant::now();
+        let score = match self.is_draw() {
+            true => 0,
+            false => {
+                let board = self.board;
+                let score = self.blended(&board, |nnue| nnue.raw_evaluate())?.saturating_add(self.tempo);
+                self.apply_perspective(self.damp(score, self.halfmove_clock))
             }
+        };
+        self.observe(EvalEvent::Evaluate { score, elapsed: start.elapsed() });
+        Ok(score)
+    }
 
-        }
-        Ok(())
+    fn perspective(&self) -> ScorePerspective {
+        self.perspective
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use chess::Square;
+    use std::str::FromStr;
+
+    use chess::{Color, Square};
 
     use super::*;
+    use crate::alloc_counter::allocations_during;
     #[test]
     fn test() {
         let test_tensor = tch::Tensor::zeros(768, (Kind::Float, Device::cuda_if_available()));
@@ -230,7 +1048,426 @@ mod tests {
         println!("{}", nnue.forward(mve).unwrap());
 
         assert!(matches!(nnue.forward(mve), Ok(_))); // Ensure forward doesnt result in error
-        assert!(nnue.forward(mve) == nnue.forward(mve)); // Ensure a repeated test yeilds the same result
+        assert!(nnue.forward(mve).unwrap() == nnue.forward(mve).unwrap()); // Ensure a repeated test yeilds the same result
         assert!(nnue.encoding_tensor.i(28) == Tensor::from(0.0)); // Check that E4 is once again unoccupied (unmake move works)
     }
+
+    #[test]
+    fn test_single_eval_allocations() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        let mve = ChessMove::new(Square::E2, Square::E4, None);
+        nnue.forward(mve).unwrap(); // Warmup
+        nnue.evaluate().unwrap();
+
+        // tch collects forward_ts arguments into a Vec, the only allocation left on the single eval path
+        let model = nnue.shared_model();
+        let (_, baseline) = allocations_during(|| model.forward_ts(&[&nnue.encoding_tensor]).unwrap());
+        let (_, forward) = allocations_during(|| nnue.forward(mve).unwrap());
+        let (_, evaluate) = allocations_during(|| nnue.evaluate().unwrap());
+        assert_eq!((forward, evaluate), (baseline, baseline));
+        assert_eq!(nnue.encoding_tensor.double_value(&[28]), 0.0); // The in-place move was unmade
+    }
+
+    #[test]
+    fn test_best_moves() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        nnue.set_board_hard(Board::default()).unwrap();
+
+        let best = nnue.best_moves(5).unwrap();
+        assert_eq!(best.len(), 5);
+        assert!(best.windows(2).all(|pair| pair[0].1 >= pair[1].1)); // Sorted best first
+        assert_eq!(best[0].1, nnue.forward(best[0].0).unwrap()); // Batched scores match single forwards
+
+        // A move from an empty square is an error, not a panic
+        let empty = ChessMove::new(Square::E4, Square::E5, None);
+        assert!(matches!(nnue.forward_batch(&[best[0].0, empty]), Err(NNUEError::IllegalMove)));
+    }
+
+    #[test]
+    fn test_boxed_nnue() {
+        struct ConstantEval(i16);
+        impl NNUE for ConstantEval {
+            fn forward(&mut self, _chess_move: ChessMove) -> Result<i16, NNUEError> {
+                Ok(self.0)
+            }
+            fn set_board_hard(&mut self, _board: Board) -> Result<(), NNUEError> {
+                Ok(())
+            }
+            fn evaluate(&mut self) -> Result<i16, NNUEError> {
+                Ok(self.0)
+            }
+        }
+
+        fn assert_send<T: Send>() {}
+        assert_send::<ShallowNNUE>();
+
+        // Evaluators can be switched at runtime behind the same type
+        let mut evaluators: Vec<BoxedNNUE> = vec![Box::new(ConstantEval(1)), Box::new(ConstantEval(2))];
+        let mve: ChessMove = ChessMove::new(Square::E2, Square::E4, None);
+        for (i, evaluator) in evaluators.iter_mut().enumerate() {
+            evaluator.set_board_hard(Board::default()).unwrap();
+            assert_eq!(evaluator.forward(mve).unwrap(), i as i16 + 1);
+        }
+    }
+
+    #[test]
+    fn test_shared_model() {
+        let model = SharedModel::load(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+            None,
+        )
+        .unwrap();
+        let mut first = ShallowNNUE::from_shared(Arc::clone(&model)).unwrap();
+        let mut second = first.fork().unwrap();
+        drop(model);
+        assert_eq!(Arc::strong_count(&first.shared_model()), 3); // Both evaluators and the temporary
+
+        // Each evaluator keeps its own board
+        second.set_board_hard(Board::default().make_move_new(ChessMove::new(Square::E2, Square::E4, None))).unwrap();
+        let mut single = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(first.evaluate().unwrap(), single.evaluate().unwrap());
+        let handle = std::thread::spawn(move || second.evaluate().unwrap());
+        single.set_board_hard(Board::default().make_move_new(ChessMove::new(Square::E2, Square::E4, None))).unwrap();
+        assert_eq!(handle.join().unwrap(), single.evaluate().unwrap());
+    }
+
+    #[test]
+    fn test_push_and_pop_moves() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        let mut reference = nnue.fork().unwrap();
+
+        // Includes castling, which BitMove alone doesn't encode
+        for mve in ["e2e4", "e7e5", "g1f3", "b8c6", "f1c4", "g8f6", "e1g1"] {
+            nnue.push_move(ChessMove::from_str(mve).unwrap()).unwrap();
+            reference.set_board_hard(nnue.board).unwrap();
+            assert_eq!(nnue.encoding_tensor, reference.encoding_tensor);
+            assert_eq!(nnue.evaluate().unwrap(), reference.evaluate().unwrap());
+        }
+        assert!(matches!(nnue.push_move(ChessMove::from_str("e1g1").unwrap()), Err(NNUEError::IllegalMove)));
+
+        while nnue.pop_move().unwrap() {}
+        assert_eq!(nnue.board, Board::default());
+        reference.set_board_hard(Board::default()).unwrap();
+        assert_eq!(nnue.encoding_tensor, reference.encoding_tensor);
+    }
+
+    #[test]
+    fn test_set_from_startpos_and_moves() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        let mut reference = nnue.fork().unwrap();
+        nnue.set_board_hard(Board::from_str("4k3/8/8/8/8/8/8/4K2R w K - 0 1").unwrap()).unwrap();
+
+        // The knights go out and back, the start position is repeated
+        let moves: Vec<ChessMove> = ["g1f3", "g8f6", "f3g1", "f6g8", "e2e4"].iter().map(|mve| ChessMove::from_str(mve).unwrap()).collect();
+        nnue.set_from_startpos_and_moves(&moves[..4]).unwrap();
+        assert_eq!((nnue.repetitions().len(), nnue.halfmove_clock()), (5, 4));
+        assert!(nnue.is_repetition());
+
+        nnue.set_from_startpos_and_moves(&moves).unwrap();
+        reference.set_board_hard(nnue.board).unwrap();
+        assert_eq!(nnue.encoding_tensor, reference.encoding_tensor);
+        assert_eq!(nnue.evaluate().unwrap(), reference.evaluate().unwrap());
+        assert_eq!(nnue.halfmove_clock(), 0);
+
+        // An illegal move anywhere leaves the evaluator where it was
+        let board = nnue.board;
+        assert!(matches!(nnue.set_from_startpos_and_moves(&[moves[0], moves[0]]), Err(NNUEError::IllegalMove)));
+        assert_eq!(nnue.board, board);
+    }
+
+    #[test]
+    fn test_set_line() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        let mut reference = nnue.fork().unwrap();
+        let moves = |line: &[&str]| line.iter().map(|mve| ChessMove::from_str(mve).unwrap()).collect::<Vec<ChessMove>>();
+
+        // Each position command of a game only adds the moves since the last one
+        assert_eq!(nnue.set_line(Board::default(), &moves(&["e2e4", "e7e5"])).unwrap(), 2);
+        assert_eq!(nnue.set_line(Board::default(), &moves(&["e2e4", "e7e5", "g1f3", "b8c6"])).unwrap(), 2);
+        assert_eq!(nnue.set_line(Board::default(), &moves(&["e2e4", "e7e5", "g1f3", "b8c6"])).unwrap(), 0);
+
+        // A takeback pops back to where the lines part, another start position starts over
+        assert_eq!(nnue.set_line(Board::default(), &moves(&["e2e4", "e7e5", "f1c4"])).unwrap(), 1);
+        assert_eq!(nnue.repetitions().len(), 4);
+        reference.set_board_hard(nnue.board).unwrap();
+        assert_eq!(nnue.encoding_tensor, reference.encoding_tensor);
+        let start = Board::from_str("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1").unwrap();
+        assert_eq!(nnue.set_line(start, &moves(&["e2e4"])).unwrap(), 1);
+        reference.set_board_hard(nnue.board).unwrap();
+        assert_eq!(nnue.encoding_tensor, reference.encoding_tensor);
+        assert_eq!(nnue.evaluate().unwrap(), reference.evaluate().unwrap());
+    }
+
+    #[test]
+    fn test_line_trend() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(nnue.line_trend(), None);
+
+        // With alpha 1 the trend is the last score, popping brings back the earlier ones
+        nnue.set_line_trend(Some(1.0)).unwrap();
+        let mut scores = Vec::new();
+        for mve in ["e2e4", "e7e5", "g1f3"] {
+            nnue.push_move(ChessMove::from_str(mve).unwrap()).unwrap();
+            scores.push(nnue.evaluate().unwrap());
+            assert_eq!(nnue.line_trend(), Some(scores[scores.len() - 1]));
+        }
+        nnue.pop_move().unwrap();
+        assert_eq!(nnue.line_trend(), Some(scores[1]));
+
+        nnue.set_board_hard(Board::default()).unwrap();
+        assert_eq!(nnue.line_trend(), None);
+        assert!(nnue.set_line_trend(Some(0.0)).is_err());
+    }
+
+    #[test]
+    fn test_halfmove_clock() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        for (mve, clock) in [("g1f3", 1), ("g8f6", 2), ("e2e4", 0), ("f6e4", 0), ("f3g1", 1)] {
+            nnue.push_move(ChessMove::from_str(mve).unwrap()).unwrap();
+            assert_eq!(nnue.halfmove_clock(), clock);
+        }
+        nnue.pop_move().unwrap();
+        nnue.pop_move().unwrap();
+        assert_eq!(nnue.halfmove_clock(), 0);
+
+        nnue.set_halfmove_clock(FIFTY_MOVE_PLIES - 1);
+        assert!(!nnue.is_draw());
+        nnue.push_move(ChessMove::from_str("b8c6").unwrap()).unwrap();
+        assert!(nnue.is_fifty_move_draw());
+        assert_eq!(nnue.evaluate().unwrap(), 0);
+
+        nnue.set_fifty_move_damping(true);
+        assert_eq!(nnue.damp(200, FIFTY_MOVE_PLIES / 2), 100);
+
+        // Batched scores are damped by the clock after each move like single ones
+        nnue.set_halfmove_clock(FIFTY_MOVE_PLIES / 2);
+        let moves: Vec<ChessMove> = ["d2d4", "f3g5"].iter().map(|mve| ChessMove::from_str(mve).unwrap()).collect();
+        let single: Vec<i16> = moves.iter().map(|mve| nnue.forward(*mve).unwrap()).collect();
+        assert_eq!(nnue.forward_batch(&moves).unwrap(), single);
+    }
+
+    #[test]
+    fn test_apply_game() {
+        let model = SharedModel::load(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+            None,
+        )
+        .unwrap();
+        let mut game = Game::new();
+        for mve in ["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1", "f6g8"] {
+            game.make_move(ChessMove::from_str(mve).unwrap());
+        }
+        game.offer_draw(Color::White);
+
+        let nnue = ShallowNNUE::from_game(Arc::clone(&model), &game).unwrap();
+        assert_eq!(nnue.board, game.current_position());
+        assert!(nnue.is_threefold());
+        assert_eq!(nnue.halfmove_clock(), 8);
+
+        // Games from another position can only be replayed when it is given
+        let start = Board::from_str("4k3/8/8/8/8/8/8/4K2R w K - 0 1").unwrap();
+        let mut game = Game::new_with_board(start);
+        game.make_move(ChessMove::from_str("e1g1").unwrap());
+        let mut nnue = ShallowNNUE::from_game(model, &game).unwrap();
+        assert_eq!((nnue.board, nnue.history.len()), (game.current_position(), 0));
+        nnue.apply_game_from(start, &game).unwrap();
+        assert_eq!((nnue.board, nnue.history.len()), (game.current_position(), 1));
+        assert!(matches!(nnue.apply_game_from(Board::default(), &game), Err(NNUEError::IllegalMove)));
+    }
+
+    #[test]
+    fn test_score_moves_with_delta() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        let moves: Vec<ChessMove> = MoveGen::new_legal(&Board::default()).collect();
+        let current = nnue.evaluate().unwrap();
+        let deltas = nnue.score_moves_with_delta(&moves).unwrap();
+        assert_eq!(deltas.len(), moves.len());
+        for (delta, chess_move) in deltas.iter().zip(&moves) {
+            assert_eq!(delta.chess_move, *chess_move);
+            assert_eq!(delta.score, nnue.forward(*chess_move).unwrap());
+            assert_eq!(delta.delta, delta.score - current);
+        }
+    }
+
+    #[test]
+    fn test_endgame_network() {
+        let path = "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt";
+        let mut single = ShallowNNUE::new(path.to_string()).unwrap();
+        let mut nnue = ShallowNNUE::builder(path.to_string())
+            .endgame(path.to_string(), EndgameGate::Crossfade { full: 20, none: 62 })
+            .build()
+            .unwrap();
+        assert_eq!(nnue.endgame_gate(), Some(EndgameGate::Crossfade { full: 20, none: 62 }));
+
+        // The same network on both sides blends to its own score, and the endgame encoding follows the moves
+        for mve in ["e2e4", "d7d5", "e4d5", "d8d5"] {
+            nnue.push_move(ChessMove::from_str(mve).unwrap()).unwrap();
+            single.push_move(ChessMove::from_str(mve).unwrap()).unwrap();
+            assert_eq!(nnue.evaluate().unwrap(), single.evaluate().unwrap());
+        }
+        let endgame = nnue.clear_endgame().unwrap();
+        assert_eq!(endgame.board, nnue.board);
+        assert_eq!(endgame.encoding_tensor, nnue.encoding_tensor);
+    }
+
+    #[test]
+    fn test_score_captures_and_quiets() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        for mve in ["e2e4", "d7d5"] {
+            nnue.push_move(ChessMove::from_str(mve).unwrap()).unwrap();
+        }
+
+        let captures = nnue.score_captures().unwrap();
+        let quiets = nnue.score_quiets().unwrap();
+        assert_eq!(captures.iter().map(|(chess_move, _)| chess_move.to_string()).collect::<Vec<_>>(), vec!["e4d5"]);
+        assert_eq!(captures.len() + quiets.len(), MoveGen::new_legal(&nnue.board).len());
+        for (chess_move, score) in captures.iter().chain(&quiets) {
+            assert_eq!(*score, nnue.forward(*chess_move).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_kind_from_name() {
+        assert_eq!(kind_from_name("torch.int8"), Some(Kind::Int8));
+        assert_eq!(kind_from_name("float32"), Some(Kind::Float));
+        assert_eq!(kind_from_name(" bool "), Some(Kind::Bool));
+        assert_eq!(kind_from_name("complex64"), None);
+    }
+
+    #[test]
+    fn test_evaluate_board_for() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        let board = Board::from_str("4k3/8/8/8/8/8/8/3QK3 b - - 0 1").unwrap();
+        let white = nnue.evaluate_board_for(&board, Color::White).unwrap();
+        assert_eq!(nnue.evaluate_board_for(&board, Color::Black).unwrap(), -white);
+        assert_eq!(nnue.evaluate().unwrap(), -white); // Black to move
+        nnue.set_perspective(ScorePerspective::White);
+        assert_eq!(nnue.evaluate_board_for(&board, Color::White).unwrap(), white);
+    }
+
+    #[test]
+    fn test_eval_observer() {
+        let mut nnue = ShallowNNUE::new(
+            "/home/jgme/Documents/software-projects/shallowNNUE/shallow-learn-tscript.pt"
+                .to_string(),
+        )
+        .unwrap();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        nnue.set_eval_observer(Box::new(move |event| recorded.lock().unwrap().push(*event)));
+
+        let board = nnue.board;
+        let mve = ChessMove::new(Square::E2, Square::E4, None);
+        nnue.set_board_hard(board).unwrap();
+        nnue.sync_to(&board).unwrap();
+        let score = nnue.forward(mve).unwrap();
+        nnue.forward_batch(&[mve]).unwrap();
+        nnue.push_move(mve).unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], EvalEvent::Refresh { features: 32 });
+        assert_eq!(events[1], EvalEvent::CacheHit);
+        assert!(matches!(events[2], EvalEvent::Forward { chess_move, score: forwarded, .. } if chess_move == mve && forwarded == score));
+        assert!(matches!(events[3], EvalEvent::BatchFlush { size: 1, .. }));
+        assert!(matches!(events[4], EvalEvent::Update { .. }));
+        assert_eq!(events.len(), 5);
+    }
+
+    fn counting_model(outputs: usize, counting: usize) -> ShallowNNUE {
+        // A model whose output counting is the number of active features, the others are zero
+        let mut weights = vec![0f32; 768 * outputs];
+        for feature in 0..768 {
+            weights[feature * outputs + counting] = 1.0;
+        }
+        let weights = Tensor::from_slice(&weights).view([768, outputs as i64]);
+        let example = Tensor::zeros(768, (Kind::Float, Device::Cpu));
+        let model = CModule::create_by_tracing("Counting", "forward", &[example], &mut |inputs| vec![inputs[0].view([-1, 768]).matmul(&weights)]).unwrap();
+        ShallowNNUE::from_shared(SharedModel::new(model, Device::Cpu).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_uncertainty_of_policy_model() {
+        // The evaluation and 128 policy logits, the logit after the evaluation is the feature count.
+        // Without dropout the sampled variance is zero, the logit isn't read as one.
+        let mut nnue = counting_model(129, 1);
+        let uncertain = nnue.evaluate_with_uncertainty(ChessMove::new(Square::E2, Square::E4, None), 4).unwrap();
+        assert_eq!(uncertain.variance, 0.0);
+    }
+
+    #[test]
+    fn test_uncertain_score_matches_forward() {
+        let mut nnue = counting_model(1, 0);
+        nnue.set_tempo(10);
+        nnue.set_phase_scale(PhaseScale { opening: 2.0, ..PhaseScale::default() }).unwrap();
+        nnue.set_fifty_move_damping(true);
+        nnue.set_halfmove_clock(FIFTY_MOVE_PLIES / 2);
+
+        let mve = ChessMove::new(Square::G1, Square::F3, None);
+        let uncertain = nnue.evaluate_with_uncertainty(mve, 4).unwrap();
+        assert_eq!(uncertain.score, nnue.forward(mve).unwrap());
+        assert_eq!(uncertain.variance, 0.0);
+    }
+
+    #[test]
+    fn test_softmax_pick() {
+        let scores = [50, 40, -300];
+        assert_eq!(softmax_pick(&scores, 0.0, 0.99), 0);
+        // e^-0.1 and e^-3.5 against 1 at a temperature of one pawn
+        assert_eq!(softmax_pick(&scores, 1.0, 0.6), 1);
+        assert_eq!(softmax_pick(&scores, 1.0, 0.45), 0);
+        assert_eq!(softmax_pick(&scores, 1.0, 0.999), 2);
+        assert_eq!(softmax_pick(&[], 1.0, 0.5), 0);
+    }
+
+    #[test]
+    fn test_mean_and_variance() {
+        assert_eq!(mean_and_variance(&[2.0, 4.0, 6.0]), (4.0, 4.0));
+        assert_eq!(mean_and_variance(&[5.0]), (5.0, 0.0));
+    }
 }
@@ -0,0 +1,309 @@
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use fnv::FnvHasher;
+use serde::{Deserialize, Serialize};
+
+use crate::compression::{read_file, FileWriter};
+use crate::dataset::Sample;
+use crate::error::NNUEError;
+use crate::features::FeatureSet;
+use crate::rng::XorShift;
+
+// Datasets too large for memory are split into shard files in one directory, each in the
+// "fen;score;result" format of dataset, next to a manifest.json listing them. Readers only ever hold
+// one shard, so the shard size bounds memory use.
+// The manifest only ever lists finished shards and is replaced atomically, so a trainer can read a
+// dataset while a generator keeps appending to it. Writers hold a lock on writer.lock, one at a time.
+pub const MANIFEST_NAME: &str = "manifest.json";
+const LOCK_NAME: &str = "writer.lock";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardInfo {
+    pub file: String, // Relative to the manifest's directory
+    pub samples: usize,
+    pub checksum: u64, // FNV-1a over the shard's text, before any compression
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardManifest {
+    pub feature_set: String, // Name of the encoding the samples were generated for, see FeatureSet::name
+    pub shards: Vec<ShardInfo>,
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+impl ShardManifest {
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<ShardManifest, NNUEError> {
+        let contents = fs::read_to_string(dir.as_ref().join(MANIFEST_NAME))?;
+        serde_json::from_str(&contents).map_err(|err| NNUEError::InvalidData(err.to_string()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<(), NNUEError> {
+        // Written next to the old one and renamed over it, readers see either manifest whole
+        let contents = serde_json::to_string_pretty(self).map_err(|err| NNUEError::InvalidData(err.to_string()))?;
+        let temporary = dir.as_ref().join(format!("{}.tmp", MANIFEST_NAME));
+        fs::write(&temporary, contents)?;
+        fs::rename(temporary, dir.as_ref().join(MANIFEST_NAME))?;
+        Ok(())
+    }
+
+    pub fn total_samples(&self) -> usize {
+        self.shards.iter().map(|shard| shard.samples).sum()
+    }
+
+    pub fn check_features(&self, expected: &FeatureSet) -> Result<(), NNUEError> {
+        if self.feature_set != expected.name() {
+            return Err(NNUEError::InvalidConfig(format!("shards are for {}, not {}", self.feature_set, expected.name())));
+        }
+        Ok(())
+    }
+}
+
+// Writes samples into shards of shard_size and publishes each shard in the manifest once it is full.
+// A shard that was still open when the writer stopped is left out of the manifest and overwritten later.
+pub struct ShardWriter {
+    dir: PathBuf,
+    shard_size: usize,
+    manifest: ShardManifest,
+    compressed: bool,
+    current: Option<(FileWriter, FnvHasher, usize)>, // Open shard, its running checksum and sample count
+    _lock: File, // Held for the writer's lifetime, the OS drops it if the process dies
+}
+
+impl ShardWriter {
+    pub fn create<P: AsRef<Path>>(dir: P, shard_size: usize, features: &FeatureSet) -> Result<ShardWriter, NNUEError> {
+        // Starts a new dataset, replacing the manifest of any earlier one in dir
+        let manifest = ShardManifest { feature_set: features.name().to_string(), shards: Vec::new() };
+        ShardWriter::open(dir.as_ref(), shard_size, manifest)
+    }
+
+    pub fn append<P: AsRef<Path>>(dir: P, shard_size: usize, features: &FeatureSet) -> Result<ShardWriter, NNUEError> {
+        // Continues the dataset in dir after its last finished shard, or starts one
+        let manifest = match ShardManifest::load(&dir) {
+            Ok(manifest) => {
+                manifest.check_features(features)?;
+                manifest
+            }
+            Err(NNUEError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                ShardManifest { feature_set: features.name().to_string(), shards: Vec::new() }
+            }
+            Err(err) => return Err(err),
+        };
+        ShardWriter::open(dir.as_ref(), shard_size, manifest)
+    }
+
+    fn open(dir: &Path, shard_size: usize, manifest: ShardManifest) -> Result<ShardWriter, NNUEError> {
+        if shard_size == 0 {
+            return Err(NNUEError::InvalidConfig("shards need room for at least one sample".to_string()));
+        }
+        fs::create_dir_all(dir)?;
+        let lock = File::create(dir.join(LOCK_NAME))?;
+        lock.try_lock().map_err(|_| NNUEError::InvalidConfig(format!("another writer is adding to {}", dir.display())))?;
+        Ok(ShardWriter {
+            dir: dir.to_path_buf(),
+            shard_size,
+            manifest,
+            compressed: false,
+            current: None,
+            _lock: lock,
+        })
+    }
+
+    pub fn manifest(&self) -> &ShardManifest {
+        // The published shards, the open one isn't in it yet
+        &self.manifest
+    }
+
+    pub fn compressed(mut self, compressed: bool) -> ShardWriter {
+        // zstd compress the shards written from now on, needs the zstd feature
+        self.compressed = compressed;
+        self
+    }
+
+    fn shard_name(&self, index: usize) -> String {
+        let extension = if self.compressed { "txt.zst" } else { "txt" };
+        format!("shard-{:05}.{}", index, extension)
+    }
+
+    pub fn push(&mut self, sample: &Sample) -> Result<(), NNUEError> {
+        if self.current.is_none() {
+            let file = FileWriter::create(self.dir.join(self.shard_name(self.manifest.shards.len())))?;
+            self.current = Some((file, FnvHasher::default(), 0));
+        }
+        if let Some((writer, hasher, count)) = self.current.as_mut() {
+            let line = format!("{}\n", sample.to_line());
+            writer.write_all(line.as_bytes())?;
+            hasher.write(line.as_bytes());
+            *count += 1;
+            if *count == self.shard_size {
+                self.close_shard()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn close_shard(&mut self) -> Result<(), NNUEError> {
+        if let Some((writer, hasher, samples)) = self.current.take() {
+            writer.finish()?;
+            let file = self.shard_name(self.manifest.shards.len());
+            self.manifest.shards.push(ShardInfo { file, samples, checksum: hasher.finish() });
+            self.manifest.save(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    pub fn rotate(&mut self) -> Result<(), NNUEError> {
+        // Publishes the open shard even though it isn't full, e.g. at the end of a self-play cycle
+        self.close_shard()
+    }
+
+    pub fn finish(mut self) -> Result<ShardManifest, NNUEError> {
+        self.close_shard()?;
+        self.manifest.save(&self.dir)?;
+        Ok(self.manifest)
+    }
+}
+
+pub fn write_shards<P: AsRef<Path>>(dir: P, samples: &[Sample], shard_size: usize, features: &FeatureSet) -> Result<ShardManifest, NNUEError> {
+    let mut writer = ShardWriter::create(dir, shard_size, features)?;
+    for sample in samples {
+        writer.push(sample)?;
+    }
+    writer.finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardOptions {
+    pub shuffle_shards: bool, // Visit the shards in a random order
+    pub shuffle_samples: bool, // Shuffle the samples inside each shard
+    pub verify: bool, // Check each shard's sample count and checksum against the manifest
+    pub seed: u64,
+}
+
+impl Default for ShardOptions {
+    fn default() -> ShardOptions {
+        ShardOptions { shuffle_shards: true, shuffle_samples: true, verify: true, seed: 1 }
+    }
+}
+
+// Streams a sharded dataset one shard at a time. Shuffling both levels mixes the data well enough for
+// training as long as every shard holds positions from many games.
+pub struct ShardReader {
+    dir: PathBuf,
+    manifest: ShardManifest,
+    order: Vec<usize>,
+    next: usize,
+    options: ShardOptions,
+    rng: XorShift,
+}
+
+impl ShardReader {
+    pub fn open<P: AsRef<Path>>(dir: P, options: ShardOptions) -> Result<ShardReader, NNUEError> {
+        let manifest = ShardManifest::load(&dir)?;
+        let mut rng = XorShift::new(options.seed);
+        let mut order: Vec<usize> = (0..manifest.shards.len()).collect();
+        if options.shuffle_shards {
+            rng.shuffle(&mut order);
+        }
+        Ok(ShardReader { dir: dir.as_ref().to_path_buf(), manifest, order, next: 0, options, rng })
+    }
+
+    pub fn manifest(&self) -> &ShardManifest {
+        &self.manifest
+    }
+
+    fn read_shard(&mut self, index: usize) -> Result<Vec<Sample>, NNUEError> {
+        let shard = &self.manifest.shards[index];
+        let bytes = read_file(self.dir.join(&shard.file))?;
+        if self.options.verify && checksum(&bytes) != shard.checksum {
+            return Err(NNUEError::InvalidData(format!("{} does not match its checksum", shard.file)));
+        }
+        let contents = String::from_utf8(bytes).map_err(|_| NNUEError::InvalidData(format!("{} is not text", shard.file)))?;
+        let mut samples = contents.lines().filter(|line| !line.trim().is_empty()).map(Sample::parse).collect::<Result<Vec<_>, _>>()?;
+        if self.options.verify && samples.len() != shard.samples {
+            return Err(NNUEError::InvalidData(format!("{} has {} samples, not {}", shard.file, samples.len(), shard.samples)));
+        }
+        if self.options.shuffle_samples {
+            self.rng.shuffle(&mut samples);
+        }
+        Ok(samples)
+    }
+}
+
+impl Iterator for ShardReader {
+    // One shard's samples at a time
+    type Item = Result<Vec<Sample>, NNUEError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = *self.order.get(self.next)?;
+        self.next += 1;
+        Some(self.read_shard(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::Board;
+
+    use super::*;
+
+    #[test]
+    fn test_shards_round_trip() {
+        let dir = std::env::temp_dir().join("shallow_nnue_shards_round_trip");
+        let _ = fs::remove_dir_all(&dir);
+        let samples: Vec<Sample> = (0..7).map(|score| Sample { board: Board::default(), score, result: 0.5, best_move: None }).collect();
+        let manifest = write_shards(&dir, &samples, 3, &FeatureSet::default()).unwrap();
+        assert_eq!(manifest.shards.iter().map(|shard| shard.samples).collect::<Vec<_>>(), vec![3, 3, 1]);
+        assert_eq!(ShardManifest::load(&dir).unwrap(), manifest);
+        assert!(manifest.check_features(&FeatureSet::from_name("absolute768").unwrap()).is_err());
+
+        // Every sample comes back once, whatever the order
+        let reader = ShardReader::open(&dir, ShardOptions::default()).unwrap();
+        let mut scores: Vec<i16> = reader.flat_map(|shard| shard.unwrap()).map(|sample| sample.score).collect();
+        scores.sort();
+        assert_eq!(scores, (0..7).collect::<Vec<_>>());
+        let ordered = ShardOptions { shuffle_shards: false, shuffle_samples: false, ..ShardOptions::default() };
+        assert_eq!(ShardReader::open(&dir, ordered).unwrap().next().unwrap().unwrap(), samples[..3]);
+
+        // A changed shard is caught by its checksum
+        let changed = Sample { board: Board::from_str("4k3/8/8/8/8/8/8/3QK3 w - - 0 1").unwrap(), score: 0, result: 1.0, best_move: None };
+        fs::write(dir.join(&manifest.shards[2].file), format!("{}\n", changed.to_line())).unwrap();
+        let errors = ShardReader::open(&dir, ordered).unwrap().filter(|shard| shard.is_err()).count();
+        assert_eq!(errors, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_append_while_reading() {
+        let dir = std::env::temp_dir().join("shallow_nnue_shards_append");
+        let _ = fs::remove_dir_all(&dir);
+        let sample = |score| Sample { board: Board::default(), score, result: 0.5, best_move: None };
+        write_shards(&dir, &[sample(0), sample(1)], 2, &FeatureSet::default()).unwrap();
+
+        let mut writer = ShardWriter::append(&dir, 2, &FeatureSet::default()).unwrap();
+        assert!(ShardWriter::append(&dir, 2, &FeatureSet::default()).is_err()); // One writer at a time
+        for score in 2..5 {
+            writer.push(&sample(score)).unwrap();
+        }
+        // The full shard is published, the open one isn't
+        let reader = ShardReader::open(&dir, ShardOptions::default()).unwrap();
+        assert_eq!((reader.manifest().shards.len(), reader.flatten().flatten().count()), (2, 4));
+        writer.rotate().unwrap();
+        assert_eq!(ShardManifest::load(&dir).unwrap().total_samples(), 5);
+        assert_eq!(writer.manifest().shards[2].file, "shard-00002.txt");
+        drop(writer);
+
+        let absolute = FeatureSet::from_name("absolute768").unwrap();
+        assert!(matches!(ShardWriter::append(&dir, 2, &absolute), Err(NNUEError::InvalidConfig(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use std::borrow::Borrow;
+use std::sync::{Arc, RwLock};
+
+use chess::Board;
+use tch::{CModule, Device, Kind, Tensor};
+
+use crate::builder::resolve_device;
+use crate::error::NNUEError;
+use crate::features::FeatureSet;
+use crate::guard::TorchGuard;
+use crate::shallow_nnue::{load_model, model_input_kind};
+
+// One loaded TorchScript model for many evaluators. Each evaluator holds an Arc, so the weights
+// live exactly as long as the last evaluator using them. Forwards only take the read lock and run
+// concurrently, switching the module to train mode (dropout sampling) takes the write lock.
+#[derive(Debug)]
+pub struct SharedModel {
+    model: RwLock<CModule>,
+    device: Device,
+    input_kind: Kind, // Dtype the model expects its encoding in
+}
+
+impl SharedModel {
+    pub fn load(global_path_to_model: String, device: Option<Device>) -> Result<Arc<SharedModel>, NNUEError> {
+        let device = resolve_device(device)?;
+        SharedModel::new(load_model(global_path_to_model, device)?, device)
+    }
+
+    pub(crate) fn new(model: CModule, device: Device) -> Result<Arc<SharedModel>, NNUEError> {
+        let input_kind = model_input_kind(&model, device)?;
+        Ok(Arc::new(SharedModel {
+            model: RwLock::new(model),
+            device,
+            input_kind,
+        }))
+    }
+
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    pub fn input_kind(&self) -> Kind {
+        self.input_kind
+    }
+
+    pub(crate) fn with_module<R>(&self, f: impl FnOnce(&CModule) -> R) -> R {
+        // A panic in another evaluator leaves the module itself intact, so poisoning is ignored
+        let model = self.model.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&model)
+    }
+
+    pub(crate) fn forward_ts<T: Borrow<Tensor>>(&self, inputs: &[T]) -> Result<Tensor, NNUEError> {
+        Ok(self.with_module(|model| model.forward_ts(inputs))?)
+    }
+
+    pub(crate) fn guarded_forward(&self, guard: &TorchGuard, input: &Tensor) -> Result<Tensor, NNUEError> {
+        // A single encoding or a stacked batch, checked against the model's dtype and device
+        self.with_module(|model| guard.forward(model, input, self.input_kind, self.device))
+    }
+
+    pub(crate) fn forward_one(&self, guard: &TorchGuard, encoding: &Tensor) -> Result<i16, NNUEError> {
+        // Score of one position from an evaluator's own encoding, see TorchGuard::forward_one
+        self.with_module(|model| guard.forward_one(model, encoding, self.input_kind, self.device))
+    }
+
+    pub fn warmup(&self, iterations: usize, batch_sizes: &[usize]) -> Result<(), NNUEError> {
+        // TorchScript specializes its graph over the first forwards for each input shape, this pays
+        // that cost up front with the single position shape and every batch size given.
+        // tch does not expose freeze/optimize_for_inference, models should be frozen when exported.
+        let position = Tensor::f_zeros(768, (self.input_kind, self.device))?;
+        FeatureSet::default().encode(&Board::default(), &position)?;
+        let batches = batch_sizes
+            .iter()
+            .filter(|size| **size > 0)
+            .map(|size| Tensor::f_stack(&vec![&position; *size], 0))
+            .collect::<Result<Vec<Tensor>, _>>()?;
+
+        tch::no_grad(|| {
+            for _ in 0..iterations {
+                self.forward_ts(&[&position])?;
+                for batch in &batches {
+                    self.forward_ts(&[batch])?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub(crate) fn with_train_mode<R>(&self, f: impl FnOnce(&CModule) -> R) -> R {
+        // Other evaluators wait until the module is back in eval mode
+        let mut model = self.model.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        model.set_train();
+        let result = f(&model);
+        model.set_eval();
+        result
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use chess::{Board, ChessMove, Color, MoveGen};
+
+use crate::error::NNUEError;
+use crate::perspective::{from_white, ScorePerspective};
+use crate::rng::XorShift;
+use crate::search::{SearchOptions, SearchResult, Searcher};
+use crate::shallow_nnue::NNUE;
+
+// Strength limiting for practice opponents, weakening the search three ways: a depth cap, noise on
+// every eval and now and then a random move instead of the searched one. Levels run from 0 to 20 like
+// Stockfish's Skill Level, 20 plays at full strength.
+pub const MAX_SKILL_LEVEL: u8 = 20;
+pub const MIN_ELO: u32 = 800;
+pub const MAX_ELO: u32 = 2400;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Skill {
+    pub max_depth: u8, // Caps the search's depth
+    pub eval_noise: i16, // Largest error added to an eval, in centipawns, the same for a position throughout a search
+    pub blunder_chance: f64, // Chance of a random legal move instead of the searched one
+}
+
+impl Skill {
+    pub fn full() -> Skill {
+        Skill { max_depth: u8::MAX, eval_noise: 0, blunder_chance: 0.0 }
+    }
+
+    pub fn level(level: u8) -> Skill {
+        // Every step down costs depth every five levels, 20 centipawns of noise and a percent of blunders
+        let level = level.min(MAX_SKILL_LEVEL);
+        if level == MAX_SKILL_LEVEL {
+            return Skill::full();
+        }
+        let weakness = MAX_SKILL_LEVEL - level;
+        Skill {
+            max_depth: 1 + level / 5,
+            eval_noise: 20 * weakness as i16,
+            blunder_chance: 0.01 * weakness as f64,
+        }
+    }
+
+    pub fn elo(elo: u32) -> Skill {
+        // Levels spread evenly over MIN_ELO..=MAX_ELO. Only a first guess at the calibration, gauntlet
+        // matches against rated opponents are the way to correct it for a given net.
+        let elo = elo.clamp(MIN_ELO, MAX_ELO);
+        let level = (elo - MIN_ELO) * MAX_SKILL_LEVEL as u32 / (MAX_ELO - MIN_ELO);
+        Skill::level(level as u8)
+    }
+}
+
+// The strength settings a UCI engine exposes, see uci_options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkillSettings {
+    pub level: u8, // "Skill Level"
+    pub limit_strength: bool, // "UCI_LimitStrength", when set elo wins over level
+    pub elo: u32, // "UCI_Elo"
+}
+
+impl Default for SkillSettings {
+    fn default() -> SkillSettings {
+        SkillSettings { level: MAX_SKILL_LEVEL, limit_strength: false, elo: MAX_ELO }
+    }
+}
+
+impl SkillSettings {
+    pub fn uci_options() -> Vec<String> {
+        // Option lines for an engine's reply to "uci"
+        vec![
+            format!("option name Skill Level type spin default {} min 0 max {}", MAX_SKILL_LEVEL, MAX_SKILL_LEVEL),
+            "option name UCI_LimitStrength type check default false".to_string(),
+            format!("option name UCI_Elo type spin default {} min {} max {}", MAX_ELO, MIN_ELO, MAX_ELO),
+        ]
+    }
+
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<bool, NNUEError> {
+        // Applies a "setoption name <name> value <value>", false for options that aren't about strength.
+        // Names are case insensitive as in the UCI protocol.
+        let invalid = || NNUEError::InvalidConfig(format!("bad value {:?} for {}", value, name));
+        match name.trim().to_lowercase().as_str() {
+            "skill level" => self.level = value.trim().parse::<u8>().map_err(|_| invalid())?.min(MAX_SKILL_LEVEL),
+            "uci_limitstrength" => self.limit_strength = value.trim().parse().map_err(|_| invalid())?,
+            "uci_elo" => self.elo = value.trim().parse::<u32>().map_err(|_| invalid())?.clamp(MIN_ELO, MAX_ELO),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn skill(&self) -> Skill {
+        match self.limit_strength {
+            true => Skill::elo(self.elo),
+            false => Skill::level(self.level),
+        }
+    }
+}
+
+fn noise(board: &Board, salt: u64, amplitude: i16) -> i16 {
+    // Fixed for a position and salt, from white's side, in -amplitude..=amplitude
+    if amplitude <= 0 {
+        return 0;
+    }
+    let mut rng = XorShift::new(board.get_hash() ^ salt);
+    rng.next_u64(); // Nearby hashes would give nearby first outputs
+    (rng.below(2 * amplitude as usize + 1) as i32 - amplitude as i32) as i16
+}
+
+// Adds the position's noise to every score of the wrapped evaluator, so the search sees a consistent
+// but wrong eval
+struct NoisyEval<'a> {
+    inner: &'a mut dyn NNUE,
+    board: Board,
+    amplitude: i16,
+    salt: u64,
+}
+
+impl NoisyEval<'_> {
+    fn add_noise(&self, score: i16, board: &Board, side_to_move: Color) -> i16 {
+        // Scores are relative to side_to_move in the side to move perspective
+        let white_noise = noise(board, self.salt, self.amplitude);
+        score.saturating_add(self.inner.perspective().from_side_to_move(from_white(white_noise, side_to_move), side_to_move))
+    }
+}
+
+impl NNUE for NoisyEval<'_> {
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        let score = self.inner.forward(chess_move)?;
+        Ok(self.add_noise(score, &self.board.make_move_new(chess_move), self.board.side_to_move()))
+    }
+
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+        self.board = board;
+        self.inner.set_board_hard(board)
+    }
+
+    fn evaluate(&mut self) -> Result<i16, NNUEError> {
+        let score = self.inner.evaluate()?;
+        Ok(self.add_noise(score, &self.board, self.board.side_to_move()))
+    }
+
+    fn perspective(&self) -> ScorePerspective {
+        self.inner.perspective()
+    }
+}
+
+// A searcher playing at a given skill
+#[derive(Debug)]
+pub struct LimitedSearcher {
+    options: SearchOptions,
+    skill: Skill,
+    rng: XorShift,
+    stop: Option<Arc<AtomicBool>>, // Ends a search early, see Searcher::set_stop
+}
+
+impl LimitedSearcher {
+    pub fn new(options: SearchOptions, skill: Skill, seed: u64) -> LimitedSearcher {
+        LimitedSearcher { options, skill, rng: XorShift::new(seed), stop: None }
+    }
+
+    pub(crate) fn set_stop(&mut self, stop: Option<Arc<AtomicBool>>) {
+        self.stop = stop;
+    }
+
+    pub fn set_skill(&mut self, skill: Skill) {
+        self.skill = skill;
+    }
+
+    pub fn skill(&self) -> Skill {
+        self.skill
+    }
+
+    pub fn search(&mut self, evaluator: &mut dyn NNUE, board: &Board) -> Result<SearchResult, NNUEError> {
+        self.search_to(evaluator, board, self.options.depth)
+    }
+
+    pub fn search_to(&mut self, evaluator: &mut dyn NNUE, board: &Board, depth: u8) -> Result<SearchResult, NNUEError> {
+        // The noise changes from search to search, so a repeated position isn't misjudged the same way.
+        // A stopped search has no best move when it didn't finish its first iteration.
+        let depth = depth.min(self.skill.max_depth).max(1);
+        let mut searcher = Searcher::new(SearchOptions { depth, ..self.options });
+        searcher.set_stop(self.stop.clone());
+        let mut noisy = NoisyEval { inner: evaluator, board: *board, amplitude: self.skill.eval_noise, salt: self.rng.next_u64() };
+        let mut result = searcher.iterate(&mut noisy, board, depth)?;
+
+        if self.rng.next_f64() < self.skill.blunder_chance {
+            let others: Vec<ChessMove> = MoveGen::new_legal(board).filter(|chess_move| Some(*chess_move) != result.best_move).collect();
+            if !others.is_empty() {
+                result.best_move = Some(others[self.rng.below(others.len())]);
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::Square;
+
+    use super::*;
+    use crate::search::tests::MaterialEval;
+
+    #[test]
+    fn test_skill_levels_and_options() {
+        assert_eq!(Skill::level(20), Skill::full());
+        assert_eq!(Skill::level(0), Skill { max_depth: 1, eval_noise: 400, blunder_chance: 0.2 });
+        assert_eq!(Skill::elo(1600), Skill::level(10));
+
+        let mut settings = SkillSettings::default();
+        assert_eq!(SkillSettings::uci_options().len(), 3);
+        assert!(settings.set_option("Skill Level", "5").unwrap());
+        assert_eq!(settings.skill(), Skill::level(5));
+        assert!(settings.set_option("UCI_LimitStrength", "true").unwrap());
+        assert!(settings.set_option("UCI_Elo", "99999").unwrap());
+        assert_eq!(settings.skill(), Skill::full());
+        assert!(!settings.set_option("Hash", "16").unwrap());
+        assert!(settings.set_option("UCI_Elo", "strong").is_err());
+    }
+
+    #[test]
+    fn test_limited_search() {
+        // The free queen is taken at full strength and never with guaranteed blunders
+        let board = Board::from_str("4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1").unwrap();
+        let capture = Some(ChessMove::new(Square::E4, Square::D5, None));
+        let options = SearchOptions { depth: 2, ..SearchOptions::default() };
+        let mut full = LimitedSearcher::new(options, Skill::full(), 1);
+        assert_eq!(full.search(&mut MaterialEval { board }, &board).unwrap().best_move, capture);
+        let blunders = Skill { blunder_chance: 1.0, ..Skill::full() };
+        let mut weak = LimitedSearcher::new(options, blunders, 1);
+        assert_ne!(weak.search(&mut MaterialEval { board }, &board).unwrap().best_move, capture);
+
+        // Noise stays within its amplitude and is fixed per position
+        for salt in 0..100 {
+            assert!(noise(&board, salt, 50).abs() <= 50);
+        }
+        assert_eq!(noise(&board, 7, 50), noise(&board, 7, 50));
+        assert_eq!(noise(&board, 7, 0), 0);
+    }
+}
@@ -0,0 +1,189 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use chess::Board;
+
+use crate::error::NNUEError;
+use crate::repetition::RepetitionHistory;
+use crate::search::{SearchOptions, SearchResult, Searcher};
+use crate::shallow_nnue::{BoxedNNUE, NNUE};
+use crate::tools::match_runner::EvaluatorFactory;
+use crate::tt::TranspositionTable;
+
+// Lazy SMP: every thread searches the same root with iterative deepening and they only share the
+// transposition table. Helpers fill the table with lines the main thread then finds already
+// searched, and their differing depths keep them from walking the tree in lockstep. The main
+// thread's result is the answer, helpers are stopped once it is done.
+pub const MAX_THREADS: usize = 256;
+pub const MAX_HASH_MB: usize = 1 << 16;
+
+// The settings a UCI engine exposes for it, see uci_options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmpSettings {
+    pub threads: usize, // "Threads", the main thread included
+    pub hash_mb: usize, // "Hash", size of the shared table in megabytes
+}
+
+impl Default for SmpSettings {
+    fn default() -> SmpSettings {
+        SmpSettings { threads: 1, hash_mb: 16 }
+    }
+}
+
+impl SmpSettings {
+    pub fn uci_options() -> Vec<String> {
+        vec![
+            format!("option name Threads type spin default 1 min 1 max {}", MAX_THREADS),
+            format!("option name Hash type spin default 16 min 1 max {}", MAX_HASH_MB),
+        ]
+    }
+
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<bool, NNUEError> {
+        // Like SkillSettings::set_option, false for options that aren't about threads or the table
+        let invalid = || NNUEError::InvalidConfig(format!("bad value {:?} for {}", value, name));
+        match name.trim().to_lowercase().as_str() {
+            "threads" => self.threads = value.trim().parse::<usize>().map_err(|_| invalid())?.clamp(1, MAX_THREADS),
+            "hash" => self.hash_mb = value.trim().parse::<usize>().map_err(|_| invalid())?.clamp(1, MAX_HASH_MB),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+pub struct LazySmp {
+    options: SearchOptions,
+    settings: SmpSettings,
+    tt: Arc<TranspositionTable>,
+    stop: Arc<AtomicBool>,
+    main: Searcher,
+    helpers: Vec<(Searcher, BoxedNNUE)>, // Each helper thread keeps its own evaluator
+}
+
+impl LazySmp {
+    pub fn new(options: SearchOptions, settings: SmpSettings, evaluator: EvaluatorFactory) -> Result<LazySmp, NNUEError> {
+        // The factory makes the helpers' evaluators, e.g. match_runner::shared_evaluator so they
+        // all run on one loaded model. The main thread uses the evaluator passed to search.
+        let tt = Arc::new(TranspositionTable::new(settings.hash_mb));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut main = Searcher::new(options);
+        main.set_transposition_table(Some(Arc::clone(&tt)));
+        main.set_stop(Some(Arc::clone(&stop)));
+        let mut smp = LazySmp {
+            options,
+            settings: SmpSettings { threads: 1, ..settings },
+            tt,
+            stop,
+            main,
+            helpers: Vec::new(),
+        };
+        smp.configure(settings, evaluator)?;
+        Ok(smp)
+    }
+
+    pub fn settings(&self) -> SmpSettings {
+        self.settings
+    }
+
+    pub(crate) fn stop_flag(&self) -> Arc<AtomicBool> {
+        // Setting it during a search stops every thread, the main one returns its last finished iteration
+        Arc::clone(&self.stop)
+    }
+
+    pub fn transposition_table(&self) -> &TranspositionTable {
+        // For the hashfull the engine reports and clearing it on ucinewgame
+        &self.tt
+    }
+
+    pub fn configure(&mut self, settings: SmpSettings, evaluator: EvaluatorFactory) -> Result<(), NNUEError> {
+        // A new hash size starts an empty table, the thread count adds or drops helpers
+        let settings = SmpSettings { threads: settings.threads.clamp(1, MAX_THREADS), hash_mb: settings.hash_mb.clamp(1, MAX_HASH_MB) };
+        if settings.hash_mb != self.settings.hash_mb {
+            self.tt = Arc::new(TranspositionTable::new(settings.hash_mb));
+            self.main.set_transposition_table(Some(Arc::clone(&self.tt)));
+            for (searcher, _) in self.helpers.iter_mut() {
+                searcher.set_transposition_table(Some(Arc::clone(&self.tt)));
+            }
+        }
+        self.helpers.truncate(settings.threads - 1);
+        while self.helpers.len() < settings.threads - 1 {
+            let mut searcher = Searcher::new(self.options);
+            searcher.set_transposition_table(Some(Arc::clone(&self.tt)));
+            searcher.set_stop(Some(Arc::clone(&self.stop)));
+            self.helpers.push((searcher, evaluator()?));
+        }
+        self.settings = settings;
+        Ok(())
+    }
+
+    pub fn set_game_history(&mut self, history: &RepetitionHistory) {
+        self.main.set_game_history(history);
+        for (searcher, _) in self.helpers.iter_mut() {
+            searcher.set_game_history(history);
+        }
+    }
+
+    pub fn search(&mut self, evaluator: &mut dyn NNUE, board: &Board) -> Result<SearchResult, NNUEError> {
+        self.search_to(evaluator, board, self.options.depth)
+    }
+
+    pub fn search_to(&mut self, evaluator: &mut dyn NNUE, board: &Board, depth: u8) -> Result<SearchResult, NNUEError> {
+        // Nodes are summed over all threads. A helper whose evaluator fails just stops helping.
+        let depth = depth.max(1);
+        self.stop.store(false, Ordering::Relaxed);
+        self.tt.new_search();
+        let (main, helpers, stop) = (&mut self.main, &mut self.helpers, &self.stop);
+        thread::scope(|scope| {
+            let handles: Vec<_> = helpers
+                .iter_mut()
+                .enumerate()
+                .map(|(index, (searcher, helper))| {
+                    let depth = depth.saturating_add((index % 2 == 0) as u8);
+                    scope.spawn(move || searcher.iterate(helper.as_mut(), board, depth))
+                })
+                .collect();
+            let result = main.iterate(evaluator, board, depth);
+            stop.store(true, Ordering::Relaxed);
+            let helper_nodes: u64 = handles
+                .into_iter()
+                .filter_map(|handle| handle.join().ok()?.ok())
+                .map(|result| result.nodes)
+                .sum();
+            result.map(|result| SearchResult { nodes: result.nodes + helper_nodes, ..result })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::{ChessMove, Square};
+
+    use super::*;
+    use crate::search::tests::MaterialEval;
+
+    #[test]
+    fn test_lazy_smp_search() {
+        let mut settings = SmpSettings::default();
+        assert_eq!(SmpSettings::uci_options().len(), 2);
+        assert!(settings.set_option("Threads", "4").unwrap());
+        assert!(settings.set_option("hash", "1").unwrap());
+        assert!(!settings.set_option("Skill Level", "3").unwrap());
+        assert!(settings.set_option("Threads", "many").is_err());
+        assert_eq!(settings, SmpSettings { threads: 4, hash_mb: 1 });
+
+        // Four threads take the free queen just like one
+        let board = Board::from_str("4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1").unwrap();
+        let factory = || Ok(Box::new(MaterialEval { board: Board::default() }) as BoxedNNUE);
+        let options = SearchOptions { depth: 3, ..SearchOptions::default() };
+        let mut smp = LazySmp::new(options, settings, &factory).unwrap();
+        let result = smp.search(&mut MaterialEval { board }, &board).unwrap();
+        assert_eq!(result.best_move, Some(ChessMove::new(Square::E4, Square::D5, None)));
+        assert_eq!(result.score, Searcher::new(options).search(&mut MaterialEval { board }, &board).unwrap().score);
+
+        smp.configure(SmpSettings { threads: 2, ..settings }, &factory).unwrap();
+        assert_eq!(smp.helpers.len(), 1);
+        assert!(smp.search(&mut MaterialEval { board }, &board).unwrap().nodes > 0);
+    }
+}
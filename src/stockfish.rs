@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::NNUEError;
+use crate::native::{NativeWeights, Perspectives};
+use crate::network::Activation;
+
+// Writer for the first Stockfish NNUE format (Stockfish 12 and 13, "HalfKP_256x2-32-32"), so nets
+// trained here can be compared inside Stockfish-derived engines. Little-endian throughout:
+//   version u32 | hash u32 | description length u32 | description
+//   transformer hash u32 | biases i16[256] | weights i16[41024][256]
+//   network hash u32 | per layer: biases i32[outputs], weights i8[outputs][inputs]
+// HalfKP has one block of 641 piece-square features for every king square of the perspective, the
+// crate's 768 features are copied into each block. Stockfish has no king features, so nets must keep
+// their king rows at zero, and it shares the transformer between both sides like Perspectives::Shared.
+const VERSION: u32 = 0x7AF32F16;
+const NUM_FEATURES: usize = 768;
+const HALF_DIMENSIONS: usize = 256;
+const HIDDEN: usize = 32;
+const PS_END: usize = 641; // Features in one king block, the first is never set
+const HALFKP_FEATURES: usize = 64 * PS_END;
+// Quantization of the Stockfish 12 inference code
+const ACTIVATION_SCALE: f32 = 127.0; // Clipped ReLU's 1.0
+const WEIGHT_SCALE: f32 = 64.0; // Hidden layer outputs are shifted right by 6
+const OUTPUT_SCALE: f32 = 16.0; // The output is divided by FV_SCALE
+const PAWN_VALUE: f32 = 208.0; // Internal units of a pawn, the nets here score in centipawns
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StockfishExport {
+    pub bytes: usize,
+    pub clipped: usize, // Weights and biases that didn't fit their integer type and were saturated
+}
+
+pub fn architecture_hashes() -> (u32, u32) {
+    // The transformer and network hashes Stockfish 12 computes for HalfKP_256x2-32-32
+    // HalfKP on the perspective's own king flips the lowest bit of its base hash
+    let transformer = (0x5D69D5B9u32 ^ 1) ^ (2 * HALF_DIMENSIONS) as u32;
+    let affine = |outputs: u32, previous: u32| 0xCC03DAE4u32.wrapping_add(outputs) ^ (previous >> 1) ^ (previous << 31);
+    let clipped_relu = |previous: u32| 0x538D24C7u32.wrapping_add(previous);
+    let input_slice = 0xEC42E90Du32 ^ (2 * HALF_DIMENSIONS) as u32;
+    let network = affine(1, clipped_relu(affine(HIDDEN as u32, clipped_relu(affine(HIDDEN as u32, input_slice)))));
+    (transformer, network)
+}
+
+fn check_architecture(weights: &NativeWeights) -> Result<(), NNUEError> {
+    let expected = vec![(NUM_FEATURES, HALF_DIMENSIONS), (2 * HALF_DIMENSIONS, HIDDEN), (HIDDEN, HIDDEN), (HIDDEN, 1)];
+    if weights.layer_sizes() != expected {
+        return Err(NNUEError::InvalidConfig(format!("layers {:?} are not 768x256 shared, 512-32-32-1", weights.layer_sizes())));
+    }
+    if weights.perspectives() != Perspectives::Shared || weights.config().activation != Activation::ClippedRelu {
+        return Err(NNUEError::InvalidConfig("Stockfish nets share the transformer between sides and use clipped ReLU".to_string()));
+    }
+    // Own king plane 5, the opponent's plane 11
+    let king_rows = [5, 11].iter().flat_map(|plane| plane * 64 * HALF_DIMENSIONS..(plane + 1) * 64 * HALF_DIMENSIONS);
+    if king_rows.map(|index| weights.weights(0)[index]).any(|weight| weight != 0.0) {
+        return Err(NNUEError::InvalidConfig("HalfKP has no king features, the king rows must be zero".to_string()));
+    }
+    Ok(())
+}
+
+fn feature_row(halfkp_index: usize) -> Option<usize> {
+    // Row of the crate's first layer behind a HalfKP feature. Pieces run pawn to queen with the
+    // perspective's own piece first, squares are oriented the same way in both encodings.
+    let piece_square = halfkp_index % PS_END;
+    if piece_square == 0 {
+        return None;
+    }
+    let (block, square) = ((piece_square - 1) / 64, (piece_square - 1) % 64);
+    let plane = if block % 2 == 0 { block / 2 } else { 6 + block / 2 };
+    Some(plane * 64 + square)
+}
+
+fn quantize<T: TryFrom<i64> + Copy>(value: f32, scale: f32, min: T, max: T, clipped: &mut usize) -> T {
+    let scaled = (value * scale).round() as i64;
+    T::try_from(scaled).unwrap_or_else(|_| {
+        *clipped += 1;
+        if scaled < 0 { min } else { max }
+    })
+}
+
+pub fn to_stockfish_bytes(weights: &NativeWeights, description: &str) -> Result<(Vec<u8>, usize), NNUEError> {
+    // The file contents and how many values were clipped
+    check_architecture(weights)?;
+    let (transformer_hash, network_hash) = architecture_hashes();
+    let mut clipped = 0;
+    let mut bytes: Vec<u8> = Vec::with_capacity(2 * HALFKP_FEATURES * HALF_DIMENSIONS + 64 * 1024);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(transformer_hash ^ network_hash).to_le_bytes());
+    bytes.extend_from_slice(&(description.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(description.as_bytes());
+
+    bytes.extend_from_slice(&transformer_hash.to_le_bytes());
+    for bias in weights.biases(0) {
+        bytes.extend_from_slice(&quantize(*bias, ACTIVATION_SCALE, i16::MIN, i16::MAX, &mut clipped).to_le_bytes());
+    }
+    // Quantized once, so a clipped weight counts once however many king blocks it is copied into
+    let first = weights.weights(0);
+    let quantized_rows: Vec<i16> = first.iter().map(|weight| quantize(*weight, ACTIVATION_SCALE, i16::MIN, i16::MAX, &mut clipped)).collect();
+    for index in 0..HALFKP_FEATURES {
+        match feature_row(index) {
+            Some(row) => {
+                for weight in &quantized_rows[row * HALF_DIMENSIONS..(row + 1) * HALF_DIMENSIONS] {
+                    bytes.extend_from_slice(&weight.to_le_bytes());
+                }
+            }
+            None => bytes.extend(std::iter::repeat_n(0u8, 2 * HALF_DIMENSIONS)),
+        }
+    }
+    bytes.extend_from_slice(&network_hash.to_le_bytes());
+
+    for layer in 1..4 {
+        // Hidden layers keep their 127 based inputs and outputs, the last one scores in internal units
+        let last = layer == 3;
+        let (weight_scale, bias_scale) = match last {
+            false => (WEIGHT_SCALE, WEIGHT_SCALE * ACTIVATION_SCALE),
+            true => (OUTPUT_SCALE * PAWN_VALUE / 100.0 / ACTIVATION_SCALE, OUTPUT_SCALE * PAWN_VALUE / 100.0),
+        };
+        for bias in weights.biases(layer) {
+            bytes.extend_from_slice(&quantize(*bias, bias_scale, i32::MIN, i32::MAX, &mut clipped).to_le_bytes());
+        }
+        for weight in weights.weights(layer) {
+            bytes.extend_from_slice(&quantize(*weight, weight_scale, i8::MIN, i8::MAX, &mut clipped).to_le_bytes());
+        }
+    }
+    Ok((bytes, clipped))
+}
+
+pub fn export_stockfish<P: AsRef<Path>>(weights: &NativeWeights, path: P, description: &str) -> Result<StockfishExport, NNUEError> {
+    let (bytes, clipped) = to_stockfish_bytes(weights, description)?;
+    File::create(path)?.write_all(&bytes)?;
+    Ok(StockfishExport { bytes: bytes.len(), clipped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native::{save_network_with_perspectives, LayerWeights};
+
+    fn layer(inputs: usize, outputs: usize, weight: f32) -> LayerWeights {
+        LayerWeights { inputs, outputs, weights: vec![weight; inputs * outputs], biases: vec![0.5; outputs] }
+    }
+
+    #[test]
+    fn test_export_halfkp() {
+        // The hash every Stockfish 12 net carries
+        let (transformer, network) = architecture_hashes();
+        assert_eq!(transformer ^ network, 0x3E5AA6EE);
+
+        let mut first = layer(NUM_FEATURES, HALF_DIMENSIONS, 0.0);
+        first.weights[28 * HALF_DIMENSIONS] = 0.25; // Own pawn on E4, first neuron
+        let mut network = vec![first, layer(2 * HALF_DIMENSIONS, HIDDEN, 0.01), layer(HIDDEN, HIDDEN, 0.01), layer(HIDDEN, 1, 1000.0)];
+        let path = std::env::temp_dir().join("shallow_nnue_stockfish_export.bin");
+        save_network_with_perspectives(&path, &network, Activation::ClippedRelu, Perspectives::Shared).unwrap();
+        let (bytes, clipped) = to_stockfish_bytes(&NativeWeights::load(&path).unwrap(), "test").unwrap();
+        let header = 12 + 4;
+        let transformer_size = 4 + 2 * HALF_DIMENSIONS + 2 * HALFKP_FEATURES * HALF_DIMENSIONS;
+        let network_size = 4 + HIDDEN * 4 + HIDDEN * 2 * HALF_DIMENSIONS + HIDDEN * 4 + HIDDEN * HIDDEN + 4 + HIDDEN;
+        assert_eq!(bytes.len(), header + transformer_size + network_size);
+        assert_eq!(clipped, HIDDEN); // 1000 * 16 * 2.08 / 127 leaves the int8 range
+        assert_eq!(&bytes[8..16], &[4, 0, 0, 0, b't', b'e', b's', b't']);
+
+        // Own pawn on E4 with the king on G1: feature 1 + 28 in the block of square 6, 0.25 * 127 rounds to 32
+        let offset = header + 4 + 2 * HALF_DIMENSIONS + 2 * HALF_DIMENSIONS * (6 * PS_END + 1 + 28);
+        assert_eq!(i16::from_le_bytes([bytes[offset], bytes[offset + 1]]), 32);
+
+        // King weights have nowhere to go
+        network[0].weights[5 * 64 * HALF_DIMENSIONS] = 0.1;
+        save_network_with_perspectives(&path, &network, Activation::ClippedRelu, Perspectives::Shared).unwrap();
+        assert!(matches!(to_stockfish_bytes(&NativeWeights::load(&path).unwrap(), ""), Err(NNUEError::InvalidConfig(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
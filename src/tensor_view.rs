@@ -0,0 +1,87 @@
+use tch::{CModule, Device, Kind, Tensor};
+
+use crate::error::NNUEError;
+
+// Rust-owned f32 buffer that tch can read in place on the CPU.
+// Invariants that make the views sound, all upheld by this type:
+//   - the memory is a boxed slice, so it never moves or reallocates while it exists
+//   - a view only lives inside with_view, which borrows the buffer, so it can't be written meanwhile
+//   - forward copies any output that still points into the buffer before handing it out
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorBuffer {
+    data: Box<[f32]>,
+    shape: Vec<i64>,
+}
+
+impl TensorBuffer {
+    pub fn zeros(shape: &[i64]) -> TensorBuffer {
+        let len = shape.iter().product::<i64>().max(0) as usize;
+        TensorBuffer {
+            data: vec![0.0; len].into_boxed_slice(),
+            shape: shape.to_vec(),
+        }
+    }
+
+    pub fn shape(&self) -> &[i64] {
+        &self.shape
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub(crate) fn with_view<R>(&self, f: impl FnOnce(&Tensor) -> R) -> R {
+        // The view must not escape f, callers in this crate never shallow_clone it
+        let strides = contiguous_strides(&self.shape);
+        // Safety: the pointer is valid for the buffer's length, the shape covers exactly that many f32s
+        // and the borrow of self keeps the memory alive and unchanged until f returns
+        let view = unsafe { Tensor::from_blob(self.data.as_ptr() as *const u8, &self.shape, &strides, Kind::Float, Device::Cpu) };
+        f(&view)
+    }
+
+    pub fn forward(&self, model: &CModule) -> Result<Tensor, NNUEError> {
+        // Runs a CPU model straight on the buffer, without copying it into a tch-owned tensor
+        self.with_view(|view| {
+            let output = tch::no_grad(|| model.forward_ts(&[view]))?;
+            let start = self.data.as_ptr() as usize;
+            let end = start + std::mem::size_of_val(&*self.data);
+            let pointer = output.data_ptr() as usize;
+            // A model that returns (part of) its input would otherwise hand out an alias
+            if (start..end).contains(&pointer) {
+                return Ok(output.copy());
+            }
+            Ok(output)
+        })
+    }
+}
+
+fn contiguous_strides(shape: &[i64]) -> Vec<i64> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_view_reads_buffer() {
+        assert_eq!(contiguous_strides(&[2, 3, 4]), vec![12, 4, 1]);
+
+        let mut buffer = TensorBuffer::zeros(&[2, 3]);
+        buffer.as_mut_slice().copy_from_slice(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let (sum, last) = buffer.with_view(|view| (view.sum(Kind::Float).double_value(&[]), view.double_value(&[1, 2])));
+        assert_eq!((sum, last), (21.0, 6.0));
+
+        // Writes after the view is gone are seen by the next one
+        buffer.as_mut_slice()[5] = 10.0;
+        assert_eq!(buffer.with_view(|view| view.double_value(&[1, 2])), 10.0);
+    }
+}
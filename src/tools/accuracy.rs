@@ -0,0 +1,185 @@
+use std::fs;
+use std::path::Path;
+
+use chess::Color;
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::error::NNUEError;
+use crate::tools::blunders::{AnnotatedMove, GameReport, MoveClass};
+
+// Evaluations are capped before losses are taken, so a missed mate doesn't outweigh a whole game
+const EVAL_CAP: f64 = 1000.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub player: String,
+    pub games: usize,
+    pub moves: usize,
+    pub average_loss: f64, // Average centipawn loss per move
+    pub accuracy: f64, // 0-100, averaged over the games
+    pub inaccuracies: usize,
+    pub mistakes: usize,
+    pub blunders: usize,
+}
+
+pub fn win_percent(eval: i16) -> f64 {
+    // Lichess' expected score for a centipawn eval, 50 is even
+    let eval = (eval as f64).clamp(-EVAL_CAP, EVAL_CAP);
+    50.0 + 50.0 * (2.0 / (1.0 + (-0.00368208 * eval).exp()) - 1.0)
+}
+
+pub fn move_accuracy(annotated: &AnnotatedMove) -> f64 {
+    // Lichess' accuracy of a single move from the drop in win percentage
+    let drop = (win_percent(annotated.eval_before) - win_percent(annotated.eval_after)).max(0.0);
+    (103.1668 * (-0.04354 * drop).exp() - 3.1669).clamp(0.0, 100.0)
+}
+
+fn capped_loss(annotated: &AnnotatedMove) -> f64 {
+    let cap = |eval: i16| (eval as f64).clamp(-EVAL_CAP, EVAL_CAP);
+    (cap(annotated.eval_before) - cap(annotated.eval_after)).max(0.0)
+}
+
+fn game_accuracy(accuracies: &[f64]) -> f64 {
+    // Lichess blends the mean with the harmonic mean, so a few bad moves weigh more than a
+    // long run of good ones. Their volatility weighting of the mean is left out.
+    if accuracies.is_empty() {
+        return 100.0;
+    }
+    let n = accuracies.len() as f64;
+    let mean = accuracies.iter().sum::<f64>() / n;
+    let harmonic = n / accuracies.iter().map(|accuracy| 1.0 / accuracy.max(1.0)).sum::<f64>();
+    (mean + harmonic) / 2.0
+}
+
+fn player_stats(report: &GameReport, colour: Color) -> PlayerStats {
+    let moves: Vec<&AnnotatedMove> = report.moves.iter().filter(|annotated| annotated.mover == colour).collect();
+    let accuracies: Vec<f64> = moves.iter().map(|annotated| move_accuracy(annotated)).collect();
+    let total_loss: f64 = moves.iter().map(|annotated| capped_loss(annotated)).sum();
+    PlayerStats {
+        player: if colour == Color::White { report.white.clone() } else { report.black.clone() },
+        games: 1,
+        moves: moves.len(),
+        average_loss: if moves.is_empty() { 0.0 } else { total_loss / moves.len() as f64 },
+        accuracy: game_accuracy(&accuracies),
+        inaccuracies: report.count(colour, MoveClass::Inaccuracy),
+        mistakes: report.count(colour, MoveClass::Mistake),
+        blunders: report.count(colour, MoveClass::Blunder),
+    }
+}
+
+pub fn game_stats(report: &GameReport) -> [PlayerStats; 2] {
+    // White first
+    [player_stats(report, Color::White), player_stats(report, Color::Black)]
+}
+
+pub fn collection_stats(reports: &[GameReport]) -> Vec<PlayerStats> {
+    // One entry per player name over all games, sorted by name. Losses are averaged over
+    // moves and accuracies over games.
+    let mut totals: FnvHashMap<String, (PlayerStats, f64, f64)> = FnvHashMap::default();
+    for stats in reports.iter().flat_map(game_stats) {
+        let entry = totals.entry(stats.player.clone()).or_insert_with(|| {
+            let empty = PlayerStats {
+                player: stats.player.clone(),
+                games: 0,
+                moves: 0,
+                average_loss: 0.0,
+                accuracy: 0.0,
+                inaccuracies: 0,
+                mistakes: 0,
+                blunders: 0,
+            };
+            (empty, 0.0, 0.0)
+        });
+        let (total, loss, accuracy) = entry;
+        total.games += 1;
+        total.moves += stats.moves;
+        total.inaccuracies += stats.inaccuracies;
+        total.mistakes += stats.mistakes;
+        total.blunders += stats.blunders;
+        *loss += stats.average_loss * stats.moves as f64;
+        *accuracy += stats.accuracy;
+    }
+
+    let mut players: Vec<PlayerStats> = totals
+        .into_values()
+        .map(|(mut total, loss, accuracy)| {
+            total.average_loss = if total.moves == 0 { 0.0 } else { loss / total.moves as f64 };
+            total.accuracy = accuracy / total.games as f64;
+            total
+        })
+        .collect();
+    players.sort_by(|a, b| a.player.cmp(&b.player));
+    players
+}
+
+pub fn to_json(stats: &[PlayerStats]) -> Result<String, NNUEError> {
+    serde_json::to_string_pretty(stats).map_err(|err| NNUEError::InvalidData(err.to_string()))
+}
+
+pub(crate) fn csv_field(text: &str) -> String {
+    // Names come from PGN headers and may contain commas or quotes
+    match text.contains([',', '"']) {
+        true => format!("\"{}\"", text.replace('"', "\"\"")),
+        false => text.to_string(),
+    }
+}
+
+pub fn to_csv(stats: &[PlayerStats]) -> String {
+    let mut csv = String::from("player,games,moves,average_loss,accuracy,inaccuracies,mistakes,blunders\n");
+    for player in stats {
+        let name = csv_field(&player.player);
+        csv.push_str(&format!(
+            "{},{},{},{:.2},{:.2},{},{},{}\n",
+            name, player.games, player.moves, player.average_loss, player.accuracy, player.inaccuracies, player.mistakes, player.blunders
+        ));
+    }
+    csv
+}
+
+pub fn write_stats<P: AsRef<Path>>(path: P, stats: &[PlayerStats]) -> Result<(), NNUEError> {
+    // CSV for a .csv extension, JSON otherwise
+    let path = path.as_ref();
+    let contents = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => to_csv(stats),
+        _ => to_json(stats)?,
+    };
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chess::Board;
+
+    use super::*;
+    use crate::pgn::PgnReader;
+    use crate::search::tests::MaterialEval;
+    use crate::tools::blunders::{analyze_game, BlunderThresholds};
+
+    #[test]
+    fn test_accuracy_per_player() {
+        let pgn = "[White \"Alice\"]\n[Black \"Bob\"]\n[Result \"*\"]\n\n1. e4 d5 2. a3 dxe4 *\n\n\
+                   [White \"Bob\"]\n[Black \"Alice\"]\n[Result \"*\"]\n\n1. e4 d5 2. exd5 *\n";
+        let mut evaluator = MaterialEval { board: Board::default() };
+        let reports: Vec<GameReport> = PgnReader::new(pgn.as_bytes())
+            .map(|game| analyze_game(&mut evaluator, &game.unwrap(), &BlunderThresholds::default()).unwrap())
+            .collect();
+
+        let [white, black] = game_stats(&reports[0]);
+        assert_eq!((white.moves, white.mistakes), (2, 1));
+        assert!((white.average_loss - 50.0).abs() < 1e-9);
+        assert!(white.accuracy < black.accuracy && black.accuracy > 99.0);
+        assert!((win_percent(0) - 50.0).abs() < 1e-9);
+
+        let players = collection_stats(&reports);
+        assert_eq!(players.iter().map(|player| player.player.as_str()).collect::<Vec<_>>(), vec!["Alice", "Bob"]);
+        assert_eq!((players[0].games, players[0].moves), (2, 3));
+        assert!((players[0].average_loss - 100.0 / 3.0).abs() < 1e-9);
+
+        let csv = to_csv(&players);
+        assert!(csv.lines().nth(1).unwrap().starts_with("Alice,2,3,33.33,"));
+        let parsed: Vec<PlayerStats> = serde_json::from_str(&to_json(&players).unwrap()).unwrap();
+        assert_eq!(parsed.len(), 2);
+    }
+}
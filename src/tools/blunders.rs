@@ -0,0 +1,250 @@
+use std::path::Path;
+
+use chess::{Board, BoardStatus, ChessMove, Color, MoveGen};
+
+use crate::error::NNUEError;
+use crate::eval_server::BatchEvaluator;
+use crate::pgn::{PgnGame, PgnReader};
+use crate::search::MATE_SCORE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlunderThresholds {
+    // Smallest loss against the best move, from the mover's side, for each class
+    pub inaccuracy: i16,
+    pub mistake: i16,
+    pub blunder: i16,
+}
+
+impl Default for BlunderThresholds {
+    fn default() -> BlunderThresholds {
+        BlunderThresholds {
+            inaccuracy: 50,
+            mistake: 100,
+            blunder: 300,
+        }
+    }
+}
+
+impl BlunderThresholds {
+    pub fn classify(&self, loss: i16) -> MoveClass {
+        if loss >= self.blunder {
+            MoveClass::Blunder
+        } else if loss >= self.mistake {
+            MoveClass::Mistake
+        } else if loss >= self.inaccuracy {
+            MoveClass::Inaccuracy
+        } else {
+            MoveClass::Good
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MoveClass {
+    Good,
+    Inaccuracy,
+    Mistake,
+    Blunder,
+}
+
+impl MoveClass {
+    pub fn annotation(&self) -> &'static str {
+        // The usual PGN suffixes
+        match self {
+            MoveClass::Good => "",
+            MoveClass::Inaccuracy => "?!",
+            MoveClass::Mistake => "?",
+            MoveClass::Blunder => "??",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnnotatedMove {
+    pub ply: usize, // Counted from 0 at the game's start position
+    pub mover: Color,
+    pub chess_move: ChessMove,
+    pub best_move: ChessMove,
+    pub eval_before: i16, // Score of the best move, from the mover's side
+    pub eval_after: i16, // Score of the played move, from the mover's side
+    pub loss: i16, // eval_before - eval_after, never negative
+    pub class: MoveClass,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameReport {
+    pub white: String,
+    pub black: String,
+    pub start: Board,
+    pub moves: Vec<AnnotatedMove>,
+}
+
+impl GameReport {
+    pub fn count(&self, mover: Color, class: MoveClass) -> usize {
+        self.moves.iter().filter(|annotated| annotated.mover == mover && annotated.class == class).count()
+    }
+
+    pub fn render(&self) -> String {
+        // Movetext in UCI notation, classified moves get their suffix and a comment with the best move
+        let mut text = format!("{} - {}\n", self.white, self.black);
+        let first_black = self.start.side_to_move() == Color::Black;
+        for annotated in &self.moves {
+            let number = (annotated.ply + first_black as usize) / 2 + 1;
+            if annotated.mover == Color::White {
+                text.push_str(&format!("{}. ", number));
+            } else if annotated.ply == 0 {
+                text.push_str(&format!("{}... ", number));
+            }
+            text.push_str(&format!("{}{} ", annotated.chess_move, annotated.class.annotation()));
+            if annotated.class != MoveClass::Good {
+                text.push_str(&format!(
+                    "{{{} -> {}, best {}}} ",
+                    annotated.eval_before, annotated.eval_after, annotated.best_move
+                ));
+            }
+        }
+        text.truncate(text.trim_end().len());
+        text.push('\n');
+
+        for (colour, name) in [(Color::White, &self.white), (Color::Black, &self.black)] {
+            text.push_str(&format!(
+                "{}: {} inaccuracies, {} mistakes, {} blunders\n",
+                name,
+                self.count(colour, MoveClass::Inaccuracy),
+                self.count(colour, MoveClass::Mistake),
+                self.count(colour, MoveClass::Blunder)
+            ));
+        }
+        text
+    }
+
+    #[cfg(feature = "render")]
+    pub fn ply_svg(&self, ply: usize, options: &crate::render::SvgOptions) -> Option<String> {
+        // The position before a move with arrows for the best move and, if different, the played one,
+        // and the bar showing the best move's score
+        use crate::analysis::{AnalysisMap, Arrow};
+        use crate::perspective::to_white;
+
+        let annotated = self.moves.get(ply)?;
+        let board = self.moves[..ply].iter().fold(self.start, |board, previous| board.make_move_new(previous.chess_move));
+        let arrow = |chess_move: ChessMove, score: i16, rank: usize| Arrow {
+            chess_move: chess_move.to_string(),
+            from: chess_move.get_source().to_index() as u8,
+            to: chess_move.get_dest().to_index() as u8,
+            score,
+            rank,
+        };
+        let mut arrows = vec![arrow(annotated.best_move, annotated.eval_before, 0)];
+        if annotated.chess_move != annotated.best_move {
+            arrows.push(arrow(annotated.chess_move, annotated.eval_after, 1));
+        }
+        let map = AnalysisMap { fen: board.to_string(), arrows, squares: Vec::new() };
+        let white_score = to_white(annotated.eval_before, annotated.mover);
+        Some(crate::render::position_svg(&board, white_score, Some(&map), options))
+    }
+}
+
+pub(crate) fn score_moves<E: BatchEvaluator + ?Sized>(evaluator: &mut E, board: &Board) -> Result<Vec<(ChessMove, i16)>, NNUEError> {
+    // Every legal move with its score from the mover's side. Positions that ended the game are
+    // scored directly, the rest go to the evaluator as one batch.
+    let moves: Vec<ChessMove> = MoveGen::new_legal(board).collect();
+    let children: Vec<Board> = moves.iter().map(|chess_move| board.make_move_new(*chess_move)).collect();
+    let open: Vec<Board> = children.iter().filter(|child| child.status() == BoardStatus::Ongoing).copied().collect();
+    let mut scores = evaluator.evaluate_batch(&open)?.into_iter();
+
+    let mut scored = Vec::with_capacity(moves.len());
+    for (chess_move, child) in moves.into_iter().zip(&children) {
+        let score = match child.status() {
+            BoardStatus::Checkmate => MATE_SCORE,
+            BoardStatus::Stalemate => 0,
+            // Scores are from the side to move of the child, which is the opponent
+            BoardStatus::Ongoing => scores
+                .next()
+                .ok_or_else(|| NNUEError::InvalidData("evaluator returned too few scores".to_string()))?
+                .saturating_neg(),
+        };
+        scored.push((chess_move, score));
+    }
+    Ok(scored)
+}
+
+pub fn analyze_game<E: BatchEvaluator + ?Sized>(evaluator: &mut E, game: &PgnGame, thresholds: &BlunderThresholds) -> Result<GameReport, NNUEError> {
+    // Compares every played move with the best move by the evaluator's one ply score
+    let mut board = game.start;
+    let mut moves = Vec::with_capacity(game.moves.len());
+    for (ply, pgn_move) in game.moves.iter().enumerate() {
+        let scored = score_moves(evaluator, &board)?;
+        let (best_move, eval_before) = scored
+            .iter()
+            .copied()
+            .max_by_key(|(_, score)| *score)
+            .ok_or_else(|| NNUEError::InvalidData(format!("no legal moves before ply {}", ply)))?;
+        let eval_after = scored
+            .iter()
+            .find(|(chess_move, _)| *chess_move == pgn_move.chess_move)
+            .map(|(_, score)| *score)
+            .ok_or_else(|| NNUEError::InvalidData(format!("illegal move {} at ply {}", pgn_move.chess_move, ply)))?;
+
+        let loss = eval_before.saturating_sub(eval_after).max(0);
+        moves.push(AnnotatedMove {
+            ply,
+            mover: board.side_to_move(),
+            chess_move: pgn_move.chess_move,
+            best_move,
+            eval_before,
+            eval_after,
+            loss,
+            class: thresholds.classify(loss),
+        });
+        board = board.make_move_new(pgn_move.chess_move);
+    }
+
+    Ok(GameReport {
+        white: game.header("White").unwrap_or("?").to_string(),
+        black: game.header("Black").unwrap_or("?").to_string(),
+        start: game.start,
+        moves,
+    })
+}
+
+pub fn analyze_pgn<P: AsRef<Path>, E: BatchEvaluator + ?Sized>(evaluator: &mut E, path: P, thresholds: &BlunderThresholds) -> Result<Vec<GameReport>, NNUEError> {
+    // One report for every game the reader accepts, malformed games are skipped like everywhere else
+    let mut reports = Vec::new();
+    for game in PgnReader::open(path)? {
+        reports.push(analyze_game(evaluator, &game?, thresholds)?);
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::tests::MaterialEval;
+
+    #[test]
+    fn test_classifies_missed_capture() {
+        let pgn = "[White \"Alice\"]\n[Black \"Bob\"]\n[Result \"*\"]\n\n1. e4 d5 2. a3 dxe4 *\n";
+        let game = PgnReader::new(pgn.as_bytes()).next().unwrap().unwrap();
+        let mut evaluator = MaterialEval { board: Board::default() };
+        let report = analyze_game(&mut evaluator, &game, &BlunderThresholds::default()).unwrap();
+
+        // White leaves the pawn on d5, black takes the one on e4
+        let classes: Vec<MoveClass> = report.moves.iter().map(|annotated| annotated.class).collect();
+        assert_eq!(classes, vec![MoveClass::Good, MoveClass::Good, MoveClass::Mistake, MoveClass::Good]);
+        assert_eq!(report.moves[2].loss, 100);
+        assert_eq!(report.moves[2].best_move.to_string(), "e4d5");
+        assert_eq!(report.count(Color::White, MoveClass::Mistake), 1);
+
+        let text = report.render();
+        assert!(text.starts_with("Alice - Bob\n1. e2e4 d7d5 2. a2a3? {100 -> 0, best e4d5} d5e4\n"));
+        assert!(text.contains("Alice: 0 inaccuracies, 1 mistakes, 0 blunders"));
+
+        #[cfg(feature = "render")]
+        {
+            let svg = report.ply_svg(2, &crate::render::SvgOptions::default()).unwrap();
+            assert_eq!(svg.matches("<line").count(), 2); // Best e4d5 and the played a2a3
+            assert!(svg.contains("<title>e4d5 100</title>"));
+            assert!(report.ply_svg(4, &crate::render::SvgOptions::default()).is_none());
+        }
+    }
+}
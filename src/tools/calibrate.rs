@@ -0,0 +1,208 @@
+use std::path::Path;
+
+use chess::Color;
+
+use crate::dataset::{read_samples, Sample};
+use crate::error::NNUEError;
+use crate::eval_server::BatchEvaluator;
+use crate::metadata::{read_metadata, write_metadata};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationOptions {
+    pub bucket_width: i16, // Score range of one point on the curve
+    pub max_score: i16, // Scores beyond this are counted in the outermost buckets
+    pub batch_size: usize, // Positions per evaluate_batch call
+}
+
+impl Default for CalibrationOptions {
+    fn default() -> CalibrationOptions {
+        CalibrationOptions {
+            bucket_width: 100,
+            max_score: 1500,
+            batch_size: 1024,
+        }
+    }
+}
+
+impl CalibrationOptions {
+    pub fn validate(&self) -> Result<(), NNUEError> {
+        if self.max_score < 0 {
+            return Err(NNUEError::InvalidConfig(format!("max_score must not be negative, got {}", self.max_score)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationBucket {
+    pub low: i16, // Scores in low..low + bucket_width, from white's side
+    pub count: usize,
+    pub mean_score: f64,
+    pub mean_result: f64, // Empirical expected result for white
+    pub predicted: f64, // Expected result of mean_score under the fitted scale
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationReport {
+    pub buckets: Vec<CalibrationBucket>, // Ascending, empty buckets left out
+    pub scale: f64, // Fitted score units for a tenfold change in the odds
+    pub error: f64, // Mean squared error of the fitted sigmoid against the results
+}
+
+impl CalibrationReport {
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("low,count,mean_score,mean_result,predicted\n");
+        for bucket in &self.buckets {
+            csv.push_str(&format!(
+                "{},{},{:.1},{:.4},{:.4}\n",
+                bucket.low, bucket.count, bucket.mean_score, bucket.mean_result, bucket.predicted
+            ));
+        }
+        csv
+    }
+}
+
+fn win_probability(score: f64, scale: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-score / scale))
+}
+
+fn mean_error(scores: &[i16], results: &[f32], scale: f64) -> f64 {
+    let total: f64 = scores
+        .iter()
+        .zip(results)
+        .map(|(score, result)| (win_probability(*score as f64, scale) - *result as f64).powi(2))
+        .sum();
+    total / scores.len().max(1) as f64
+}
+
+pub fn fit_scale(scores: &[i16], results: &[f32]) -> f64 {
+    // Golden section search over the log of the scale, the error has a single minimum in practice
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let error = |log_scale: f64| mean_error(scores, results, log_scale.exp());
+    let (mut low, mut high) = (10f64.ln(), 10000f64.ln());
+    let mut a = high - ratio * (high - low);
+    let mut b = low + ratio * (high - low);
+    let (mut error_a, mut error_b) = (error(a), error(b));
+    while high - low > 1e-6 {
+        if error_a < error_b {
+            high = b;
+            (b, error_b) = (a, error_a);
+            a = high - ratio * (high - low);
+            error_a = error(a);
+        } else {
+            low = a;
+            (a, error_a) = (b, error_b);
+            b = low + ratio * (high - low);
+            error_b = error(b);
+        }
+    }
+    ((low + high) / 2.0).exp()
+}
+
+pub fn score_samples<E: BatchEvaluator + ?Sized>(evaluator: &mut E, samples: &[Sample], batch_size: usize) -> Result<Vec<i16>, NNUEError> {
+    // Scores from white's side, like the sample labels
+    let mut scores = Vec::with_capacity(samples.len());
+    for chunk in samples.chunks(batch_size.max(1)) {
+        let boards: Vec<_> = chunk.iter().map(|sample| sample.board).collect();
+        for (score, board) in evaluator.evaluate_batch(&boards)?.into_iter().zip(&boards) {
+            scores.push(if board.side_to_move() == Color::White { score } else { score.saturating_neg() });
+        }
+    }
+    Ok(scores)
+}
+
+pub fn calibrate(scores: &[i16], samples: &[Sample], options: &CalibrationOptions) -> Result<CalibrationReport, NNUEError> {
+    // scores are from white's side, in the order of samples
+    options.validate()?;
+    let results: Vec<f32> = samples.iter().map(|sample| sample.result).collect();
+    let scale = fit_scale(scores, &results);
+
+    let width = options.bucket_width.max(1) as i32;
+    let limit = options.max_score as i32;
+    let count = (2 * limit / width + 1) as usize;
+    let mut sums = vec![(0usize, 0f64, 0f64); count];
+    for (score, result) in scores.iter().zip(&results) {
+        let clamped = (*score as i32).clamp(-limit, limit);
+        let index = ((clamped + limit) / width) as usize;
+        let sum = &mut sums[index.min(count - 1)];
+        *sum = (sum.0 + 1, sum.1 + *score as f64, sum.2 + *result as f64);
+    }
+
+    let buckets = sums
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (count, _, _))| *count > 0)
+        .map(|(index, (count, score, result))| {
+            let mean_score = score / count as f64;
+            CalibrationBucket {
+                low: (index as i32 * width - limit) as i16,
+                count,
+                mean_score,
+                mean_result: result / count as f64,
+                predicted: win_probability(mean_score, scale),
+            }
+        })
+        .collect();
+
+    Ok(CalibrationReport {
+        buckets,
+        scale,
+        error: mean_error(scores, &results, scale),
+    })
+}
+
+pub fn calibrate_dataset<P: AsRef<Path>, E: BatchEvaluator + ?Sized>(
+    evaluator: &mut E,
+    path: P,
+    options: &CalibrationOptions,
+) -> Result<CalibrationReport, NNUEError> {
+    // Checked before scoring, which is the slow part
+    options.validate()?;
+    let samples = read_samples(path)?;
+    let scores = score_samples(evaluator, &samples, options.batch_size)?;
+    calibrate(&scores, &samples, options)
+}
+
+pub fn write_scale<P: AsRef<Path>>(model_path: P, scale: f64) -> Result<(), NNUEError> {
+    // Stored in the model's metadata file, ShallowNNUE picks it up on load for to_win_probability
+    let mut metadata = read_metadata(&model_path)?;
+    metadata.win_scale = Some(scale);
+    write_metadata(model_path, &metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::Board;
+
+    use super::*;
+    use crate::search::tests::MaterialEval;
+
+    #[test]
+    fn test_calibration_recovers_scale() {
+        // Results drawn exactly from a 300 scale sigmoid
+        let scores: Vec<i16> = (-100..=100).map(|i| i * 10).collect();
+        let samples: Vec<Sample> = scores
+            .iter()
+            .map(|score| Sample { board: Board::default(), score: 0, result: win_probability(*score as f64, 300.0) as f32, best_move: None })
+            .collect();
+        let report = calibrate(&scores, &samples, &CalibrationOptions::default()).unwrap();
+        assert!((report.scale - 300.0).abs() < 1.0);
+        assert_eq!(report.buckets.len(), 21);
+        assert_eq!((report.buckets[0].low, report.buckets[0].count), (-1000, 10));
+        assert!(report.buckets.iter().all(|bucket| (bucket.mean_result - bucket.predicted).abs() < 0.02));
+        assert!(report.to_csv().starts_with("low,count,mean_score,mean_result,predicted\n-1000,10,"));
+        let negative = CalibrationOptions { max_score: -100, ..CalibrationOptions::default() };
+        assert!(matches!(calibrate(&scores, &samples, &negative), Err(NNUEError::InvalidConfig(_))));
+
+        // Scores are turned to white's side like the labels
+        let black = Sample::parse("4k3/8/8/8/8/8/8/3QK3 b - - 0 1;900;1").unwrap();
+        let mut evaluator = MaterialEval { board: Board::from_str("4k3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap() };
+        assert_eq!(score_samples(&mut evaluator, &[black], 8).unwrap(), vec![900]);
+
+        let model = std::env::temp_dir().join("shallow_nnue_calibrate_model.pt");
+        write_scale(&model, 321.0).unwrap();
+        assert_eq!(read_metadata(&model).unwrap().win_scale, Some(321.0));
+    }
+}
@@ -0,0 +1,236 @@
+use chess::{Board, ChessMove, Color, MoveGen};
+
+use crate::error::NNUEError;
+use crate::perspective::ScorePerspective;
+use crate::rng::XorShift;
+use crate::shallow_nnue::NNUE;
+use crate::training::trainer::Trainer;
+
+// Backend conformance: two evaluators holding the same weights must agree on every position and move.
+// Exactly when both run the quantized integer accumulator, within a tolerance for float rounding
+// otherwise. Positions come from random playouts of a seed, so a failing one can be replayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disagreement {
+    pub fen: String,
+    pub chess_move: Option<ChessMove>, // None for the position's own evaluation
+    pub reference: i16, // Both scores from the side to move, or the mover's side for a move
+    pub candidate: i16,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConformanceReport {
+    pub positions: usize,
+    pub scores: usize, // Evaluations and scored moves compared
+    pub max_difference: i16,
+    pub disagreements: Vec<Disagreement>, // Differences beyond the tolerance
+}
+
+impl ConformanceReport {
+    pub fn is_clean(&self) -> bool {
+        self.disagreements.is_empty()
+    }
+
+    fn record(&mut self, board: &Board, chess_move: Option<ChessMove>, expected: i16, actual: i16, tolerance: i16) {
+        let difference = (expected as i32 - actual as i32).unsigned_abs().min(i16::MAX as u32) as i16;
+        self.scores += 1;
+        self.max_difference = self.max_difference.max(difference);
+        if difference > tolerance {
+            self.disagreements.push(Disagreement { fen: board.to_string(), chess_move, reference: expected, candidate: actual });
+        }
+    }
+}
+
+pub fn random_positions(count: usize, seed: u64) -> Vec<Board> {
+    // Each position is up to 80 random plies from the start, games that end early stop where they end
+    let mut rng = XorShift::new(seed);
+    (0..count)
+        .map(|_| {
+            let mut board = Board::default();
+            for _ in 0..rng.below(81) {
+                let moves: Vec<ChessMove> = MoveGen::new_legal(&board).collect();
+                if moves.is_empty() {
+                    break;
+                }
+                board = board.make_move_new(moves[rng.below(moves.len())]);
+            }
+            board
+        })
+        .collect()
+}
+
+pub fn compare(reference: &mut dyn NNUE, candidate: &mut dyn NNUE, positions: &[Board], tolerance: i16) -> Result<ConformanceReport, NNUEError> {
+    // Compares evaluate and the forward of every legal move, the evaluators' perspectives may differ
+    let mut report = ConformanceReport { positions: positions.len(), ..ConformanceReport::default() };
+    for board in positions {
+        reference.set_board_hard(*board)?;
+        candidate.set_board_hard(*board)?;
+        let (turn, reference_view, candidate_view) = (board.side_to_move(), reference.perspective(), candidate.perspective());
+        let mut check = |chess_move: Option<ChessMove>, expected: i16, actual: i16| {
+            let (expected, actual) = (reference_view.to_side_to_move(expected, turn), candidate_view.to_side_to_move(actual, turn));
+            report.record(board, chess_move, expected, actual, tolerance);
+        };
+        check(None, reference.evaluate()?, candidate.evaluate()?);
+        for chess_move in MoveGen::new_legal(board) {
+            check(Some(chess_move), reference.forward(chess_move)?, candidate.forward(chess_move)?);
+        }
+    }
+    Ok(report)
+}
+
+pub fn check_consistency(evaluator: &mut dyn NNUE, positions: &[Board], tolerance: i16) -> Result<ConformanceReport, NNUEError> {
+    // One evaluator against itself: forward(m) must match setting up the position after m and
+    // evaluating it, seen from the mover's side, and scoring the moves must leave the position's own
+    // evaluation untouched. The set up and evaluated score is the reference, forward the candidate.
+    let mut report = ConformanceReport { positions: positions.len(), ..ConformanceReport::default() };
+    let view = evaluator.perspective();
+    for board in positions {
+        let turn = board.side_to_move();
+        evaluator.set_board_hard(*board)?;
+        let before = view.to_side_to_move(evaluator.evaluate()?, turn);
+        let mut forwards = Vec::new();
+        for chess_move in MoveGen::new_legal(board) {
+            forwards.push((chess_move, view.to_side_to_move(evaluator.forward(chess_move)?, turn)));
+        }
+        let after = view.to_side_to_move(evaluator.evaluate()?, turn);
+        report.record(board, None, before, after, tolerance);
+
+        for (chess_move, forward) in forwards {
+            let child = board.make_move_new(chess_move);
+            evaluator.set_board_hard(child)?;
+            let evaluated = view.to_side_to_move(evaluator.evaluate()?, !turn).saturating_neg();
+            report.record(board, Some(chess_move), evaluated, forward, tolerance);
+        }
+    }
+    Ok(report)
+}
+
+// A trainer's tch model behind the NNUE trait, the reference for its exported native weights. Every
+// score runs the model from scratch on the CPU or whichever device the trainer uses.
+#[derive(Debug)]
+pub struct TrainerEval<'a> {
+    trainer: &'a Trainer,
+    board: Board,
+}
+
+impl TrainerEval<'_> {
+    pub fn new(trainer: &Trainer) -> TrainerEval<'_> {
+        TrainerEval { trainer, board: Board::default() }
+    }
+
+    fn score(&self, board: &Board, colour: Color) -> Result<i16, NNUEError> {
+        // Truncated like the native evaluator's output
+        Ok(self.trainer.predict(board, colour)? as i16)
+    }
+}
+
+impl NNUE for TrainerEval<'_> {
+    fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+        // The position after the move, still encoded from the mover's side
+        self.score(&self.board.make_move_new(chess_move), self.board.side_to_move())
+    }
+
+    fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+        self.board = board;
+        Ok(())
+    }
+
+    fn evaluate(&mut self) -> Result<i16, NNUEError> {
+        self.score(&self.board, self.board.side_to_move())
+    }
+
+    fn perspective(&self) -> ScorePerspective {
+        ScorePerspective::SideToMove
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::native::tests::antisymmetric_network;
+    use crate::native::{save_network, save_weights, LayerWeights, NativeNNUE, NativeWeights};
+    use crate::network::{Activation, NetworkConfig};
+    use crate::search::tests::MaterialEval;
+    use crate::training::trainer::TrainerOptions;
+
+    #[test]
+    fn test_float_backends_conform() {
+        // An untrained net is as good a test as a trained one, its weights are just as arbitrary
+        let network = NetworkConfig { hidden: vec![16, 8], ..NetworkConfig::default() };
+        let trainer = Trainer::new(TrainerOptions { network: network.clone(), ..TrainerOptions::default() }).unwrap();
+        let path = std::env::temp_dir().join("shallow_nnue_conformance_float.bin");
+        trainer.export_native(&path).unwrap();
+
+        let mut native = NativeNNUE::load_with_config(&path, &network).unwrap();
+        native.set_perspective(ScorePerspective::White);
+        let report = compare(&mut TrainerEval::new(&trainer), &mut native, &random_positions(2000, 1), 1).unwrap();
+        assert!(report.is_clean(), "{:?}", &report.disagreements[..report.disagreements.len().min(5)]);
+        assert!(report.scores > 20_000);
+    }
+
+    #[test]
+    fn test_forward_matches_evaluate() {
+        let positions = random_positions(50, 4);
+        let report = check_consistency(&mut MaterialEval { board: Board::default() }, &positions, 0).unwrap();
+        assert!(report.is_clean());
+        assert!(report.scores > 500);
+
+        // Scoring moves from the wrong side is caught on every position with a material imbalance
+        struct Stale(MaterialEval);
+        impl NNUE for Stale {
+            fn forward(&mut self, chess_move: ChessMove) -> Result<i16, NNUEError> {
+                Ok(self.0.forward(chess_move)?.saturating_neg())
+            }
+            fn set_board_hard(&mut self, board: Board) -> Result<(), NNUEError> {
+                self.0.set_board_hard(board)
+            }
+            fn evaluate(&mut self) -> Result<i16, NNUEError> {
+                self.0.evaluate()
+            }
+        }
+        let board = Board::from_str("4k3/8/8/3q4/8/8/8/4K3 w - - 0 1").unwrap();
+        let report = check_consistency(&mut Stale(MaterialEval { board }), &[board], 0).unwrap();
+        assert_eq!(report.disagreements.len(), MoveGen::new_legal(&board).len());
+        assert!(report.disagreements.iter().all(|disagreement| disagreement.chess_move.is_some()));
+    }
+
+    #[test]
+    fn test_native_forward_is_consistent() {
+        // Random playouts reach castling, en passant and promotions, forward has to encode all of them.
+        // The network scores a position as minus its score from the other side, so forward and
+        // evaluating the position after the move agree exactly.
+        let path = std::env::temp_dir().join("shallow_nnue_conformance_consistency.bin");
+        save_weights(&path, &antisymmetric_network(8)).unwrap();
+        let mut native = NativeNNUE::load(&path).unwrap();
+        let report = check_consistency(&mut native, &random_positions(300, 6), 0).unwrap();
+        assert!(report.is_clean(), "{:?}", &report.disagreements[..report.disagreements.len().min(5)]);
+        assert!(report.scores > 5000);
+    }
+
+    #[test]
+    fn test_quantized_backends_conform() {
+        // The memory mapped and the decoded weights, as big-endian and wasm hosts load them, both
+        // quantized: integer accumulators leave no room for rounding differences
+        let mut rng = XorShift::new(7);
+        let mut random = |count: usize| (0..count).map(|_| rng.next_f64() as f32 - 0.5).collect::<Vec<f32>>();
+        let network = vec![
+            LayerWeights { inputs: 768, outputs: 16, weights: random(768 * 16), biases: random(16) },
+            LayerWeights { inputs: 16, outputs: 1, weights: random(16), biases: random(1) },
+        ];
+        let path = std::env::temp_dir().join("shallow_nnue_conformance_quantized.bin");
+        save_network(&path, &network, Activation::ClippedRelu).unwrap();
+
+        let mut mapped = NativeNNUE::quantized(NativeWeights::load(&path).unwrap()).unwrap();
+        let mut decoded = NativeNNUE::quantized(NativeWeights::from_bytes(&std::fs::read(&path).unwrap()).unwrap()).unwrap();
+        let report = compare(&mut mapped, &mut decoded, &random_positions(2000, 2), 0).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.max_difference, 0);
+
+        // Scores are compared from the side to move whatever perspective each evaluator reports in
+        let mut white = NativeNNUE::load(&path).unwrap();
+        white.set_perspective(ScorePerspective::White);
+        let report = compare(&mut white, &mut NativeNNUE::load(&path).unwrap(), &random_positions(50, 3), 0).unwrap();
+        assert!(report.is_clean());
+    }
+}
@@ -0,0 +1,206 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use chess::Board;
+use serde::{Deserialize, Serialize};
+
+use crate::error::NNUEError;
+use crate::eval_server::BatchEvaluator;
+
+// Labels a file of FENs, one per line, with the evaluator's scores, for files too large to hold in
+// memory. Scores go to a CSV of fen,score with the score from the side to move. After every batch
+// the output is flushed and a checkpoint next to it records how far both files got, so an
+// interrupted run resumes at the next batch instead of starting over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvalFileOptions {
+    pub batch_size: usize,
+    pub resume: bool, // Continue from the checkpoint if there is one, otherwise the output is overwritten
+}
+
+impl Default for EvalFileOptions {
+    fn default() -> EvalFileOptions {
+        EvalFileOptions { batch_size: 4096, resume: true }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EvalFileProgress {
+    pub positions: u64, // Scored so far, including the runs before a resume
+    pub skipped: u64, // Lines that weren't a FEN, blank lines and # comments aside
+    pub bytes_read: u64,
+    pub total_bytes: u64,
+    pub positions_per_second: f64, // Over this run only
+}
+
+impl EvalFileProgress {
+    pub fn fraction(&self) -> f64 {
+        // Share of the input read, 1 for an empty input
+        match self.total_bytes {
+            0 => 1.0,
+            total => self.bytes_read as f64 / total as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Checkpoint {
+    input_offset: u64, // Bytes of the input whose positions are in the output
+    output_len: u64,
+    positions: u64,
+    skipped: u64,
+}
+
+pub fn checkpoint_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".checkpoint");
+    PathBuf::from(name)
+}
+
+fn write_checkpoint(path: &Path, checkpoint: &Checkpoint) -> Result<(), NNUEError> {
+    // Written aside and renamed over the old one, so an interruption never leaves half a checkpoint
+    let temporary = path.with_extension("checkpoint.tmp");
+    let json = serde_json::to_string(checkpoint).map_err(|err| NNUEError::InvalidData(err.to_string()))?;
+    fs::write(&temporary, json)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+fn read_checkpoint(path: &Path) -> Result<Option<Checkpoint>, NNUEError> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).map(Some).map_err(|err| NNUEError::InvalidData(format!("bad checkpoint {}: {}", path.display(), err))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn eval_file<E: BatchEvaluator + ?Sized>(
+    evaluator: &mut E,
+    input: &Path,
+    output: &Path,
+    options: &EvalFileOptions,
+    progress: &dyn Fn(&EvalFileProgress),
+) -> Result<EvalFileProgress, NNUEError> {
+    // progress is called after every batch. The checkpoint is removed once the whole input is done.
+    let checkpoint_file = checkpoint_path(output);
+    let total_bytes = fs::metadata(input)?.len();
+    let mut reader = BufReader::new(File::open(input)?);
+    let resumed = match options.resume {
+        true => read_checkpoint(&checkpoint_file)?,
+        false => None,
+    };
+
+    // Rows written after the last checkpoint are cut off, their batch is scored again
+    let mut checkpoint = match resumed {
+        Some(checkpoint) => checkpoint,
+        None => {
+            fs::write(output, "fen,score\n")?;
+            Checkpoint { input_offset: 0, output_len: "fen,score\n".len() as u64, positions: 0, skipped: 0 }
+        }
+    };
+    let file = OpenOptions::new().write(true).open(output)?;
+    file.set_len(checkpoint.output_len)?;
+    let mut writer = BufWriter::new(file);
+    writer.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(checkpoint.input_offset))?;
+
+    let start = Instant::now();
+    let already = checkpoint.positions;
+    let report = |checkpoint: &Checkpoint| EvalFileProgress {
+        positions: checkpoint.positions,
+        skipped: checkpoint.skipped,
+        bytes_read: checkpoint.input_offset,
+        total_bytes,
+        positions_per_second: (checkpoint.positions - already) as f64 / start.elapsed().as_secs_f64().max(1e-9),
+    };
+
+    let batch_size = options.batch_size.max(1);
+    let (mut fens, mut boards) = (Vec::with_capacity(batch_size), Vec::with_capacity(batch_size));
+    let (mut line, mut offset, mut skipped) = (String::new(), checkpoint.input_offset, checkpoint.skipped);
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        offset += read as u64;
+        let fen = line.trim();
+        if !fen.is_empty() && !fen.starts_with('#') {
+            match Board::from_str(fen) {
+                Ok(board) => {
+                    fens.push(fen.to_string());
+                    boards.push(board);
+                }
+                Err(_) => skipped += 1,
+            }
+        }
+
+        if boards.len() == batch_size || (read == 0 && offset > checkpoint.input_offset) {
+            // A last batch may hold only skipped lines, the checkpoint still moves past them
+            if !boards.is_empty() {
+                let scores = evaluator.evaluate_batch(&boards)?;
+                for (fen, score) in fens.iter().zip(scores) {
+                    writeln!(writer, "{},{}", fen, score)?;
+                }
+            }
+            writer.flush()?;
+            checkpoint = Checkpoint {
+                input_offset: offset,
+                output_len: writer.stream_position()?,
+                positions: checkpoint.positions + boards.len() as u64,
+                skipped,
+            };
+            write_checkpoint(&checkpoint_file, &checkpoint)?;
+            progress(&report(&checkpoint));
+            fens.clear();
+            boards.clear();
+        }
+        if read == 0 {
+            break;
+        }
+    }
+    if checkpoint_file.exists() {
+        fs::remove_file(&checkpoint_file)?;
+    }
+    Ok(report(&checkpoint))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::search::tests::MaterialEval;
+
+    #[test]
+    fn test_eval_file_resumes() {
+        let input = std::env::temp_dir().join("shallow_nnue_eval_file.fen");
+        let output = std::env::temp_dir().join("shallow_nnue_eval_file.csv");
+        let fens = "4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1\n# comment\nnot a fen\n\n\
+                    4k3/8/8/3q4/4P3/8/8/4K3 b - - 0 1\n4k3/8/8/8/8/8/8/4K3 w - - 0 1\n";
+        fs::write(&input, fens).unwrap();
+        let mut evaluator = MaterialEval { board: Board::default() };
+        let options = EvalFileOptions { batch_size: 2, resume: true };
+
+        let calls = Cell::new(0);
+        let done = eval_file(&mut evaluator, &input, &output, &options, &|_| calls.set(calls.get() + 1)).unwrap();
+        let expected = "fen,score\n4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1,-800\n4k3/8/8/3q4/4P3/8/8/4K3 b - - 0 1,800\n4k3/8/8/8/8/8/8/4K3 w - - 0 1,0\n";
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+        assert_eq!((done.positions, done.skipped, done.fraction(), calls.get()), (3, 1, 1.0, 2));
+        assert!(!checkpoint_path(&output).exists());
+
+        // Interrupted after the first batch with a row of the next half written: the row is dropped
+        // and only the last position is scored again
+        let first_batch = "fen,score\n4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1,-800\n4k3/8/8/3q4/4P3/8/8/4K3 b - - 0 1,800\n";
+        let checkpoint = Checkpoint {
+            input_offset: fens.rfind("4k3/8/8/8/").unwrap() as u64,
+            output_len: first_batch.len() as u64,
+            positions: 2,
+            skipped: 1,
+        };
+        fs::write(&output, format!("{}4k3/8/8/8/8/8/8/4K3 w - -", first_batch)).unwrap();
+        write_checkpoint(&checkpoint_path(&output), &checkpoint).unwrap();
+        let resumed = eval_file(&mut evaluator, &input, &output, &options, &|_| {}).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+        assert_eq!((resumed.positions, resumed.skipped), (3, 1));
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use chess::{Board, BoardStatus, Color};
+use serde::{Deserialize, Serialize};
+
+use crate::dataset::Sample;
+use crate::error::NNUEError;
+use crate::eval_server::BatchEvaluator;
+use crate::pgn::{PgnGame, PgnReader};
+use crate::search::MATE_SCORE;
+use crate::tools::accuracy::csv_field;
+
+// The evaluation after every ply of a game, the input for eval graphs and, for finished games, the
+// calibration tool. Scores are from white's side so a series plots without knowing who moved.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EvalPoint {
+    pub ply: usize, // 0 for the starting position
+    pub chess_move: Option<String>, // The move that led here, None at ply 0
+    pub fen: String,
+    pub white_score: i16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EvalSeries {
+    pub white: String,
+    pub black: String,
+    pub event: Option<String>,
+    pub date: Option<String>,
+    pub result: Option<f32>, // From white's side, None for unfinished games
+    pub points: Vec<EvalPoint>,
+}
+
+impl EvalSeries {
+    pub fn samples(&self) -> Result<Vec<Sample>, NNUEError> {
+        // Every position labelled with the game result and its score, empty for unfinished games.
+        // calibrate takes the scores and samples apart, see calibration_data.
+        let result = match self.result {
+            Some(result) => result,
+            None => return Ok(Vec::new()),
+        };
+        self.points
+            .iter()
+            .map(|point| {
+                let board = Board::from_str(&point.fen).map_err(|_| NNUEError::InvalidData(format!("bad fen {:?} at ply {}", point.fen, point.ply)))?;
+                Ok(Sample { board, score: point.white_score, result, best_move: None })
+            })
+            .collect()
+    }
+}
+
+pub fn game_series<E: BatchEvaluator + ?Sized>(evaluator: &mut E, game: &PgnGame) -> Result<EvalSeries, NNUEError> {
+    // Every position of the game in one batch, positions that ended the game are scored directly
+    let mut boards = Vec::with_capacity(game.moves.len() + 1);
+    boards.push(game.start);
+    for (ply, pgn_move) in game.moves.iter().enumerate() {
+        let board = boards[ply];
+        if !board.legal(pgn_move.chess_move) {
+            return Err(NNUEError::InvalidData(format!("illegal move {} at ply {}", pgn_move.chess_move, ply)));
+        }
+        boards.push(board.make_move_new(pgn_move.chess_move));
+    }
+    let open: Vec<Board> = boards.iter().filter(|board| board.status() == BoardStatus::Ongoing).copied().collect();
+    let mut scores = evaluator.evaluate_batch(&open)?.into_iter();
+
+    let mut points = Vec::with_capacity(boards.len());
+    for (ply, board) in boards.iter().enumerate() {
+        let score = match board.status() {
+            BoardStatus::Checkmate => -MATE_SCORE,
+            BoardStatus::Stalemate => 0,
+            BoardStatus::Ongoing => scores.next().ok_or_else(|| NNUEError::InvalidData("evaluator returned too few scores".to_string()))?,
+        };
+        points.push(EvalPoint {
+            ply,
+            chess_move: ply.checked_sub(1).map(|previous| game.moves[previous].chess_move.to_string()),
+            fen: board.to_string(),
+            white_score: if board.side_to_move() == Color::White { score } else { score.saturating_neg() },
+        });
+    }
+
+    Ok(EvalSeries {
+        white: game.header("White").unwrap_or("?").to_string(),
+        black: game.header("Black").unwrap_or("?").to_string(),
+        event: game.header("Event").map(str::to_string),
+        date: game.header("Date").map(str::to_string),
+        result: game.result,
+        points,
+    })
+}
+
+pub fn pgn_series<P: AsRef<Path>, E: BatchEvaluator + ?Sized>(evaluator: &mut E, path: P) -> Result<Vec<EvalSeries>, NNUEError> {
+    // One series for every game the reader accepts, malformed games are skipped like everywhere else
+    let mut series = Vec::new();
+    for game in PgnReader::open(path)? {
+        series.push(game_series(evaluator, &game?)?);
+    }
+    Ok(series)
+}
+
+pub fn calibration_data(series: &[EvalSeries]) -> Result<(Vec<i16>, Vec<Sample>), NNUEError> {
+    // Scores and samples of the finished games, ready for calibrate without evaluating again
+    let samples: Vec<Sample> = series.iter().map(EvalSeries::samples).collect::<Result<Vec<_>, _>>()?.concat();
+    Ok((samples.iter().map(|sample| sample.score).collect(), samples))
+}
+
+pub fn to_json(series: &[EvalSeries]) -> Result<String, NNUEError> {
+    serde_json::to_string_pretty(series).map_err(|err| NNUEError::InvalidData(err.to_string()))
+}
+
+pub fn to_csv(series: &[EvalSeries]) -> String {
+    // One row per position in long format, games numbered from 0 in the order given. The result is
+    // empty for unfinished games.
+    let mut csv = String::from("game,white,black,result,ply,move,white_score\n");
+    for (game, game_series) in series.iter().enumerate() {
+        let result = game_series.result.map(|result| result.to_string()).unwrap_or_default();
+        for point in &game_series.points {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                game,
+                csv_field(&game_series.white),
+                csv_field(&game_series.black),
+                result,
+                point.ply,
+                point.chess_move.as_deref().unwrap_or(""),
+                point.white_score
+            ));
+        }
+    }
+    csv
+}
+
+pub fn write_series<P: AsRef<Path>>(path: P, series: &[EvalSeries]) -> Result<(), NNUEError> {
+    // CSV for a .csv extension, JSON otherwise
+    let path = path.as_ref();
+    let contents = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => to_csv(series),
+        _ => to_json(series)?,
+    };
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::tests::MaterialEval;
+
+    #[test]
+    fn test_series_of_games() {
+        let pgn = "[White \"Alice, A.\"]\n[Black \"Bob\"]\n[Result \"0-1\"]\n\n1. f3 e5 2. g4 Qh4# 0-1\n\n\
+                   [White \"Bob\"]\n[Black \"Alice\"]\n[Result \"*\"]\n\n1. e4 d5 2. exd5 *\n";
+        let mut evaluator = MaterialEval { board: Board::default() };
+        let series: Vec<EvalSeries> = PgnReader::new(pgn.as_bytes())
+            .map(|game| game_series(&mut evaluator, &game.unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(series[0].points.len(), 5);
+        assert_eq!(series[0].points[0].chess_move, None);
+        assert_eq!(series[0].points[4].chess_move.as_deref(), Some("d8h4"));
+        assert_eq!(series[0].points[4].white_score, -MATE_SCORE);
+        assert_eq!(series[1].points[3].white_score, 100); // White is a pawn up, black to move
+
+        // Only the finished game feeds calibration
+        let (scores, samples) = calibration_data(&series).unwrap();
+        assert_eq!((scores.len(), samples.len()), (5, 5));
+        assert_eq!(samples[0].result, 0.0);
+
+        let csv = to_csv(&series);
+        assert_eq!(csv.lines().count(), 1 + 5 + 4);
+        assert_eq!(csv.lines().nth(5).unwrap(), "0,\"Alice, A.\",Bob,0,4,d8h4,-30000");
+        let parsed: Vec<EvalSeries> = serde_json::from_str(&to_json(&series).unwrap()).unwrap();
+        assert_eq!(parsed, series);
+    }
+}
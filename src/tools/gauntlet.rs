@@ -0,0 +1,287 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chess::Board;
+use serde::{Deserialize, Serialize};
+use tch::Device;
+
+use crate::error::NNUEError;
+use crate::search::SearchOptions;
+use crate::shared_model::SharedModel;
+use crate::tools::match_runner::{
+    play_match_parallel, shared_evaluator, Engine, EvaluatorFactory, MatchOptions, MatchProgress, MatchResult, ParallelOptions,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RatedPlayer {
+    pub name: String, // Checkpoint path for gauntlets run from files
+    pub rating: f64, // Relative to the first player in the list
+    pub games: u32,
+    pub score: f64, // Points taken, out of games
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pairing {
+    pub first: String,
+    pub second: String,
+    pub wins: u32, // Counted from the first player's side
+    pub losses: u32,
+    pub draws: u32,
+}
+
+// Every player and every pairing played so far. Ratings are refitted from all pairings at once,
+// like ordo does, rather than updated game by game, so the order the games were played in doesn't matter.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RatingList {
+    pub players: Vec<RatedPlayer>, // In the order they joined, the first one anchors the ratings at 0
+    pub pairings: Vec<Pairing>,
+}
+
+fn expected(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+impl RatingList {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RatingList, NNUEError> {
+        // A missing file is an empty list, for the first checkpoint of a run
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|err| NNUEError::InvalidData(err.to_string())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(RatingList::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), NNUEError> {
+        let contents = serde_json::to_string_pretty(self).map_err(|err| NNUEError::InvalidData(err.to_string()))?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    pub fn player(&self, name: &str) -> Option<&RatedPlayer> {
+        self.players.iter().find(|player| player.name == name)
+    }
+
+    fn index_of(&mut self, name: &str) -> usize {
+        match self.players.iter().position(|player| player.name == name) {
+            Some(index) => index,
+            None => {
+                self.players.push(RatedPlayer { name: name.to_string(), rating: 0.0, games: 0, score: 0.0 });
+                self.players.len() - 1
+            }
+        }
+    }
+
+    pub fn record(&mut self, first: &str, second: &str, result: &MatchResult) {
+        // Adds the players if they are new, the ratings change on the next fit
+        self.index_of(first);
+        self.index_of(second);
+        self.pairings.push(Pairing {
+            first: first.to_string(),
+            second: second.to_string(),
+            wins: result.wins,
+            losses: result.losses,
+            draws: result.draws,
+        });
+    }
+
+    pub fn fit(&mut self) {
+        // Maximum likelihood ratings under the logistic model, by Newton steps on each rating in turn.
+        // Every pairing counts one extra draw, so players that won or lost everything stay finite.
+        let count = self.players.len();
+        let mut games = vec![vec![0.0; count]; count];
+        let mut points = vec![0.0; count];
+        for pairing in &self.pairings {
+            let (Some(first), Some(second)) = (
+                self.players.iter().position(|player| player.name == pairing.first),
+                self.players.iter().position(|player| player.name == pairing.second),
+            ) else {
+                continue;
+            };
+            let played = (pairing.wins + pairing.losses + pairing.draws) as f64;
+            games[first][second] += played + 1.0;
+            games[second][first] += played + 1.0;
+            points[first] += pairing.wins as f64 + 0.5 * (pairing.draws as f64 + 1.0);
+            points[second] += pairing.losses as f64 + 0.5 * (pairing.draws as f64 + 1.0);
+        }
+
+        let mut ratings = vec![0.0; count];
+        for _ in 0..1000 {
+            let mut largest_step: f64 = 0.0;
+            for player in 0..count {
+                let (mut expected_points, mut slope) = (0.0, 0.0);
+                for opponent in 0..count {
+                    let p = expected(ratings[player], ratings[opponent]);
+                    expected_points += games[player][opponent] * p;
+                    slope += games[player][opponent] * p * (1.0 - p) * 10f64.ln() / 400.0;
+                }
+                if slope > 0.0 {
+                    let step = (points[player] - expected_points) / slope;
+                    ratings[player] += step;
+                    largest_step = largest_step.max(step.abs());
+                }
+            }
+            if largest_step < 1e-6 {
+                break;
+            }
+        }
+
+        let anchor = ratings.first().copied().unwrap_or(0.0);
+        for (index, player) in self.players.iter_mut().enumerate() {
+            player.rating = ratings[index] - anchor;
+            // The virtual draws only steady the fit, the table shows the real games
+            player.games = 0;
+            player.score = 0.0;
+        }
+        for pairing in &self.pairings {
+            let played = pairing.wins + pairing.losses + pairing.draws;
+            let half_draws = 0.5 * pairing.draws as f64;
+            for (name, points) in [(&pairing.first, pairing.wins as f64 + half_draws), (&pairing.second, pairing.losses as f64 + half_draws)] {
+                if let Some(player) = self.players.iter_mut().find(|player| player.name == *name) {
+                    player.games += played;
+                    player.score += points;
+                }
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        // Strongest first, like ordo's output
+        let mut players: Vec<&RatedPlayer> = self.players.iter().collect();
+        players.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+        let mut table = String::from("rank name rating games score\n");
+        for (rank, player) in players.iter().enumerate() {
+            let percent = if player.games == 0 { 50.0 } else { 100.0 * player.score / player.games as f64 };
+            table.push_str(&format!("{} {} {:.1} {} {:.1}%\n", rank + 1, player.name, player.rating, player.games, percent));
+        }
+        table
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GauntletOptions {
+    pub pool_size: usize, // The latest earlier checkpoints the new one plays against
+    pub search: SearchOptions, // Used by every checkpoint
+    pub match_options: MatchOptions,
+    pub parallel: ParallelOptions,
+    pub device: Option<Device>, // Checkpoints are loaded here, see the builder for the default
+}
+
+impl Default for GauntletOptions {
+    fn default() -> GauntletOptions {
+        GauntletOptions {
+            pool_size: 4,
+            search: SearchOptions::default(),
+            match_options: MatchOptions::default(),
+            parallel: ParallelOptions::default(),
+            device: None,
+        }
+    }
+}
+
+pub fn play_gauntlet(
+    list: &mut RatingList,
+    candidate: (&str, EvaluatorFactory),
+    pool: &[(String, EvaluatorFactory)],
+    openings: &[Board],
+    options: &GauntletOptions,
+    progress: &(dyn Fn(&str, &MatchProgress) + Sync),
+) -> Result<(), NNUEError> {
+    // Plays the candidate against each pool opponent, records the matches and refits the ratings.
+    // progress gets the opponent's name with each update.
+    let (name, evaluator) = candidate;
+    let engine = Engine { evaluator, search: options.search };
+    for (opponent_name, opponent) in pool {
+        let opponent_engine = Engine { evaluator: *opponent, search: options.search };
+        let report = |update: &MatchProgress| progress(opponent_name, update);
+        let result = play_match_parallel(&engine, &opponent_engine, openings, &options.match_options, &options.parallel, &report)?;
+        list.record(name, opponent_name, &result);
+    }
+    list.index_of(name); // A first checkpoint joins the list without games
+    list.fit();
+    Ok(())
+}
+
+pub fn run_gauntlet<P: AsRef<Path>>(
+    checkpoint: &str,
+    ratings_path: P,
+    openings: &[Board],
+    options: &GauntletOptions,
+) -> Result<RatingList, NNUEError> {
+    // Loads the rating list, plays the checkpoint against the latest pool_size checkpoints in it
+    // and saves the list with the new ratings
+    let mut list = RatingList::load(&ratings_path)?;
+    let opponents: Vec<String> = list
+        .players
+        .iter()
+        .rev()
+        .filter(|player| player.name != checkpoint)
+        .take(options.pool_size)
+        .map(|player| player.name.clone())
+        .collect();
+
+    let load = |path: &str| SharedModel::load(path.to_string(), options.device).map(shared_evaluator);
+    let candidate = load(checkpoint)?;
+    let pool_evaluators = opponents.iter().map(|name| load(name)).collect::<Result<Vec<_>, NNUEError>>()?;
+    let pool: Vec<(String, EvaluatorFactory)> = opponents
+        .iter()
+        .zip(&pool_evaluators)
+        .map(|(name, evaluator)| (name.clone(), evaluator as EvaluatorFactory))
+        .collect();
+
+    play_gauntlet(&mut list, (checkpoint, &candidate), &pool, openings, options, &|_, _| {})?;
+    list.save(&ratings_path)?;
+    Ok(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::search::tests::MaterialEval;
+    use crate::shallow_nnue::BoxedNNUE;
+
+    #[test]
+    fn test_ratings_fit_and_persist() {
+        // B scores 70% against A and C 70% against B, a little under 150 Elo a step with the virtual draws
+        let mut list = RatingList::default();
+        list.record("a", "b", &MatchResult { wins: 20, losses: 60, draws: 20 });
+        list.record("b", "c", &MatchResult { wins: 20, losses: 60, draws: 20 });
+        list.fit();
+        assert_eq!(list.player("a").unwrap().rating, 0.0);
+        let (b, c) = (list.player("b").unwrap().rating, list.player("c").unwrap().rating);
+        assert!((120.0..150.0).contains(&b), "{}", b);
+        assert!((c - 2.0 * b).abs() < 1.0, "{} {}", b, c);
+        assert_eq!((list.player("b").unwrap().games, list.player("b").unwrap().score), (200, 100.0));
+        assert!(list.render().starts_with("rank name rating games score\n1 c "));
+
+        let path = std::env::temp_dir().join("shallow_nnue_gauntlet_ratings.json");
+        list.save(&path).unwrap();
+        assert_eq!(RatingList::load(&path).unwrap(), list);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(RatingList::load(&path).unwrap(), RatingList::default());
+    }
+
+    #[test]
+    fn test_gauntlet_against_pool() {
+        let material = || Ok(Box::new(MaterialEval { board: Board::default() }) as BoxedNNUE);
+        let openings = [Board::from_str("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1").unwrap()];
+        let options = GauntletOptions {
+            search: SearchOptions { depth: 1, ..SearchOptions::default() },
+            match_options: MatchOptions { max_plies: 4, ..MatchOptions::default() },
+            parallel: ParallelOptions { threads: 2, ..ParallelOptions::default() },
+            ..GauntletOptions::default()
+        };
+
+        let mut list = RatingList::default();
+        play_gauntlet(&mut list, ("first", &material), &[], &openings, &options, &|_, _| {}).unwrap();
+        assert_eq!((list.players.len(), list.pairings.len()), (1, 0));
+
+        let pool = [("first".to_string(), &material as EvaluatorFactory)];
+        play_gauntlet(&mut list, ("second", &material), &pool, &openings, &options, &|_, _| {}).unwrap();
+        // Each side mates with white, so the equal engines split the pair
+        assert_eq!(list.pairings[0].wins + list.pairings[0].losses + list.pairings[0].draws, 2);
+        assert!(list.player("second").unwrap().rating.abs() < 1e-6);
+    }
+}
@@ -0,0 +1,403 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chess::{Board, BoardStatus, Color, Piece};
+
+use crate::error::NNUEError;
+use crate::perspective::to_white;
+use crate::rng::XorShift;
+use crate::search::{SearchOptions, Searcher};
+use crate::shallow_nnue::{BoxedNNUE, ShallowNNUE, NNUE};
+use crate::shared_model::SharedModel;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchOptions {
+    pub max_plies: usize, // Games still running after this many plies are scored as draws
+    pub fifty_move_plies: usize, // Plies without a capture or pawn move before the game is drawn
+    pub adjudication: Option<Adjudication>, // Ends decided games early, off by default
+}
+
+impl Default for MatchOptions {
+    fn default() -> MatchOptions {
+        MatchOptions {
+            max_plies: 300,
+            fifty_move_plies: 100,
+            adjudication: None,
+        }
+    }
+}
+
+// Rules for ending games before mate or a draw by the rules, from the scores the engines' searches
+// report. Moves count per engine, so 3 moves means both engines agreed over 6 plies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adjudication {
+    pub resign_score: i16, // The side both engines see at least this far behind loses
+    pub resign_moves: usize,
+    pub draw_score: i16, // Drawn when both engines stay within this of zero
+    pub draw_moves: usize,
+    pub draw_min_ply: usize, // No draw adjudication before this ply
+    pub draw_by_material: bool, // Positions no tablebase would call anything but a draw, e.g. king and knight against king
+}
+
+impl Default for Adjudication {
+    fn default() -> Adjudication {
+        Adjudication {
+            resign_score: 1000,
+            resign_moves: 3,
+            draw_score: 10,
+            draw_moves: 8,
+            draw_min_ply: 80,
+            draw_by_material: true,
+        }
+    }
+}
+
+// Consecutive plies each adjudication rule held for, scores from white's side
+#[derive(Debug, Clone, Copy, Default)]
+struct AdjudicationStreaks {
+    white_winning: usize,
+    black_winning: usize,
+    drawish: usize,
+}
+
+impl AdjudicationStreaks {
+    fn update(&mut self, rules: &Adjudication, white_score: i16, ply: usize) -> Option<GameResult> {
+        let extend = |streak: usize, holds: bool| if holds { streak + 1 } else { 0 };
+        self.white_winning = extend(self.white_winning, white_score >= rules.resign_score);
+        self.black_winning = extend(self.black_winning, white_score <= rules.resign_score.saturating_neg());
+        self.drawish = extend(self.drawish, white_score.saturating_abs() <= rules.draw_score);
+
+        if self.white_winning >= 2 * rules.resign_moves {
+            Some(GameResult::WhiteWins)
+        } else if self.black_winning >= 2 * rules.resign_moves {
+            Some(GameResult::BlackWins)
+        } else if self.drawish >= 2 * rules.draw_moves && ply >= rules.draw_min_ply {
+            Some(GameResult::Draw)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn is_insufficient_material(board: &Board) -> bool {
+    // Bare kings, or a single minor piece left on the board
+    let heavy = *board.pieces(Piece::Pawn) | *board.pieces(Piece::Rook) | *board.pieces(Piece::Queen);
+    let minors = *board.pieces(Piece::Knight) | *board.pieces(Piece::Bishop);
+    heavy.popcnt() == 0 && minors.popcnt() <= 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameResult {
+    WhiteWins,
+    BlackWins,
+    Draw,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatchResult {
+    // Counted from the first player's side
+    pub wins: u32,
+    pub losses: u32,
+    pub draws: u32,
+}
+
+impl MatchResult {
+    pub fn games(&self) -> u32 {
+        self.wins + self.losses + self.draws
+    }
+
+    pub fn score(&self) -> f64 {
+        // Fraction of the points the first player took, 0.5 for an empty match
+        if self.games() == 0 {
+            return 0.5;
+        }
+        (self.wins as f64 + 0.5 * self.draws as f64) / self.games() as f64
+    }
+}
+
+pub struct Player<'a> {
+    pub evaluator: &'a mut dyn NNUE,
+    pub searcher: Searcher,
+}
+
+pub fn play_game(white: &mut Player, black: &mut Player, opening: &Board, options: &MatchOptions) -> Result<GameResult, NNUEError> {
+    let mut board = *opening;
+    let mut history = vec![board.get_hash()];
+    let mut quiet_plies = 0;
+    let mut streaks = AdjudicationStreaks::default();
+
+    for ply in 0..options.max_plies {
+        match board.status() {
+            BoardStatus::Checkmate if board.side_to_move() == Color::White => return Ok(GameResult::BlackWins),
+            BoardStatus::Checkmate => return Ok(GameResult::WhiteWins),
+            BoardStatus::Stalemate => return Ok(GameResult::Draw),
+            BoardStatus::Ongoing => {}
+        }
+        if matches!(options.adjudication, Some(rules) if rules.draw_by_material) && is_insufficient_material(&board) {
+            return Ok(GameResult::Draw);
+        }
+
+        let result = match board.side_to_move() {
+            Color::White => white.searcher.search(white.evaluator, &board)?,
+            Color::Black => black.searcher.search(black.evaluator, &board)?,
+        };
+        let chess_move = result.best_move.ok_or(NNUEError::IllegalMove)?;
+        if let Some(rules) = &options.adjudication {
+            if let Some(adjudicated) = streaks.update(rules, to_white(result.score, board.side_to_move()), ply + 1) {
+                return Ok(adjudicated);
+            }
+        }
+
+        let resets_clock = board.piece_on(chess_move.get_source()) == Some(Piece::Pawn) || board.piece_on(chess_move.get_dest()).is_some();
+        quiet_plies = if resets_clock { 0 } else { quiet_plies + 1 };
+        board = board.make_move_new(chess_move);
+
+        let hash = board.get_hash();
+        let repetitions = history.iter().filter(|seen| **seen == hash).count();
+        history.push(hash);
+        if repetitions >= 2 || quiet_plies >= options.fifty_move_plies {
+            return Ok(GameResult::Draw);
+        }
+    }
+
+    Ok(GameResult::Draw)
+}
+
+impl MatchResult {
+    fn record(&mut self, game: GameResult, first_is_white: bool) {
+        match (game, first_is_white) {
+            (GameResult::Draw, _) => self.draws += 1,
+            (GameResult::WhiteWins, true) | (GameResult::BlackWins, false) => self.wins += 1,
+            _ => self.losses += 1,
+        }
+    }
+}
+
+pub fn play_match(first: &mut Player, second: &mut Player, openings: &[Board], options: &MatchOptions) -> Result<MatchResult, NNUEError> {
+    // Every opening is played twice with colours swapped, so neither side profits from a lopsided opening
+    let mut result = MatchResult::default();
+    for opening in openings {
+        result.record(play_game(first, second, opening, options)?, true);
+        result.record(play_game(second, first, opening, options)?, false);
+    }
+    Ok(result)
+}
+
+// Makes a fresh evaluator for each worker thread
+pub type EvaluatorFactory<'a> = &'a (dyn Fn() -> Result<BoxedNNUE, NNUEError> + Sync);
+
+pub struct Engine<'a> {
+    pub evaluator: EvaluatorFactory<'a>,
+    pub search: SearchOptions,
+}
+
+pub fn shared_evaluator(model: Arc<SharedModel>) -> impl Fn() -> Result<BoxedNNUE, NNUEError> + Sync {
+    // Every evaluator made runs on the same loaded weights
+    move || Ok(Box::new(ShallowNNUE::from_shared(Arc::clone(&model))?) as BoxedNNUE)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParallelOptions {
+    pub threads: usize,
+    pub pairs: Option<usize>, // Openings to play, each with colours swapped. None plays every opening once in order
+    pub seed: u64, // Picks the openings when pairs is set, the same seed plays the same openings
+}
+
+impl Default for ParallelOptions {
+    fn default() -> ParallelOptions {
+        ParallelOptions {
+            threads: thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1),
+            pairs: None,
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchProgress {
+    pub result: MatchResult, // Games finished so far, from the first engine's side
+    pub total_games: usize,
+}
+
+fn opening_schedule(openings: usize, parallel: &ParallelOptions) -> Vec<usize> {
+    // Seeded shuffles of the openings, repeated until there are enough pairs, so no opening
+    // comes back before every other one was played
+    let Some(pairs) = parallel.pairs else {
+        return (0..openings).collect();
+    };
+    let mut rng = XorShift::new(parallel.seed);
+    let mut schedule = Vec::with_capacity(pairs);
+    while schedule.len() < pairs && openings > 0 {
+        let mut round: Vec<usize> = (0..openings).collect();
+        for i in 0..openings {
+            let j = i + rng.below(openings - i);
+            round.swap(i, j);
+        }
+        schedule.extend(round.into_iter().take(pairs - schedule.len()));
+    }
+    schedule
+}
+
+pub fn play_match_parallel(
+    first: &Engine,
+    second: &Engine,
+    openings: &[Board],
+    options: &MatchOptions,
+    parallel: &ParallelOptions,
+    progress: &(dyn Fn(&MatchProgress) + Sync),
+) -> Result<MatchResult, NNUEError> {
+    // Like play_match, with the games spread over worker threads that each make their own evaluators.
+    // Every game starts with fresh searchers, so results don't depend on which worker played what.
+    // progress is called after each game, one call at a time.
+    let schedule = opening_schedule(openings.len(), parallel);
+    let total_games = 2 * schedule.len();
+    let next_game = AtomicUsize::new(0);
+    let state: Mutex<(MatchResult, Option<NNUEError>)> = Mutex::new((MatchResult::default(), None));
+
+    let work = || -> Result<(), NNUEError> {
+        let mut first_evaluator = (first.evaluator)()?;
+        let mut second_evaluator = (second.evaluator)()?;
+        loop {
+            let game = next_game.fetch_add(1, Ordering::Relaxed);
+            if game >= total_games || state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).1.is_some() {
+                return Ok(());
+            }
+
+            let opening = &openings[schedule[game / 2]];
+            let mut first_player = Player {
+                evaluator: first_evaluator.as_mut(),
+                searcher: Searcher::new(first.search),
+            };
+            let mut second_player = Player {
+                evaluator: second_evaluator.as_mut(),
+                searcher: Searcher::new(second.search),
+            };
+            let first_is_white = game.is_multiple_of(2);
+            let outcome = match first_is_white {
+                true => play_game(&mut first_player, &mut second_player, opening, options)?,
+                false => play_game(&mut second_player, &mut first_player, opening, options)?,
+            };
+
+            let mut state = state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            state.0.record(outcome, first_is_white);
+            progress(&MatchProgress { result: state.0, total_games });
+        }
+    };
+
+    let threads = parallel.threads.clamp(1, total_games.max(1));
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                if let Err(err) = work() {
+                    state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).1.get_or_insert(err);
+                }
+            });
+        }
+    });
+
+    match state.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+        (_, Some(err)) => Err(err),
+        (result, None) => Ok(result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::search::tests::MaterialEval;
+    use crate::search::SearchOptions;
+
+    #[test]
+    fn test_play_match() {
+        let mut first_eval = MaterialEval { board: Board::default() };
+        let mut second_eval = MaterialEval { board: Board::default() };
+        let mut first = Player {
+            evaluator: &mut first_eval,
+            searcher: Searcher::new(SearchOptions { depth: 2, ..SearchOptions::default() }),
+        };
+        let mut second = Player {
+            evaluator: &mut second_eval,
+            searcher: Searcher::new(SearchOptions { depth: 1, ..SearchOptions::default() }),
+        };
+
+        // White mates on the back rank at once
+        let mate_in_one = Board::from_str("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1").unwrap();
+        let options = MatchOptions::default();
+        assert_eq!(play_game(&mut first, &mut second, &mate_in_one, &options).unwrap(), GameResult::WhiteWins);
+
+        // Nothing is decided within four plies of the start position
+        let options = MatchOptions { max_plies: 4, ..MatchOptions::default() };
+        let result = play_match(&mut first, &mut second, &[Board::default()], &options).unwrap();
+        assert_eq!(result, MatchResult { wins: 0, losses: 0, draws: 2 });
+        assert_eq!(result.score(), 0.5);
+    }
+
+    #[test]
+    fn test_adjudication() {
+        let mut first_eval = MaterialEval { board: Board::default() };
+        let mut second_eval = MaterialEval { board: Board::default() };
+        let mut first = Player {
+            evaluator: &mut first_eval,
+            searcher: Searcher::new(SearchOptions { depth: 1, ..SearchOptions::default() }),
+        };
+        let mut second = Player {
+            evaluator: &mut second_eval,
+            searcher: Searcher::new(SearchOptions { depth: 1, ..SearchOptions::default() }),
+        };
+
+        // Both engines see white a queen up, so black resigns long before mate
+        let queen_up = Board::from_str("4k3/8/8/8/8/8/8/3QK3 w - - 0 1").unwrap();
+        let short = MatchOptions { max_plies: 4, ..MatchOptions::default() };
+        assert_eq!(play_game(&mut first, &mut second, &queen_up, &short).unwrap(), GameResult::Draw);
+        let rules = Adjudication { resign_score: 500, resign_moves: 2, ..Adjudication::default() };
+        let adjudicated = MatchOptions { adjudication: Some(rules), ..short };
+        assert_eq!(play_game(&mut first, &mut second, &queen_up, &adjudicated).unwrap(), GameResult::WhiteWins);
+
+        let knight = Board::from_str("4k3/8/8/8/8/8/8/3NK3 w - - 0 1").unwrap();
+        assert!(is_insufficient_material(&knight));
+        assert!(!is_insufficient_material(&queen_up));
+
+        let mut streaks = AdjudicationStreaks::default();
+        let rules = Adjudication { draw_moves: 1, draw_min_ply: 2, ..Adjudication::default() };
+        assert_eq!(streaks.update(&rules, 5, 1), None);
+        assert_eq!(streaks.update(&rules, -5, 2), Some(GameResult::Draw));
+    }
+    #[test]
+    fn test_parallel_match() {
+        let material = || Ok(Box::new(MaterialEval { board: Board::default() }) as BoxedNNUE);
+        let first = Engine { evaluator: &material, search: SearchOptions { depth: 2, ..SearchOptions::default() } };
+        let second = Engine { evaluator: &material, search: SearchOptions { depth: 1, ..SearchOptions::default() } };
+        let openings = [
+            Board::default(),
+            Board::from_str("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1").unwrap(),
+            Board::from_str("4k3/8/8/8/8/8/8/3QK3 w - - 0 1").unwrap(),
+        ];
+        let options = MatchOptions { max_plies: 8, ..MatchOptions::default() };
+        let parallel = ParallelOptions { threads: 3, pairs: Some(5), seed: 7 };
+
+        // The same seed gives the same result, whatever the number of threads
+        let calls = AtomicUsize::new(0);
+        let count = |progress: &MatchProgress| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            assert!(progress.result.games() as usize <= progress.total_games);
+        };
+        let threaded = play_match_parallel(&first, &second, &openings, &options, &parallel, &count).unwrap();
+        let single = ParallelOptions { threads: 1, ..parallel };
+        assert_eq!(play_match_parallel(&first, &second, &openings, &options, &single, &|_| {}).unwrap(), threaded);
+        assert_eq!((threaded.games(), calls.load(Ordering::Relaxed)), (10, 10));
+
+        // Every opening is scheduled before any comes back
+        let schedule = opening_schedule(3, &parallel);
+        assert_eq!(schedule.len(), 5);
+        assert_eq!(sorted(schedule[..3].to_vec()), vec![0, 1, 2]);
+        assert_eq!(opening_schedule(3, &ParallelOptions { pairs: None, ..parallel }), vec![0, 1, 2]);
+    }
+
+    fn sorted(mut values: Vec<usize>) -> Vec<usize> {
+        values.sort_unstable();
+        values
+    }
+}
@@ -0,0 +1,13 @@
+pub mod accuracy;
+pub mod blunders;
+pub mod calibrate;
+pub mod conformance;
+pub mod eval_file;
+pub mod evalseries;
+pub mod gauntlet;
+pub mod match_runner;
+pub mod parity;
+pub mod puzzles;
+pub mod results;
+pub mod texel;
+pub mod tune;
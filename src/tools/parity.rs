@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use chess::Board;
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::bit_move::active_indices;
+use crate::error::NNUEError;
+
+// Canonical dump, one JSON object per line with the indices sorted ascending:
+//   {"fen":"<fen>","indices":[...]}
+// The Python side should write the same with json.dumps({"fen": fen, "indices": sorted(indices)})
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeatureDump {
+    pub fen: String,
+    pub indices: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub fen: String,
+    pub only_rust: Vec<u16>, // Active here but not in the reference
+    pub only_reference: Vec<u16>, // Active in the reference but not here
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParityReport {
+    pub compared: usize,
+    pub mismatches: Vec<Mismatch>,
+    pub missing: Vec<String>, // FENs with no entry in the reference dump
+}
+
+impl ParityReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty() && self.missing.is_empty()
+    }
+}
+
+pub fn dump_position(fen: &str) -> Result<FeatureDump, NNUEError> {
+    let board = Board::from_str(fen).map_err(|_| NNUEError::InvalidData(format!("bad fen {:?}", fen)))?;
+    let mut indices = active_indices(&board);
+    indices.sort_unstable();
+    Ok(FeatureDump {
+        fen: fen.to_string(),
+        indices,
+    })
+}
+
+pub fn read_fens<P: AsRef<Path>>(path: P) -> Result<Vec<String>, NNUEError> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+pub fn write_dump<P: AsRef<Path>>(path: P, dumps: &[FeatureDump]) -> Result<(), NNUEError> {
+    let mut contents = String::new();
+    for dump in dumps {
+        contents.push_str(&serde_json::to_string(dump).map_err(|err| NNUEError::InvalidData(err.to_string()))?);
+        contents.push('\n');
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+pub fn read_dump<P: AsRef<Path>>(path: P) -> Result<Vec<FeatureDump>, NNUEError> {
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|err| NNUEError::InvalidData(err.to_string())))
+        .collect()
+}
+
+pub fn compare(ours: &[FeatureDump], reference: &[FeatureDump]) -> ParityReport {
+    // Positions are matched on the FEN text, the index order in the reference doesn't matter
+    let reference: FnvHashMap<&str, &FeatureDump> = reference.iter().map(|dump| (dump.fen.trim(), dump)).collect();
+    let mut report = ParityReport::default();
+
+    for dump in ours {
+        let expected = match reference.get(dump.fen.trim()) {
+            Some(expected) => expected,
+            None => {
+                report.missing.push(dump.fen.clone());
+                continue;
+            }
+        };
+
+        report.compared += 1;
+        let only_rust: Vec<u16> = dump.indices.iter().filter(|index| !expected.indices.contains(index)).copied().collect();
+        let only_reference: Vec<u16> = expected.indices.iter().filter(|index| !dump.indices.contains(index)).copied().collect();
+        if !only_rust.is_empty() || !only_reference.is_empty() {
+            report.mismatches.push(Mismatch {
+                fen: dump.fen.clone(),
+                only_rust,
+                only_reference,
+            });
+        }
+    }
+    report
+}
+
+pub fn run<P: AsRef<Path>>(fen_path: P, reference_path: P, output_path: Option<P>) -> Result<ParityReport, NNUEError> {
+    // Encodes every FEN, optionally writes our own dump for inspection, and compares against the reference
+    let ours = read_fens(fen_path)?
+        .iter()
+        .map(|fen| dump_position(fen))
+        .collect::<Result<Vec<FeatureDump>, NNUEError>>()?;
+    if let Some(output_path) = output_path {
+        write_dump(output_path, &ours)?;
+    }
+    Ok(compare(&ours, &read_dump(reference_path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parity_report() {
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let endgame = "4k3/8/8/8/4P3/8/8/4K3 b - - 0 1";
+        let ours = vec![dump_position(start).unwrap(), dump_position(endgame).unwrap()];
+        assert_eq!(ours[0].indices.len(), 32);
+        assert!(ours[0].indices.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // The reference flips the pawn's square, as a missing orientation would
+        let mut reference = ours.clone();
+        let pawn = reference[1].indices.iter().position(|index| *index / 64 == 6).unwrap();
+        reference[1].indices[pawn] ^= 56;
+
+        let path = std::env::temp_dir().join("shallow_nnue_parity_reference.jsonl");
+        write_dump(&path, &reference).unwrap();
+        let report = compare(&ours, &read_dump(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(report.compared, 2);
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].fen, endgame);
+        assert!(!report.is_clean());
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::Path;
+
+use chess::{Board, BoardStatus, ChessMove};
+use serde::{Deserialize, Serialize};
+
+use crate::error::NNUEError;
+use crate::eval_server::BatchEvaluator;
+use crate::search::{SearchOptions, Searcher};
+use crate::shallow_nnue::NNUE;
+use crate::tools::blunders::{score_moves, GameReport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PuzzleOptions {
+    pub min_advantage: i16, // Score the solution has to keep, from the mover's side
+    pub margin: i16, // Every other move has to score at least this much worse
+    pub solution_plies: usize, // Length of the exported line, the puzzle move included
+    pub search: SearchOptions, // Short search that confirms the candidates
+}
+
+impl Default for PuzzleOptions {
+    fn default() -> PuzzleOptions {
+        PuzzleOptions {
+            min_advantage: 300,
+            margin: 200,
+            solution_plies: 3,
+            search: SearchOptions { depth: 2, ..SearchOptions::default() },
+        }
+    }
+}
+
+// One JSON object per line: {"fen":"<fen>","solution":["e4d5",...],"advantage":900,"ply":12}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Puzzle {
+    pub fen: String,
+    pub solution: Vec<String>, // UCI moves, starting with the only good move
+    pub advantage: i16, // Searched score of the solution, from the mover's side
+    pub ply: usize, // Where in the game the position came up
+}
+
+fn is_unique(scored: &[(ChessMove, i16)], options: &PuzzleOptions) -> Option<(ChessMove, i16)> {
+    // The best move if it keeps the advantage and nothing else comes close
+    let (best_move, best) = scored.iter().copied().max_by_key(|(_, score)| *score)?;
+    let unique = best >= options.min_advantage
+        && scored
+            .iter()
+            .filter(|(chess_move, _)| *chess_move != best_move)
+            .all(|(_, score)| *score < options.min_advantage && *score <= best.saturating_sub(options.margin));
+    unique.then_some((best_move, best))
+}
+
+fn searched_scores(searcher: &mut Searcher, evaluator: &mut dyn NNUE, board: &Board, moves: &[(ChessMove, i16)]) -> Result<Vec<(ChessMove, i16)>, NNUEError> {
+    // Scores from the side to move of board, each move searched one ply shallower than the options
+    let mut scored = Vec::with_capacity(moves.len());
+    for (chess_move, score) in moves {
+        let child = board.make_move_new(*chess_move);
+        let score = match child.status() {
+            BoardStatus::Ongoing => searcher.search(evaluator, &child)?.score.saturating_neg(),
+            _ => *score, // Already exact
+        };
+        scored.push((*chess_move, score));
+    }
+    Ok(scored)
+}
+
+fn solution_line(searcher: &mut Searcher, evaluator: &mut dyn NNUE, board: &Board, first: ChessMove, plies: usize) -> Result<Vec<String>, NNUEError> {
+    let mut line = vec![first.to_string()];
+    let mut board = board.make_move_new(first);
+    while line.len() < plies {
+        let Some(chess_move) = searcher.search(evaluator, &board)?.best_move else {
+            break;
+        };
+        line.push(chess_move.to_string());
+        board = board.make_move_new(chess_move);
+    }
+    Ok(line)
+}
+
+pub fn find_puzzles(
+    report: &GameReport,
+    batch: &mut dyn BatchEvaluator,
+    evaluator: &mut dyn NNUE,
+    options: &PuzzleOptions,
+) -> Result<Vec<Puzzle>, NNUEError> {
+    // Positions of an evaluated game where the best move wins, screened with one batched eval of
+    // every move and confirmed by searching each of them
+    let mut searcher = Searcher::new(SearchOptions { depth: options.search.depth.saturating_sub(1).max(1), ..options.search });
+    let mut full_searcher = Searcher::new(options.search);
+    let mut puzzles = Vec::new();
+    let mut board = report.start;
+    for annotated in &report.moves {
+        if annotated.eval_before >= options.min_advantage {
+            let scored = score_moves(batch, &board)?;
+            if is_unique(&scored, options).is_some() {
+                if let Some((solution, advantage)) = is_unique(&searched_scores(&mut searcher, evaluator, &board, &scored)?, options) {
+                    puzzles.push(Puzzle {
+                        fen: board.to_string(),
+                        solution: solution_line(&mut full_searcher, evaluator, &board, solution, options.solution_plies)?,
+                        advantage,
+                        ply: annotated.ply,
+                    });
+                }
+            }
+        }
+        board = board.make_move_new(annotated.chess_move);
+    }
+    Ok(puzzles)
+}
+
+pub fn write_puzzles<P: AsRef<Path>>(path: P, puzzles: &[Puzzle]) -> Result<(), NNUEError> {
+    let mut contents = String::new();
+    for puzzle in puzzles {
+        contents.push_str(&serde_json::to_string(puzzle).map_err(|err| NNUEError::InvalidData(err.to_string()))?);
+        contents.push('\n');
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgn::PgnReader;
+    use crate::search::tests::MaterialEval;
+    use crate::tools::blunders::{analyze_game, BlunderThresholds};
+
+    #[test]
+    fn test_finds_hanging_queen() {
+        let pgn = "[FEN \"4k3/8/8/3q4/4P3/8/8/R3K3 w - - 0 1\"]\n[Result \"*\"]\n\n1. exd5 Ke7 *\n";
+        let game = PgnReader::new(pgn.as_bytes()).next().unwrap().unwrap();
+        let mut batch = MaterialEval { board: game.start };
+        let report = analyze_game(&mut batch, &game, &BlunderThresholds::default()).unwrap();
+
+        let mut evaluator = MaterialEval { board: game.start };
+        let puzzles = find_puzzles(&report, &mut batch, &mut evaluator, &PuzzleOptions::default()).unwrap();
+
+        // Only taking the queen keeps the advantage, black is lost afterwards so its move is no puzzle
+        assert_eq!(puzzles.len(), 1);
+        assert_eq!(puzzles[0].fen, "4k3/8/8/3q4/4P3/8/8/R3K3 w - - 0 1");
+        assert_eq!(puzzles[0].solution.len(), 3);
+        assert_eq!(puzzles[0].solution[0], "e4d5");
+        assert_eq!((puzzles[0].advantage, puzzles[0].ply), (600, 0));
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use std::path::Path;
+
+use crate::error::NNUEError;
+use crate::pgn::parse_header;
+use crate::tools::match_runner::{GameResult, MatchResult};
+
+// Results of an external tournament runner (cutechess-cli, fastchess), read either from the
+// "Finished game" lines of its log or from the headers of the PGN it writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TournamentGame {
+    pub number: Option<usize>, // Game number from the log, games are paired in this order
+    pub white: String,
+    pub black: String,
+    pub result: GameResult,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResultsSummary {
+    pub games: MatchResult, // Wins, losses and draws of the player
+    pub pentanomial: [u32; 5], // Game pairs scoring 0, 0.5, 1, 1.5 and 2 points
+    pub elo: f64,
+    pub elo_error: f64, // Half width of the 95% interval
+    pub los: f64, // Likelihood of superiority
+}
+
+fn parse_result(token: &str) -> Option<GameResult> {
+    match token {
+        "1-0" => Some(GameResult::WhiteWins),
+        "0-1" => Some(GameResult::BlackWins),
+        "1/2-1/2" => Some(GameResult::Draw),
+        _ => None,
+    }
+}
+
+fn parse_finished(line: &str) -> Option<TournamentGame> {
+    // Finished game 12 (engine A vs engine B): 1-0 {White mates}
+    let rest = line.trim().strip_prefix("Finished game ")?;
+    let (number, rest) = rest.split_once(" (")?;
+    let (players, rest) = rest.split_once("): ")?;
+    let (white, black) = players.split_once(" vs ")?;
+    Some(TournamentGame {
+        number: Some(number.trim().parse().ok()?),
+        white: white.to_string(),
+        black: black.to_string(),
+        result: parse_result(rest.split_whitespace().next()?)?,
+    })
+}
+
+pub fn parse_log(text: &str) -> Vec<TournamentGame> {
+    // Unfinished games and every other line are ignored
+    let mut games: Vec<TournamentGame> = text.lines().filter_map(parse_finished).collect();
+    games.sort_by_key(|game| game.number);
+    games
+}
+
+pub fn parse_pgn(text: &str) -> Vec<TournamentGame> {
+    // Only the headers are read, a game ends where the next header block starts
+    let mut games = Vec::new();
+    let (mut white, mut black, mut result) = (None, None, None);
+    let mut in_headers = false;
+    for line in text.lines().chain(std::iter::once("")) {
+        let header = parse_header(line);
+        if header.is_some() && !in_headers {
+            if let (Some(white), Some(black), Some(result)) = (white.take(), black.take(), result.take()) {
+                games.push(TournamentGame { number: None, white, black, result });
+            }
+        }
+        in_headers = header.is_some();
+        match header {
+            Some((name, value)) if name == "White" => white = Some(value),
+            Some((name, value)) if name == "Black" => black = Some(value),
+            Some((name, value)) if name == "Result" => result = parse_result(&value),
+            _ => {}
+        }
+    }
+    if let (Some(white), Some(black), Some(result)) = (white, black, result) {
+        games.push(TournamentGame { number: None, white, black, result });
+    }
+    games
+}
+
+pub fn read_results<P: AsRef<Path>>(path: P) -> Result<Vec<TournamentGame>, NNUEError> {
+    let text = fs::read_to_string(path)?;
+    if text.contains("Finished game") {
+        Ok(parse_log(&text))
+    } else {
+        Ok(parse_pgn(&text))
+    }
+}
+
+pub fn elo(score: f64) -> f64 {
+    // Logistic Elo difference for an expected score, clamped so sweeps stay finite
+    let score = score.clamp(1e-6, 1.0 - 1e-6);
+    -400.0 * (1.0 / score - 1.0).log10()
+}
+
+fn erf(x: f64) -> f64 {
+    // Abramowitz and Stegun 7.1.26, good to about 1e-7
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    (1.0 - poly * (-x * x).exp()).copysign(x)
+}
+
+pub fn los(wins: u32, losses: u32) -> f64 {
+    if wins + losses == 0 {
+        return 0.5;
+    }
+    0.5 * (1.0 + erf((wins as f64 - losses as f64) / (2.0 * (wins + losses) as f64).sqrt()))
+}
+
+fn interval(outcomes: &[(f64, u32)]) -> (f64, f64) {
+    // Mean score and the 95% half width of its estimate, for counted per-game or per-pair outcomes
+    let total: u32 = outcomes.iter().map(|(_, count)| count).sum();
+    if total == 0 {
+        return (0.5, 0.0);
+    }
+    let mean = outcomes.iter().map(|(score, count)| score * *count as f64).sum::<f64>() / total as f64;
+    let variance = outcomes.iter().map(|(score, count)| (score - mean).powi(2) * *count as f64).sum::<f64>() / total as f64;
+    (mean, 1.96 * (variance / total as f64).sqrt())
+}
+
+pub fn summarize(games: &[TournamentGame], player: &str) -> ResultsSummary {
+    // Statistics for player against everyone else, games without the player are skipped
+    let scores: Vec<f64> = games
+        .iter()
+        .filter_map(|game| {
+            let white = if game.white == player {
+                true
+            } else if game.black == player {
+                false
+            } else {
+                return None;
+            };
+            Some(match (game.result, white) {
+                (GameResult::Draw, _) => 0.5,
+                (GameResult::WhiteWins, true) | (GameResult::BlackWins, false) => 1.0,
+                _ => 0.0,
+            })
+        })
+        .collect();
+
+    let mut summary = ResultsSummary::default();
+    for score in &scores {
+        match score {
+            1.0 => summary.games.wins += 1,
+            0.0 => summary.games.losses += 1,
+            _ => summary.games.draws += 1,
+        }
+    }
+    // Runners play each opening twice with colours swapped, so consecutive games form a pair
+    for pair in scores.chunks_exact(2) {
+        summary.pentanomial[((pair[0] + pair[1]) * 2.0) as usize] += 1;
+    }
+
+    // Pairs give the tighter (and correct) error when openings repeat, single games are the fallback
+    let (_, half_width) = if summary.pentanomial.iter().sum::<u32>() > 0 {
+        let pairs: Vec<(f64, u32)> = summary.pentanomial.iter().enumerate().map(|(points, count)| (points as f64 / 4.0, *count)).collect();
+        interval(&pairs)
+    } else {
+        interval(&[(1.0, summary.games.wins), (0.5, summary.games.draws), (0.0, summary.games.losses)])
+    };
+    let mean = summary.games.score();
+    summary.elo = elo(mean);
+    summary.elo_error = (elo(mean + half_width) - elo(mean - half_width)) / 2.0;
+    summary.los = los(summary.games.wins, summary.games.losses);
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_summarize() {
+        let log = "Started game 1 of 4 (new vs old)\n\
+                   Finished game 2 (old vs new): 1/2-1/2 {Draw by 3-fold repetition}\n\
+                   Finished game 1 (new vs old): 1-0 {White mates}\n\
+                   Finished game 3 (new vs old): 0-1 {Black mates}\n\
+                   Finished game 4 (old vs new): 1/2-1/2 {Draw by adjudication}\n";
+        let games = parse_log(log);
+        assert_eq!(games.len(), 4);
+        assert_eq!(games[1], TournamentGame { number: Some(2), white: "old".into(), black: "new".into(), result: GameResult::Draw });
+
+        let summary = summarize(&games, "new");
+        assert_eq!(summary.games, MatchResult { wins: 1, losses: 1, draws: 2 });
+        assert_eq!(summary.pentanomial, [0, 1, 0, 1, 0]);
+        assert!(summary.elo.abs() < 1e-9);
+        assert!(summary.elo_error > 0.0);
+        assert!((summary.los - 0.5).abs() < 1e-6);
+
+        let pgn = "[White \"new\"]\n[Black \"old\"]\n[Result \"1-0\"]\n\n1. e4 e5 1-0\n\n[White \"old\"]\n[Black \"new\"]\n[Result \"1-0\"]\n\n1. d4 1-0\n";
+        let games = parse_pgn(pgn);
+        assert_eq!(games.len(), 2);
+        assert_eq!(summarize(&games, "new").pentanomial, [0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_elo_and_los() {
+        assert!((elo(0.75) - 190.848).abs() < 1e-3);
+        assert!(los(30, 10) > 0.99);
+        assert!((erf(0.5) - 0.5204999).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::classical::{features, ClassicalWeights, NUM_PARAMETERS};
+use crate::dataset::Sample;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TexelOptions {
+    pub epochs: usize,
+    pub learning_rate: f64,
+    pub scale: f64, // Score units for a tenfold change in the predicted odds, 400 for centipawn-like scores
+    pub result_weight: f64, // 1.0 fits game results only, 0.0 fits the score labels only
+}
+
+impl Default for TexelOptions {
+    fn default() -> TexelOptions {
+        TexelOptions {
+            epochs: 200,
+            learning_rate: 1000.0,
+            scale: 400.0,
+            result_weight: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TexelReport {
+    pub weights: ClassicalWeights,
+    pub initial_error: f64,
+    pub final_error: f64, // Mean squared error on the samples, a high floor points at noisy labels
+}
+
+fn win_probability(score: f64, scale: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-score / scale))
+}
+
+fn target(sample: &Sample, options: &TexelOptions) -> f64 {
+    let label = win_probability(sample.score as f64, options.scale);
+    options.result_weight * sample.result as f64 + (1.0 - options.result_weight) * label
+}
+
+fn mean_error(encoded: &[(Vec<(usize, f32)>, f64)], parameters: &[f64], scale: f64) -> f64 {
+    let total: f64 = encoded
+        .iter()
+        .map(|(features, target)| {
+            let score: f64 = features.iter().map(|(index, value)| parameters[*index] * *value as f64).sum();
+            (win_probability(score, scale) - target).powi(2)
+        })
+        .sum();
+    total / encoded.len().max(1) as f64
+}
+
+pub fn texel_error(samples: &[Sample], weights: &ClassicalWeights, options: &TexelOptions) -> f64 {
+    let encoded: Vec<_> = samples.iter().map(|sample| (features(&sample.board), target(sample, options))).collect();
+    mean_error(&encoded, &weights.to_vec(), options.scale)
+}
+
+pub fn texel_tune(samples: &[Sample], initial: &ClassicalWeights, options: &TexelOptions) -> TexelReport {
+    // The eval is linear in its weights, so features are extracted once and the
+    // squared error of the predicted win probability is minimised by gradient descent
+    let encoded: Vec<_> = samples.iter().map(|sample| (features(&sample.board), target(sample, options))).collect();
+    let mut parameters = initial.to_vec();
+    let initial_error = mean_error(&encoded, &parameters, options.scale);
+
+    let ln10 = std::f64::consts::LN_10;
+    let mut gradient = vec![0.0; NUM_PARAMETERS];
+    for _ in 0..options.epochs {
+        gradient.iter_mut().for_each(|value| *value = 0.0);
+        for (features, target) in encoded.iter() {
+            let score: f64 = features.iter().map(|(index, value)| parameters[*index] * *value as f64).sum();
+            let p = win_probability(score, options.scale);
+            let slope = 2.0 * (p - target) * p * (1.0 - p) * ln10 / options.scale;
+            for (index, value) in features {
+                gradient[*index] += slope * *value as f64;
+            }
+        }
+
+        let n = encoded.len().max(1) as f64;
+        for (parameter, gradient) in parameters.iter_mut().zip(gradient.iter()) {
+            *parameter -= options.learning_rate * options.scale * gradient / n;
+        }
+    }
+
+    let weights = ClassicalWeights::from_vec(&parameters);
+    let final_error = mean_error(&encoded, &weights.to_vec(), options.scale);
+    TexelReport {
+        weights,
+        initial_error,
+        final_error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::Board;
+
+    use super::*;
+
+    #[test]
+    fn test_texel_reduces_error() {
+        // Extra queens win, extra knights only draw, so the fitted queen should be worth more than the knight
+        let samples: Vec<Sample> = [
+            ("4k3/8/8/8/8/8/8/3QK3 w - - 0 1", 1.0),
+            ("3qk3/8/8/8/8/8/8/4K3 w - - 0 1", 0.0),
+            ("4k3/8/8/8/8/8/8/3NK3 w - - 0 1", 0.5),
+            ("3nk3/8/8/8/8/8/8/4K3 w - - 0 1", 0.5),
+        ]
+        .iter()
+        .map(|(fen, result)| Sample { board: Board::from_str(fen).unwrap(), score: 0, result: *result, best_move: None })
+        .collect();
+
+        let flat = ClassicalWeights { material: [100; 6], ..ClassicalWeights::default() };
+        let report = texel_tune(&samples, &flat, &TexelOptions::default());
+        assert!(report.final_error < report.initial_error);
+        assert!(report.weights.material[4] > report.weights.material[1]);
+        assert!((texel_error(&samples, &report.weights, &TexelOptions::default()) - report.final_error).abs() < 1e-9);
+    }
+}
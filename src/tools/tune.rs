@@ -0,0 +1,243 @@
+use std::fs;
+use std::path::Path;
+
+use chess::Board;
+
+use crate::error::NNUEError;
+use crate::rng::XorShift;
+use crate::search::{SearchOptions, Searcher};
+use crate::shallow_nnue::NNUE;
+use crate::tools::match_runner::{play_match, MatchOptions, MatchResult, Player};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub value: f64,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64, // Perturbation size at the first iteration, shrinks as tuning goes on
+}
+
+impl Parameter {
+    pub fn new(name: &str, value: f64, min: f64, max: f64, step: f64) -> Parameter {
+        Parameter {
+            name: name.to_string(),
+            value,
+            min,
+            max,
+            step,
+        }
+    }
+
+    fn clamp(&self, value: f64) -> f64 {
+        value.max(self.min).min(self.max)
+    }
+}
+
+pub fn search_parameters(options: &SearchOptions) -> Vec<Parameter> {
+    // The tunable parameters, starting from the values in options
+    vec![
+        Parameter::new("history_bonus", options.ordering.history_bonus as f64, 0.0, 64.0, 2.0),
+        Parameter::new("pawn_value", options.quiescence.pawn_value as f64, 20.0, 1000.0, 10.0),
+        Parameter::new("delta_margin", options.quiescence.delta_margin as f64, 0.0, 1000.0, 50.0),
+    ]
+}
+
+pub fn apply_parameter(options: &mut SearchOptions, name: &str, value: f64) -> Result<(), NNUEError> {
+    let value = value.round();
+    match name {
+        "history_bonus" => options.ordering.history_bonus = value as i32,
+        "pawn_value" => options.quiescence.pawn_value = value.max(i16::MIN as f64).min(i16::MAX as f64) as i16,
+        "delta_margin" => options.quiescence.delta_margin = value.max(i16::MIN as f64).min(i16::MAX as f64) as i16,
+        _ => return Err(NNUEError::InvalidConfig(format!("unknown parameter {}", name))),
+    }
+    Ok(())
+}
+
+pub fn apply_parameters(base: SearchOptions, parameters: &[Parameter]) -> Result<SearchOptions, NNUEError> {
+    let mut options = base;
+    for parameter in parameters {
+        apply_parameter(&mut options, &parameter.name, parameter.value)?;
+    }
+    Ok(options)
+}
+
+pub fn save_parameters<P: AsRef<Path>>(path: P, parameters: &[Parameter]) -> Result<(), NNUEError> {
+    // One "name = value" line per parameter, values rounded the way the engine applies them
+    let mut contents = String::new();
+    for parameter in parameters {
+        contents.push_str(&format!("{} = {}\n", parameter.name, parameter.value.round()));
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+pub fn load_search_options<P: AsRef<Path>>(path: P, base: SearchOptions) -> Result<SearchOptions, NNUEError> {
+    // Applies a saved parameter file on top of base, blank lines and lines starting with # are skipped
+    let contents = fs::read_to_string(path)?;
+    let mut options = base;
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| NNUEError::InvalidConfig(format!("expected name = value, got {}", line)))?;
+        let value = value
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| NNUEError::InvalidConfig(format!("bad value in {}", line)))?;
+        apply_parameter(&mut options, name.trim(), value)?;
+    }
+    Ok(options)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpsaOptions {
+    pub iterations: usize,
+    pub learning_rate: f64, // Movement per iteration in units of a parameter's step, before decay
+    pub stability: f64, // Delays the learning rate decay, usually around a tenth of the iterations
+    pub alpha: f64, // Learning rate decay exponent
+    pub gamma: f64, // Perturbation decay exponent
+    pub seed: u64,
+    pub match_options: MatchOptions,
+}
+
+impl Default for SpsaOptions {
+    fn default() -> SpsaOptions {
+        SpsaOptions {
+            iterations: 100,
+            learning_rate: 1.0,
+            stability: 10.0,
+            alpha: 0.602,
+            gamma: 0.101,
+            seed: 0x5eed,
+            match_options: MatchOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Tuner {
+    options: SpsaOptions,
+    base: SearchOptions,
+    parameters: Vec<Parameter>,
+    rng: XorShift,
+    iteration: usize,
+}
+
+impl Tuner {
+    pub fn new(base: SearchOptions, parameters: Vec<Parameter>, options: SpsaOptions) -> Tuner {
+        Tuner {
+            options,
+            base,
+            parameters,
+            rng: XorShift::new(options.seed),
+            iteration: 0,
+        }
+    }
+
+    pub fn parameters(&self) -> &[Parameter] {
+        &self.parameters
+    }
+
+    pub fn search_options(&self) -> Result<SearchOptions, NNUEError> {
+        apply_parameters(self.base, &self.parameters)
+    }
+
+    fn next_sign(&mut self) -> f64 {
+        if self.rng.next_u64() & 1 == 0 {
+            1.0
+        } else {
+            -1.0
+        }
+    }
+
+    pub fn step(&mut self, first: &mut dyn NNUE, second: &mut dyn NNUE, openings: &[Board]) -> Result<MatchResult, NNUEError> {
+        // One SPSA iteration: play every parameter nudged one way against every parameter nudged the other way,
+        // then move all parameters towards the side that scored better
+        let k = self.iteration as f64;
+        let perturbation = 1.0 / (k + 1.0).powf(self.options.gamma);
+        let learning_rate = self.options.learning_rate / (k + 1.0 + self.options.stability).powf(self.options.alpha);
+
+        let signs: Vec<f64> = (0..self.parameters.len()).map(|_| self.next_sign()).collect();
+        let mut plus = self.base;
+        let mut minus = self.base;
+        for (parameter, sign) in self.parameters.iter().zip(&signs) {
+            let delta = parameter.step * perturbation * sign;
+            apply_parameter(&mut plus, &parameter.name, parameter.clamp(parameter.value + delta))?;
+            apply_parameter(&mut minus, &parameter.name, parameter.clamp(parameter.value - delta))?;
+        }
+
+        let mut plus_player = Player {
+            evaluator: first,
+            searcher: Searcher::new(plus),
+        };
+        let mut minus_player = Player {
+            evaluator: second,
+            searcher: Searcher::new(minus),
+        };
+        let result = play_match(&mut plus_player, &mut minus_player, openings, &self.options.match_options)?;
+
+        // +1 when the plus side won everything, -1 when the minus side did
+        let gradient = 2.0 * result.score() - 1.0;
+        for (parameter, sign) in self.parameters.iter_mut().zip(&signs) {
+            parameter.value = parameter.clamp(parameter.value + learning_rate * parameter.step * gradient * sign);
+        }
+
+        self.iteration += 1;
+        Ok(result)
+    }
+
+    pub fn tune(&mut self, first: &mut dyn NNUE, second: &mut dyn NNUE, openings: &[Board]) -> Result<&[Parameter], NNUEError> {
+        // first and second should be two instances of the same evaluator, one for each side of the match
+        while self.iteration < self.options.iterations {
+            self.step(first, second, openings)?;
+        }
+        Ok(&self.parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::tests::MaterialEval;
+
+    #[test]
+    fn test_parameter_file_round_trip() {
+        let path = std::env::temp_dir().join("shallow_nnue_tune_round_trip.txt");
+        let mut parameters = search_parameters(&SearchOptions::default());
+        parameters[0].value = 7.4;
+        parameters[2].value = 150.0;
+        save_parameters(&path, &parameters).unwrap();
+
+        let options = load_search_options(&path, SearchOptions::default()).unwrap();
+        assert_eq!(options.ordering.history_bonus, 7);
+        assert_eq!(options.quiescence.delta_margin, 150);
+        assert_eq!(options.depth, SearchOptions::default().depth);
+
+        fs::write(&path, "# tuned\nnull_move = 3\n").unwrap();
+        assert!(matches!(load_search_options(&path, SearchOptions::default()), Err(NNUEError::InvalidConfig(_))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_spsa_stays_in_bounds() {
+        let base = SearchOptions { depth: 1, ..SearchOptions::default() };
+        let options = SpsaOptions {
+            iterations: 2,
+            learning_rate: 100.0,
+            match_options: MatchOptions { max_plies: 6, ..MatchOptions::default() },
+            ..SpsaOptions::default()
+        };
+        let mut tuner = Tuner::new(base, search_parameters(&base), options);
+        let mut first = MaterialEval { board: Board::default() };
+        let mut second = MaterialEval { board: Board::default() };
+
+        let parameters = tuner.tune(&mut first, &mut second, &[Board::default()]).unwrap();
+        for parameter in parameters {
+            assert!(parameter.value >= parameter.min && parameter.value <= parameter.max);
+        }
+        assert_eq!(tuner.search_options().unwrap().depth, 1);
+    }
+}
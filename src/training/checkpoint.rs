@@ -0,0 +1,69 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tch::nn;
+
+use crate::error::NNUEError;
+use crate::training::optim::Optimizer;
+
+// A checkpoint directory holds the best weights (best.ot) and the latest resumable state:
+//   last.ot        weights after the last finished epoch
+//   last.optim.ot  optimizer moments and step count for that epoch
+//   last.json      counters and RNG state for that epoch
+pub const BEST_WEIGHTS: &str = "best.ot";
+const LAST_WEIGHTS: &str = "last.ot";
+const LAST_OPTIMIZER: &str = "last.optim.ot";
+const LAST_STATE: &str = "last.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrainingState {
+    pub epoch: usize, // Epochs finished
+    pub step: usize, // Optimizer steps taken
+    pub rng: u64, // Shuffle RNG state
+    pub best_loss: Option<f64>, // Best validation loss so far
+    pub epochs_since_best: usize,
+}
+
+fn json_error(err: serde_json::Error) -> NNUEError {
+    NNUEError::InvalidData(err.to_string())
+}
+
+pub fn save(dir: &Path, vs: &nn::VarStore, optimizer: &Optimizer, state: &TrainingState) -> Result<(), NNUEError> {
+    // The state is written last, through a rename, so a crash mid-save leaves the previous checkpoint usable
+    fs::create_dir_all(dir)?;
+    vs.save(dir.join(LAST_WEIGHTS))?;
+    optimizer.save(&dir.join(LAST_OPTIMIZER))?;
+    let temporary = dir.join(format!("{}.tmp", LAST_STATE));
+    fs::write(&temporary, serde_json::to_string_pretty(state).map_err(json_error)?)?;
+    fs::rename(temporary, dir.join(LAST_STATE))?;
+    Ok(())
+}
+
+pub fn load(dir: &Path, vs: &mut nn::VarStore, optimizer: &mut Optimizer) -> Result<TrainingState, NNUEError> {
+    // The optimizer must be built on vs, its variables share their storage with the loaded weights
+    let state = serde_json::from_str(&fs::read_to_string(dir.join(LAST_STATE))?).map_err(json_error)?;
+    vs.load(dir.join(LAST_WEIGHTS))?;
+    optimizer.load(&dir.join(LAST_OPTIMIZER))?;
+    Ok(state)
+}
+
+pub fn best_weights(dir: &Path) -> PathBuf {
+    dir.join(BEST_WEIGHTS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_training_state_round_trip() {
+        let state = TrainingState { epoch: 4, step: 400, rng: 0xdead_beef, best_loss: Some(0.0125), epochs_since_best: 1 };
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(serde_json::from_str::<TrainingState>(&json).unwrap(), state);
+
+        // A fresh run has no best loss yet, which JSON can't express as infinity
+        let fresh = serde_json::to_string(&TrainingState::default()).unwrap();
+        assert_eq!(serde_json::from_str::<TrainingState>(&fresh).unwrap().best_loss, None);
+    }
+}
@@ -0,0 +1,140 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use chess::{Board, BoardStatus, ChessMove};
+
+use crate::dataset::{write_samples, Sample};
+use crate::error::NNUEError;
+use crate::lichess::MATE_LABEL;
+use crate::perspective::to_white;
+use crate::tools::parity::read_fens;
+use crate::uci::client::{Limit, Score, UciEngine};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistillOptions {
+    pub limit: Limit, // Search budget per position, fixed depth or nodes keep labels reproducible
+    pub units_per_pawn: i16, // Network output units per 100 engine centipawns
+    pub scale: f64, // Turns the label into the result field, same meaning as the trainer's scale
+}
+
+impl Default for DistillOptions {
+    fn default() -> DistillOptions {
+        DistillOptions {
+            limit: Limit::Depth(10),
+            units_per_pawn: 100,
+            scale: 400.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DistillReport {
+    pub labelled: usize,
+    pub skipped: usize, // Finished games and positions the engine gave no score for
+}
+
+pub fn score_to_label(score: Score, units_per_pawn: i16) -> i16 {
+    // Same scale and mate encoding as the Lichess labels
+    match score {
+        Score::Centipawns(cp) => {
+            let units = cp as i64 * units_per_pawn as i64 / 100;
+            units.clamp(-(MATE_LABEL as i64) + 1, MATE_LABEL as i64 - 1) as i16
+        }
+        Score::Mate(moves) => {
+            let label = MATE_LABEL - (moves.unsigned_abs().min(MATE_LABEL as u32) as i16);
+            if moves < 0 { -label } else { label }
+        }
+    }
+}
+
+pub struct Distiller {
+    engine: UciEngine,
+    options: DistillOptions,
+}
+
+impl Distiller {
+    pub fn new(engine: UciEngine, options: DistillOptions) -> Distiller {
+        Distiller { engine, options }
+    }
+
+    pub fn spawn<P: AsRef<Path>>(engine_path: P, options: DistillOptions) -> Result<Distiller, NNUEError> {
+        Ok(Distiller::new(UciEngine::spawn(engine_path)?, options))
+    }
+
+    pub fn label(&mut self, board: &Board) -> Result<Option<Sample>, NNUEError> {
+        // The engine's view of the position as a training sample, None when there is nothing to search
+        if board.status() != BoardStatus::Ongoing {
+            return Ok(None);
+        }
+        let search = self.engine.search(board, self.options.limit)?;
+        let score = match search.score {
+            Some(score) => score_to_label(score, self.options.units_per_pawn),
+            None => return Ok(None),
+        };
+        let best_move = search.best_move.and_then(|name| ChessMove::from_str(&name).ok()).filter(|chess_move| board.legal(*chess_move));
+
+        // No game was played, so the result is the win probability the label implies
+        let score = to_white(score, board.side_to_move());
+        let result = 1.0 / (1.0 + 10f64.powf(-score as f64 / self.options.scale));
+        Ok(Some(Sample { board: *board, score, result: result as f32, best_move }))
+    }
+
+    pub fn distill<'a, I: IntoIterator<Item = &'a Board>>(&mut self, boards: I) -> Result<(Vec<Sample>, DistillReport), NNUEError> {
+        // Clears the engine's hash first so labels don't depend on what it searched before this run
+        self.engine.new_game()?;
+        let mut samples = Vec::new();
+        let mut report = DistillReport::default();
+        for board in boards {
+            match self.label(board)? {
+                Some(sample) => {
+                    samples.push(sample);
+                    report.labelled += 1;
+                }
+                None => report.skipped += 1,
+            }
+        }
+        Ok((samples, report))
+    }
+}
+
+pub fn distill_file<P: AsRef<Path>>(engine_path: P, fen_path: P, output_path: P, options: DistillOptions) -> Result<DistillReport, NNUEError> {
+    // Labels one FEN per line with the engine and writes them in the dataset format
+    let boards = read_fens(fen_path)?
+        .iter()
+        .map(|fen| Board::from_str(fen).map_err(|_| NNUEError::InvalidData(format!("bad fen {:?}", fen))))
+        .collect::<Result<Vec<Board>, NNUEError>>()?;
+
+    let mut distiller = Distiller::spawn(engine_path, options)?;
+    let (samples, report) = distiller.distill(&boards)?;
+    write_samples(output_path, &samples)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score_to_label() {
+        assert_eq!(score_to_label(Score::Centipawns(-150), 100), -150);
+        assert_eq!(score_to_label(Score::Centipawns(150), 200), 300);
+        assert_eq!(score_to_label(Score::Centipawns(1_000_000), 100), MATE_LABEL - 1);
+        assert_eq!(score_to_label(Score::Mate(-3), 100), -(MATE_LABEL - 3));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_distill_fake_engine() {
+        use crate::uci::client::tests::fake_engine;
+
+        let engine = fake_engine("distill", "info depth 5 score cp 80 pv e7e5");
+        let black_to_move = Board::from_str("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1").unwrap();
+        let mated = Board::from_str("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3").unwrap();
+
+        let mut distiller = Distiller::spawn(&engine, DistillOptions::default()).unwrap();
+        let (samples, report) = distiller.distill(&[black_to_move, mated]).unwrap();
+        assert_eq!(report, DistillReport { labelled: 1, skipped: 1 });
+        assert_eq!(samples[0].score, -80); // Good for black, stored from white's side
+        assert!(samples[0].result < 0.5);
+    }
+}
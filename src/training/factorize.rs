@@ -0,0 +1,63 @@
+// Virtual features that only exist while training. Every real feature also switches on the virtual
+// features it factorizes to, so related features learn a shared part from each other's samples,
+// which matters most on small datasets. The first layer is linear, so on export each virtual
+// feature's weights are added onto the rows of its real features and the virtual inputs disappear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Factorizer {
+    Pieces, // One virtual feature per plane, the piece regardless of its square
+}
+
+impl Factorizer {
+    pub fn name(self) -> &'static str {
+        // As recorded in the exported model's metadata
+        match self {
+            Factorizer::Pieces => "pieces",
+        }
+    }
+
+    pub fn virtual_features(self) -> usize {
+        match self {
+            Factorizer::Pieces => 12,
+        }
+    }
+
+    pub fn virtual_index(self, feature: u16) -> usize {
+        // Offset among the virtual features, which follow the 768 real ones
+        match self {
+            Factorizer::Pieces => (feature / 64) as usize,
+        }
+    }
+
+    pub fn fold(self, weights: &[f32], real_features: usize) -> Vec<f32> {
+        // Turns first layer weights in the torch.nn.Linear layout, [outputs][real + virtual],
+        // into [outputs][real] computing the same thing for any real input
+        let width = real_features + self.virtual_features();
+        weights
+            .chunks(width)
+            .flat_map(|row| (0..real_features).map(move |feature| row[feature] + row[real_features + self.virtual_index(feature as u16)]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold_keeps_outputs() {
+        // Two outputs over 768 real and 12 virtual inputs
+        let width = 768 + 12;
+        let weights: Vec<f32> = (0..2 * width).map(|i| (i % 97) as f32 * 0.01).collect();
+        let folded = Factorizer::Pieces.fold(&weights, 768);
+        assert_eq!(folded.len(), 2 * 768);
+
+        // Own pawns on e2 and d2 and the opponent's king on e8, with their virtual features
+        let active = [12u16, 11, 11 * 64 + 60];
+        for output in 0..2 {
+            let row = &weights[output * width..(output + 1) * width];
+            let factorized: f32 = active.iter().map(|feature| row[*feature as usize] + row[768 + Factorizer::Pieces.virtual_index(*feature)]).sum();
+            let real: f32 = active.iter().map(|feature| folded[output * 768 + *feature as usize]).sum();
+            assert!((factorized - real).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::Path;
+
+use fnv::FnvHashSet;
+
+use crate::dataset::{read_samples, Sample};
+use crate::error::NNUEError;
+use crate::features::{FeatureSet, NUM_FEATURES};
+use crate::phase::phase;
+
+const SCORE_BUCKET: i32 = 100; // Centipawns per score histogram bucket
+const SCORE_BUCKETS: usize = 21; // -1000 to 1000, the outer buckets also take everything beyond
+
+// Statistics of a dataset, to spot skewed labels, phases or duplicated positions before a long run
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatasetReport {
+    pub samples: usize,
+    pub results: [usize; 3], // Black wins, draws and white wins
+    pub mean_score: f64,
+    pub score_histogram: [usize; SCORE_BUCKETS], // Bucket i holds scores around (i - 10) * 100
+    pub phases: [usize; 3], // Opening, middlegame and endgame as phase sees them
+    pub duplicates: usize, // Samples whose position already appeared earlier in the file
+    pub piece_counts: [usize; 33], // Samples by the number of pieces on the board, kings included
+    pub out_of_range: usize, // Samples with a model input index outside the 768 inputs
+    pub collisions: usize, // Samples where two pieces landed on the same model input
+}
+
+impl DatasetReport {
+    pub fn duplicate_rate(&self) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        self.duplicates as f64 / self.samples as f64
+    }
+
+    pub fn is_sane(&self) -> bool {
+        // Whether every sample encodes to one distinct, in range input per piece
+        self.out_of_range == 0 && self.collisions == 0
+    }
+
+    pub fn render(&self) -> String {
+        let share = |count: usize| if self.samples == 0 { 0.0 } else { 100.0 * count as f64 / self.samples as f64 };
+        let mut text = format!("samples {}\n", self.samples);
+        text.push_str(&format!(
+            "results black {:.1}% draw {:.1}% white {:.1}%\n",
+            share(self.results[0]),
+            share(self.results[1]),
+            share(self.results[2])
+        ));
+        text.push_str(&format!("mean score {:.1}\n", self.mean_score));
+        for (bucket, count) in self.score_histogram.iter().enumerate().filter(|(_, count)| **count > 0) {
+            text.push_str(&format!("score {} {}\n", (bucket as i32 - SCORE_BUCKETS as i32 / 2) * SCORE_BUCKET, count));
+        }
+        text.push_str(&format!(
+            "phases opening {:.1}% middlegame {:.1}% endgame {:.1}%\n",
+            share(self.phases[0]),
+            share(self.phases[1]),
+            share(self.phases[2])
+        ));
+        text.push_str(&format!("duplicates {} ({:.1}%)\n", self.duplicates, 100.0 * self.duplicate_rate()));
+        for (pieces, count) in self.piece_counts.iter().enumerate().filter(|(_, count)| **count > 0) {
+            text.push_str(&format!("pieces {} {}\n", pieces, count));
+        }
+        text.push_str(&format!("encoding out of range {} collisions {}\n", self.out_of_range, self.collisions));
+        text
+    }
+}
+
+fn score_bucket(score: i16) -> usize {
+    // Rounded to the nearest bucket
+    let half = SCORE_BUCKETS as i32 / 2;
+    let bucket = (score as i32 + SCORE_BUCKET / 2).div_euclid(SCORE_BUCKET);
+    (bucket.clamp(-half, half) + half) as usize
+}
+
+pub fn inspect_samples(samples: &[Sample], features: &FeatureSet) -> DatasetReport {
+    let mut report = DatasetReport {
+        samples: samples.len(),
+        results: [0; 3],
+        mean_score: 0.0,
+        score_histogram: [0; SCORE_BUCKETS],
+        phases: [0; 3],
+        duplicates: 0,
+        piece_counts: [0; 33],
+        out_of_range: 0,
+        collisions: 0,
+    };
+    let mut seen = FnvHashSet::default();
+    let mut total_score = 0.0;
+    for sample in samples {
+        // Results are 0, 0.5 or 1 but any value in between is accepted by the parser,
+        // results outside 0 to 1 are left out of the counts
+        let result_bucket = (sample.result >= 0.0).then(|| (sample.result * 2.0).round() as usize);
+        if let Some(count) = result_bucket.and_then(|bucket| report.results.get_mut(bucket)) {
+            *count += 1;
+        }
+        total_score += sample.score as f64;
+        report.score_histogram[score_bucket(sample.score)] += 1;
+        report.phases[phase(&sample.board).index()] += 1;
+        if !seen.insert(sample.board.get_hash()) {
+            report.duplicates += 1;
+        }
+        let pieces = sample.board.combined().popcnt() as usize;
+        report.piece_counts[pieces.min(32)] += 1;
+
+        let active = features.active(&sample.board);
+        if active.iter().any(|index| *index >= NUM_FEATURES) {
+            report.out_of_range += 1;
+        }
+        let distinct: FnvHashSet<u16> = active.iter().copied().collect();
+        if distinct.len() != pieces {
+            report.collisions += 1;
+        }
+    }
+    if !samples.is_empty() {
+        report.mean_score = total_score / samples.len() as f64;
+    }
+    report
+}
+
+pub fn inspect<P: AsRef<Path>>(path: P, features: &FeatureSet) -> Result<DatasetReport, NNUEError> {
+    Ok(inspect_samples(&read_samples(path)?, features))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use chess::Board;
+
+    use super::*;
+    use crate::features::IndexingScheme;
+
+    #[test]
+    fn test_inspect_samples() {
+        let start = Board::default();
+        let endgame = Board::from_str("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1").unwrap();
+        let samples = [
+            Sample { board: start, score: 20, result: 0.5, best_move: None },
+            Sample { board: start, score: 30, result: 1.0, best_move: None },
+            Sample { board: endgame, score: -1500, result: 0.0, best_move: None },
+        ];
+        let report = inspect_samples(&samples, &FeatureSet::default());
+        assert_eq!((report.samples, report.results, report.duplicates), (3, [1, 1, 1], 1));
+        assert_eq!(report.phases, [2, 0, 1]);
+        assert_eq!((report.piece_counts[32], report.piece_counts[6]), (2, 1));
+        assert_eq!((report.score_histogram[10], report.score_histogram[0]), (2, 1));
+        assert!(report.is_sane());
+        assert!(report.render().contains("duplicates 1 (33.3%)"));
+
+        let odd_results = [Sample { result: 2.0, ..samples[0] }, Sample { result: -1.0, ..samples[0] }];
+        assert_eq!(inspect_samples(&odd_results, &FeatureSet::default()).results, [0, 0, 0]);
+
+        let overflowing = FeatureSet::Relative768(IndexingScheme::Custom(Arc::new(|plane, square| plane * 64 + square + 64)));
+        let collapsed = FeatureSet::Relative768(IndexingScheme::Custom(Arc::new(|_, square| square % 8)));
+        assert_eq!(inspect_samples(&samples, &overflowing).out_of_range, 3); // The opponent's king plane is pushed past the end
+        assert_eq!(inspect_samples(&samples, &collapsed).collisions, 3);
+    }
+}
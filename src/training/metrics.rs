@@ -0,0 +1,115 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::NNUEError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ValidationMetrics {
+    pub loss: f64,
+    pub sign_accuracy: f64, // Share of decided labels where the prediction has the same sign
+    pub correlation: f64, // Pearson correlation between predictions and score labels
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EpochMetrics {
+    pub epoch: usize,
+    pub train_loss: f64,
+    pub validation: ValidationMetrics,
+}
+
+pub(crate) fn sign_accuracy(predictions: &[f32], labels: &[f32]) -> f64 {
+    // Drawn (zero) labels have no sign to agree with and are left out
+    let (agree, decided) = predictions
+        .iter()
+        .zip(labels)
+        .filter(|(_, label)| **label != 0.0)
+        .fold((0, 0), |(agree, decided), (prediction, label)| {
+            (agree + (prediction.signum() == label.signum()) as usize, decided + 1)
+        });
+    if decided == 0 {
+        return 0.0;
+    }
+    agree as f64 / decided as f64
+}
+
+pub(crate) fn correlation(predictions: &[f32], labels: &[f32]) -> f64 {
+    let n = predictions.len().min(labels.len()) as f64;
+    if n < 2.0 {
+        return 0.0;
+    }
+    let mean_x = predictions.iter().map(|x| *x as f64).sum::<f64>() / n;
+    let mean_y = labels.iter().map(|y| *y as f64).sum::<f64>() / n;
+
+    let (mut covariance, mut variance_x, mut variance_y) = (0.0, 0.0, 0.0);
+    for (x, y) in predictions.iter().zip(labels) {
+        let (dx, dy) = (*x as f64 - mean_x, *y as f64 - mean_y);
+        covariance += dx * dy;
+        variance_x += dx * dx;
+        variance_y += dy * dy;
+    }
+    if variance_x == 0.0 || variance_y == 0.0 {
+        return 0.0;
+    }
+    covariance / (variance_x * variance_y).sqrt()
+}
+
+pub struct MetricsLog {
+    // Long format "wall_time,step,tag,value", the same columns TensorBoard exports scalars with
+    writer: BufWriter<File>,
+}
+
+impl MetricsLog {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<MetricsLog, NNUEError> {
+        MetricsLog::open(path, false)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, append: bool) -> Result<MetricsLog, NNUEError> {
+        // Appending keeps the rows of a resumed run in the same file, the header is only written once
+        let file = OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if is_empty {
+            writeln!(writer, "wall_time,step,tag,value")?;
+        }
+        Ok(MetricsLog { writer })
+    }
+
+    pub fn scalar(&mut self, step: usize, tag: &str, value: f64) -> Result<(), NNUEError> {
+        let wall_time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |time| time.as_secs_f64());
+        writeln!(self.writer, "{:.3},{},{},{}", wall_time, step, tag, value)?;
+        Ok(())
+    }
+
+    pub fn epoch(&mut self, metrics: &EpochMetrics) -> Result<(), NNUEError> {
+        self.scalar(metrics.epoch, "train/loss", metrics.train_loss)?;
+        self.scalar(metrics.epoch, "validation/loss", metrics.validation.loss)?;
+        self.scalar(metrics.epoch, "validation/sign_accuracy", metrics.validation.sign_accuracy)?;
+        self.scalar(metrics.epoch, "validation/correlation", metrics.validation.correlation)?;
+        self.writer.flush()?; // Keep the file readable while a long run is going
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation_metrics() {
+        let predictions = [120.0, -40.0, 10.0, 300.0];
+        let labels = [100.0, -50.0, 0.0, -20.0];
+        assert_eq!(sign_accuracy(&predictions, &labels), 2.0 / 3.0);
+        assert!((correlation(&labels, &labels) - 1.0).abs() < 1e-9);
+        assert_eq!(correlation(&[1.0, 1.0], &[0.0, 5.0]), 0.0);
+
+        let path = std::env::temp_dir().join("shallow_nnue_metrics.csv");
+        let mut log = MetricsLog::create(&path).unwrap();
+        log.epoch(&EpochMetrics { epoch: 3, ..EpochMetrics::default() }).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents.lines().count(), 5);
+        assert!(contents.lines().nth(1).unwrap().ends_with(",3,train/loss,0"));
+    }
+}
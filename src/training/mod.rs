@@ -0,0 +1,10 @@
+pub mod checkpoint;
+pub mod distill;
+pub mod factorize;
+pub mod inspect;
+pub mod metrics;
+pub mod optim;
+pub mod policy;
+pub mod replay;
+pub mod trainer;
+pub mod widen;
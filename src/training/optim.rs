@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::path::Path;
+
+use tch::{nn, Tensor};
+
+use crate::error::NNUEError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptimizerKind {
+    Adam, // Weight decay is added to the gradient (L2)
+    AdamW, // Weight decay is applied to the weights directly
+    Sgd { momentum: f64, nesterov: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LrSchedule {
+    Constant,
+    Step { every: usize, gamma: f64 }, // Multiply by gamma every this many optimizer steps
+    Cosine { min_lr: f64 }, // Anneal from the base rate to min_lr over the run
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OptimizerOptions {
+    pub kind: OptimizerKind,
+    pub learning_rate: f64,
+    pub weight_decay: f64,
+    pub grad_clip: Option<f64>, // Maximum gradient norm
+    pub schedule: LrSchedule,
+    pub warmup_steps: usize, // Linear ramp from zero before the schedule starts
+    pub accumulation_steps: usize, // Batches whose gradients are summed into one optimizer step
+    pub mixed_precision: Option<LossScaling>, // f16 autocast on CUDA, ignored on other devices
+}
+
+// Dynamic loss scaling for f16 training. The loss is scaled up before the backward pass so small
+// gradients don't underflow in f16, and the gradients are scaled back down before the step. Steps
+// with overflowing gradients are skipped and the scale backs off, long runs without one let it grow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LossScaling {
+    pub initial_scale: f64,
+    pub growth_interval: usize, // Steps without an overflow before the scale doubles
+}
+
+impl Default for LossScaling {
+    fn default() -> LossScaling {
+        LossScaling {
+            initial_scale: 65536.0,
+            growth_interval: 2000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct LossScaler {
+    options: LossScaling,
+    scale: f64,
+    good_steps: usize,
+}
+
+impl LossScaler {
+    pub(crate) fn new(options: LossScaling) -> LossScaler {
+        LossScaler { options, scale: options.initial_scale, good_steps: 0 }
+    }
+
+    pub(crate) fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    pub(crate) fn update(&mut self, finite: bool) -> bool {
+        // Whether the step should be taken, after adjusting the scale for the next one
+        if !finite {
+            self.scale = (self.scale * 0.5).max(1.0);
+            self.good_steps = 0;
+            return false;
+        }
+        self.good_steps += 1;
+        if self.good_steps >= self.options.growth_interval {
+            self.scale *= 2.0;
+            self.good_steps = 0;
+        }
+        true
+    }
+}
+
+impl Default for OptimizerOptions {
+    fn default() -> OptimizerOptions {
+        OptimizerOptions {
+            kind: OptimizerKind::Adam,
+            learning_rate: 1e-3,
+            weight_decay: 0.0,
+            grad_clip: None,
+            schedule: LrSchedule::Constant,
+            warmup_steps: 0,
+            accumulation_steps: 1,
+            mixed_precision: None,
+        }
+    }
+}
+
+impl OptimizerOptions {
+    pub fn build(&self, vs: &nn::VarStore) -> Result<Optimizer, NNUEError> {
+        let mut variables: Vec<(String, Tensor)> = vs.variables().into_iter().filter(|(_, variable)| variable.requires_grad()).collect();
+        variables.sort_by(|a, b| a.0.cmp(&b.0));
+        let moments = variables
+            .iter()
+            .map(|(_, variable)| Ok([variable.f_zeros_like()?, variable.f_zeros_like()?]))
+            .collect::<Result<_, NNUEError>>()?;
+        Ok(Optimizer {
+            kind: self.kind,
+            weight_decay: self.weight_decay,
+            learning_rate: self.learning_rate,
+            steps: 0,
+            variables,
+            moments,
+        })
+    }
+
+    pub fn learning_rate(&self, step: usize, total_steps: usize) -> f64 {
+        // Rate for the given optimizer step, counted from 0
+        if step < self.warmup_steps {
+            return self.learning_rate * (step + 1) as f64 / self.warmup_steps as f64;
+        }
+
+        let step = step - self.warmup_steps;
+        match self.schedule {
+            LrSchedule::Constant => self.learning_rate,
+            LrSchedule::Step { every, gamma } => self.learning_rate * gamma.powi((step / every.max(1)) as i32),
+            LrSchedule::Cosine { min_lr } => {
+                let span = total_steps.saturating_sub(self.warmup_steps).max(1);
+                let progress = (step as f64 / span as f64).min(1.0);
+                min_lr + 0.5 * (self.learning_rate - min_lr) * (1.0 + (PI * progress).cos())
+            }
+        }
+    }
+
+    pub(crate) fn apply_gradients(&self, optimizer: &mut Optimizer, scaler: Option<&mut LossScaler>) -> Result<bool, NNUEError> {
+        // Steps on the gradients of the backward passes since the last zero_grad, first unscaling them
+        // with mixed precision. False when the step was skipped for an overflow.
+        if let Some(scaler) = scaler {
+            let mut finite = true;
+            for (_, variable) in &optimizer.variables {
+                let mut grad = variable.grad();
+                if grad.defined() {
+                    grad.f_mul_scalar_(1.0 / scaler.scale())?;
+                    finite &= grad.f_isfinite()?.f_all()?.f_int64_value(&[])? != 0;
+                }
+            }
+            if !scaler.update(finite) {
+                optimizer.zero_grad()?;
+                return Ok(false);
+            }
+        }
+        if let Some(max_norm) = self.grad_clip {
+            optimizer.clip_grad_norm(max_norm)?;
+        }
+        optimizer.step()?;
+        Ok(true)
+    }
+}
+
+// Torch's Adam, AdamW and SGD steps, done here rather than through tch's optimizer so the moments can
+// be saved with a checkpoint and a resumed run follows the same trajectory as an uninterrupted one.
+// Adam keeps the first and second moments, SGD the momentum buffer in the first slot.
+const BETA1: f64 = 0.9;
+const BETA2: f64 = 0.999;
+const EPSILON: f64 = 1e-8;
+const STEPS: &str = "steps";
+
+#[derive(Debug)]
+pub struct Optimizer {
+    kind: OptimizerKind,
+    weight_decay: f64,
+    learning_rate: f64,
+    steps: i64, // Steps taken, for Adam's bias correction
+    variables: Vec<(String, Tensor)>, // Trainable variables by name, sharing storage with the VarStore
+    moments: Vec<[Tensor; 2]>,
+}
+
+impl Optimizer {
+    pub fn steps(&self) -> i64 {
+        self.steps
+    }
+
+    pub fn set_lr(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    pub fn zero_grad(&mut self) -> Result<(), NNUEError> {
+        for (_, variable) in &self.variables {
+            let mut grad = variable.grad();
+            if grad.defined() {
+                grad.f_zero_()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clip_grad_norm(&mut self, max_norm: f64) -> Result<(), NNUEError> {
+        // Scales all gradients together so their combined norm is at most max_norm
+        let mut squared = 0f64;
+        for (_, variable) in &self.variables {
+            let grad = variable.grad();
+            if grad.defined() {
+                squared += grad.f_norm()?.f_double_value(&[])?.powi(2);
+            }
+        }
+        let norm = squared.sqrt();
+        if norm > max_norm {
+            for (_, variable) in &self.variables {
+                let mut grad = variable.grad();
+                if grad.defined() {
+                    grad.f_mul_scalar_(max_norm / (norm + 1e-6))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn step(&mut self) -> Result<(), NNUEError> {
+        self.steps += 1;
+        let (lr, weight_decay) = (self.learning_rate, self.weight_decay);
+        let correction1 = 1.0 - BETA1.powi(self.steps as i32);
+        let correction2 = 1.0 - BETA2.powi(self.steps as i32);
+        tch::no_grad(|| {
+            for ((_, variable), [first, second]) in self.variables.iter_mut().zip(self.moments.iter_mut()) {
+                let grad = variable.grad();
+                if !grad.defined() {
+                    continue;
+                }
+                let update = match self.kind {
+                    OptimizerKind::Adam | OptimizerKind::AdamW => {
+                        let grad = match self.kind {
+                            OptimizerKind::Adam if weight_decay != 0.0 => grad + &*variable * weight_decay,
+                            _ => grad,
+                        };
+                        if self.kind == OptimizerKind::AdamW {
+                            variable.f_mul_scalar_(1.0 - lr * weight_decay)?;
+                        }
+                        first.f_copy_(&(&*first * BETA1 + &grad * (1.0 - BETA1)))?;
+                        second.f_copy_(&(&*second * BETA2 + &grad * &grad * (1.0 - BETA2)))?;
+                        let denominator = (&*second / correction2).f_sqrt()? + EPSILON;
+                        &*first / correction1 / &denominator * lr
+                    }
+                    OptimizerKind::Sgd { momentum, nesterov } => {
+                        let grad = grad + &*variable * weight_decay;
+                        first.f_copy_(&(&*first * momentum + &grad))?;
+                        match nesterov {
+                            true => (grad + &*first * momentum) * lr,
+                            false => &*first * lr,
+                        }
+                    }
+                };
+                let stepped = &*variable - &update;
+                variable.f_copy_(&stepped)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<(), NNUEError> {
+        let mut named = vec![(STEPS.to_string(), Tensor::from(self.steps))];
+        for ((name, _), [first, second]) in self.variables.iter().zip(&self.moments) {
+            named.push((format!("{}.first", name), first.shallow_clone()));
+            named.push((format!("{}.second", name), second.shallow_clone()));
+        }
+        Tensor::save_multi(&named, path)?;
+        Ok(())
+    }
+
+    pub(crate) fn load(&mut self, path: &Path) -> Result<(), NNUEError> {
+        let mut saved: HashMap<String, Tensor> = Tensor::load_multi(path)?.into_iter().collect();
+        let mut take = |name: String| saved.remove(&name).ok_or_else(|| NNUEError::InvalidData(format!("optimizer state is missing {}", name)));
+        let steps = take(STEPS.to_string())?.f_int64_value(&[])?;
+        for ((name, _), moments) in self.variables.iter().zip(self.moments.iter_mut()) {
+            for (moment, suffix) in moments.iter_mut().zip(["first", "second"]) {
+                let tensor = take(format!("{}.{}", name, suffix))?;
+                if tensor.size() != moment.size() {
+                    return Err(NNUEError::InvalidData(format!("optimizer state for {} has the wrong shape", name)));
+                }
+                moment.f_copy_(&tensor.f_to_kind(moment.kind())?)?;
+            }
+        }
+        self.steps = steps;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learning_rate_schedules() {
+        let warmup = OptimizerOptions { learning_rate: 1.0, warmup_steps: 4, ..OptimizerOptions::default() };
+        assert_eq!(warmup.learning_rate(0, 100), 0.25);
+        assert_eq!(warmup.learning_rate(3, 100), 1.0);
+        assert_eq!(warmup.learning_rate(50, 100), 1.0);
+
+        let step = OptimizerOptions { learning_rate: 1.0, schedule: LrSchedule::Step { every: 10, gamma: 0.5 }, ..OptimizerOptions::default() };
+        assert_eq!(step.learning_rate(9, 100), 1.0);
+        assert_eq!(step.learning_rate(25, 100), 0.25);
+
+        let cosine = OptimizerOptions { learning_rate: 1.0, schedule: LrSchedule::Cosine { min_lr: 0.1 }, ..OptimizerOptions::default() };
+        assert_eq!(cosine.learning_rate(0, 100), 1.0);
+        assert!((cosine.learning_rate(50, 100) - 0.55).abs() < 1e-12);
+        assert!((cosine.learning_rate(100, 100) - 0.1).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_loss_scaler() {
+        let mut scaler = LossScaler::new(LossScaling { initial_scale: 1024.0, growth_interval: 2 });
+        assert!(!scaler.update(false));
+        assert_eq!(scaler.scale(), 512.0);
+        assert!(scaler.update(true));
+        assert!(scaler.update(true));
+        assert_eq!(scaler.scale(), 1024.0);
+    }
+}
@@ -0,0 +1,54 @@
+use chess::{Board, ChessMove, MoveGen};
+
+use crate::bit_move::orient;
+
+// A policy head for the auxiliary move loss: after the evaluation output come 64 logits for the
+// square the move starts on and 64 for the square it goes to, both turned around for the side to
+// move like the features. Factored this way the head stays small enough for tiny networks, which
+// learn better features when they also have to say which piece moves where.
+pub const POLICY_OUTPUTS: usize = 128;
+pub(crate) const POLICY_OFFSET: usize = 1; // The evaluation output comes first
+
+pub fn policy_targets(board: &Board, chess_move: ChessMove) -> (i64, i64) {
+    // Class of the source square and of the destination square
+    let turn = board.side_to_move();
+    (orient(chess_move.get_source(), turn) as i64, orient(chess_move.get_dest(), turn) as i64)
+}
+
+pub fn best_policy_move(board: &Board, outputs: &[f32]) -> Option<ChessMove> {
+    // The legal move the head likes most, by the sum of its source and destination logits.
+    // outputs are a network's outputs for the board, the evaluation included.
+    let logits = outputs.get(POLICY_OFFSET..POLICY_OFFSET + POLICY_OUTPUTS)?;
+    MoveGen::new_legal(board)
+        .map(|chess_move| {
+            let (source, dest) = policy_targets(board, chess_move);
+            (chess_move, logits[source as usize] + logits[64 + dest as usize])
+        })
+        .max_by(|(_, first), (_, second)| first.total_cmp(second))
+        .map(|(chess_move, _)| chess_move)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::Square;
+
+    use super::*;
+
+    #[test]
+    fn test_policy_targets() {
+        // Black's squares are turned around, e7e5 looks like d2d4
+        let e4 = ChessMove::new(Square::E2, Square::E4, None);
+        let after = Board::default().make_move_new(e4);
+        assert_eq!(policy_targets(&Board::default(), e4), (12, 28));
+        assert_eq!(policy_targets(&after, ChessMove::new(Square::E7, Square::E5, None)), (11, 27));
+
+        let board = Board::from_str("4k3/8/8/8/8/8/8/3QK3 w - - 0 1").unwrap();
+        let mut outputs = vec![0.0; 1 + POLICY_OUTPUTS];
+        outputs[POLICY_OFFSET + 3] = 1.0; // From d1
+        outputs[POLICY_OFFSET + 64 + 51] = 2.0; // To d7
+        assert_eq!(best_policy_move(&board, &outputs), Some(ChessMove::new(Square::D1, Square::D7, None)));
+        assert_eq!(best_policy_move(&board, &outputs[..1]), None);
+    }
+}
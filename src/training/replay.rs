@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+use std::path::Path;
+
+use crate::dataset::Sample;
+use crate::error::NNUEError;
+use crate::rng::XorShift;
+use crate::shards::{ShardManifest, ShardOptions, ShardReader};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayOptions {
+    pub capacity: usize, // The oldest samples are dropped beyond this
+    pub window: Option<usize>, // Only the newest samples are drawn from, None draws from all of them
+    pub priority_exponent: Option<f64>, // Draw proportionally to eval error to this power, None draws uniformly
+    pub seed: u64,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> ReplayOptions {
+        ReplayOptions { capacity: 1_000_000, window: None, priority_exponent: None, seed: 1 }
+    }
+}
+
+// Samples of the latest self-play cycles for training in between them. With prioritization, positions the
+// net still gets wrong are drawn more often: each sample's priority is its last measured error, and
+// new samples start at the highest priority so far so they're seen at least once soon.
+#[derive(Debug, Clone)]
+pub struct ReplayBuffer {
+    options: ReplayOptions,
+    samples: VecDeque<Sample>, // Oldest first
+    priorities: VecDeque<f64>,
+    rng: XorShift,
+}
+
+const MIN_PRIORITY: f64 = 1e-4; // Keeps perfectly predicted samples drawable
+
+impl ReplayBuffer {
+    pub fn new(options: ReplayOptions) -> ReplayBuffer {
+        ReplayBuffer { rng: XorShift::new(options.seed), options, samples: VecDeque::new(), priorities: VecDeque::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn push<I: IntoIterator<Item = Sample>>(&mut self, samples: I) {
+        let priority = self.priorities.iter().copied().reduce(f64::max).unwrap_or(1.0);
+        for sample in samples {
+            self.samples.push_back(sample);
+            self.priorities.push_back(priority);
+        }
+        while self.samples.len() > self.options.capacity {
+            self.samples.pop_front();
+            self.priorities.pop_front();
+        }
+    }
+
+    pub fn load_shards<P: AsRef<Path>>(&mut self, dir: P) -> Result<usize, NNUEError> {
+        // Fills the buffer from a sharded dataset on disk, newest shards last so they survive eviction.
+        // Shards that would be evicted right away aren't read. Returns the number of samples read.
+        let manifest = ShardManifest::load(&dir)?;
+        let (mut skipped, mut kept) = (manifest.shards.len(), 0);
+        while skipped > 0 && kept < self.options.capacity {
+            skipped -= 1;
+            kept += manifest.shards[skipped].samples;
+        }
+        let ordered = ShardOptions { shuffle_shards: false, shuffle_samples: false, ..ShardOptions::default() };
+        let mut added = 0;
+        for shard in ShardReader::open(&dir, ordered)?.skip(skipped) {
+            let shard = shard?;
+            added += shard.len();
+            self.push(shard);
+        }
+        Ok(added)
+    }
+
+    fn window_start(&self) -> usize {
+        self.options.window.map_or(0, |window| self.samples.len().saturating_sub(window))
+    }
+
+    pub fn sample_indices(&mut self, count: usize) -> Vec<usize> {
+        // Draws with replacement from the window, the indices stay valid until the next push
+        let start = self.window_start();
+        if start == self.samples.len() {
+            return Vec::new();
+        }
+        let Some(exponent) = self.options.priority_exponent else {
+            return (0..count).map(|_| start + self.rng.below(self.samples.len() - start)).collect();
+        };
+
+        let mut cumulative = Vec::with_capacity(self.samples.len() - start);
+        let mut total = 0.0;
+        for priority in self.priorities.range(start..) {
+            total += priority.max(MIN_PRIORITY).powf(exponent);
+            cumulative.push(total);
+        }
+        (0..count)
+            .map(|_| {
+                let target = self.rng.next_f64() * total;
+                start + cumulative.partition_point(|sum| *sum <= target).min(cumulative.len() - 1)
+            })
+            .collect()
+    }
+
+    pub fn sample(&mut self, count: usize) -> Vec<Sample> {
+        self.sample_indices(count).into_iter().map(|index| self.samples[index]).collect()
+    }
+
+    pub fn prioritized(&self) -> bool {
+        self.options.priority_exponent.is_some()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Sample> {
+        self.samples.get(index)
+    }
+
+    pub fn priority(&self, index: usize) -> Option<f64> {
+        self.priorities.get(index).copied()
+    }
+
+    pub fn set_priority(&mut self, index: usize, error: f64) {
+        if let Some(priority) = self.priorities.get_mut(index) {
+            *priority = error.abs();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::Board;
+
+    use super::*;
+
+    fn sample(score: i16) -> Sample {
+        Sample { board: Board::default(), score, result: 0.5, best_move: None }
+    }
+
+    #[test]
+    fn test_replay_window_and_priorities() {
+        let mut buffer = ReplayBuffer::new(ReplayOptions { capacity: 10, window: Some(4), ..ReplayOptions::default() });
+        buffer.push((0..12).map(sample));
+        assert_eq!((buffer.len(), buffer.get(0).unwrap().score), (10, 2));
+        assert!(buffer.sample(100).iter().all(|sample| sample.score >= 8));
+
+        // Only one sample has any error left, it takes nearly every draw
+        let mut prioritized = ReplayBuffer::new(ReplayOptions { capacity: 10, priority_exponent: Some(1.0), ..ReplayOptions::default() });
+        prioritized.push((0..10).map(sample));
+        for index in 0..10 {
+            prioritized.set_priority(index, if index == 3 { 0.5 } else { 0.0 });
+        }
+        let draws = prioritized.sample(1000);
+        assert!(draws.iter().filter(|sample| sample.score == 3).count() > 950);
+        // New samples start at the top priority
+        prioritized.push([Sample { board: Board::from_str("4k3/8/8/8/8/8/8/3QK3 w - - 0 1").unwrap(), score: 900, result: 1.0, best_move: None }]);
+        let draws = prioritized.sample(1000);
+        assert!(draws.iter().filter(|sample| sample.score == 900).count() > 400);
+    }
+}
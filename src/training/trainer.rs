@@ -0,0 +1,682 @@
+use std::f64::consts::LN_10;
+use std::path::{Path, PathBuf};
+
+use chess::{Board, Color};
+use tch::nn::{self, Module};
+use tch::{Device, Kind, Reduction, Tensor};
+
+use crate::bit_move::active_indices_for;
+use crate::builder::resolve_device;
+use crate::dataset::Sample;
+use crate::error::NNUEError;
+use crate::features::FeatureSet;
+use crate::native::{save_network, LayerWeights};
+use crate::network::NetworkConfig;
+use crate::metadata::{read_metadata, write_metadata};
+use crate::onnx::save_onnx;
+use crate::perspective::from_white;
+use crate::rng::XorShift;
+use crate::shards::{ShardOptions, ShardReader};
+use crate::training::checkpoint::{self, TrainingState};
+use crate::training::factorize::Factorizer;
+use crate::training::metrics::{correlation, sign_accuracy, EpochMetrics, MetricsLog, ValidationMetrics};
+use crate::training::optim::{LossScaler, Optimizer, OptimizerOptions};
+use crate::training::policy::{policy_targets, POLICY_OFFSET, POLICY_OUTPUTS};
+use crate::training::replay::ReplayBuffer;
+use crate::training::widen::widen_layers;
+
+const NUM_FEATURES: i64 = 768;
+
+pub(crate) fn blend_target(result: f64, score_probability: f64, result_weight: f64, label_smoothing: f64) -> f64 {
+    // Win probability target from the game result and the score label, both for the side to move
+    let target = result_weight * result + (1.0 - result_weight) * score_probability;
+    target * (1.0 - label_smoothing) + 0.5 * label_smoothing
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainerOptions {
+    pub network: NetworkConfig, // Architecture of the trained model, load the exported weights with the same config
+    pub epochs: usize,
+    pub batch_size: usize,
+    pub optimizer: OptimizerOptions, // Optimizer, weight decay, clipping and learning rate schedule
+    pub scale: f64, // Output units for a tenfold change in predicted odds, matches the Texel scale
+    pub result_weight: f64, // Blend of game result and score label in the target, 0.0 is scores only (lambda 1 elsewhere)
+    pub final_result_weight: Option<f64>, // Moves result_weight linearly to this by the last epoch
+    pub label_smoothing: f64, // Pulls targets towards 0.5 by this share, so no position is trained as a sure win
+    pub policy_weight: f64, // Weight of the move loss on samples with a best_move, needs 1 + POLICY_OUTPUTS outputs
+    pub patience: Option<usize>, // Stop after this many epochs without a better validation loss
+    pub checkpoint_dir: Option<PathBuf>, // Best weights and the resumable state of the last epoch, see checkpoint
+    pub metrics_path: Option<PathBuf>, // CSV metric log
+    pub factorizer: Option<Factorizer>, // Virtual features folded away on export, see factorize
+    pub seed: u64,
+}
+
+impl Default for TrainerOptions {
+    fn default() -> TrainerOptions {
+        TrainerOptions {
+            network: NetworkConfig::default(),
+            epochs: 20,
+            batch_size: 1024,
+            optimizer: OptimizerOptions::default(),
+            scale: 400.0,
+            result_weight: 0.0,
+            final_result_weight: None,
+            label_smoothing: 0.0,
+            policy_weight: 0.0,
+            patience: Some(3),
+            checkpoint_dir: None,
+            metrics_path: None,
+            factorizer: None,
+            seed: 0x5eed,
+        }
+    }
+}
+
+enum TrainData<'a> {
+    Samples(&'a [Sample]),
+    Shards(&'a Path, ShardOptions),
+}
+
+#[derive(Debug)]
+pub struct Trainer {
+    options: TrainerOptions,
+    device: Device,
+    vs: nn::VarStore,
+    model: nn::Sequential,
+    rng: XorShift,
+    state: TrainingState,
+    scaler: Option<LossScaler>, // With mixed precision, starts over on resume
+    optimizer: Option<Optimizer>, // Restored by resume, kept between fit calls
+    replay_optimizer: Option<Optimizer>, // Kept between train_replay cycles
+}
+
+impl Trainer {
+    pub fn new(options: TrainerOptions) -> Result<Trainer, NNUEError> {
+        if options.network.inputs != NUM_FEATURES as usize {
+            return Err(NNUEError::InvalidConfig(format!("the trainer encodes {} features, not {}", NUM_FEATURES, options.network.inputs)));
+        }
+        for weight in [Some(options.result_weight), options.final_result_weight].into_iter().flatten() {
+            if !(0.0..=1.0).contains(&weight) {
+                return Err(NNUEError::InvalidConfig(format!("result weights must be within 0 and 1, got {}", weight)));
+            }
+        }
+        if !(0.0..1.0).contains(&options.label_smoothing) {
+            return Err(NNUEError::InvalidConfig(format!("label smoothing must be at least 0 and below 1, got {}", options.label_smoothing)));
+        }
+        if !(options.policy_weight.is_finite() && options.policy_weight >= 0.0) {
+            return Err(NNUEError::InvalidConfig(format!("policy weight must be finite and not negative, got {}", options.policy_weight)));
+        }
+        if options.policy_weight > 0.0 && options.network.outputs < POLICY_OFFSET + POLICY_OUTPUTS {
+            return Err(NNUEError::InvalidConfig(format!(
+                "a policy weight needs {} network outputs, the evaluation and the policy head",
+                POLICY_OFFSET + POLICY_OUTPUTS
+            )));
+        }
+        let device = resolve_device(None)?;
+        let vs = nn::VarStore::new(device);
+        // Virtual features widen the trained first layer only
+        let virtual_features = options.factorizer.map_or(0, Factorizer::virtual_features);
+        let trained = NetworkConfig { inputs: options.network.inputs + virtual_features, ..options.network.clone() };
+        let model = trained.build(&vs.root());
+
+        Ok(Trainer {
+            rng: XorShift::new(options.seed),
+            scaler: options.optimizer.mixed_precision.map(LossScaler::new),
+            options,
+            device,
+            vs,
+            model,
+            state: TrainingState::default(),
+            optimizer: None,
+            replay_optimizer: None,
+        })
+    }
+
+    pub fn resume(options: TrainerOptions) -> Result<Trainer, NNUEError> {
+        // Picks up from the last finished epoch in options.checkpoint_dir
+        let dir = options
+            .checkpoint_dir
+            .clone()
+            .ok_or_else(|| NNUEError::InvalidConfig("resuming needs a checkpoint_dir".to_string()))?;
+        let mut trainer = Trainer::new(options)?;
+        let mut optimizer = trainer.options.optimizer.build(&trainer.vs)?;
+        trainer.state = checkpoint::load(&dir, &mut trainer.vs, &mut optimizer)?;
+        trainer.optimizer = Some(optimizer);
+        trainer.rng = XorShift::new(trainer.state.rng);
+        Ok(trainer)
+    }
+
+    pub fn state(&self) -> TrainingState {
+        self.state
+    }
+
+    pub fn var_store(&self) -> &nn::VarStore {
+        &self.vs
+    }
+
+    fn input_width(&self) -> usize {
+        NUM_FEATURES as usize + self.options.factorizer.map_or(0, Factorizer::virtual_features)
+    }
+
+    fn trained_config(&self) -> NetworkConfig {
+        // The network as trained, virtual features widen the first layer
+        NetworkConfig { inputs: self.input_width(), ..self.options.network.clone() }
+    }
+
+    fn trained_layers(&self) -> Result<Vec<LayerWeights>, NNUEError> {
+        // Weights of every layer as trained, in the torch.nn.Linear layout
+        let variables = self.vs.variables();
+        let mut layers = Vec::new();
+        for (layer, (inputs, outputs)) in self.trained_config().layer_sizes().into_iter().enumerate() {
+            let tensor = |name: &str| {
+                variables
+                    .get(&format!("layer{}.{}", layer, name))
+                    .ok_or_else(|| NNUEError::InvalidWeights(format!("missing layer{}.{}", layer, name)))
+            };
+            layers.push(LayerWeights {
+                inputs,
+                outputs,
+                weights: Vec::<f32>::try_from(tensor("weight")?.f_to_device(Device::Cpu)?.f_view([-1])?)?,
+                biases: Vec::<f32>::try_from(tensor("bias")?.f_to_device(Device::Cpu)?)?,
+            });
+        }
+        Ok(layers)
+    }
+
+    fn layers(&self, feature_major_first: bool) -> Result<Vec<LayerWeights>, NNUEError> {
+        // Weights of every layer in the torch.nn.Linear layout with virtual features folded in,
+        // optionally with the first layer transposed
+        let mut layers = self.trained_layers()?;
+        let first = &mut layers[0];
+        if let Some(factorizer) = self.options.factorizer {
+            first.weights = factorizer.fold(&first.weights, NUM_FEATURES as usize);
+            first.inputs = NUM_FEATURES as usize;
+        }
+        if feature_major_first {
+            let (inputs, outputs) = (first.inputs, first.outputs);
+            first.weights = (0..inputs * outputs).map(|i| first.weights[(i % outputs) * inputs + i / outputs]).collect();
+        }
+        Ok(layers)
+    }
+
+    pub fn widen_from(&mut self, smaller: &Trainer, input_map: &dyn Fn(usize) -> Option<usize>) -> Result<(), NNUEError> {
+        // Starts this trainer's network from a smaller trained one, computing the same scores, see
+        // widen_layers. input_map gives the smaller trainer's input behind each input of this one,
+        // virtual features included, for identical feature sets that is Some(input).
+        let layers = widen_layers(&smaller.trained_layers()?, &self.trained_config(), input_map, self.options.seed)?;
+        let mut variables = self.vs.variables();
+        tch::no_grad(|| {
+            for (index, layer) in layers.iter().enumerate() {
+                let mut copy = |name: &str, values: &[f32], shape: &[i64]| -> Result<(), NNUEError> {
+                    let variable = variables
+                        .get_mut(&format!("layer{}.{}", index, name))
+                        .ok_or_else(|| NNUEError::InvalidWeights(format!("missing layer{}.{}", index, name)))?;
+                    variable.f_copy_(&Tensor::f_from_slice(values)?.f_view(shape)?.f_to_device(self.device)?)?;
+                    Ok(())
+                };
+                copy("weight", &layer.weights, &[layer.outputs as i64, layer.inputs as i64])?;
+                copy("bias", &layer.biases, &[layer.outputs as i64])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn record_factorizer<P: AsRef<Path>>(&self, path: P) -> Result<(), NNUEError> {
+        // Exported nets have no virtual features left, the metadata notes how they were trained
+        if let Some(factorizer) = self.options.factorizer {
+            let mut metadata = read_metadata(&path)?;
+            metadata.factorizer = Some(factorizer.name().to_string());
+            write_metadata(&path, &metadata)?;
+        }
+        Ok(())
+    }
+
+    pub fn export_native<P: AsRef<Path>>(&self, path: P) -> Result<(), NNUEError> {
+        // Writes the native weight format, the first layer transposed to feature-major
+        save_network(&path, &self.layers(true)?, self.options.network.activation)?;
+        self.record_factorizer(path)
+    }
+
+    pub fn export_onnx<P: AsRef<Path>>(&self, path: P) -> Result<(), NNUEError> {
+        // The same network as an ONNX graph for external runtimes, it takes the dense 768 feature
+        // encoding of the side to move and returns the raw outputs
+        save_onnx(&path, &self.layers(false)?, self.options.network.activation)?;
+        self.record_factorizer(path)
+    }
+
+    fn label(sample: &Sample) -> f32 {
+        // Score label from the side to move, which is what the network predicts
+        from_white(sample.score, sample.board.side_to_move()) as f32
+    }
+
+    fn result_weight(&self) -> f64 {
+        // For the epoch being trained. A schedule changes the targets, so validation losses of
+        // different epochs aren't quite comparable, patience still goes by them.
+        match self.options.final_result_weight {
+            Some(last) => {
+                let progress = (self.state.epoch as f64 / self.options.epochs.saturating_sub(1).max(1) as f64).min(1.0);
+                self.options.result_weight + (last - self.options.result_weight) * progress
+            }
+            None => self.options.result_weight,
+        }
+    }
+
+    fn encode(&self, row: &mut [f32], board: &Board, colour: Color) {
+        // Dense features of board from colour's side, virtual features included
+        for index in active_indices_for(board, colour) {
+            row[index as usize] = 1.0;
+            if let Some(factorizer) = self.options.factorizer {
+                row[NUM_FEATURES as usize + factorizer.virtual_index(index)] += 1.0;
+            }
+        }
+    }
+
+    fn batch(&self, samples: &[&Sample]) -> Result<(Tensor, Tensor), NNUEError> {
+        // Dense inputs and win probability targets, both from the side to move
+        let width = self.input_width();
+        let mut inputs = vec![0f32; samples.len() * width];
+        let mut targets = Vec::with_capacity(samples.len());
+        let result_weight = self.result_weight();
+        for (row, sample) in samples.iter().enumerate() {
+            self.encode(&mut inputs[row * width..(row + 1) * width], &sample.board, sample.board.side_to_move());
+            let result = match sample.board.side_to_move() {
+                Color::White => sample.result as f64,
+                Color::Black => 1.0 - sample.result as f64,
+            };
+            let label = 1.0 / (1.0 + 10f64.powf(-Trainer::label(sample) as f64 / self.options.scale));
+            targets.push(blend_target(result, label, result_weight, self.options.label_smoothing) as f32);
+        }
+
+        let inputs = Tensor::f_from_slice(&inputs)?.f_view([samples.len() as i64, width as i64])?.f_to_device(self.device)?;
+        let targets = Tensor::f_from_slice(&targets)?.f_to_device(self.device)?;
+        Ok((inputs, targets))
+    }
+
+    fn policy_batch(&self, samples: &[&Sample]) -> Result<Option<(Tensor, Tensor, Tensor)>, NNUEError> {
+        // Rows of the samples that have a move, with their source and destination classes
+        if self.options.policy_weight == 0.0 {
+            return Ok(None);
+        }
+        let (mut rows, mut sources, mut dests) = (Vec::new(), Vec::new(), Vec::new());
+        for (row, sample) in samples.iter().enumerate() {
+            if let Some(chess_move) = sample.best_move {
+                let (source, dest) = policy_targets(&sample.board, chess_move);
+                rows.push(row as i64);
+                sources.push(source);
+                dests.push(dest);
+            }
+        }
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        let tensor = |values: &[i64]| Tensor::f_from_slice(values)?.f_to_device(self.device);
+        Ok(Some((tensor(&rows)?, tensor(&sources)?, tensor(&dests)?)))
+    }
+
+    fn policy_loss(output: &Tensor, (rows, sources, dests): &(Tensor, Tensor, Tensor)) -> Tensor {
+        // Cross entropy of the source and of the destination square
+        let logits = output.index_select(0, rows);
+        let source = logits.narrow(1, POLICY_OFFSET as i64, 64).cross_entropy_for_logits(sources);
+        let dest = logits.narrow(1, (POLICY_OFFSET + 64) as i64, 64).cross_entropy_for_logits(dests);
+        source + dest
+    }
+
+    fn evaluation(output: &Tensor) -> Tensor {
+        // The first output head is the evaluation, extra heads are not trained on score labels
+        output.select(1, 0)
+    }
+
+    fn loss(&self, output: &Tensor, targets: &Tensor) -> Tensor {
+        // Squared error in win probability space, so won positions don't dominate
+        (Trainer::evaluation(output) * (LN_10 / self.options.scale)).sigmoid().mse_loss(targets, Reduction::Mean)
+    }
+
+    fn forward(&self, inputs: &Tensor) -> Tensor {
+        // f16 autocast only pays off (and is only supported well) on CUDA, the loss is always in f32
+        let autocast = self.options.optimizer.mixed_precision.is_some() && self.device.is_cuda();
+        tch::autocast(autocast, || self.model.forward(inputs)).to_kind(Kind::Float)
+    }
+
+    fn total_steps(&self, samples: usize) -> usize {
+        let batches = samples.div_ceil(self.options.batch_size.max(1));
+        self.options.epochs * batches.div_ceil(self.options.optimizer.accumulation_steps.max(1))
+    }
+
+    fn train_samples(&mut self, optimizer: &mut Optimizer, samples: &[Sample], total_steps: usize) -> Result<f64, NNUEError> {
+        // Returns the summed loss of the samples, total_steps is the run's length for the schedule
+        let mut order: Vec<&Sample> = samples.iter().collect();
+        self.rng.shuffle(&mut order);
+
+        // Each optimizer step sums the gradients of accumulation_steps batches, each batch weighted by
+        // its share of the group's samples, so the step sees the mean loss over the whole group
+        let batches: Vec<&[&Sample]> = order.chunks(self.options.batch_size.max(1)).collect();
+        let mut total = 0.0;
+        for group in batches.chunks(self.options.optimizer.accumulation_steps.max(1)) {
+            optimizer.set_lr(self.options.optimizer.learning_rate(self.state.step, total_steps));
+            self.state.step += 1;
+            optimizer.zero_grad()?;
+
+            let group_size: usize = group.iter().map(|batch| batch.len()).sum();
+            let loss_scale = self.scaler.map_or(1.0, |scaler| scaler.scale());
+            for batch in group {
+                let (inputs, targets) = self.batch(batch)?;
+                let output = self.forward(&inputs);
+                let mut loss = self.loss(&output, &targets);
+                if let Some(policy) = self.policy_batch(batch)? {
+                    loss = loss + Trainer::policy_loss(&output, &policy) * self.options.policy_weight;
+                }
+                (&loss * (loss_scale * batch.len() as f64 / group_size as f64)).backward();
+                total += loss.f_double_value(&[])? * batch.len() as f64;
+            }
+            self.options.optimizer.apply_gradients(optimizer, self.scaler.as_mut())?;
+        }
+        Ok(total)
+    }
+
+    fn train_epoch(&mut self, optimizer: &mut Optimizer, data: &TrainData) -> Result<f64, NNUEError> {
+        match data {
+            TrainData::Samples(samples) => {
+                let total_steps = self.total_steps(samples.len());
+                Ok(self.train_samples(optimizer, samples, total_steps)? / samples.len().max(1) as f64)
+            }
+            TrainData::Shards(dir, options) => {
+                // A new shuffle every epoch, batches don't span shards
+                let reader = ShardReader::open(dir, ShardOptions { seed: self.rng.next_u64(), ..*options })?;
+                reader.manifest().check_features(&FeatureSet::default())?;
+                let total_steps = self.total_steps(reader.manifest().total_samples());
+                let (mut total, mut count) = (0.0, 0);
+                for shard in reader {
+                    let shard = shard?;
+                    total += self.train_samples(optimizer, &shard, total_steps)?;
+                    count += shard.len();
+                }
+                Ok(total / count.max(1) as f64)
+            }
+        }
+    }
+
+    fn errors(&self, samples: &[Sample]) -> Result<Vec<f64>, NNUEError> {
+        // Absolute error of each prediction in win probability, the space the loss works in
+        let mut errors = Vec::with_capacity(samples.len());
+        for chunk in samples.chunks(self.options.batch_size.max(1)) {
+            let chunk: Vec<&Sample> = chunk.iter().collect();
+            let (inputs, targets) = self.batch(&chunk)?;
+            let output = tch::no_grad(|| self.forward(&inputs));
+            let predictions = (Trainer::evaluation(&output) * (LN_10 / self.options.scale)).sigmoid();
+            errors.extend(Vec::<f32>::try_from((predictions - targets).abs().f_to_device(Device::Cpu)?)?.into_iter().map(f64::from));
+        }
+        Ok(errors)
+    }
+
+    pub fn train_replay(&mut self, buffer: &mut ReplayBuffer, samples: usize) -> Result<f64, NNUEError> {
+        // One training cycle on samples drawn from the buffer, for alternating self-play and training.
+        // The optimizer state carries over between cycles and options.epochs is the number of cycles
+        // the learning rate schedule spans. A prioritized buffer gets the drawn samples' new errors.
+        let indices = buffer.sample_indices(samples);
+        let drawn: Vec<Sample> = indices.iter().filter_map(|index| buffer.get(*index).copied()).collect();
+        let mut optimizer = match self.replay_optimizer.take() {
+            Some(optimizer) => optimizer,
+            None => self.options.optimizer.build(&self.vs)?,
+        };
+        let total_steps = self.total_steps(drawn.len());
+        let trained = self.train_samples(&mut optimizer, &drawn, total_steps);
+        self.replay_optimizer = Some(optimizer);
+        let loss = trained? / drawn.len().max(1) as f64;
+
+        if buffer.prioritized() {
+            for (index, error) in indices.iter().zip(self.errors(&drawn)?) {
+                buffer.set_priority(*index, error);
+            }
+        }
+        Ok(loss)
+    }
+
+    pub fn predict(&self, board: &Board, colour: Color) -> Result<f32, NNUEError> {
+        // The model's evaluation of board encoded from colour's side, unrounded. Evaluators encode
+        // from the side to move, and from the mover's side when scoring a move with forward.
+        let mut row = vec![0f32; self.input_width()];
+        self.encode(&mut row, board, colour);
+        let inputs = Tensor::f_from_slice(&row)?.f_view([1, row.len() as i64])?.f_to_device(self.device)?;
+        let output = tch::no_grad(|| self.forward(&inputs));
+        Ok(Trainer::evaluation(&output).f_double_value(&[0])? as f32)
+    }
+
+    pub fn validate(&self, samples: &[Sample]) -> Result<ValidationMetrics, NNUEError> {
+        let mut predictions = Vec::with_capacity(samples.len());
+        let mut total = 0.0;
+        for chunk in samples.chunks(self.options.batch_size.max(1)) {
+            let chunk: Vec<&Sample> = chunk.iter().collect();
+            let (inputs, targets) = self.batch(&chunk)?;
+            let output = tch::no_grad(|| self.forward(&inputs));
+            total += self.loss(&output, &targets).f_double_value(&[])? * chunk.len() as f64;
+            predictions.extend(Vec::<f32>::try_from(Trainer::evaluation(&output).f_to_device(Device::Cpu)?)?);
+        }
+
+        let labels: Vec<f32> = samples.iter().map(Trainer::label).collect();
+        Ok(ValidationMetrics {
+            loss: total / samples.len().max(1) as f64,
+            sign_accuracy: sign_accuracy(&predictions, &labels),
+            correlation: correlation(&predictions, &labels),
+        })
+    }
+
+    pub fn fit(&mut self, train: &[Sample], validation: &[Sample]) -> Result<Vec<EpochMetrics>, NNUEError> {
+        // Trains until the epoch budget or patience runs out, keeping the best checkpoint on disk
+        self.fit_data(TrainData::Samples(train), validation)
+    }
+
+    pub fn fit_shards<P: AsRef<Path>>(&mut self, dir: P, options: ShardOptions, validation: &[Sample]) -> Result<Vec<EpochMetrics>, NNUEError> {
+        // Like fit, streaming the training set from a sharded dataset so it never has to fit in memory.
+        // options.seed is ignored, each epoch draws its shuffle from the trainer's seed. The manifest is
+        // reread every epoch, so shards a generator appends in the meantime join the next epoch.
+        self.fit_data(TrainData::Shards(dir.as_ref(), options), validation)
+    }
+
+    fn fit_data(&mut self, train: TrainData, validation: &[Sample]) -> Result<Vec<EpochMetrics>, NNUEError> {
+        let mut optimizer = match self.optimizer.take() {
+            Some(optimizer) => optimizer,
+            None => self.options.optimizer.build(&self.vs)?,
+        };
+        let fitted = self.fit_epochs(&mut optimizer, train, validation);
+        self.optimizer = Some(optimizer);
+        fitted
+    }
+
+    fn fit_epochs(&mut self, optimizer: &mut Optimizer, train: TrainData, validation: &[Sample]) -> Result<Vec<EpochMetrics>, NNUEError> {
+        let mut log = match &self.options.metrics_path {
+            Some(path) => Some(MetricsLog::open(path, self.state.epoch > 0)?),
+            None => None,
+        };
+
+        // A resumed run continues counting epochs where the checkpoint left off
+        let mut history = Vec::new();
+        let patience = self.options.patience;
+        while self.state.epoch < self.options.epochs && patience.is_none_or(|patience| self.state.epochs_since_best < patience) {
+            let train_loss = self.train_epoch(optimizer, &train)?;
+            self.state.epoch += 1;
+            let metrics = EpochMetrics {
+                epoch: self.state.epoch,
+                train_loss,
+                validation: self.validate(validation)?,
+            };
+            if let Some(log) = log.as_mut() {
+                log.epoch(&metrics)?;
+            }
+            history.push(metrics);
+
+            let improved = self.state.best_loss.is_none_or(|best| metrics.validation.loss < best);
+            if improved {
+                self.state.best_loss = Some(metrics.validation.loss);
+                self.state.epochs_since_best = 0;
+            } else {
+                self.state.epochs_since_best += 1;
+            }
+            self.state.rng = self.rng.state();
+
+            if let Some(dir) = &self.options.checkpoint_dir {
+                checkpoint::save(dir, &self.vs, optimizer, &self.state)?;
+                if improved {
+                    self.vs.save(checkpoint::best_weights(dir))?;
+                }
+            }
+        }
+        Ok(history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chess::ChessMove;
+
+    use super::*;
+    use crate::native::NativeNNUE;
+    use crate::shallow_nnue::NNUE;
+    use crate::shards::write_shards;
+    use crate::training::replay::ReplayOptions;
+
+    fn material_samples() -> Vec<Sample> {
+        [
+            ("4k3/8/8/8/8/8/8/3QK3 w - - 0 1", 900),
+            ("4k3/8/8/8/8/8/8/3QK3 b - - 0 1", 900),
+            ("3qk3/8/8/8/8/8/8/4K3 w - - 0 1", -900),
+            ("3qk3/8/8/8/8/8/8/4K3 b - - 0 1", -900),
+        ]
+        .iter()
+        .map(|(fen, score)| Sample { board: Board::from_str(fen).unwrap(), score: *score, result: 0.5, best_move: None })
+        .collect()
+    }
+
+    #[test]
+    fn test_trainer_fits_material() {
+        let samples = material_samples();
+
+        let optimizer = OptimizerOptions { learning_rate: 1e-2, ..OptimizerOptions::default() };
+        let network = NetworkConfig { hidden: vec![8], ..NetworkConfig::default() };
+        let options = TrainerOptions { network, epochs: 200, batch_size: 4, optimizer, patience: None, ..TrainerOptions::default() };
+        let mut trainer = Trainer::new(options).unwrap();
+        let history = trainer.fit(&samples, &samples).unwrap();
+
+        assert_eq!(history.len(), 200);
+        assert!(history[199].validation.loss < history[0].validation.loss);
+        assert_eq!(history[199].validation.sign_accuracy, 1.0);
+
+        // The exported weights load natively with the same config and agree on the sign
+        let path = std::env::temp_dir().join("shallow_nnue_trainer_export.bin");
+        trainer.export_native(&path).unwrap();
+        let mut native = NativeNNUE::load_with_config(&path, &trainer.options.network).unwrap();
+        native.set_board_hard(samples[0].board).unwrap();
+        assert!(native.evaluate().unwrap() > 0);
+
+        let onnx = std::env::temp_dir().join("shallow_nnue_trainer_export.onnx");
+        trainer.export_onnx(&onnx).unwrap();
+        assert!(std::fs::metadata(&onnx).unwrap().len() > 768 * 8 * 4);
+    }
+
+    #[test]
+    fn test_fit_shards() {
+        let samples = material_samples();
+        let dir = std::env::temp_dir().join("shallow_nnue_trainer_shards");
+        let _ = std::fs::remove_dir_all(&dir);
+        write_shards(&dir, &samples, 3, &FeatureSet::default()).unwrap();
+
+        let network = NetworkConfig { hidden: vec![8], ..NetworkConfig::default() };
+        let options = TrainerOptions { network, epochs: 3, batch_size: 2, patience: None, ..TrainerOptions::default() };
+        let mut trainer = Trainer::new(options).unwrap();
+        let history = trainer.fit_shards(&dir, ShardOptions::default(), &samples).unwrap();
+
+        // One entry per epoch, and batches don't span the shards of 3 and 1 samples
+        assert_eq!(history.iter().map(|metrics| metrics.epoch).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(trainer.state().step, 9);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_train_replay() {
+        let mut buffer = ReplayBuffer::new(ReplayOptions { priority_exponent: Some(1.0), ..ReplayOptions::default() });
+        buffer.push(material_samples());
+        let network = NetworkConfig { hidden: vec![8], ..NetworkConfig::default() };
+        let options = TrainerOptions { network, epochs: 2, batch_size: 2, ..TrainerOptions::default() };
+        let mut trainer = Trainer::new(options).unwrap();
+
+        // The second cycle keeps stepping the first cycle's optimizer
+        trainer.train_replay(&mut buffer, 4).unwrap();
+        assert_eq!(trainer.replay_optimizer.as_ref().unwrap().steps(), 2);
+        trainer.train_replay(&mut buffer, 4).unwrap();
+        assert_eq!(trainer.replay_optimizer.as_ref().unwrap().steps(), 4);
+
+        // Drawn samples get their error, a win probability, in place of the starting priority of 1
+        let priorities: Vec<f64> = (0..buffer.len()).map(|index| buffer.priority(index).unwrap()).collect();
+        assert!(priorities.iter().all(|priority| *priority == 1.0 || (0.0..1.0).contains(priority)));
+        assert!(priorities.iter().any(|priority| *priority < 1.0));
+    }
+
+    #[test]
+    fn test_resume_matches_uninterrupted() {
+        // A run resumed from a checkpoint takes the same steps as one that kept going, which needs
+        // the optimizer moments as well as the weights
+        let samples = material_samples();
+        let dir = std::env::temp_dir().join("shallow_nnue_trainer_resume");
+        let _ = std::fs::remove_dir_all(&dir);
+        let network = NetworkConfig { hidden: vec![8], ..NetworkConfig::default() };
+        let options = TrainerOptions { network, epochs: 1, batch_size: 2, patience: None, checkpoint_dir: Some(dir), ..TrainerOptions::default() };
+        let mut trainer = Trainer::new(options.clone()).unwrap();
+        trainer.fit(&samples, &samples).unwrap();
+
+        let mut resumed = Trainer::resume(TrainerOptions { epochs: 3, ..options }).unwrap();
+        assert_eq!(resumed.state().step, 2);
+        trainer.options.epochs = 3;
+        trainer.fit(&samples, &samples).unwrap();
+        resumed.fit(&samples, &samples).unwrap();
+
+        assert_eq!(resumed.state(), trainer.state());
+        for sample in &samples {
+            let expected = trainer.predict(&sample.board, Color::White).unwrap();
+            assert!((resumed.predict(&sample.board, Color::White).unwrap() - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_trainer_fits_policy() {
+        let moves = ["d1d7", "e8f7", "e1f2", "d8d2"];
+        let samples: Vec<Sample> = material_samples()
+            .into_iter()
+            .zip(moves)
+            .map(|(sample, best_move)| Sample { best_move: Some(ChessMove::from_str(best_move).unwrap()), ..sample })
+            .collect();
+        let network = NetworkConfig { hidden: vec![8], outputs: POLICY_OFFSET + POLICY_OUTPUTS, ..NetworkConfig::default() };
+        let optimizer = OptimizerOptions { learning_rate: 1e-2, ..OptimizerOptions::default() };
+        let options = TrainerOptions { network, epochs: 50, batch_size: 4, optimizer, patience: None, policy_weight: 1.0, ..TrainerOptions::default() };
+        let mut trainer = Trainer::new(options).unwrap();
+
+        let policy_loss = |trainer: &Trainer| {
+            let batch: Vec<&Sample> = samples.iter().collect();
+            let (inputs, _) = trainer.batch(&batch).unwrap();
+            let output = tch::no_grad(|| trainer.forward(&inputs));
+            Trainer::policy_loss(&output, &trainer.policy_batch(&batch).unwrap().unwrap()).double_value(&[])
+        };
+        let before = policy_loss(&trainer);
+        trainer.fit(&samples, &samples).unwrap();
+        assert!(policy_loss(&trainer) < before * 0.5);
+    }
+
+    #[test]
+    fn test_blend_target() {
+        // A won game whose score says the position is level
+        assert_eq!(blend_target(1.0, 0.5, 0.0, 0.0), 0.5);
+        assert_eq!(blend_target(1.0, 0.5, 1.0, 0.0), 1.0);
+        assert_eq!(blend_target(1.0, 0.5, 0.5, 0.0), 0.75);
+        // Smoothing moves a sure win a tenth of the way to a draw
+        assert!((blend_target(1.0, 0.5, 1.0, 0.1) - 0.95).abs() < 1e-12);
+        assert!((blend_target(0.0, 0.0, 0.0, 0.2) - 0.1).abs() < 1e-12);
+
+        let options = TrainerOptions { label_smoothing: 1.0, ..TrainerOptions::default() };
+        assert!(matches!(Trainer::new(options), Err(NNUEError::InvalidConfig(_))));
+        let options = TrainerOptions { final_result_weight: Some(1.5), ..TrainerOptions::default() };
+        assert!(matches!(Trainer::new(options), Err(NNUEError::InvalidConfig(_))));
+        // The policy loss needs the head's outputs
+        let options = TrainerOptions { policy_weight: 0.5, ..TrainerOptions::default() };
+        assert!(matches!(Trainer::new(options), Err(NNUEError::InvalidConfig(_))));
+    }
+}
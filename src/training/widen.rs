@@ -0,0 +1,118 @@
+use crate::error::NNUEError;
+use crate::native::LayerWeights;
+use crate::network::NetworkConfig;
+use crate::rng::XorShift;
+
+// Net2net style growth: a larger network that computes exactly what a trained smaller one does, to
+// continue training from instead of a random start. Hidden units are widened by copying existing
+// units, each copy's outgoing weights divided by the number of copies, so the next layer sums the
+// same. Inputs are tiled from the old ones through an input map, a bigger feature set whose features
+// each refine one old feature (a piece on a square with a king bucket for HalfKP style inputs) gives
+// the same first layer sums as long as one refinement of each old feature is active. Extra outputs,
+// like a new policy head, start at zero.
+// Layers are in the torch.nn.Linear layout, [outputs][inputs] for every layer.
+pub fn widen_layers(
+    layers: &[LayerWeights],
+    target: &NetworkConfig,
+    input_map: &dyn Fn(usize) -> Option<usize>,
+    seed: u64,
+) -> Result<Vec<LayerWeights>, NNUEError> {
+    let sizes = target.layer_sizes();
+    if sizes.len() != layers.len() {
+        return Err(NNUEError::InvalidConfig(format!("can't widen {} layers into {}", layers.len(), sizes.len())));
+    }
+    for (layer, (old, (_, outputs))) in layers.iter().zip(&sizes).enumerate() {
+        if *outputs < old.outputs || old.weights.len() != old.inputs * old.outputs || old.biases.len() != old.outputs {
+            return Err(NNUEError::InvalidConfig(format!("layer {} of {} outputs can't become {} outputs", layer, old.outputs, outputs)));
+        }
+    }
+
+    let mut rng = XorShift::new(seed);
+    // Old input of every new input, None for inputs that start at zero
+    let mut sources: Vec<Option<usize>> = (0..target.inputs).map(input_map).collect();
+    if let Some(bad) = sources.iter().flatten().find(|source| **source >= layers[0].inputs) {
+        return Err(NNUEError::InvalidConfig(format!("the input map points at input {} of {}", bad, layers[0].inputs)));
+    }
+    let mut widened = Vec::with_capacity(layers.len());
+    for (layer, (old, (inputs, outputs))) in layers.iter().zip(sizes).enumerate() {
+        // Copies of every old input among the new ones, they share the old weight between them
+        let mut copies = vec![0usize; old.inputs];
+        for source in sources.iter().flatten() {
+            copies[*source] += 1;
+        }
+        // The first layer's inputs are features, only one copy of each is active at a time
+        let share = |source: usize| if layer == 0 { 1.0 } else { copies[source] as f32 };
+
+        // New units past the old ones copy a random old unit, except on the output layer
+        let hidden = layer + 1 < layers.len();
+        let units: Vec<Option<usize>> =
+            (0..outputs).map(|unit| if unit < old.outputs { Some(unit) } else if hidden { Some(rng.below(old.outputs)) } else { None }).collect();
+
+        let mut weights = vec![0f32; outputs * inputs];
+        let mut biases = vec![0f32; outputs];
+        for (unit, copied) in units.iter().enumerate() {
+            let Some(copied) = copied else {
+                continue;
+            };
+            biases[unit] = old.biases[*copied];
+            for (input, source) in sources.iter().enumerate() {
+                if let Some(source) = source {
+                    weights[unit * inputs + input] = old.weights[copied * old.inputs + source] / share(*source);
+                }
+            }
+        }
+        widened.push(LayerWeights { inputs, outputs, weights, biases });
+        sources = units;
+    }
+    Ok(widened)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Activation;
+
+    fn forward(layers: &[LayerWeights], input: &[f32]) -> Vec<f32> {
+        let mut values = input.to_vec();
+        for (index, layer) in layers.iter().enumerate() {
+            values = (0..layer.outputs)
+                .map(|unit| {
+                    let sum = layer.biases[unit] + (0..layer.inputs).map(|i| layer.weights[unit * layer.inputs + i] * values[i]).sum::<f32>();
+                    if index + 1 < layers.len() { Activation::Relu.apply(sum) } else { sum }
+                })
+                .collect();
+        }
+        values
+    }
+
+    #[test]
+    fn test_widening_keeps_outputs() {
+        let mut rng = XorShift::new(3);
+        let mut layer = |inputs: usize, outputs: usize| LayerWeights {
+            inputs,
+            outputs,
+            weights: (0..inputs * outputs).map(|_| rng.below(200) as f32 / 100.0 - 1.0).collect(),
+            biases: (0..outputs).map(|_| rng.below(200) as f32 / 100.0 - 1.0).collect(),
+        };
+        let small = vec![layer(4, 3), layer(3, 2), layer(2, 1)];
+
+        // Every old input split in two, like a feature with two king buckets, and a head more
+        let target = NetworkConfig { inputs: 8, hidden: vec![7, 5], activation: Activation::Relu, outputs: 2 };
+        let large = widen_layers(&small, &target, &|input| Some(input % 4), 9).unwrap();
+        assert_eq!(large.iter().map(|layer| (layer.inputs, layer.outputs)).collect::<Vec<_>>(), target.layer_sizes());
+        for active in [[1.0, 0.0, 1.0, 1.0], [0.0, 1.0, 0.0, 0.0], [1.0, 1.0, 1.0, 1.0]] {
+            // Either bucket of a feature gives the small network's result
+            let mut split = [0.0; 8];
+            for (input, value) in active.iter().enumerate() {
+                split[input + 4 * (input % 2)] = *value;
+            }
+            let (before, after) = (forward(&small, &active), forward(&large, &split));
+            assert!((before[0] - after[0]).abs() < 1e-5, "{:?} {:?}", before, after);
+            assert_eq!(after[1], 0.0);
+        }
+
+        let narrower = NetworkConfig { inputs: 4, hidden: vec![2, 2], activation: Activation::Relu, outputs: 1 };
+        assert!(widen_layers(&small, &narrower, &|input| Some(input), 9).is_err());
+        assert!(widen_layers(&small, &target, &|input| Some(input), 9).is_err());
+    }
+}
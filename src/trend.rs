@@ -0,0 +1,76 @@
+use crate::error::NNUEError;
+
+// Exponential moving average of the scores along the current line, pushed and popped together with
+// the moves. Scores are kept from white's side, so they don't flip sign every ply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineTrend {
+    alpha: f32, // Weight of the newest score, 1 follows the last score only
+    averages: Vec<f32>, // Average after each pushed score, the current one last
+}
+
+impl LineTrend {
+    pub fn new(alpha: f32) -> Result<LineTrend, NNUEError> {
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            return Err(NNUEError::InvalidConfig(format!("trend alpha must be in (0, 1], got {}", alpha)));
+        }
+        Ok(LineTrend { alpha, averages: Vec::new() })
+    }
+
+    pub fn alpha(&self) -> f32 {
+        self.alpha
+    }
+
+    pub fn reset(&mut self) {
+        self.averages.clear();
+    }
+
+    pub fn push(&mut self, white_score: i16) {
+        // The first score starts the average
+        let average = match self.averages.last() {
+            Some(last) => last + self.alpha * (white_score as f32 - last),
+            None => white_score as f32,
+        };
+        self.averages.push(average);
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        // Back to the average after the first len scores
+        self.averages.truncate(len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.averages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.averages.is_empty()
+    }
+
+    pub fn value(&self) -> Option<f32> {
+        // From white's side, None before the first score
+        self.averages.last().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trend_follows_the_line() {
+        let mut trend = LineTrend::new(0.5).unwrap();
+        assert_eq!(trend.value(), None);
+        trend.push(100);
+        trend.push(200);
+        trend.push(-50);
+        assert_eq!(trend.value(), Some(50.0));
+
+        trend.truncate(2);
+        assert_eq!(trend.value(), Some(150.0));
+        trend.push(150);
+        assert_eq!((trend.len(), trend.value()), (3, Some(150.0)));
+
+        assert!(matches!(LineTrend::new(0.0), Err(NNUEError::InvalidConfig(_))));
+        assert!(LineTrend::new(1.5).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use chess::{ChessMove, Piece, ALL_SQUARES};
+
+// Transposition table shared by search threads without locks, usable by any engine keyed by Zobrist
+// hashes. Each slot holds two words, the data and the position key xored with it. Two threads
+// storing into a slot at once can leave the words of different entries, which then fail the key
+// check on probe instead of passing as a mix. Entries are stamped with the table's generation, so a
+// new search can overwrite what older searches left without clearing the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bound {
+    Exact,
+    Lower, // The search failed high, the score is at least this
+    Upper, // It failed low, the score is at most this
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtEntry {
+    pub score: i16, // Mate scores should be stored relative to the node, not the root
+    pub depth: u8,
+    pub bound: Bound,
+    pub chess_move: Option<ChessMove>, // Best or refuting move, not checked for legality in the probed position
+    pub generation: u8, // Set by store to the table's generation
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    check: AtomicU64, // Key xor data, zero with data zero for an empty slot
+    data: AtomicU64,
+}
+
+const PROMOTIONS: [Option<Piece>; 5] = [None, Some(Piece::Knight), Some(Piece::Bishop), Some(Piece::Rook), Some(Piece::Queen)];
+
+fn pack_move(chess_move: Option<ChessMove>) -> u64 {
+    // from | to << 6 | promotion << 12, with bit 15 set for a move
+    match chess_move {
+        Some(chess_move) => {
+            let promotion = PROMOTIONS.iter().position(|piece| *piece == chess_move.get_promotion()).unwrap_or(0) as u64;
+            1 << 15 | promotion << 12 | (chess_move.get_dest().to_index() as u64) << 6 | chess_move.get_source().to_index() as u64
+        }
+        None => 0,
+    }
+}
+
+fn unpack_move(bits: u64) -> Option<ChessMove> {
+    if bits & 1 << 15 == 0 {
+        return None;
+    }
+    let promotion = PROMOTIONS.get((bits >> 12 & 7) as usize).copied().flatten();
+    Some(ChessMove::new(ALL_SQUARES[(bits & 63) as usize], ALL_SQUARES[(bits >> 6 & 63) as usize], promotion))
+}
+
+fn pack(entry: &TtEntry) -> u64 {
+    // score in bits 0-15, depth 16-23, bound 24-25, move 26-41, generation 42-49. Bound codes start at
+    // one, so no stored entry packs to zero.
+    let bound = match entry.bound {
+        Bound::Exact => 1,
+        Bound::Lower => 2,
+        Bound::Upper => 3,
+    };
+    entry.score as u16 as u64 | (entry.depth as u64) << 16 | bound << 24 | pack_move(entry.chess_move) << 26 | (entry.generation as u64) << 42
+}
+
+fn unpack(data: u64) -> Option<TtEntry> {
+    let bound = match data >> 24 & 3 {
+        1 => Bound::Exact,
+        2 => Bound::Lower,
+        3 => Bound::Upper,
+        _ => return None,
+    };
+    Some(TtEntry {
+        score: data as u16 as i16,
+        depth: (data >> 16) as u8,
+        bound,
+        chess_move: unpack_move(data >> 26 & 0xffff),
+        generation: (data >> 42) as u8,
+    })
+}
+
+#[derive(Debug)]
+pub struct TranspositionTable {
+    slots: Vec<Slot>,
+    mask: u64, // Slot count minus one, the count is a power of two
+    generation: AtomicU8,
+}
+
+impl TranspositionTable {
+    pub fn new(megabytes: usize) -> TranspositionTable {
+        // The largest power of two number of slots that fits, at least one
+        let fits = ((megabytes << 20) / std::mem::size_of::<Slot>()).max(1);
+        let count = if fits.is_power_of_two() { fits } else { fits.next_power_of_two() / 2 };
+        TranspositionTable { slots: (0..count).map(|_| Slot::default()).collect(), mask: count as u64 - 1, generation: AtomicU8::new(0) }
+    }
+
+    pub fn len(&self) -> usize {
+        // Number of slots, one entry each
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn generation(&self) -> u8 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    pub fn new_search(&self) {
+        // Call once per search, entries of earlier generations are replaced first. Wraps after 256.
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn clear(&self) {
+        // Not meant to run during a search, a concurrent store may survive it
+        for slot in &self.slots {
+            slot.check.store(0, Ordering::Relaxed);
+            slot.data.store(0, Ordering::Relaxed);
+        }
+        self.generation.store(0, Ordering::Relaxed);
+    }
+
+    fn read(&self, key: u64) -> (&Slot, u64, u64) {
+        // The slot for key with its data and the key stored in it
+        let slot = &self.slots[(key & self.mask) as usize];
+        let data = slot.data.load(Ordering::Relaxed);
+        (slot, data, slot.check.load(Ordering::Relaxed) ^ data)
+    }
+
+    pub fn probe(&self, key: u64) -> Option<TtEntry> {
+        let (_, data, stored) = self.read(key);
+        match stored == key {
+            true => unpack(data),
+            false => None,
+        }
+    }
+
+    pub fn store(&self, key: u64, entry: TtEntry) {
+        // Replaces the slot's entry unless that is another position's from this generation searched
+        // deeper. A new entry for the same position keeps the old move if it has none.
+        let generation = self.generation();
+        let (slot, data, stored) = self.read(key);
+        let existing = unpack(data);
+        let mut entry = TtEntry { generation, ..entry };
+        match existing {
+            Some(existing) if stored == key => entry.chess_move = entry.chess_move.or(existing.chess_move),
+            Some(existing) if existing.generation == generation && existing.depth > entry.depth => return,
+            _ => {}
+        }
+        let data = pack(&entry);
+        slot.check.store(key ^ data, Ordering::Relaxed);
+        slot.data.store(data, Ordering::Relaxed);
+    }
+
+    pub fn hashfull(&self) -> usize {
+        // Permille of the first thousand slots holding entries of this generation, for UCI's info hashfull
+        let sample = self.slots.len().min(1000);
+        let generation = self.generation();
+        let current = self.slots[..sample]
+            .iter()
+            .filter_map(|slot| unpack(slot.data.load(Ordering::Relaxed)))
+            .filter(|entry| entry.generation == generation)
+            .count();
+        current * 1000 / sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chess::{Board, Square};
+
+    use super::*;
+
+    #[test]
+    fn test_store_and_probe() {
+        let table = TranspositionTable::new(1);
+        assert_eq!(table.len(), 1 << 16);
+        assert_eq!(TranspositionTable::new(3).len(), 1 << 17);
+        let key = Board::default().get_hash();
+        assert_eq!(table.probe(key), None);
+
+        let promotion = ChessMove::new(Square::A7, Square::A8, Some(Piece::Knight));
+        let entry = TtEntry { score: -29990, depth: 7, bound: Bound::Lower, chess_move: Some(promotion), generation: 0 };
+        table.store(key, entry);
+        assert_eq!(table.probe(key), Some(entry));
+
+        // Another position in the same slot misses, and a torn slot fails the key check
+        assert_eq!(table.probe(key ^ 1 << 40), None);
+        let slot = &table.slots[(key & table.mask) as usize];
+        slot.data.store(pack(&TtEntry { chess_move: None, ..entry }), Ordering::Relaxed);
+        assert_eq!(table.probe(key), None);
+    }
+
+    #[test]
+    fn test_replacement_by_generation() {
+        let table = TranspositionTable::new(1);
+        let key = 0x1234_5678_0000_0005; // A slot hashfull samples
+        let other = key ^ 1 << 40; // Same slot
+        let deep = TtEntry { score: 10, depth: 8, bound: Bound::Exact, chess_move: Some(ChessMove::new(Square::E2, Square::E4, None)), generation: 0 };
+        let shallow = TtEntry { score: 20, depth: 2, bound: Bound::Upper, chess_move: None, generation: 0 };
+
+        // A shallower entry of another position can't push out a deeper one of the same search
+        table.store(key, deep);
+        table.store(other, shallow);
+        assert_eq!(table.probe(key), Some(deep));
+        assert_eq!(table.hashfull(), 1);
+
+        // The same position always replaces, keeping the move, and any position replaces older searches
+        table.store(key, shallow);
+        assert_eq!(table.probe(key), Some(TtEntry { chess_move: deep.chess_move, ..shallow }));
+        table.store(key, deep);
+        table.new_search();
+        assert_eq!(table.hashfull(), 0);
+        table.store(other, shallow);
+        assert_eq!(table.probe(other), Some(TtEntry { generation: 1, ..shallow }));
+        assert_eq!(table.probe(key), None);
+
+        table.clear();
+        assert_eq!((table.probe(other), table.generation()), (None, 0));
+    }
+}
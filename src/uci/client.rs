@@ -0,0 +1,422 @@
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chess::{Board, ChessMove};
+
+use crate::error::NNUEError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Depth(u32),
+    Nodes(u64),
+    MoveTime(u64), // Milliseconds
+}
+
+impl Limit {
+    fn go_command(self) -> String {
+        match self {
+            Limit::Depth(depth) => format!("go depth {}", depth),
+            Limit::Nodes(nodes) => format!("go nodes {}", nodes),
+            Limit::MoveTime(millis) => format!("go movetime {}", millis),
+        }
+    }
+}
+
+// Engine scores are from the side to move, as UCI reports them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Score {
+    Centipawns(i32),
+    Mate(i32), // Moves to mate, negative when the side to move is getting mated
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+// One "info" line, fields the engine left out stay None
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Info {
+    pub depth: Option<u32>,
+    pub seldepth: Option<u32>,
+    pub multipv: Option<u32>,
+    pub score: Option<(Score, Bound)>,
+    pub nodes: Option<u64>,
+    pub nps: Option<u64>,
+    pub time: Option<u64>, // Milliseconds
+    pub pv: Vec<String>, // Long algebraic moves
+    pub string: Option<String>,
+}
+
+impl Info {
+    pub fn parse(line: &str) -> Option<Info> {
+        let mut tokens = line.split_whitespace();
+        if tokens.next() != Some("info") {
+            return None;
+        }
+        let mut info = Info::default();
+        while let Some(token) = tokens.next() {
+            match token {
+                "depth" => info.depth = tokens.next()?.parse().ok(),
+                "seldepth" => info.seldepth = tokens.next()?.parse().ok(),
+                "multipv" => info.multipv = tokens.next()?.parse().ok(),
+                "nodes" => info.nodes = tokens.next()?.parse().ok(),
+                "nps" => info.nps = tokens.next()?.parse().ok(),
+                "time" => info.time = tokens.next()?.parse().ok(),
+                "score" => {
+                    let kind = tokens.next()?;
+                    let value: i32 = tokens.next()?.parse().ok()?;
+                    let score = match kind {
+                        "cp" => Score::Centipawns(value),
+                        "mate" => Score::Mate(value),
+                        _ => return None,
+                    };
+                    info.score = Some((score, Bound::Exact));
+                }
+                "lowerbound" => info.score = info.score.map(|(score, _)| (score, Bound::Lower)),
+                "upperbound" => info.score = info.score.map(|(score, _)| (score, Bound::Upper)),
+                // Both run to the end of the line
+                "pv" => info.pv = tokens.by_ref().map(str::to_string).collect(),
+                "string" => info.string = Some(tokens.by_ref().collect::<Vec<&str>>().join(" ")),
+                _ => {}
+            }
+        }
+        Some(info)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchResult {
+    pub best_move: Option<String>, // Long algebraic, None for "bestmove (none)"
+    pub ponder: Option<String>,
+    pub score: Option<Score>, // Exact score of the deepest first-line info
+    pub depth: u32,
+    pub nodes: u64,
+    pub pv: Vec<String>,
+}
+
+impl SearchResult {
+    fn update(&mut self, info: &Info) {
+        // Only the main line counts, bound scores from fail highs and lows are not final
+        if info.multipv.unwrap_or(1) != 1 {
+            return;
+        }
+        if let Some(nodes) = info.nodes {
+            self.nodes = nodes;
+        }
+        if let (Some((score, Bound::Exact)), Some(depth)) = (info.score, info.depth) {
+            if depth >= self.depth {
+                self.depth = depth;
+                self.score = Some(score);
+                self.pv.clone_from(&info.pv);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientOptions {
+    pub handshake_timeout: Duration, // For uci/uciok and isready/readyok
+    pub search_timeout: Duration, // Extra time on top of a movetime limit, the whole budget for depth and nodes
+    pub max_restarts: usize, // Times a crashed or hung engine is started again before giving up
+    pub engine_options: Vec<(String, String)>, // Sent with setoption after every (re)start
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            handshake_timeout: Duration::from_secs(10),
+            search_timeout: Duration::from_secs(60),
+            max_restarts: 3,
+            engine_options: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>, // Filled by a reader thread, so reads can time out
+}
+
+impl Process {
+    fn spawn(path: &Path) -> Result<Process, NNUEError> {
+        let mut child = Command::new(path).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn()?;
+        let stdin = child.stdin.take().ok_or_else(|| NNUEError::Engine("no stdin".to_string()))?;
+        let stdout = child.stdout.take().ok_or_else(|| NNUEError::Engine("no stdout".to_string()))?;
+
+        // The thread ends when the engine closes its output, which drops the sender
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) if sender.send(line.trim_end().to_string()).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        Ok(Process { child, stdin, lines })
+    }
+
+    fn kill(&mut self) {
+        // Ask nicely first, a hung engine is killed either way
+        let _ = writeln!(self.stdin, "quit");
+        let _ = self.stdin.flush();
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[derive(Debug)]
+pub struct UciEngine {
+    path: PathBuf,
+    options: ClientOptions,
+    process: Process,
+    name: Option<String>,
+    restarts: usize,
+}
+
+impl UciEngine {
+    pub fn spawn<P: AsRef<Path>>(path: P) -> Result<UciEngine, NNUEError> {
+        UciEngine::with_options(path, ClientOptions::default())
+    }
+
+    pub fn with_options<P: AsRef<Path>>(path: P, options: ClientOptions) -> Result<UciEngine, NNUEError> {
+        let mut engine = UciEngine {
+            path: path.as_ref().to_path_buf(),
+            options,
+            process: Process::spawn(path.as_ref())?,
+            name: None,
+            restarts: 0,
+        };
+        engine.handshake()?;
+        Ok(engine)
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn restarts(&self) -> usize {
+        self.restarts
+    }
+
+    fn handshake(&mut self) -> Result<(), NNUEError> {
+        self.send("uci")?;
+        for line in self.read_until("uciok", self.options.handshake_timeout)? {
+            if let Some(name) = line.strip_prefix("id name ") {
+                self.name = Some(name.trim().to_string());
+            }
+        }
+        for (name, value) in self.options.engine_options.clone() {
+            self.send(&format!("setoption name {} value {}", name, value))?;
+        }
+        self.ready()
+    }
+
+    fn restart(&mut self) -> Result<(), NNUEError> {
+        self.process.kill();
+        self.process = Process::spawn(&self.path)?;
+        self.restarts += 1;
+        self.handshake()
+    }
+
+    pub fn send(&mut self, command: &str) -> Result<(), NNUEError> {
+        writeln!(self.process.stdin, "{}", command)?;
+        self.process.stdin.flush()?;
+        Ok(())
+    }
+
+    fn read_until(&mut self, token: &str, timeout: Duration) -> Result<Vec<String>, NNUEError> {
+        // Every line up to and including the first that starts with token
+        let deadline = Instant::now() + timeout;
+        let mut lines = Vec::new();
+        loop {
+            let line = match self.process.lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(NNUEError::Engine(format!("{} did not send {} in time", self.path.display(), token)))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(NNUEError::Engine(format!("{} closed its output", self.path.display())))
+                }
+            };
+            let done = line.split_whitespace().next() == Some(token);
+            lines.push(line);
+            if done {
+                return Ok(lines);
+            }
+        }
+    }
+
+    pub fn ready(&mut self) -> Result<(), NNUEError> {
+        self.send("isready")?;
+        self.read_until("readyok", self.options.handshake_timeout)?;
+        Ok(())
+    }
+
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), NNUEError> {
+        // Kept for restarts as well
+        self.options.engine_options.retain(|(existing, _)| existing != name);
+        self.options.engine_options.push((name.to_string(), value.to_string()));
+        self.send(&format!("setoption name {} value {}", name, value))
+    }
+
+    pub fn new_game(&mut self) -> Result<(), NNUEError> {
+        self.send("ucinewgame")?;
+        self.ready()
+    }
+
+    pub fn stop(&mut self) -> Result<SearchResult, NNUEError> {
+        // Ends a running search and collects its result
+        self.send("stop")?;
+        let lines = self.read_until("bestmove", self.options.handshake_timeout)?;
+        Ok(UciEngine::collect(&lines))
+    }
+
+    fn collect(lines: &[String]) -> SearchResult {
+        let mut result = SearchResult::default();
+        for line in lines {
+            if let Some(info) = Info::parse(line) {
+                result.update(&info);
+            } else if let Some(rest) = line.strip_prefix("bestmove") {
+                let mut tokens = rest.split_whitespace();
+                result.best_move = tokens.next().filter(|mve| *mve != "(none)").map(str::to_string);
+                if tokens.next() == Some("ponder") {
+                    result.ponder = tokens.next().map(str::to_string);
+                }
+            }
+        }
+        result
+    }
+
+    fn try_search(&mut self, position: &str, limit: Limit) -> Result<SearchResult, NNUEError> {
+        self.send(position)?;
+        self.send(&limit.go_command())?;
+
+        let timeout = match limit {
+            Limit::MoveTime(millis) => Duration::from_millis(millis) + self.options.search_timeout,
+            _ => self.options.search_timeout,
+        };
+        match self.read_until("bestmove", timeout) {
+            Ok(lines) => Ok(UciEngine::collect(&lines)),
+            // A slow engine gets a stop, one that doesn't answer that either is treated as crashed
+            Err(err) => self.stop().map_err(|_| err),
+        }
+    }
+
+    fn search_position(&mut self, position: String, limit: Limit) -> Result<SearchResult, NNUEError> {
+        // Protocol failures restart the engine and retry the same search
+        loop {
+            match self.try_search(&position, limit) {
+                Err(NNUEError::Engine(_)) | Err(NNUEError::Io(_)) if self.restarts < self.options.max_restarts => self.restart()?,
+                result => return result,
+            }
+        }
+    }
+
+    pub fn search(&mut self, board: &Board, limit: Limit) -> Result<SearchResult, NNUEError> {
+        self.search_position(format!("position fen {}", board), limit)
+    }
+
+    pub fn search_moves(&mut self, start: &Board, moves: &[ChessMove], limit: Limit) -> Result<SearchResult, NNUEError> {
+        // Sends the game history so the engine can see repetitions
+        let moves: Vec<String> = moves.iter().map(|mve| mve.to_string()).collect();
+        let position = if moves.is_empty() {
+            format!("position fen {}", start)
+        } else {
+            format!("position fen {} moves {}", start, moves.join(" "))
+        };
+        self.search_position(position, limit)
+    }
+}
+
+impl Drop for UciEngine {
+    fn drop(&mut self) {
+        self.process.kill();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    pub(crate) fn fake_engine_script(name: &str, on_go: &str) -> PathBuf {
+        // Shell script speaking just enough UCI, on_go is the shell code run for every "go"
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("shallow_nnue_fake_engine_{}.sh", name));
+        let script = format!(
+            "#!/bin/sh\nwhile read -r cmd rest; do\n  case \"$cmd\" in\n    uci) echo \"id name {}\"; echo uciok ;;\n    isready) echo readyok ;;\n    go) {} ;;\n    quit) exit 0 ;;\n  esac\ndone\n",
+            name, on_go
+        );
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn fake_engine(name: &str, info: &str) -> PathBuf {
+        // Every search answers with the given info line and e2e4
+        fake_engine_script(name, &format!("echo \"{}\"; echo \"bestmove e2e4\"", info))
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let info = Info::parse("info depth 12 seldepth 18 multipv 1 score cp -35 nodes 1000 nps 5000 time 200 pv e2e4 e7e5").unwrap();
+        assert_eq!(info.depth, Some(12));
+        assert_eq!(info.score, Some((Score::Centipawns(-35), Bound::Exact)));
+        assert_eq!(info.pv, vec!["e2e4", "e7e5"]);
+        assert_eq!(info.time, Some(200));
+
+        let bound = Info::parse("info depth 9 score mate -3 lowerbound").unwrap();
+        assert_eq!(bound.score, Some((Score::Mate(-3), Bound::Lower)));
+        assert_eq!(Info::parse("info string hello world").unwrap().string.as_deref(), Some("hello world"));
+        assert_eq!(Info::parse("bestmove e2e4"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_search_fake_engine() {
+        let path = fake_engine("client", "info depth 7 score cp 42 pv e2e4");
+        let mut engine = UciEngine::spawn(&path).unwrap();
+        assert_eq!(engine.name(), Some("client"));
+
+        let result = engine.search(&Board::default(), Limit::Depth(7)).unwrap();
+        assert_eq!(result.best_move.as_deref(), Some("e2e4"));
+        assert_eq!((result.score, result.depth), (Some(Score::Centipawns(42)), 7));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_recovers_from_crash_and_timeout() {
+        // The first search kills the engine, the restarted one answers
+        let marker = std::env::temp_dir().join("shallow_nnue_fake_engine_crash.marker");
+        let _ = std::fs::remove_file(&marker);
+        let on_go = format!(
+            "if [ -f {0} ]; then echo \"info depth 1 score cp 5\"; echo \"bestmove e2e4\"; else touch {0}; exit 1; fi",
+            marker.display()
+        );
+        let mut engine = UciEngine::spawn(fake_engine_script("crash", &on_go)).unwrap();
+        assert_eq!(engine.search(&Board::default(), Limit::Depth(1)).unwrap().score, Some(Score::Centipawns(5)));
+        assert_eq!(engine.restarts(), 1);
+        std::fs::remove_file(&marker).unwrap();
+
+        // An engine that never answers runs out of restarts and reports the timeout
+        let options = ClientOptions { search_timeout: Duration::from_millis(50), handshake_timeout: Duration::from_millis(200), max_restarts: 1, ..ClientOptions::default() };
+        let mut engine = UciEngine::with_options(fake_engine_script("hang", "true"), options).unwrap();
+        assert!(matches!(engine.search(&Board::default(), Limit::Nodes(100)), Err(NNUEError::Engine(_))));
+        assert_eq!(engine.restarts(), 1);
+    }
+}
@@ -0,0 +1,148 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use chess::{Board, ChessMove, Color};
+
+use crate::engine::{EngineCore, Frontend, SearchLimit};
+use crate::error::NNUEError;
+
+// The engine side of UCI, for GUIs driving an EngineCore (client is the other side). "go" answers
+// once the search reached its limit or a "stop" arrived, there is no pondering.
+pub struct UciFrontend {
+    core: EngineCore,
+    name: String,
+}
+
+fn parse_moves(names: &[&str]) -> Result<Vec<ChessMove>, NNUEError> {
+    names
+        .iter()
+        .map(|name| ChessMove::from_str(name).map_err(|_| NNUEError::InvalidData(format!("bad move {:?}", name))))
+        .collect()
+}
+
+impl UciFrontend {
+    pub fn new(core: EngineCore, name: String) -> UciFrontend {
+        UciFrontend { core, name }
+    }
+
+    fn set_option(&mut self, args: &[&str]) -> Result<(), NNUEError> {
+        // setoption name <name> value <value>, both may contain spaces
+        let value_at = args.iter().position(|token| *token == "value").unwrap_or(args.len());
+        let name = args.get(1..value_at).unwrap_or_default().join(" ");
+        let value = args.get(value_at + 1..).unwrap_or_default().join(" ");
+        // Options the engine doesn't have are ignored
+        self.core.set_option(&name, &value).map(|_| ())
+    }
+
+    fn position(&mut self, args: &[&str]) -> Result<(), NNUEError> {
+        // position (startpos | fen <fen>) [moves <move>...]
+        let moves_at = args.iter().position(|token| *token == "moves").unwrap_or(args.len());
+        let start = match args.first() {
+            Some(&"startpos") => Board::default(),
+            Some(&"fen") => {
+                let fen = args[1..moves_at].join(" ");
+                Board::from_str(&fen).map_err(|_| NNUEError::InvalidData(format!("bad fen {:?}", fen)))?
+            }
+            _ => return Err(NNUEError::InvalidData("position needs startpos or fen".to_string())),
+        };
+        let moves = parse_moves(args.get(moves_at + 1..).unwrap_or_default())?;
+        self.core.set_position(start, &moves).map(|_| ())
+    }
+
+    fn limit(&self, args: &[&str]) -> SearchLimit {
+        // A depth or movetime wins over the clocks, without any of them the search runs to the options' depth
+        let value = |name: &str| args.iter().position(|token| *token == name).and_then(|at| args.get(at + 1)?.parse::<u64>().ok());
+        let (time, increment) = match self.core.board().side_to_move() {
+            Color::White => ("wtime", "winc"),
+            Color::Black => ("btime", "binc"),
+        };
+        if let Some(depth) = value("depth") {
+            SearchLimit::Depth(depth.min(u8::MAX as u64) as u8)
+        } else if let Some(millis) = value("movetime") {
+            SearchLimit::MoveTime(Duration::from_millis(millis))
+        } else if let Some(remaining_ms) = value(time) {
+            SearchLimit::Clock { remaining_ms, increment_ms: value(increment).unwrap_or(0), moves_to_go: value("movestogo") }
+        } else {
+            SearchLimit::Depth(self.core.options().depth)
+        }
+    }
+}
+
+impl Frontend for UciFrontend {
+    fn handle(&mut self, line: &str, out: &mut Vec<String>) -> Result<bool, NNUEError> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.first().copied() {
+            Some("uci") => {
+                out.push(format!("id name {}", self.name));
+                out.push("id author shallowNNUE".to_string());
+                out.extend(EngineCore::uci_options());
+                out.push("uciok".to_string());
+            }
+            Some("isready") => out.push("readyok".to_string()),
+            Some("ucinewgame") => self.core.new_game()?,
+            Some("setoption") => self.set_option(&args[1..])?,
+            Some("position") => self.position(&args[1..])?,
+            Some("go") => {
+                let result = self.core.search(self.limit(&args[1..]))?;
+                out.push(result.report().to_uci_info());
+                out.push(format!("bestmove {}", result.search.best_move.map_or("0000".to_string(), |chess_move| chess_move.to_string())));
+            }
+            // run_frontend already stopped the search when it read the line, after a search there is nothing to stop
+            Some("stop") => {}
+            Some("quit") => return Ok(false),
+            _ => {} // ponderhit, debug and anything unknown are ignored, as the protocol asks
+        }
+        Ok(true)
+    }
+
+    fn core(&self) -> &EngineCore {
+        &self.core
+    }
+
+    fn stop_command(&self) -> &'static str {
+        "stop"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::run_frontend;
+    use crate::engine::tests::material_core;
+    use crate::search::SearchOptions;
+
+    #[test]
+    fn test_uci_session() {
+        let core = material_core(SearchOptions::default());
+        let mut frontend = UciFrontend::new(core, "shallow".to_string());
+        // Threads goes up after the search, helpers filling the table would make the score depend on timing
+        let input = "uci\nsetoption name Hash value 2\nsetoption name Tempo value 10\nisready\nucinewgame\n\
+                     position fen 4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1 moves e1e2 e8e7\ngo depth 2\n\
+                     setoption name Threads value 2\nquit\nisready\n";
+        let mut output = Vec::new();
+        run_frontend(&mut frontend, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(lines[0], "id name shallow");
+        assert!(lines[2].starts_with("option name Threads") && lines[4].starts_with("option name Skill Level"));
+        assert!(lines[7].starts_with("option name Tempo"));
+        assert_eq!(lines[8..10], ["uciok", "readyok"]);
+        assert!(lines[10].starts_with("info depth 2 score cp 110")); // The tempo on top of the pawn
+        assert_eq!(lines[11], "bestmove e4d5");
+        assert_eq!(lines.len(), 12); // Nothing after quit
+        assert_eq!((frontend.core().smp_settings().threads, frontend.core().tempo()), (2, 10));
+    }
+
+    #[test]
+    fn test_uci_stop() {
+        // Without the stop this search runs for a minute
+        let mut frontend = UciFrontend::new(material_core(SearchOptions { depth: 64, ..SearchOptions::default() }), "shallow".to_string());
+        let input = "position fen 4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1\ngo movetime 60000\nstop\nquit\n";
+        let mut output = Vec::new();
+        let start = std::time::Instant::now();
+        run_frontend(&mut frontend, input.as_bytes(), &mut output).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(String::from_utf8(output).unwrap().lines().last().is_some_and(|line| line.starts_with("bestmove ")));
+    }
+}